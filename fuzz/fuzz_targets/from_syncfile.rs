@@ -0,0 +1,53 @@
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate grey_crypt;
+extern crate toml;
+
+use std::env;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::process;
+
+use grey_crypt::config;
+use grey_crypt::crypto_util;
+use grey_crypt::mapping;
+use grey_crypt::syncfile::SyncFile;
+
+// The key and keyword that the unit tests use, so that the syncfiles in testdata/ are readable
+// and mutations of them get past the header hmac now and then.
+fn fuzz_config() -> config::SyncConfig {
+    let wd = env::current_dir().unwrap();
+    let mapping = format!("gcprojroot = '{}'", wd.to_str().unwrap());
+    let mapping = toml::Parser::new(&mapping).parse().unwrap();
+    let mapping = mapping::Mapping::new(&mapping).unwrap();
+    let mut sync_dir = env::temp_dir();
+    sync_dir.push("greycrypt_fuzz");
+    config::SyncConfig::new(
+        sync_dir.to_str().unwrap().to_owned(),
+        "FuzzHost".to_owned(),
+        mapping,
+        Some(crypto_util::SecretKey::zeroed()),
+        None,
+        Vec::new())
+}
+
+// Every entry point that reads a syncfile must return an error, not panic, whatever the input.
+fuzz_target!(|data: &[u8]| {
+    let conf = fuzz_config();
+    let mut path = PathBuf::from(env::temp_dir());
+    // one per process, so that parallel jobs (-jobs, -workers) don't overwrite each other's input
+    path.push(format!("greycrypt_fuzz_input_{}.dat", process::id()));
+    {
+        let mut f = File::create(&path).unwrap();
+        f.write_all(data).unwrap();
+    }
+
+    let _ = SyncFile::key_available(&conf, &path);
+    let _ = SyncFile::get_syncid_from_file(&conf, &path);
+    let _ = SyncFile::get_metadata_hash(&conf, &path);
+    if let Ok(mut sf) = SyncFile::from_syncfile(&conf, &path) {
+        let _ = sf.decrypt_to_writer(&conf, &mut io::sink());
+    }
+});
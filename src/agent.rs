@@ -0,0 +1,431 @@
+// A per-user agent, like ssh-agent, that keeps unlocked master keys in memory so that the
+// password only has to be entered once per login session.  It listens on a unix socket in the
+// app data directory; only processes running as the same user may connect.  Keys are forgotten
+// after an idle timeout, or when the agent is told to lock.
+//
+// The protocol is one text line per connection, and one line of response:
+//   GET <sync dir>                      -> KEY <key hex> <slot name> | NONE
+//   PUT <key hex> <slot name> <sync dir> -> OK
+//   DEL <sync dir>                      -> OK
+//   LOCK                                -> OK
+// Errors are returned as "ERR <message>".
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+extern crate rustc_serialize;
+use self::rustc_serialize::hex::{ToHex, FromHex};
+
+extern crate time;
+
+use config;
+use crypto_util::SecretKey;
+#[cfg(not(test))]
+use util;
+
+#[cfg(not(target_os = "windows"))]
+use std::fs;
+#[cfg(not(target_os = "windows"))]
+use std::io::{BufRead,BufReader,Write};
+#[cfg(not(target_os = "windows"))]
+use std::os::unix::fs::PermissionsExt;
+#[cfg(not(target_os = "windows"))]
+use std::os::unix::io::{AsRawFd,RawFd};
+#[cfg(not(target_os = "windows"))]
+use std::sync::{Arc,Mutex};
+#[cfg(not(target_os = "windows"))]
+use std::thread;
+
+#[cfg(not(target_os = "windows"))]
+extern crate unix_socket;
+#[cfg(not(target_os = "windows"))]
+use self::unix_socket::{UnixListener,UnixStream};
+
+#[cfg(target_os = "linux")]
+extern crate nix;
+#[cfg(target_os = "linux")]
+use self::nix::sys::socket::{getsockopt,sockopt};
+
+pub const DEFAULT_IDLE_TIMEOUT_SECS: u64 = 3600;
+
+// How long a client may take to send its request; the agent handles one connection at a time.
+#[cfg(not(target_os = "windows"))]
+const REQUEST_TIMEOUT_MS: u32 = 5000;
+
+struct AgentKey {
+    slot: String,
+    key: SecretKey
+}
+
+pub struct Agent {
+    keys: HashMap<String,AgentKey>,
+    idle_timeout_secs: u64,
+    last_used: u64
+}
+
+fn now_secs() -> u64 {
+    time::get_time().sec as u64
+}
+
+fn err_response(msg:&str) -> String {
+    format!("ERR {}", msg)
+}
+
+impl Agent {
+    // idle_timeout_secs of 0 means keys are kept until the agent is locked or stopped.
+    pub fn new(idle_timeout_secs: u64) -> Self {
+        Agent {
+            keys: HashMap::new(),
+            idle_timeout_secs: idle_timeout_secs,
+            last_used: now_secs()
+        }
+    }
+
+    // Forget all keys; SecretKey overwrites them as they are dropped.
+    pub fn lock(&mut self) {
+        self.keys.clear();
+    }
+
+    // Lock if nothing has used the agent for the idle timeout.  Returns true if it locked.
+    pub fn expire(&mut self, now: u64) -> bool {
+        if self.idle_timeout_secs == 0 || self.keys.is_empty() {
+            return false;
+        }
+        if now.saturating_sub(self.last_used) >= self.idle_timeout_secs {
+            self.lock();
+            return true;
+        }
+        false
+    }
+
+    pub fn handle_request(&mut self, line: &str, now: u64) -> String {
+        self.expire(now);
+        self.last_used = now;
+
+        let line = line.trim_right_matches(|c:char| c == '\r' || c == '\n');
+        let (cmd, args) = match line.find(' ') {
+            None => (line, ""),
+            Some(i) => (&line[0..i], &line[i+1..])
+        };
+        match cmd {
+            "GET" => {
+                match self.keys.get(args) {
+                    None => format!("NONE"),
+                    Some(ak) => format!("KEY {} {}", ak.key.to_hex(), ak.slot)
+                }
+            },
+            "PUT" => {
+                let parts:Vec<&str> = args.splitn(3, ' ').collect();
+                if parts.len() != 3 || parts[2].is_empty() {
+                    return err_response("PUT needs a key, a slot name and a sync directory");
+                }
+                let key = match parts[0].from_hex().ok().and_then(|k| SecretKey::from_slice(&k)) {
+                    None => return err_response("Invalid key"),
+                    Some(k) => k
+                };
+                self.keys.insert(parts[2].to_owned(), AgentKey { slot: parts[1].to_owned(), key: key });
+                format!("OK")
+            },
+            "DEL" => {
+                self.keys.remove(args);
+                format!("OK")
+            },
+            "LOCK" => {
+                self.lock();
+                format!("OK")
+            },
+            _ => err_response(&format!("Unknown request: {}", cmd))
+        }
+    }
+}
+
+// The socket lives with the sync db, under the app data dir, so release and debug builds
+// each have their own agent.  Tests never use an agent.
+#[cfg(not(test))]
+pub fn socket_path() -> Option<PathBuf> {
+    util::get_appdata_dir().map(|dir| {
+        let mut pb = PathBuf::from(&dir);
+        pb.push("GreyCrypt");
+        pb.push(config::BUILD_PREFIX);
+        pb.push("agent.sock");
+        pb
+    })
+}
+
+#[cfg(test)]
+pub fn socket_path() -> Option<PathBuf> {
+    None
+}
+
+#[cfg(not(target_os = "windows"))]
+extern {
+    fn getuid() -> u32;
+    fn mlockall(flags: i32) -> i32;
+}
+
+#[cfg(all(not(target_os = "windows"), not(target_os = "linux")))]
+extern {
+    fn getpeereid(fd: i32, uid: *mut u32, gid: *mut u32) -> i32;
+}
+
+// MCL_CURRENT | MCL_FUTURE, the same on linux and mac
+#[cfg(not(target_os = "windows"))]
+const MCL_ALL: i32 = 3;
+
+#[cfg(target_os = "linux")]
+fn peer_uid(fd: RawFd) -> Result<u32,String> {
+    match getsockopt(fd, sockopt::PeerCredentials) {
+        Err(e) => Err(format!("Unable to get agent socket peer credentials: {:?}", e)),
+        Ok(cred) => Ok(cred.uid)
+    }
+}
+
+#[cfg(all(not(target_os = "windows"), not(target_os = "linux")))]
+fn peer_uid(fd: RawFd) -> Result<u32,String> {
+    let mut uid = 0;
+    let mut gid = 0;
+    if unsafe { getpeereid(fd, &mut uid, &mut gid) } != 0 {
+        return Err(format!("Unable to get agent socket peer credentials: {}", ::std::io::Error::last_os_error()));
+    }
+    Ok(uid)
+}
+
+// Both ends check that the other is running as the same user.
+#[cfg(not(target_os = "windows"))]
+fn check_peer(stream: &UnixStream) -> Result<(),String> {
+    let uid = try!(peer_uid(stream.as_raw_fd()));
+    let my_uid = unsafe { getuid() };
+    if uid != my_uid {
+        return Err(format!("Agent socket peer is running as uid {}, not {}", uid, my_uid));
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "windows"))]
+fn request_at(path: &PathBuf, line: &str) -> Result<String,String> {
+    let mut stream = match UnixStream::connect(path) {
+        Err(e) => return Err(format!("Unable to connect to agent at {:?}: {}", path, e)),
+        Ok(s) => s
+    };
+    try!(check_peer(&stream));
+    match stream.write_all(format!("{}\n", line).as_bytes()) {
+        Err(e) => return Err(format!("Unable to send request to agent: {}", e)),
+        Ok(_) => ()
+    }
+    let mut response = String::new();
+    match BufReader::new(stream).read_line(&mut response) {
+        Err(e) => return Err(format!("Unable to read response from agent: {}", e)),
+        Ok(_) => ()
+    }
+    let response = response.trim_right().to_owned();
+    if response.starts_with("ERR ") {
+        return Err(format!("Agent error: {}", &response[4..]));
+    }
+    Ok(response)
+}
+
+#[cfg(target_os = "windows")]
+fn request_at(_: &PathBuf, _: &str) -> Result<String,String> {
+    Err(format!("The agent is not supported on windows"))
+}
+
+fn request(line: &str) -> Result<String,String> {
+    match socket_path() {
+        None => Err(format!("No agent socket path available")),
+        Some(path) => request_at(&path, line)
+    }
+}
+
+fn parse_key_response(response: &str) -> Option<(String, SecretKey)> {
+    let parts:Vec<&str> = response.splitn(3, ' ').collect();
+    if parts.len() != 3 || parts[0] != "KEY" {
+        return None;
+    }
+    parts[1].from_hex().ok()
+        .and_then(|k| SecretKey::from_slice(&k))
+        .map(|key| (parts[2].to_owned(), key))
+}
+
+// Returns the key slot name and master key the agent holds for sync_dir, if an agent is
+// running and has one.  Never fails; without an agent, the caller asks for a password.
+pub fn get_key(sync_dir: &str) -> Option<(String, SecretKey)> {
+    match request(&format!("GET {}", sync_dir)) {
+        Err(e) => {
+            debug!("Not using agent: {}", e);
+            None
+        },
+        Ok(response) => parse_key_response(&response)
+    }
+}
+
+// Give an unlocked master key to the agent, if one is running.
+pub fn add_key(sync_dir: &str, slot: &str, key: &SecretKey) {
+    match request(&format!("PUT {} {} {}", key.to_hex(), slot, sync_dir)) {
+        Err(e) => debug!("Not using agent: {}", e),
+        Ok(_) => info!("Master key for key slot '{}' added to agent", slot)
+    }
+}
+
+// Make the agent forget the key for sync_dir, such as one that is no longer the master key.
+pub fn remove_key(sync_dir: &str) {
+    match request(&format!("DEL {}", sync_dir)) {
+        Err(e) => debug!("Not using agent: {}", e),
+        Ok(_) => info!("Removed the master key for {} from the agent", sync_dir)
+    }
+}
+
+pub fn lock() -> Result<(),String> {
+    request("LOCK").map(|_| ())
+}
+
+#[cfg(not(target_os = "windows"))]
+fn serve_connection(agent: &Mutex<Agent>, stream: UnixStream) -> Result<(),String> {
+    try!(check_peer(&stream));
+    let _ = stream.set_read_timeout(Some(::std::time::Duration::from_millis(REQUEST_TIMEOUT_MS as u64)));
+    let mut line = String::new();
+    match BufReader::new(&stream).read_line(&mut line) {
+        Err(e) => return Err(format!("Unable to read agent request: {}", e)),
+        Ok(_) => ()
+    }
+    let response = agent.lock().unwrap().handle_request(&line, now_secs());
+    let mut stream = stream;
+    match stream.write_all(format!("{}\n", response).as_bytes()) {
+        Err(e) => Err(format!("Unable to write agent response: {}", e)),
+        Ok(_) => Ok(())
+    }
+}
+
+#[cfg(not(target_os = "windows"))]
+fn listen(path: &PathBuf) -> UnixListener {
+    let dir = path.parent().unwrap().to_path_buf();
+    match fs::create_dir_all(&dir).and_then(|_| fs::set_permissions(&dir, fs::Permissions::from_mode(0o700))) {
+        Err(e) => panic!("Unable to create agent socket directory {:?}: {}", dir, e),
+        Ok(_) => ()
+    }
+
+    // a socket left over from an agent that didn't exit cleanly can be removed, but not one
+    // that is still being served
+    if fs::metadata(path).is_ok() {
+        if UnixStream::connect(path).is_ok() {
+            panic!("An agent is already running at {:?}", path);
+        }
+        let _ = fs::remove_file(path);
+    }
+
+    let listener = match UnixListener::bind(path) {
+        Err(e) => panic!("Unable to create agent socket at {:?}: {}", path, e),
+        Ok(l) => l
+    };
+    match fs::set_permissions(path, fs::Permissions::from_mode(0o600)) {
+        Err(e) => panic!("Unable to set permissions on agent socket {:?}: {}", path, e),
+        Ok(_) => ()
+    }
+    listener
+}
+
+// Run the agent in the foreground until killed.
+#[cfg(not(target_os = "windows"))]
+pub fn run(idle_timeout_secs: u64) {
+    let path = match socket_path() {
+        None => panic!("No app data directory available for the agent socket"),
+        Some(p) => p
+    };
+
+    // keep keys out of swap
+    if unsafe { mlockall(MCL_ALL) } != 0 {
+        warn!("Unable to lock agent memory, keys may be swapped to disk: {}", ::std::io::Error::last_os_error());
+    }
+
+    let listener = listen(&path);
+    let agent = Arc::new(Mutex::new(Agent::new(idle_timeout_secs)));
+
+    {
+        let agent = agent.clone();
+        thread::spawn(move || {
+            loop {
+                thread::sleep_ms(1000);
+                if agent.lock().unwrap().expire(now_secs()) {
+                    info!("Agent idle; keys forgotten");
+                }
+            }
+        });
+    }
+
+    if idle_timeout_secs > 0 {
+        info!("Agent listening on {:?}; keys are forgotten after {} idle seconds", path, idle_timeout_secs);
+    } else {
+        info!("Agent listening on {:?}", path);
+    }
+    for stream in listener.incoming() {
+        match stream {
+            Err(e) => warn!("Agent connection failed: {}", e),
+            Ok(stream) => {
+                match serve_connection(&agent, stream) {
+                    Err(e) => warn!("{}", e),
+                    Ok(_) => ()
+                }
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "windows")]
+pub fn run(_: u64) {
+    panic!("The agent is not supported on windows");
+}
+
+#[cfg(test)]
+mod tests {
+    use agent;
+
+    const KEY_HEX: &'static str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+    #[test]
+    fn requests() {
+        let mut a = agent::Agent::new(60);
+        assert_eq!(a.handle_request("GET /sync dir\n", 100), "NONE");
+        assert_eq!(a.handle_request(&format!("PUT {} default /sync dir\n", KEY_HEX), 100), "OK");
+
+        let response = a.handle_request("GET /sync dir\n", 110);
+        assert_eq!(response, format!("KEY {} default", KEY_HEX));
+        let (slot, key) = agent::parse_key_response(&response).unwrap();
+        assert_eq!(slot, "default");
+        assert_eq!(key[31], 31);
+        assert_eq!(a.handle_request("GET /other\n", 110), "NONE");
+
+        assert_eq!(a.handle_request("DEL /other", 110), "OK");
+        assert!(a.handle_request("GET /sync dir", 110).starts_with("KEY "));
+        assert_eq!(a.handle_request("DEL /sync dir", 110), "OK");
+        assert_eq!(a.handle_request("GET /sync dir", 110), "NONE");
+        assert_eq!(a.handle_request(&format!("PUT {} default /sync dir\n", KEY_HEX), 110), "OK");
+
+        assert!(a.handle_request("PUT 0011 default /sync dir", 110).starts_with("ERR "));
+        assert!(a.handle_request("PUT", 110).starts_with("ERR "));
+        assert!(a.handle_request("FROB", 110).starts_with("ERR "));
+
+        assert_eq!(a.handle_request("LOCK", 110), "OK");
+        assert_eq!(a.handle_request("GET /sync dir", 110), "NONE");
+    }
+
+    #[test]
+    fn idle_timeout() {
+        let mut a = agent::Agent::new(60);
+        a.handle_request(&format!("PUT {} default /sync", KEY_HEX), 100);
+
+        // each request resets the timer
+        assert!(!a.expire(150));
+        assert!(a.handle_request("GET /sync", 150).starts_with("KEY "));
+        assert!(!a.expire(200));
+        assert!(a.expire(210));
+        assert_eq!(a.handle_request("GET /sync", 210), "NONE");
+
+        // a late request doesn't get the expired key either
+        a.handle_request(&format!("PUT {} default /sync", KEY_HEX), 300);
+        assert_eq!(a.handle_request("GET /sync", 400), "NONE");
+
+        // no timeout
+        let mut a = agent::Agent::new(0);
+        a.handle_request(&format!("PUT {} default /sync", KEY_HEX), 100);
+        assert!(!a.expire(1000000));
+    }
+}
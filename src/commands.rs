@@ -1,12 +1,22 @@
 //use std::fs::{PathExt,remove_file,remove_dir,read_dir};
+use std::fs::{PathExt,metadata,rename,remove_file};
 use std::path::{PathBuf};
+use std::io;
+use std::thread;
 // use std::collections::HashSet;
 // use std::collections::HashMap;
 // use std::cmp::Ordering;
 
 use config;
 use syncfile;
+use syncdb;
 use core;
+use kdf;
+use crypto_util;
+use shamir;
+use agent;
+use host_key;
+use password_source::PasswordSource;
 
 #[allow(dead_code)]
 pub fn show_syncfile_meta(state: &mut core::SyncState, filename:&str) {
@@ -30,7 +40,10 @@ pub fn show_syncfile_meta(state: &mut core::SyncState, filename:&str) {
         Err(e) => panic!("Failed to read syncfile: {:?}", e),
         Ok(sf) => sf
     };
-    let entry = state.syncdb.get(&sf);
+    let entry = match state.syncdb.get(&sf) {
+        Err(e) => panic!("Failed to read syncdb entry: {}", e),
+        Ok(entry) => entry
+    };
     match entry {
         None => println!("No sync db entry (file has not yet been processed on this machine)"),
         Some(entry) => {
@@ -41,16 +54,17 @@ pub fn show_syncfile_meta(state: &mut core::SyncState, filename:&str) {
         }
     }
 
-    let mut data:Vec<u8> = Vec::new();
+    // wiped when dropped
+    let mut data = crypto_util::SecretBuf::with_capacity(metadata(&syncpath).map(|m| m.len() as usize).unwrap_or(0));
 
-    match sf.decrypt_to_writer(&state.conf, &mut data) {
+    match sf.decrypt_to_writer(&state.conf, &mut *data) {
         Err(e) => panic!("Error {:?}", e),
         Ok(_) => {
             println!("decrypted size: {}", data.len());
 
             if !sf.is_binary {
                 println!("text:");
-                println!("{}", String::from_utf8(data).unwrap());
+                println!("{}", String::from_utf8_lossy(&data));
             } else {
                 println!("binary file data omitted");
             }
@@ -94,57 +108,1093 @@ fn collect_new_password() -> String {
     "swordfish".to_owned()
 }
 
-pub fn change_password(state: &mut core::SyncState) {
-    let new_password = collect_new_password();
-    
-    let new_conf = state.conf.with_encryption_key(Some(config::get_encryption_key(&new_password))); 
-    
-    let syncfiles = core::find_all_syncfiles(state);
-    
-    let mut count = 0;
-    for (_,files) in syncfiles.iter() {
-        for f in files.iter() {
-            // try to decode with new pw
-            let syncfile = PathBuf::from(&f);
-            let mut sf = match syncfile::SyncFile::from_syncfile(&new_conf,&syncfile) {
-                Ok(_) => continue, // already updated
-                Err(_) => {
-                    // try old pw
-                    match syncfile::SyncFile::from_syncfile(&state.conf,&syncfile) {
-                        Err(e) => {
-                            // fail, log and skip
-                            warn!("Failed to decode syncfile with old & new password, skipping: {}; error: {}", f, e);
-                            continue;
-                        },
-                        Ok(sf) => sf
+// The password for --init or --upgrade.  A typo there would lock the sync dir, so it is typed
+// twice when prompting; non-interactive sources are used as they are.
+pub fn get_new_password(source: &PasswordSource) -> String {
+    match *source {
+        PasswordSource::Prompt => collect_new_password(),
+        _ => config::get_password(source, None)
+    }
+}
+
+// Where a syncfile belongs after its sync id changes: in the new id's prefix dir, with the
+// old id in its name replaced, so that conflicted copies keep their distinct names.
+fn renamed_syncfile_path(conf: &config::SyncConfig, syncfile: &PathBuf, old_sid: &str, new_sid: &str) -> PathBuf {
+    if old_sid == new_sid {
+        return syncfile.clone();
+    }
+    let name = syncfile.file_name().unwrap().to_str().unwrap().replace(old_sid, new_sid);
+    let mut pb = PathBuf::from(conf.sync_dir());
+    pb.push(&new_sid[0..2]);
+    pb.push(&name);
+    pb
+}
+
+// Re-encrypt one syncfile for reencrypt_syncfiles(), moving it and its syncdb entry if its sync
+// id changes.  Returns where the file is now, its sync id and whether it was re-encrypted, or None
+// if neither conf can read it.
+fn reencrypt_syncfile(old_conf: &config::SyncConfig, new_conf: &config::SyncConfig, f: &str, syncdb: &mut syncdb::SyncDb) -> Result<Option<(PathBuf,String,bool)>,String> {
+    // try to decode with new pw
+    let syncfile = PathBuf::from(f);
+    let (mut sf, file_sid) = match syncfile::SyncFile::from_syncfile(new_conf,&syncfile) {
+        Ok(sf) => {
+            let file_sid = match syncfile::SyncFile::get_syncid_from_file(new_conf,&syncfile) {
+                Err(e) => return Err(format!("Error reading syncid: {}", e)),
+                Ok(sid) => sid
+            };
+            if file_sid == sf.id {
+                return Ok(Some((syncfile, file_sid, false))); // already updated
+            }
+            (sf, file_sid)
+        },
+        Err(_) => {
+            // try old pw
+            match syncfile::SyncFile::from_syncfile(old_conf,&syncfile) {
+                Err(e) => {
+                    // fail, log and skip
+                    warn!("Failed to decode syncfile with old & new password, skipping: {}; error: {}", f, e);
+                    return Ok(None);
+                },
+                Ok(sf) => {
+                    match syncfile::SyncFile::get_syncid_from_file(old_conf,&syncfile) {
+                        Err(e) => return Err(format!("Error reading syncid: {}", e)),
+                        Ok(sid) => (sf, sid)
                     }
                 }
-            };
-            
-            let mut data:Vec<u8> = Vec::new();
-
-            match sf.decrypt_to_writer(&state.conf, &mut data) {
-                Err(e) => panic!("Error decrypting file data for {}: {:?}", f, e),
-                Ok(_) => {
-                    // re-encrypt with new conf
-                    match sf.save_with_data(&new_conf, Some(syncfile), data) {
-                        Err(e) => panic!("Error encrypting file data for {}: {:?}", f, e),
-                        Ok(_) => ()
+            }
+        }
+    };
+
+    let new_sid = match syncfile::SyncFile::get_sync_id(new_conf, &sf.keyword, &sf.relpath) {
+        Err(e) => return Err(format!("Can't get new sync id: {}", e)),
+        Ok(sid) => sid
+    };
+    let new_path = renamed_syncfile_path(new_conf, &syncfile, &file_sid, &new_sid);
+
+    // wiped when dropped
+    let mut data = crypto_util::SecretBuf::with_capacity(metadata(&syncfile).map(|m| m.len() as usize).unwrap_or(0));
+    match sf.decrypt_to_writer(old_conf, &mut *data) {
+        Err(e) => return Err(format!("Error decrypting file data: {}", e)),
+        Ok(_) => ()
+    }
+    // re-encrypt with new conf; the new file only replaces the old one once it is complete
+    match sf.save_with_data(new_conf, Some(new_path.clone()), &data) {
+        Err(e) => return Err(format!("Error encrypting file data: {}", e)),
+        Ok(_) => ()
+    }
+
+    // the new file is complete, so move the syncdb entry to it, then the old file can go.  in
+    // that order, a failure at either step leaves the old file, and running again finishes the
+    // job; move_entry() does nothing if the entry has already moved.
+    if new_sid != file_sid {
+        match syncdb.move_entry(&file_sid, &new_sid) {
+            Err(e) => return Err(format!("Failed to update syncdb: {}", e)),
+            Ok(_) => ()
+        }
+    }
+    if new_path != syncfile {
+        match remove_file(&syncfile) {
+            Err(e) => return Err(format!("Failed to remove old syncfile: {}", e)),
+            Ok(_) => ()
+        }
+    }
+    Ok(Some((new_path, new_sid, true)))
+}
+
+// Read a syncfile back with new_conf, all the way through, and check its sync id.
+fn verify_syncfile(new_conf: &config::SyncConfig, syncfile: &PathBuf, sid: &str) -> Result<(),String> {
+    match syncfile::SyncFile::get_syncid_from_file(new_conf, syncfile) {
+        Err(e) => return Err(format!("{}", e)),
+        Ok(ref file_sid) if file_sid != sid => return Err(format!("sync id is {}, expected {}", file_sid, sid)),
+        Ok(_) => ()
+    }
+    let mut sf = match syncfile::SyncFile::from_syncfile(new_conf, syncfile) {
+        Err(e) => return Err(format!("{}", e)),
+        Ok(sf) => sf
+    };
+    match sf.decrypt_to_writer(new_conf, &mut io::sink()) {
+        Err(e) => Err(format!("{}", e)),
+        Ok(_) => Ok(())
+    }
+}
+
+struct Reencrypted {
+    // files that were re-encrypted
+    count: usize,
+    // files that couldn't be re-encrypted, or didn't verify afterwards
+    failed: Vec<String>
+}
+
+// Re-encrypt the specified syncfiles from the key and sync id scheme in old_conf to those in
+// new_conf.  Keyed sync ids depend on the key, so they can change even when the scheme doesn't;
+// files whose id changes are moved to match it, along with their entries in this host's syncdb.
+// Files that are already readable with the new key and have the new id are skipped, so this can
+// simply be run again if it is interrupted.  Each file is written to a temporary file first and
+// then moved into place, so an interruption never leaves a half-written syncfile.  A file that
+// fails is logged and left as it was, and the rest are still converted.  Once they all have been,
+// a final pass reads every converted file back with new_conf.  Stops after max_files files have
+// been tried, if set.
+fn reencrypt_syncfiles(old_conf: &config::SyncConfig, new_conf: &config::SyncConfig, files: &Vec<String>, syncdb: &mut syncdb::SyncDb, max_files: Option<usize>) -> Reencrypted {
+    let mut count = 0;
+    let mut failed = Vec::new();
+    let mut converted = Vec::new();
+    for f in files.iter() {
+        if max_files == Some(count + failed.len()) {
+            break;
+        }
+        match reencrypt_syncfile(old_conf, new_conf, f, syncdb) {
+            Err(e) => {
+                warn!("Failed to re-encrypt syncfile {}: {}", f, e);
+                failed.push(f.clone());
+            },
+            Ok(None) => (),
+            Ok(Some((path, sid, reencrypted))) => {
+                if reencrypted {
+                    count = count + 1;
+                }
+                converted.push((path, sid));
+            }
+        }
+    }
+
+    for &(ref path, ref sid) in converted.iter() {
+        match verify_syncfile(new_conf, path, sid) {
+            Err(e) => {
+                warn!("Re-encrypted syncfile {:?} failed verification: {}", path, e);
+                failed.push(path.to_str().unwrap().to_owned());
+            },
+            Ok(_) => ()
+        }
+    }
+    Reencrypted { count: count, failed: failed }
+}
+
+// reencrypt_syncfiles() for the commands that switch every file to new_conf at once.  If any file
+// failed, panics before the caller commits the new key, so that running the command again, once
+// the files are fixed or removed, finishes it.  Returns the number of files that were
+// re-encrypted.
+fn reencrypt_all_syncfiles(old_conf: &config::SyncConfig, new_conf: &config::SyncConfig, files: &Vec<String>, syncdb: &mut syncdb::SyncDb) -> usize {
+    let r = reencrypt_syncfiles(old_conf, new_conf, files, syncdb, None);
+    if !r.failed.is_empty() {
+        panic!("{} sync files could not be re-encrypted or failed verification; see the errors above.  \
+            Fix or remove them, then run the same command again to finish: {}", r.failed.len(), r.failed.join(", "));
+    }
+    r.count
+}
+
+// Panic if another host has synced recently (see core::other_hosts_syncing()): it could write
+// syncfiles with the old key, or under sync ids that are about to change, while every file is
+// being re-encrypted.
+fn refuse_if_other_hosts_syncing(conf: &config::SyncConfig) {
+    match core::other_hosts_syncing(conf.sync_dir(), &conf.host_name) {
+        Err(e) => panic!("{}", e),
+        Ok(ref hosts) if hosts.is_empty() => (),
+        Ok(hosts) => panic!("Other hosts have synced in the last {} minutes and may still be syncing: {}.  \
+            Stop greycrypt on them, then try again once they have been idle that long.", core::SYNC_MARKER_WINDOW_SECS / 60, hosts.join(", "))
+    }
+}
+
+// The new manifest left by an interrupted upgrade or host revoke, if any.
+fn load_pending_manifest(sync_dir: &str) -> Option<kdf::KdfManifest> {
+    let pending_path = kdf::pending_manifest_path(sync_dir);
+    if !pending_path.is_file() {
+        return None;
+    }
+    match kdf::KdfManifest::load_from(&pending_path) {
+        Err(e) => panic!("{}", e),
+        Ok(m) => Some(m)
+    }
+}
+
+fn write_pending_manifest(sync_dir: &str, manifest: &kdf::KdfManifest) {
+    match manifest.write_to(&kdf::pending_manifest_path(sync_dir)) {
+        Err(e) => panic!("{}", e),
+        Ok(_) => ()
+    }
+}
+
+fn commit_pending_manifest(sync_dir: &str) {
+    let pending_path = kdf::pending_manifest_path(sync_dir);
+    match rename(&pending_path, kdf::manifest_path(sync_dir)) {
+        Err(e) => panic!("Failed to move KDF manifest into place: {:?}: {}", pending_path, e),
+        Ok(_) => ()
+    }
+}
+
+// Panic unless the first syncfile can be read with conf.  Keys derived directly from a
+// password can't be checked any other way.
+fn check_key_reads_syncfiles(conf: &config::SyncConfig, syncfiles: &Vec<String>) {
+    match syncfiles.iter().nth(0) {
+        None => (),
+        Some(f) => {
+            let pb = PathBuf::from(f);
+            if syncfile::SyncFile::get_syncid_from_file(conf,&pb).is_err() {
+                panic!("Unable to read syncfile with the supplied password; likely incorrect password: {}", f);
+            }
+        }
+    }
+}
+
+fn load_manifest(sync_dir: &str) -> kdf::KdfManifest {
+    if kdf::pending_manifest_path(sync_dir).is_file() {
+        panic!("An upgrade or key rotation of this sync directory was interrupted; run the same command (--upgrade or host revoke) again to finish it first");
+    }
+    match kdf::KdfManifest::load(sync_dir) {
+        Err(e) => panic!("{}", e),
+        Ok(m) => m
+    }
+}
+
+fn write_manifest(sync_dir: &str, manifest: &kdf::KdfManifest) {
+    match manifest.write_to(&kdf::manifest_path(sync_dir)) {
+        Err(e) => panic!("{}", e),
+        Ok(_) => ()
+    }
+}
+
+// Change the password of the key slot that old_password unlocks, and optionally its KDF.  This
+// is also how a key file is added to a slot.
+// Only the manifest is rewritten: the master key is wrapped with a key derived from the new
+// password and a new salt.  Syncfiles, and so the other hosts' sync state, are not touched.
+// The manifest is replaced in one rename (see KdfManifest::write_to()), so if this is
+// interrupted either the old password or the new one works; there is nothing to resume.
+pub fn change_password(conf: &config::SyncConfig, old_password: &str, new_kdf: Option<kdf::KdfAlgorithm>) {
+    let manifest = load_manifest(conf.sync_dir());
+    let key_file = config::read_key_file(conf);
+
+    let (idx, master_key) = match manifest.unlock_slot(old_password, key_file.as_ref().map(|kf| &kf[..])) {
+        Err(e) => panic!("{}", e),
+        Ok(r) => r
+    };
+    change_slot_password(conf, manifest, idx, master_key, new_kdf);
+}
+
+// Same as change_password(), but for a slot whose master key is already unlocked, as held by
+// the agent.
+pub fn change_password_unlocked(conf: &config::SyncConfig, slot_name: &str, master_key: crypto_util::SecretKey, new_kdf: Option<kdf::KdfAlgorithm>) {
+    let manifest = load_manifest(conf.sync_dir());
+    let idx = match manifest.find_slot(slot_name) {
+        None => panic!("No key slot named '{}'", slot_name),
+        Some(idx) => idx
+    };
+    change_slot_password(conf, manifest, idx, master_key, new_kdf);
+}
+
+fn change_slot_password(conf: &config::SyncConfig, manifest: kdf::KdfManifest, idx: usize, master_key: crypto_util::SecretKey, new_kdf: Option<kdf::KdfAlgorithm>) {
+    let sync_dir = conf.sync_dir();
+    let mut manifest = manifest;
+    let key_file = config::read_key_file(conf);
+    let key_file = key_file.as_ref().map(|kf| &kf[..]);
+
+    // older manifests have no wrapped key; the key derived from the old password becomes the
+    // master key, so make sure it is the right one.
+    if !manifest.has_wrapped_key() {
+        let keyed_conf = conf.with_encryption_key(Some(master_key.clone())).with_sync_ids(manifest.sync_ids);
+        check_key_reads_syncfiles(&keyed_conf, &core::find_syncfile_paths(sync_dir));
+    }
+
+    let old_slot = manifest.slots[idx].clone();
+    if old_slot.is_recovery() {
+        panic!("Key slot '{}' holds a recovery key, which can't be changed; use \"keyslot add\" to add a password instead", old_slot.name);
+    }
+    let alg = new_kdf.unwrap_or(old_slot.algorithm.clone());
+    let new_password = collect_new_password();
+
+    // the new slot uses the configured key file, if any, whether or not the old one did
+    manifest.replace_slot(idx, kdf::KeySlot::create(&old_slot.name, alg, &new_password, key_file, &master_key));
+    manifest.set_key_id(&master_key);
+    write_manifest(sync_dir, &manifest);
+
+    info!("Password changed for key slot '{}'; it uses kdf {}", old_slot.name, manifest.slots[idx].algorithm.spec());
+}
+
+pub fn keyslot_list(conf: &config::SyncConfig) {
+    let manifest = match kdf::KdfManifest::load(conf.sync_dir()) {
+        Err(e) => panic!("{}", e),
+        Ok(m) => m
+    };
+    for slot in &manifest.slots {
+        if slot.is_data_key() {
+            continue;
+        }
+        if slot.is_recovery() {
+            println!("{}: recovery key", slot.name);
+        } else if slot.is_host() {
+            let public = slot.host_key.as_ref().map(|k| &k[..]).unwrap_or(&[]);
+            let signing = slot.signing_key.as_ref().map(|k| &k[..]).unwrap_or(&[]);
+            let unsigned = if slot.signing_key.is_none() { " (no signing key; enroll it again)" } else { "" };
+            println!("{}: host key, fingerprint {}{}", slot.name, host_key::fingerprint(public, signing), unsigned);
+        } else {
+            let key_file = if slot.uses_key_file() { " and key file" } else { "" };
+            println!("{}: password{}, kdf {}", slot.name, key_file, slot.algorithm.spec());
+        }
+    }
+    if !manifest.has_wrapped_key() {
+        println!("This sync directory was created by an older version of greycrypt; run with --upgrade to use key slots.");
+    }
+    if manifest.find_slot(kdf::RETIRED_DATA_SLOT_NAME).is_some() {
+        println!("The data key is being replaced; run rotate-key to finish re-encrypting the sync files.");
+    }
+}
+
+// The master key for the keyslot and recovery commands, from password and the configured key
+// file.
+fn unlock_master_key(conf: &config::SyncConfig, password: &str) -> crypto_util::SecretKey {
+    let manifest = load_manifest(conf.sync_dir());
+    let key_file = config::read_key_file(conf);
+    config::get_encryption_key(&manifest, password, key_file.as_ref().map(|kf| &kf[..]))
+}
+
+// Add a key slot, unlocked either by a new password or by a new random recovery key.  password
+// must unlock one of the existing slots.  Returns the recovery key, which is also printed; it
+// is not stored anywhere else.
+pub fn keyslot_add(conf: &config::SyncConfig, password: &str, name: &str, recovery: bool, algorithm: kdf::KdfAlgorithm) -> Option<String> {
+    let master_key = unlock_master_key(conf, password);
+    keyslot_add_unlocked(conf, master_key, name, recovery, algorithm)
+}
+
+// Same as keyslot_add(), with a master key that is already unlocked, as held by the agent.
+pub fn keyslot_add_unlocked(conf: &config::SyncConfig, master_key: crypto_util::SecretKey, name: &str, recovery: bool, algorithm: kdf::KdfAlgorithm) -> Option<String> {
+    let sync_dir = conf.sync_dir();
+    let mut manifest = load_manifest(sync_dir);
+    let key_file = config::read_key_file(conf);
+    let key_file = key_file.as_ref().map(|kf| &kf[..]);
+
+    let (slot, recovery_key) = if recovery {
+        let recovery_key = kdf::new_recovery_key();
+        (kdf::KeySlot::create_recovery(name, &recovery_key, &master_key), Some(recovery_key))
+    } else {
+        (kdf::KeySlot::create(name, algorithm, &collect_new_password(), key_file, &master_key), None)
+    };
+    match manifest.add_slot(slot) {
+        Err(e) => panic!("{}", e),
+        Ok(_) => ()
+    }
+    manifest.set_key_id(&master_key);
+    write_manifest(sync_dir, &manifest);
+
+    info!("Added key slot '{}'", name);
+    if let Some(ref rk) = recovery_key {
+        print_recovery_key(name, rk);
+    }
+    recovery_key
+}
+
+fn print_recovery_key(name: &str, recovery_key: &str) {
+    println!("Recovery key for slot '{}':", name);
+    println!("");
+    println!("    {}", recovery_key);
+    println!("");
+    println!("It can be entered instead of a password.  Write it down and keep it somewhere safe;");
+    println!("it is not shown again.");
+}
+
+fn print_recovery_shares(name: &str, threshold: u8, shares: &Vec<String>) {
+    println!("Recovery key shares for slot '{}'; any {} of them can be combined to unlock it:", name, threshold);
+    println!("");
+    for t in shares {
+        println!("    {}", t);
+    }
+    println!("");
+    println!("Give each share to a different person.  They are not shown again.");
+}
+
+// Remove a key slot.  password must unlock one of the slots, though not necessarily the one
+// being removed.  The master key doesn't change, so this only stops the removed password
+// from being used with the manifest; it doesn't revoke access to the files from anyone who
+// already unlocked them.
+pub fn keyslot_remove(conf: &config::SyncConfig, password: &str, name: &str) {
+    let master_key = unlock_master_key(conf, password);
+    keyslot_remove_unlocked(conf, master_key, name);
+}
+
+// Same as keyslot_remove(), with a master key that is already unlocked.  The key isn't needed
+// to remove the slot; taking it means the caller has checked that the user may.
+pub fn keyslot_remove_unlocked(conf: &config::SyncConfig, _master_key: crypto_util::SecretKey, name: &str) {
+    let sync_dir = conf.sync_dir();
+    let mut manifest = load_manifest(sync_dir);
+
+    match manifest.remove_slot(name) {
+        Err(e) => panic!("{}", e),
+        Ok(_) => ()
+    }
+    write_manifest(sync_dir, &manifest);
+
+    info!("Removed key slot '{}'", name);
+}
+
+// Add a recovery key slot, and print its key split into shares rather than the key itself.
+// Any threshold of the shares can be combined to get the key back.  Returns the shares.
+pub fn recovery_split(conf: &config::SyncConfig, password: &str, name: &str, count: u8, threshold: u8) -> Vec<String> {
+    let master_key = unlock_master_key(conf, password);
+    recovery_split_unlocked(conf, master_key, name, count, threshold)
+}
+
+// Same as recovery_split(), with a master key that is already unlocked, as held by the agent.
+pub fn recovery_split_unlocked(conf: &config::SyncConfig, master_key: crypto_util::SecretKey, name: &str, count: u8, threshold: u8) -> Vec<String> {
+    let sync_dir = conf.sync_dir();
+    let mut manifest = load_manifest(sync_dir);
+
+    let recovery_key = kdf::new_recovery_key();
+    let shares = match shamir::split(&kdf::parse_recovery_key(&recovery_key).unwrap(), threshold, count) {
+        Err(e) => panic!("{}", e),
+        Ok(s) => s
+    };
+
+    match manifest.add_slot(kdf::KeySlot::create_recovery(name, &recovery_key, &master_key)) {
+        Err(e) => panic!("{}", e),
+        Ok(_) => ()
+    }
+    write_manifest(sync_dir, &manifest);
+
+    info!("Added key slot '{}'", name);
+    let texts:Vec<String> = shares.iter().map(|s| s.to_text()).collect();
+    print_recovery_shares(name, threshold, &texts);
+    texts
+}
+
+// Prompt for shares until there are enough to combine.  The first share says how many that is.
+#[cfg(not(test))]
+pub fn collect_recovery_shares() -> Vec<String> {
+    let mut shares:Vec<String> = Vec::new();
+    let mut needed = 0;
+    while needed == 0 || shares.len() < needed {
+        let text = config::pw_prompt(Some(&format!("Enter share {}:", shares.len() + 1)));
+        match shamir::Share::parse(&text) {
+            Err(e) => println!("{}", e),
+            Ok(share) => {
+                needed = share.threshold as usize;
+                shares.push(text);
+            }
+        }
+    }
+    shares
+}
+
+#[cfg(test)]
+pub fn collect_recovery_shares() -> Vec<String> {
+    Vec::new()
+}
+
+// Rebuild a recovery key from its shares, and use it to add a key slot with a new password.
+pub fn recovery_combine(conf: &config::SyncConfig, shares: &Vec<String>, name: &str, algorithm: kdf::KdfAlgorithm) {
+    let sync_dir = conf.sync_dir();
+    let mut manifest = load_manifest(sync_dir);
+
+    let mut parsed:Vec<shamir::Share> = Vec::new();
+    for s in shares {
+        match shamir::Share::parse(s) {
+            Err(e) => panic!("Invalid share: {}", e),
+            Ok(share) => parsed.push(share)
+        }
+    }
+    let recovery_key = match shamir::combine(&parsed) {
+        Err(e) => panic!("Unable to combine shares: {}", e),
+        Ok(k) => kdf::format_recovery_key(&k)
+    };
+    let master_key = match manifest.unlock(&recovery_key, None) {
+        Err(_) => panic!("The combined shares don't unlock any recovery key slot; they may be from different splits"),
+        Ok(k) => k
+    };
+
+    let key_file = config::read_key_file(conf);
+    let slot = kdf::KeySlot::create(name, algorithm, &collect_new_password(), key_file.as_ref().map(|kf| &kf[..]), &master_key);
+    match manifest.add_slot(slot) {
+        Err(e) => panic!("{}", e),
+        Ok(_) => ()
+    }
+    manifest.set_key_id(&master_key);
+    write_manifest(sync_dir, &manifest);
+
+    info!("Added key slot '{}' from recovery key shares", name);
+}
+
+// Give a keyword its own key, so that it can be shared with someone who shouldn't have the
+// rest of the sync dir: the key is stored in the keyword's manifest, unlocked both by the master
+// key and by a new password in slot name, which is the one to give out.  The keyword's
+// syncfiles are re-encrypted with the new key, so this refuses to run while other hosts are
+// syncing.  If it is interrupted, or some files fail, run it again to finish.
+pub fn keyword_add(state: &mut core::SyncState, keyword: &str, name: &str, algorithm: kdf::KdfAlgorithm) {
+    let sync_dir = state.conf.sync_dir().to_owned();
+    refuse_if_other_hosts_syncing(&state.conf);
+    if !kdf::valid_keyword(keyword) {
+        panic!("Invalid keyword '{}'; use letters, digits, '-', '_' and '.'", keyword);
+    }
+    let kw = keyword.to_uppercase();
+    let master_key = match state.conf.encryption_key {
+        None => panic!("The master key is needed to add a keyword key; use a password that unlocks the sync directory"),
+        Some(ref k) => k.clone()
+    };
+
+    let kw_key = match state.conf.keyword_keys.get(&kw) {
+        Some(&Some(ref k)) => {
+            info!("Keyword {} already has its own key; re-encrypting any of its syncfiles that don't use it", kw);
+            k.clone()
+        },
+        Some(&None) => panic!("Keyword {} has its own key, but the master key doesn't unlock it", kw),
+        None => {
+            let kw_key = crypto_util::new_random_key();
+            let mut manifest = kdf::KdfManifest::create_for_keyword(&master_key, &kw_key);
+            match manifest.add_slot(kdf::KeySlot::create(name, algorithm, &collect_new_password(), None, &kw_key)) {
+                Err(e) => panic!("{}", e),
+                Ok(_) => ()
+            }
+            // written first, so that the key isn't lost if the re-encryption is interrupted
+            match manifest.write_to(&kdf::keyword_manifest_path(&sync_dir, &kw)) {
+                Err(e) => panic!("{}", e),
+                Ok(_) => ()
+            }
+            kw_key
+        }
+    };
+
+    let mut old_keys = state.conf.keyword_keys.clone();
+    old_keys.remove(&kw);
+    let old_conf = state.conf.with_keyword_keys(old_keys);
+    let mut new_keys = state.conf.keyword_keys.clone();
+    new_keys.insert(kw.clone(), Some(kw_key));
+    let new_conf = state.conf.with_keyword_keys(new_keys);
+
+    let syncfiles = core::find_syncfile_paths(&sync_dir);
+    let count = reencrypt_all_syncfiles(&old_conf, &new_conf, &syncfiles, &mut state.syncdb);
+
+    state.conf = new_conf;
+    info!("Keyword {} has its own key; re-encrypted {} sync files", kw, count);
+}
+
+// Make a key pair for this host, if it doesn't have one yet, and publish its public key in the
+// sync dir, so that an enrolled host can finish enrolling it with host_enroll().  Doesn't need
+// the password.
+pub fn host_enroll_request(conf: &config::SyncConfig) {
+    let sync_dir = conf.sync_dir();
+    if !kdf::valid_slot_name(&conf.host_name) {
+        panic!("Host name '{}' can't be used as a key slot name; set HostnameOverride in the [General] section of the config file", conf.host_name);
+    }
+    let hk = match host_key::load_or_create(conf) {
+        Err(e) => panic!("{}", e),
+        Ok(hk) => hk
+    };
+    let manifest = load_manifest(sync_dir);
+    match manifest.unlock_with_host_key(hk.secret()) {
+        Some((idx, _)) if manifest.slots[idx].signing_key.is_some() => {
+            info!("Host {} is already enrolled", conf.host_name);
+            return;
+        },
+        _ => ()
+    }
+    match host_key::publish(sync_dir, &conf.host_name, &hk) {
+        Err(e) => panic!("{}", e),
+        Ok(_) => ()
+    }
+    println!("Published the public keys for host {}; their fingerprint is {}", conf.host_name, host_key::fingerprint(&hk.public, &hk.signing_public));
+    println!("To finish, run \"greycrypt host enroll {}\" on a host that can unlock the sync directory,", conf.host_name);
+    println!("and check that it shows the same fingerprint.");
+}
+
+// Add a host key slot for host_name, which must be listed in [Mapping].  This host is enrolled
+// with its own key, made if needed; any other host must have published its key with
+// host_enroll_request().  A host enrolled before signing keys existed is enrolled again with
+// its signing key.  Needs the master key.
+pub fn host_enroll(conf: &config::SyncConfig, host_name: &str) {
+    let sync_dir = conf.sync_dir();
+    if !conf.known_hosts.iter().any(|h| h == host_name) {
+        panic!("Host {} is not listed in [Mapping] in the config file", host_name);
+    }
+    let master_key = match conf.encryption_key {
+        None => panic!("The master key is needed to enroll a host; use a password that unlocks the sync directory"),
+        Some(ref k) => k
+    };
+    let (public, signing_public) = if host_name == conf.host_name {
+        match host_key::load_or_create(conf) {
+            Err(e) => panic!("{}", e),
+            Ok(hk) => (hk.public, hk.signing_public)
+        }
+    } else {
+        match host_key::read_published(sync_dir, host_name) {
+            Err(e) => panic!("{}", e),
+            Ok(keys) => keys
+        }
+    };
+
+    let mut manifest = load_manifest(sync_dir);
+    let slot = kdf::KeySlot::create_for_host(host_name, &public, Some(&signing_public[..]), master_key);
+    let existing = manifest.slots.iter().position(|s| s.host_key.as_ref().map(|k| &k[..]) == Some(&public[..]));
+    match existing {
+        Some(idx) if manifest.slots[idx].name == host_name && manifest.slots[idx].signing_key.is_none() => {
+            manifest.replace_slot(idx, slot);
+        },
+        Some(_) => panic!("Host {} is already enrolled with this key", host_name),
+        None => match manifest.add_slot(slot) {
+            Err(e) => panic!("{}", e),
+            Ok(_) => ()
+        }
+    }
+    manifest.set_key_id(master_key);
+    write_manifest(sync_dir, &manifest);
+    host_key::remove_published(sync_dir, host_name);
+
+    info!("Enrolled host {}; its key fingerprint is {}", host_name, host_key::fingerprint(&public, &signing_public));
+}
+
+// How host_revoke() replaces the recovery key slots, which can't be moved to the new master key
+// since their keys aren't stored anywhere.
+pub enum RecoveryReplacement {
+    // drop them
+    None,
+    // add slots with the same names and new recovery keys
+    Keys,
+    // the same, but print each new key as shares; see recovery_split()
+    Shares { count: u8, threshold: u8 }
+}
+
+// The slots that revoking host_name removes without a replacement, and the password slot that
+// gets a new password, if any.  Host key slots are rewrapped, and recovery slots are replaced
+// unless recovery is RecoveryReplacement::None.  Only one password can be asked for, so only the
+// first password slot is kept.
+fn revoke_dropped_slots(manifest: &kdf::KdfManifest, host_name: &str, recovery: &RecoveryReplacement) -> (Vec<String>, Option<usize>) {
+    let mut dropped = Vec::new();
+    let mut password_slot = None;
+    for (i, slot) in manifest.slots.iter().enumerate() {
+        if slot.name == host_name || slot.is_data_key() || slot.is_host() {
+            continue;
+        }
+        if slot.is_recovery() {
+            if let RecoveryReplacement::None = *recovery {
+                dropped.push(slot.name.clone());
+            }
+        } else if !slot.is_wrapped_by_key() && password_slot.is_none() {
+            password_slot = Some(i);
+        } else {
+            dropped.push(slot.name.clone());
+        }
+    }
+    (dropped, password_slot)
+}
+
+// The manifest after revoking host_name: the other host key slots are rewrapped to new_key.
+// Password and recovery slots can't be rewrapped without their secrets.  The first password slot
+// keeps its name with a new password, recovery slots are replaced as recovery says, and the rest
+// are dropped; see revoke_dropped_slots().  The rotation slot is added so that an interrupted
+// rotation can find new_key again.  Also returns each new recovery key slot's name and its key,
+// or its shares.
+fn rotated_manifest(conf: &config::SyncConfig, manifest: &kdf::KdfManifest, host_name: &str, old_key: &crypto_util::SecretKey, new_key: &crypto_util::SecretKey, recovery: &RecoveryReplacement) -> (kdf::KdfManifest, Vec<(String, Vec<String>)>) {
+    let (_, password_slot) = revoke_dropped_slots(manifest, host_name, recovery);
+    let mut slots:Vec<kdf::KeySlot> = Vec::new();
+    let mut recovery_keys = Vec::new();
+    for slot in &manifest.slots {
+        if slot.name == host_name || slot.is_data_key() {
+            continue;
+        }
+        match slot.host_key {
+            Some(ref hk) if slot.is_host() => {
+                let mut public = [0; config::KEY_SIZE];
+                for i in 0..config::KEY_SIZE {
+                    public[i] = hk[i];
+                }
+                slots.push(kdf::KeySlot::create_for_host(&slot.name, &public, slot.signing_key.as_ref().map(|k| &k[..]), new_key));
+            },
+            _ if slot.is_recovery() => {
+                let recovery_key = kdf::new_recovery_key();
+                let shown = match *recovery {
+                    RecoveryReplacement::None => continue,
+                    RecoveryReplacement::Keys => vec![recovery_key.clone()],
+                    RecoveryReplacement::Shares { count, threshold } => {
+                        match shamir::split(&kdf::parse_recovery_key(&recovery_key).unwrap(), threshold, count) {
+                            Err(e) => panic!("{}", e),
+                            Ok(shares) => shares.iter().map(|s| s.to_text()).collect()
+                        }
                     }
+                };
+                slots.push(kdf::KeySlot::create_recovery(&slot.name, &recovery_key, new_key));
+                recovery_keys.push((slot.name.clone(), shown));
+            },
+            _ => ()
+        }
+    }
+    if let Some(idx) = password_slot {
+        let old_slot = &manifest.slots[idx];
+        println!("The master key is being replaced, so the existing passwords will stop working.");
+        println!("Enter the new password for key slot '{}'.", old_slot.name);
+        let key_file = config::read_key_file(conf);
+        if old_slot.uses_key_file() && key_file.is_none() {
+            warn!("Key slot '{}' used a key file, but none is configured on this host; the new password works without one", old_slot.name);
+        }
+        slots.push(kdf::KeySlot::create(&old_slot.name, old_slot.algorithm.clone(), &collect_new_password(), key_file.as_ref().map(|kf| &kf[..]), new_key));
+    }
+    if slots.is_empty() {
+        panic!("Can't revoke host {}; nothing else could unlock the sync directory", host_name);
+    }
+    slots.push(kdf::KeySlot::create_with_key(kdf::ROTATION_SLOT_NAME, old_key, new_key));
+    let manifest = kdf::KdfManifest {
+        version: kdf::MANIFEST_VERSION,
+        sync_ids: manifest.sync_ids,
+        slots: slots,
+        key_id: Some(crypto_util::key_id(new_key))
+    };
+    (manifest, recovery_keys)
+}
+
+// Keyword keys don't change when the master key does, but their manifests must be unlocked by
+// the new one.  Already-moved manifests are skipped.
+fn rewrap_keyword_manifests(sync_dir: &str, old_key: &crypto_util::SecretKey, new_key: &crypto_util::SecretKey) {
+    let keywords = match kdf::find_keyword_manifests(sync_dir) {
+        Err(e) => panic!("{}", e),
+        Ok(kws) => kws
+    };
+    for kw in keywords {
+        let path = kdf::keyword_manifest_path(sync_dir, &kw);
+        let mut manifest = match kdf::KdfManifest::load_from(&path) {
+            Err(e) => panic!("{}", e),
+            Ok(m) => m
+        };
+        if manifest.unlock_with_key(new_key).is_ok() {
+            continue;
+        }
+        let kw_key = match manifest.unlock_with_key(old_key) {
+            Err(e) => {
+                warn!("Keyword {}: {}; it will not be unlocked by the new master key", kw, e);
+                continue;
+            },
+            Ok(k) => k
+        };
+        let idx = manifest.find_slot(kdf::MASTER_SLOT_NAME).unwrap();
+        manifest.replace_slot(idx, kdf::KeySlot::create_with_key(kdf::MASTER_SLOT_NAME, new_key, &kw_key));
+        match manifest.write_to(&path) {
+            Err(e) => panic!("{}", e),
+            Ok(_) => ()
+        }
+    }
+}
+
+// Remove host_name's key slot and replace the master key, so that a lost or retired host can't
+// read anything written after this.  Files it may already have copied are not protected.  All
+// syncfiles are re-encrypted with the new key, which also becomes the data key, so this refuses
+// to run while other hosts are syncing; if it is interrupted, or some files fail, run it again
+// to finish.  Keyword keys are not replaced.  Other hosts must unlock with the password once
+// before their host keys work again, since they pinned the old master key.
+// Slots that can't be moved to the new key (see revoke_dropped_slots()) are listed, and nothing
+// is changed unless drop_slots is set.  Returns the new recovery keys or shares, which are also
+// printed.
+pub fn host_revoke(state: &mut core::SyncState, host_name: &str, drop_slots: bool, recovery: RecoveryReplacement) -> Vec<(String, Vec<String>)> {
+    let sync_dir = state.conf.sync_dir().to_owned();
+    refuse_if_other_hosts_syncing(&state.conf);
+    let old_key = match state.conf.encryption_key {
+        None => panic!("The master key is needed to revoke a host; use a password that unlocks the sync directory"),
+        Some(ref k) => k.clone()
+    };
+
+    let mut new_recovery_keys = Vec::new();
+    let pending = match load_pending_manifest(&sync_dir) {
+        Some(m) => {
+            info!("Resuming interrupted key rotation");
+            m
+        },
+        None => {
+            let manifest = load_manifest(&sync_dir);
+            match manifest.find_slot(host_name) {
+                Some(idx) if manifest.slots[idx].is_host() => (),
+                _ => panic!("No host key slot for host {}", host_name)
+            }
+            let (dropped, _) = revoke_dropped_slots(&manifest, host_name, &recovery);
+            if !dropped.is_empty() && !drop_slots {
+                panic!("Revoking host {} would remove key slots {:?}, which can't be moved to the new master key.  \
+                    Run it again with --drop-slots to go ahead, and with --recovery to replace any recovery keys; \
+                    add the other slots again afterwards with \"keyslot add\".", host_name, dropped);
+            }
+            let (m, recovery_keys) = rotated_manifest(&state.conf, &manifest, host_name, &old_key, &crypto_util::new_random_key(), &recovery);
+            write_pending_manifest(&sync_dir, &m);
+            if !dropped.is_empty() {
+                warn!("Removed key slots {:?}, which can't be moved to the new master key", dropped);
+            }
+            // shown once the new manifest is safely written
+            for &(ref name, ref shown) in recovery_keys.iter() {
+                match recovery {
+                    RecoveryReplacement::Shares { threshold, .. } => print_recovery_shares(name, threshold, shown),
+                    _ => print_recovery_key(name, &shown[0])
                 }
             }
-            
-            count = count + 1;
+            new_recovery_keys = recovery_keys;
+            m
+        }
+    };
+
+    let rotation = pending.find_slot(kdf::ROTATION_SLOT_NAME);
+    let new_key = match rotation.and_then(|i| pending.slots[i].unlock_with_key(&old_key)) {
+        Some(k) => k,
+        None => {
+            // the new manifest was already moved into place, and the current key is the new one
+            match remove_file(kdf::pending_manifest_path(&sync_dir)) {
+                Err(e) => panic!("Failed to remove pending KDF manifest: {}", e),
+                Ok(_) => ()
+            }
+            info!("Key rotation was already finished");
+            return new_recovery_keys;
         }
+    };
+
+    let new_conf = state.conf.with_encryption_key(Some(new_key.clone())).with_data_keys(None, None);
+    let syncfiles = core::find_syncfile_paths(&sync_dir);
+    let count = reencrypt_all_syncfiles(&state.conf, &new_conf, &syncfiles, &mut state.syncdb);
+    rewrap_keyword_manifests(&sync_dir, &old_key, &new_key);
+
+    let mut manifest = pending;
+    manifest.remove_slot(kdf::ROTATION_SLOT_NAME).unwrap();
+    write_manifest(&sync_dir, &manifest);
+    match remove_file(kdf::pending_manifest_path(&sync_dir)) {
+        Err(e) => panic!("Failed to remove pending KDF manifest: {}", e),
+        Ok(_) => ()
     }
+    // the password slot, if it kept one
+    match manifest.slots.iter().find(|s| !s.is_host() && !s.is_recovery() && !s.is_wrapped_by_key()) {
+        None => (),
+        Some(slot) => agent::add_key(&sync_dir, &slot.name, &new_key)
+    }
+    match host_key::pin_master_key(&state.conf, &new_key) {
+        Err(e) => warn!("{}", e),
+        Ok(_) => ()
+    }
+
     state.conf = new_conf;
-    info!("Password changed on {} sync files", count);   
+    info!("Revoked host {} and replaced the master key; re-encrypted {} sync files", host_name, count);
+    new_recovery_keys
+}
+
+// Number of syncfiles that rotate_key() re-encrypts between syncs.
+const ROTATE_KEY_BATCH: usize = 20;
+
+// Start replacing the data key (see kdf::DATA_SLOT_NAME), or resume a replacement that was
+// interrupted.  The new key goes into the manifest right away, along with the old one as the
+// retired key, so from then on new syncfiles are written with the new key while files that
+// haven't been re-encrypted yet can still be read.  Unlike host revoke, the master key and the
+// key slots don't change.
+pub fn rotate_key_start(state: &mut core::SyncState) {
+    let sync_dir = state.conf.sync_dir().to_owned();
+    let master_key = match state.conf.encryption_key {
+        None => panic!("The master key is needed to replace the data key; use a password that unlocks the sync directory"),
+        Some(ref k) => k.clone()
+    };
+    let mut manifest = load_manifest(&sync_dir);
+    if !manifest.has_wrapped_key() {
+        panic!("This sync directory was created by an older version of greycrypt; run with --upgrade first");
+    }
+
+    if manifest.find_slot(kdf::RETIRED_DATA_SLOT_NAME).is_some() {
+        info!("Resuming interrupted data key rotation");
+    } else {
+        let old_key = state.conf.data_key.clone().unwrap_or(master_key.clone());
+        manifest.set_data_keys(&master_key, &crypto_util::new_random_key(), Some(&*old_key));
+        write_manifest(&sync_dir, &manifest);
+        info!("Replacing the data key; new sync files use the new key");
+    }
+
+    let (data_key, retired_data_key) = match manifest.unlock_data_keys(&master_key) {
+        Err(e) => panic!("{}", e),
+        Ok(keys) => keys
+    };
+    state.conf = state.conf.with_data_keys(data_key, retired_data_key);
+}
+
+// Re-encrypt up to max_files of the syncfiles that still use the retired data key.  When none
+// are left, the retired key is removed from the manifest.  Which files are done is read from
+// their preambles, so nothing else needs to be saved to resume.  Returns the number of files
+// still left.
+pub fn rotate_key_step(state: &mut core::SyncState, max_files: usize) -> usize {
+    let sync_dir = state.conf.sync_dir().to_owned();
+    let (master_key, data_key, retired) = match (&state.conf.encryption_key, &state.conf.data_key, &state.conf.retired_data_key) {
+        (&Some(ref mk), &Some(ref dk), &Some(ref rk)) => (mk.clone(), dk.clone(), rk.clone()),
+        _ => return 0
+    };
+    let retired_id = if retired == master_key { None } else { Some(crypto_util::key_id(&retired)) };
+    let using_retired = |files:Vec<String>| -> Vec<String> {
+        files.into_iter().filter(|f| {
+            match syncfile::SyncFile::get_key_id(&PathBuf::from(f)) {
+                Err(e) => {
+                    warn!("Unable to read syncfile {}: {}", f, e);
+                    false
+                },
+                Ok(id) => id == retired_id
+            }
+        }).collect()
+    };
+
+    let remaining = using_retired(core::find_syncfile_paths(&sync_dir));
+    if !remaining.is_empty() {
+        let new_conf = state.conf.with_data_keys(Some(data_key.clone()), None);
+        let count = reencrypt_syncfiles(&state.conf, &new_conf, &remaining, &mut state.syncdb, Some(max_files)).count;
+        let left = remaining.len() - count;
+        info!("Re-encrypted {} sync files with the new data key, {} left", count, left);
+        if left > 0 {
+            return left;
+        }
+    }
+
+    // check again, in case another host wrote one with the retired key before it saw the new one
+    let late = using_retired(core::find_syncfile_paths(&sync_dir)).len();
+    if late > 0 {
+        return late;
+    }
+    let mut manifest = load_manifest(&sync_dir);
+    manifest.set_data_keys(&master_key, &data_key, None);
+    write_manifest(&sync_dir, &manifest);
+    state.conf = state.conf.with_data_keys(Some(data_key), None);
+    info!("Finished replacing the data key; the old key was removed from the KDF manifest");
+    0
+}
+
+// Replace the data key, re-encrypting a few syncfiles at a time between syncs, which carry on
+// as usual.  Other hosts pick up the new key from the manifest; they can keep syncing too.  If
+// this is interrupted, run it again to carry on.  Once every file is re-encrypted, it just syncs.
+pub fn rotate_key(state: &mut core::SyncState, poll_interval: u32) {
+    rotate_key_start(state);
+
+    let mut rotating = true;
+    let mut last_left = None;
+    loop {
+        core::do_sync(state);
+        if rotating {
+            let left = rotate_key_step(state, ROTATE_KEY_BATCH);
+            if left == 0 {
+                rotating = false;
+            } else if last_left == Some(left) {
+                warn!("{} sync files that use the old data key can't be re-encrypted; see the errors above.  \
+                    Fix or remove them, then run rotate-key again to finish.", left);
+                rotating = false;
+            }
+            last_left = Some(left);
+        }
+        thread::sleep_ms(poll_interval * 1000);
+    }
+}
+
+// Time scrypt on this host and print parameters that take about target_ms to derive a key.
+pub fn kdf_benchmark(target_ms: u64) {
+    println!("Benchmarking scrypt with a target of {} ms...", target_ms);
+    let (alg, elapsed_ms) = kdf::benchmark_scrypt(target_ms);
+    println!("Recommended: --kdf {}  ({} ms on this host)", alg.spec(), elapsed_ms);
+    println!("Every host derives the key at startup, so run this on the slowest one.");
+    println!("Use it with --init for a new sync directory, or with -p to switch an existing one.");
+}
+
+fn sync_dir_has_syncfiles(sync_dir: &str) -> bool {
+    let sdp = PathBuf::from(sync_dir);
+    sdp.is_dir() && !core::find_syncfile_paths(sync_dir).is_empty()
+}
+
+// Create the KDF manifest for a new sync dir, with a random master key.
+pub fn init_sync_dir(conf: &config::SyncConfig, algorithm: kdf::KdfAlgorithm, password: &str) {
+    if sync_dir_has_syncfiles(conf.sync_dir()) {
+        panic!("Sync directory already contains syncfiles; if it was created by an older version of greycrypt, use --upgrade instead: {}", conf.sync_dir());
+    }
+
+    let key_file = config::read_key_file(conf);
+    let manifest = kdf::KdfManifest::create(algorithm, password, key_file.as_ref().map(|kf| &kf[..]), &crypto_util::new_random_key());
+    match manifest.save(conf.sync_dir()) {
+        Err(e) => panic!("Failed to initialize sync directory: {}", e),
+        Ok(_) => info!("Initialized sync directory: {}", conf.sync_dir())
+    }
+}
+
+// Upgrade a sync dir created by an older version of greycrypt.  Depending on its age:
+// - Dirs created before the KDF manifest existed have syncfiles encrypted with a key derived
+// using the old hardcoded salt; they are re-encrypted with a new random master key.
+// - Dirs with a manifest but no wrapped master key get one; it is the key they already use, so
+// their syncfiles are unchanged.
+// - Dirs that use unkeyed sync ids have their syncfiles renamed (and their headers rewritten) to
+// keyed ids.
+// The new manifest is first written to a "pending" file so that an interrupted upgrade resumes
+// with the same key; it is moved into place when all files have been converted.
+// This refuses to run while other hosts are syncing.  They move their own syncdb entries to the
+// new ids the next time they start.
+pub fn upgrade_sync_dir(state: &mut core::SyncState, password: &str, algorithm: kdf::KdfAlgorithm) {
+    let sync_dir = state.conf.sync_dir().to_owned();
+
+    let has_manifest = kdf::manifest_path(&sync_dir).is_file();
+    let old_manifest = if has_manifest {
+        match kdf::KdfManifest::load(&sync_dir) {
+            Err(e) => panic!("{}", e),
+            Ok(m) => m
+        }
+    } else {
+        kdf::KdfManifest::legacy()
+    };
+    if old_manifest.has_wrapped_key() && old_manifest.sync_ids == kdf::SyncIdScheme::Hmac {
+        info!("Sync directory is up to date, nothing to upgrade: {}", sync_dir);
+        return;
+    }
+    refuse_if_other_hosts_syncing(&state.conf);
+
+    let key_file = config::read_key_file(&state.conf);
+    let key_file = key_file.as_ref().map(|kf| &kf[..]);
+    let old_key = config::get_encryption_key(&old_manifest, password, key_file);
+    // the slots, and so any data keys, are kept if the master key is
+    let (data_key, retired_data_key) = if old_manifest.has_wrapped_key() {
+        match old_manifest.unlock_data_keys(&old_key) {
+            Err(e) => panic!("{}", e),
+            Ok(keys) => keys
+        }
+    } else {
+        (None, None)
+    };
+    let old_conf = state.conf
+        .with_encryption_key(Some(old_key.clone()))
+        .with_data_keys(data_key.clone(), retired_data_key.clone())
+        .with_sync_ids(old_manifest.sync_ids);
+
+    let syncfiles = core::find_syncfile_paths(&sync_dir);
+
+    let new_manifest = match load_pending_manifest(&sync_dir) {
+        Some(m) => {
+            info!("Resuming interrupted upgrade");
+            m
+        },
+        None => {
+            // make sure the password is right before converting anything
+            check_key_reads_syncfiles(&old_conf, &syncfiles);
+
+            let m = if old_manifest.has_wrapped_key() {
+                let mut m = old_manifest.with_keyed_sync_ids();
+                m.set_key_id(&old_key);
+                m
+            } else if has_manifest {
+                kdf::KdfManifest::create(old_manifest.slots[0].algorithm.clone(), password, key_file, &old_key)
+            } else {
+                kdf::KdfManifest::create(algorithm, password, key_file, &crypto_util::new_random_key())
+            };
+            write_pending_manifest(&sync_dir, &m);
+            m
+        }
+    };
+
+    let new_conf = state.conf
+        .with_encryption_key(Some(config::get_encryption_key(&new_manifest, password, key_file)))
+        .with_data_keys(data_key, retired_data_key)
+        .with_sync_ids(new_manifest.sync_ids);
+
+    let count = reencrypt_all_syncfiles(&old_conf, &new_conf, &syncfiles, &mut state.syncdb);
+
+    commit_pending_manifest(&sync_dir);
+
+    state.conf = new_conf;
+    core::migrate_syncdb_sync_ids(state);
+    info!("Upgraded {} sync files in {}", count, sync_dir);
 }
 
 #[cfg(test)]
 mod tests {
+    use std::fs::{File,PathExt,remove_file};
+    use std::io::Write;
+    use std::path::{PathBuf};
+    use std::collections::HashMap;
+    use std::thread;
+
+    use config;
     use core;
-    use testlib::util::{basic_alice_bob_setup,verify_sync_state};
+    use crypto_util;
+    use kdf;
+    use util;
+    use host_key;
+    use syncfile;
+    use testlib::util::{MetaConfig,basic_alice_bob_setup,verify_sync_state,test_kdf,write_text_file};
     
     #[test]
     fn change_password() {
@@ -153,29 +1203,533 @@ mod tests {
         core::do_sync(&mut alice_mconf.state);
         verify_sync_state(alice_mconf, 2, 2);
         
+        let sync_dir = alice_mconf.state.conf.sync_dir().to_owned();
+        let orig_manifest = kdf::KdfManifest::load(&sync_dir).unwrap();
         let orig_ek = alice_mconf.state.conf.encryption_key.clone();
+        let syncfiles = core::find_syncfile_paths(&sync_dir);
+        let orig_data:Vec<Vec<u8>> = syncfiles.iter().map(|f| util::slurp_bin_file(f)).collect();
         
-        super::change_password(&mut alice_mconf.state);
+        super::change_password(&alice_mconf.state.conf, "swordfish", None);
         
-        assert!(alice_mconf.state.conf.encryption_key != None);
-        assert!(alice_mconf.state.conf.encryption_key != orig_ek);
+        // only the manifest changed
+        assert!(alice_mconf.state.conf.encryption_key == orig_ek);
+        let manifest = kdf::KdfManifest::load(&sync_dir).unwrap();
+        assert_eq!(manifest.slots.len(), 1);
+        assert!(manifest.slots[0].salt != orig_manifest.slots[0].salt);
+        assert!(manifest.slots[0].wrapped_key != orig_manifest.slots[0].wrapped_key);
+        assert!(Some(config::get_encryption_key(&manifest, "swordfish", None)) == orig_ek);
+        assert_eq!(core::find_syncfile_paths(&sync_dir), syncfiles);
+        let new_data:Vec<Vec<u8>> = syncfiles.iter().map(|f| util::slurp_bin_file(f)).collect();
+        assert!(new_data == orig_data);
         
         verify_sync_state(alice_mconf, 2, 2);
     }
     
+    #[test]
+    fn upgrade_sync_dir() {
+        let (ref mut alice_mconf, _) = basic_alice_bob_setup("commands_upgrade_sync_dir");
+
+        // make it look like a sync dir from before the manifest existed
+        let sync_dir = alice_mconf.state.conf.sync_dir().to_owned();
+        remove_file(kdf::manifest_path(&sync_dir)).unwrap();
+        let legacy_ek = config::get_encryption_key(&kdf::KdfManifest::legacy(), "swordfish", None);
+        alice_mconf.state.conf = alice_mconf.state.conf
+            .with_encryption_key(Some(legacy_ek.clone()))
+            .with_sync_ids(kdf::SyncIdScheme::Sha256);
+
+        core::do_sync(&mut alice_mconf.state);
+        verify_sync_state(alice_mconf, 2, 2);
+
+        super::upgrade_sync_dir(&mut alice_mconf.state, "swordfish", kdf::KdfAlgorithm::Scrypt { log_n: 10, r: 8, p: 1 });
+
+        assert!(kdf::manifest_path(&sync_dir).is_file());
+        assert!(!kdf::pending_manifest_path(&sync_dir).is_file());
+        let manifest = kdf::KdfManifest::load(&sync_dir).unwrap();
+        let ek = config::get_encryption_key(&manifest, "swordfish", None);
+        assert!(alice_mconf.state.conf.encryption_key == Some(ek.clone()));
+        assert!(ek != legacy_ek);
+        assert_eq!(manifest.sync_ids, kdf::SyncIdScheme::Hmac);
+        assert_eq!(alice_mconf.state.conf.sync_ids, kdf::SyncIdScheme::Hmac);
+        assert!(alice_mconf.state.syncdb.has_keyed_sync_ids());
+
+        verify_sync_state(alice_mconf, 2, 2);
+    }
+
+    #[test]
+    fn upgrade_sync_ids() {
+        let (ref mut alice_mconf, _) = basic_alice_bob_setup("commands_upgrade_sync_ids");
+
+        // make it look like a sync dir from before keyed sync ids
+        let sync_dir = alice_mconf.state.conf.sync_dir().to_owned();
+        let mut manifest = kdf::KdfManifest::load(&sync_dir).unwrap();
+        manifest.version = 1;
+        manifest.sync_ids = kdf::SyncIdScheme::Sha256;
+        manifest.slots[0].wrapped_key = None;
+        manifest.write_to(&kdf::manifest_path(&sync_dir)).unwrap();
+        let ek = config::get_encryption_key(&manifest, "swordfish", None);
+        alice_mconf.state.conf = alice_mconf.state.conf
+            .with_encryption_key(Some(ek.clone()))
+            .with_sync_ids(kdf::SyncIdScheme::Sha256);
+
+        core::do_sync(&mut alice_mconf.state);
+        verify_sync_state(alice_mconf, 2, 2);
+        let old_syncfiles = core::find_syncfile_paths(&sync_dir);
+
+        super::upgrade_sync_dir(&mut alice_mconf.state, "swordfish", kdf::KdfAlgorithm::BcryptPbkdf { rounds: 4 });
+
+        // same key, now wrapped, and new ids
+        let new_manifest = kdf::KdfManifest::load(&sync_dir).unwrap();
+        assert_eq!(new_manifest.sync_ids, kdf::SyncIdScheme::Hmac);
+        assert_eq!(new_manifest.version, kdf::MANIFEST_VERSION);
+        assert!(config::get_encryption_key(&new_manifest, "swordfish", None) == ek);
+        assert!(alice_mconf.state.conf.encryption_key == Some(ek.clone()));
+        for f in &old_syncfiles {
+            assert!(!PathBuf::from(f).is_file(), "syncfile was not renamed: {}", f);
+        }
+
+        // syncdb entries were moved, so nothing changes on the next sync
+        verify_sync_state(alice_mconf, 2, 2);
+        core::do_sync(&mut alice_mconf.state);
+        verify_sync_state(alice_mconf, 2, 2);
+    }
+
+    #[test]
+    fn change_password_switch_kdf() {
+        let (ref mut alice_mconf, _) = basic_alice_bob_setup("commands_change_password_switch_kdf");
+
+        core::do_sync(&mut alice_mconf.state);
+        verify_sync_state(alice_mconf, 2, 2);
+
+        let sync_dir = alice_mconf.state.conf.sync_dir().to_owned();
+        let orig_manifest = kdf::KdfManifest::load(&sync_dir).unwrap();
+        let alg = kdf::KdfAlgorithm::BcryptPbkdf { rounds: 4 };
+
+        super::change_password(&alice_mconf.state.conf, "swordfish", Some(alg.clone()));
+
+        assert!(!kdf::pending_manifest_path(&sync_dir).is_file());
+        let manifest = kdf::KdfManifest::load(&sync_dir).unwrap();
+        assert_eq!(manifest.slots[0].algorithm, alg);
+        assert!(manifest.slots[0].salt != orig_manifest.slots[0].salt);
+        assert_eq!(manifest.sync_ids, orig_manifest.sync_ids);
+        assert!(Some(config::get_encryption_key(&manifest, "swordfish", None)) == alice_mconf.state.conf.encryption_key);
+
+        verify_sync_state(alice_mconf, 2, 2);
+    }
+
     #[test]
     #[should_panic(expected="incorrect password")]
     fn change_password_old_fails() {
         let (ref mut alice_mconf, _) = basic_alice_bob_setup("commands_change_password_old_fails");
         
+        // start with a different password than the new one
+        let sync_dir = alice_mconf.state.conf.sync_dir().to_owned();
+        let ek = alice_mconf.state.conf.encryption_key.clone().unwrap();
+        kdf::KdfManifest::create(test_kdf(), "oldpassword", None, &ek).write_to(&kdf::manifest_path(&sync_dir)).unwrap();
+        
         core::do_sync(&mut alice_mconf.state);
         verify_sync_state(alice_mconf, 2, 2);
         
-        let orig_conf = alice_mconf.state.conf.clone();
+        super::change_password(&alice_mconf.state.conf, "oldpassword", None);
         
-        super::change_password(&mut alice_mconf.state);
-        
-        alice_mconf.state.conf = orig_conf;
+        let manifest = kdf::KdfManifest::load(&sync_dir).unwrap();
+        config::get_encryption_key(&manifest, "oldpassword", None);
+    }
+
+    #[test]
+    fn change_password_adds_key_file() {
+        let (ref mut alice_mconf, _) = basic_alice_bob_setup("commands_change_password_adds_key_file");
+
+        core::do_sync(&mut alice_mconf.state);
         verify_sync_state(alice_mconf, 2, 2);
+
+        let sync_dir = alice_mconf.state.conf.sync_dir().to_owned();
+        let key_path = format!("{}.key", sync_dir);
+        write_text_file(&key_path, "not a very random key file");
+        let conf = alice_mconf.state.conf.with_key_file(Some(key_path.clone()));
+
+        super::change_password(&conf, "swordfish", None);
+
+        let manifest = kdf::KdfManifest::load(&sync_dir).unwrap();
+        assert!(manifest.slots[0].uses_key_file());
+        let key_file = config::read_key_file(&conf).unwrap();
+        assert!(Some(config::get_encryption_key(&manifest, "swordfish", Some(&key_file[..]))) == conf.encryption_key);
+        let e = manifest.unlock("swordfish", None).err().unwrap();
+        assert!(e.contains("key file"), "{}", e);
+
+        write_text_file(&key_path, "a different key file");
+        let key_file = config::read_key_file(&conf).unwrap();
+        let e = manifest.unlock("swordfish", Some(&key_file[..])).err().unwrap();
+        assert!(e.contains("does not match"), "{}", e);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn change_password_unlocked() {
+        let (ref mut alice_mconf, _) = basic_alice_bob_setup("commands_change_password_unlocked");
+        let sync_dir = alice_mconf.state.conf.sync_dir().to_owned();
+        let ek = alice_mconf.state.conf.encryption_key.clone().unwrap();
+
+        // as when the agent holds the key; no old password needed
+        super::change_password_unlocked(&alice_mconf.state.conf, kdf::DEFAULT_SLOT_NAME, ek.clone(), None);
+        let manifest = kdf::KdfManifest::load(&sync_dir).unwrap();
+        assert!(config::get_encryption_key(&manifest, "swordfish", None) == ek);
+        verify_sync_state(alice_mconf, 2, 2);
+    }
+
+    #[test]
+    fn keyslots() {
+        let (ref mut alice_mconf, _) = basic_alice_bob_setup("commands_keyslots");
+
+        core::do_sync(&mut alice_mconf.state);
+        verify_sync_state(alice_mconf, 2, 2);
+
+        let sync_dir = alice_mconf.state.conf.sync_dir().to_owned();
+        let ek = alice_mconf.state.conf.encryption_key.clone().unwrap();
+
+        assert!(super::keyslot_add(&alice_mconf.state.conf, "swordfish", "bob", false, test_kdf()).is_none());
+        let recovery_key = super::keyslot_add(&alice_mconf.state.conf, "swordfish", "recovery", true, test_kdf()).unwrap();
+
+        let manifest = kdf::KdfManifest::load(&sync_dir).unwrap();
+        let names:Vec<&str> = manifest.slots.iter().map(|s| &s.name[..]).collect();
+        assert_eq!(names, vec![kdf::DEFAULT_SLOT_NAME, "bob", "recovery"]);
+        assert!(config::get_encryption_key(&manifest, &recovery_key, None) == ek);
+
+        // any slot can authorize removing another
+        super::keyslot_remove(&alice_mconf.state.conf, &recovery_key, kdf::DEFAULT_SLOT_NAME);
+        let manifest = kdf::KdfManifest::load(&sync_dir).unwrap();
+        assert_eq!(manifest.slots.len(), 2);
+        assert!(manifest.find_slot(kdf::DEFAULT_SLOT_NAME).is_none());
+        assert!(config::get_encryption_key(&manifest, "swordfish", None) == ek);
+
+        // files are untouched
+        verify_sync_state(alice_mconf, 2, 2);
+    }
+
+    #[test]
+    fn keyslots_unlocked() {
+        let (ref mut alice_mconf, _) = basic_alice_bob_setup("commands_keyslots_unlocked");
+
+        let sync_dir = alice_mconf.state.conf.sync_dir().to_owned();
+        let ek = alice_mconf.state.conf.encryption_key.clone().unwrap();
+
+        // as with a key from the agent: no password
+        assert!(super::keyslot_add_unlocked(&alice_mconf.state.conf, ek.clone(), "bob", false, test_kdf()).is_none());
+        let shares = super::recovery_split_unlocked(&alice_mconf.state.conf, ek.clone(), "recovery", 3, 2);
+        assert_eq!(shares.len(), 3);
+        super::keyslot_remove_unlocked(&alice_mconf.state.conf, ek.clone(), kdf::DEFAULT_SLOT_NAME);
+
+        let manifest = kdf::KdfManifest::load(&sync_dir).unwrap();
+        let names:Vec<&str> = manifest.slots.iter().map(|s| &s.name[..]).collect();
+        assert_eq!(names, vec!["bob", "recovery"]);
+        assert!(config::get_encryption_key(&manifest, "swordfish", None) == ek);
+    }
+
+    #[test]
+    #[should_panic(expected="only key slot")]
+    fn keyslot_remove_last() {
+        let (ref mut alice_mconf, _) = basic_alice_bob_setup("commands_keyslot_remove_last");
+        super::keyslot_remove(&alice_mconf.state.conf, "swordfish", kdf::DEFAULT_SLOT_NAME);
+    }
+
+    #[test]
+    fn recovery_shares() {
+        let (ref mut alice_mconf, _) = basic_alice_bob_setup("commands_recovery_shares");
+
+        let sync_dir = alice_mconf.state.conf.sync_dir().to_owned();
+        let ek = alice_mconf.state.conf.encryption_key.clone().unwrap();
+
+        let shares = super::recovery_split(&alice_mconf.state.conf, "swordfish", "recovery", 5, 3);
+        assert_eq!(shares.len(), 5);
+
+        let subset = vec![shares[4].clone(), shares[0].clone(), shares[2].clone()];
+        super::recovery_combine(&alice_mconf.state.conf, &subset, "recovered", test_kdf());
+
+        let manifest = kdf::KdfManifest::load(&sync_dir).unwrap();
+        assert_eq!(manifest.slots.len(), 3);
+        let idx = manifest.find_slot("recovered").unwrap();
+        assert!(manifest.slots[idx].unlock("swordfish", None) == Some(ek));
+    }
+
+    #[test]
+    #[should_panic(expected="Need 3 shares")]
+    fn recovery_too_few_shares() {
+        let (ref mut alice_mconf, _) = basic_alice_bob_setup("commands_recovery_too_few_shares");
+
+        let shares = super::recovery_split(&alice_mconf.state.conf, "swordfish", "recovery", 5, 3);
+        super::recovery_combine(&alice_mconf.state.conf, &shares[0..2].to_vec(), "recovered", test_kdf());
+    }
+
+    #[test]
+    fn keyword_add() {
+        let (ref mut alice_mconf, ref mut bob_mconf) = basic_alice_bob_setup("commands_keyword_add");
+
+        core::do_sync(&mut alice_mconf.state);
+        verify_sync_state(alice_mconf, 2, 2);
+        let sync_dir = alice_mconf.state.conf.sync_dir().to_owned();
+        let old_syncfiles = core::find_syncfile_paths(&sync_dir);
+
+        super::keyword_add(&mut alice_mconf.state, "home", "shared", test_kdf());
+
+        let manifest = kdf::KdfManifest::load_from(&kdf::keyword_manifest_path(&sync_dir, "HOME")).unwrap();
+        let master_key = alice_mconf.state.conf.encryption_key.clone().unwrap();
+        let kw_key = manifest.unlock_with_key(&master_key).unwrap();
+        assert!(config::get_encryption_key(&manifest, "swordfish", None) == kw_key);
+        assert!(alice_mconf.state.conf.keyword_keys.get("HOME") == Some(&Some(kw_key.clone())));
+        // sync ids depend on the key, so the files were renamed
+        for f in &old_syncfiles {
+            assert!(!PathBuf::from(f).is_file(), "syncfile was not renamed: {}", f);
+        }
+
+        // syncdb entries were moved, so nothing changes on the next sync
+        verify_sync_state(alice_mconf, 2, 2);
+        core::do_sync(&mut alice_mconf.state);
+        verify_sync_state(alice_mconf, 2, 2);
+
+        // someone who only has the keyword's password gets its files
+        let mut kw_keys = HashMap::new();
+        kw_keys.insert("HOME".to_owned(), Some(kw_key));
+        bob_mconf.state.conf = bob_mconf.state.conf.with_encryption_key(None).with_keyword_keys(kw_keys);
+        core::do_sync(&mut bob_mconf.state);
+        verify_sync_state(bob_mconf, 2, 2);
+
+        // running it again changes nothing
+        super::keyword_add(&mut alice_mconf.state, "home", "shared", test_kdf());
+        verify_sync_state(alice_mconf, 2, 2);
+    }
+
+    #[test]
+    fn reencrypt_failures() {
+        let (ref mut alice_mconf, _) = basic_alice_bob_setup("commands_reencrypt_failures");
+        core::do_sync(&mut alice_mconf.state);
+        verify_sync_state(alice_mconf, 2, 2);
+        let sync_dir = alice_mconf.state.conf.sync_dir().to_owned();
+        let syncfiles = core::find_syncfile_paths(&sync_dir);
+
+        // cut the end off one file's data; its header still reads
+        let mut data = util::slurp_bin_file(&syncfiles[0]);
+        let len = data.len();
+        data.truncate(len - 1);
+        File::create(&syncfiles[0]).unwrap().write_all(&data).unwrap();
+        assert!(syncfile::SyncFile::get_syncid_from_file(&alice_mconf.state.conf, &PathBuf::from(&syncfiles[0])).is_ok());
+
+        let old_conf = alice_mconf.state.conf.clone();
+        let mut kw_keys = HashMap::new();
+        kw_keys.insert("HOME".to_owned(), Some(crypto_util::new_random_key()));
+        let new_conf = old_conf.with_keyword_keys(kw_keys);
+        let r = super::reencrypt_syncfiles(&old_conf, &new_conf, &syncfiles, &mut alice_mconf.state.syncdb, None);
+
+        // the other file is converted and verified, and the broken one is left as it was
+        assert_eq!(r.count, 1);
+        assert_eq!(r.failed, vec![syncfiles[0].clone()]);
+        assert!(PathBuf::from(&syncfiles[0]).is_file());
+        assert!(!PathBuf::from(&syncfiles[1]).is_file());
+        let converted = core::find_syncfile_paths(&sync_dir);
+        assert_eq!(converted.len(), 2);
+        for f in converted.iter().filter(|f| **f != syncfiles[0]) {
+            let sid = syncfile::SyncFile::get_syncid_from_file(&new_conf, &PathBuf::from(f)).unwrap();
+            assert!(super::verify_syncfile(&new_conf, &PathBuf::from(f), &sid).is_ok());
+        }
+    }
+
+    #[test]
+    #[should_panic(expected="may still be syncing")]
+    fn keyword_add_while_other_host_syncing() {
+        let (ref mut alice_mconf, ref mut bob_mconf) = basic_alice_bob_setup("commands_keyword_add_while_other_host_syncing");
+        alice_mconf.state.conf.host_name = "alice".to_owned();
+        bob_mconf.state.conf.host_name = "bob".to_owned();
+
+        core::do_sync(&mut alice_mconf.state);
+        core::do_sync(&mut bob_mconf.state);
+        let sync_dir = alice_mconf.state.conf.sync_dir().to_owned();
+        assert!(core::sync_marker_path(&sync_dir, "bob").is_file());
+        assert_eq!(core::other_hosts_syncing(&sync_dir, "alice").unwrap(), vec!["bob".to_owned()]);
+        assert_eq!(core::other_hosts_syncing(&sync_dir, "bob").unwrap(), vec!["alice".to_owned()]);
+
+        super::keyword_add(&mut alice_mconf.state, "home", "shared", test_kdf());
+    }
+
+    #[test]
+    fn rotate_key() {
+        let (ref mut alice_mconf, ref mut bob_mconf) = basic_alice_bob_setup("commands_rotate_key");
+        core::do_sync(&mut alice_mconf.state);
+        core::do_sync(&mut bob_mconf.state);
+        verify_sync_state(bob_mconf, 2, 2);
+        let sync_dir = alice_mconf.state.conf.sync_dir().to_owned();
+        let master_key = alice_mconf.state.conf.encryption_key.clone().unwrap();
+
+        // the hosts notice a changed manifest by its mtime
+        thread::sleep_ms(1000);
+
+        super::rotate_key_start(&mut alice_mconf.state);
+        let manifest = kdf::KdfManifest::load(&sync_dir).unwrap();
+        let (data_key, retired) = manifest.unlock_data_keys(&master_key).unwrap();
+        let data_key = data_key.unwrap();
+        assert!(retired == Some(master_key.clone()));
+        assert!(alice_mconf.state.conf.data_key == Some(data_key.clone()));
+        let data_key_id = Some(crypto_util::key_id(&data_key));
+
+        // new files use the new key, and bob keeps syncing
+        let mut new_pb = PathBuf::from(&alice_mconf.native_root);
+        new_pb.push("docs");
+        new_pb.push("new_text_file.txt");
+        write_text_file(new_pb.to_str().unwrap(), "Some new text");
+        core::do_sync(&mut alice_mconf.state);
+        core::do_sync(&mut bob_mconf.state);
+        verify_sync_state(bob_mconf, 3, 3);
+        let key_ids:Vec<Option<String>> = core::find_syncfile_paths(&sync_dir).iter()
+            .map(|f| syncfile::SyncFile::get_key_id(&PathBuf::from(f)).unwrap()).collect();
+        assert_eq!(key_ids.iter().filter(|id| **id == data_key_id).count(), 1);
+
+        // a few at a time
+        assert_eq!(super::rotate_key_step(&mut alice_mconf.state, 1), 1);
+        assert!(kdf::KdfManifest::load(&sync_dir).unwrap().find_slot(kdf::RETIRED_DATA_SLOT_NAME).is_some());
+        thread::sleep_ms(1000);
+        assert_eq!(super::rotate_key_step(&mut alice_mconf.state, 20), 0);
+
+        let manifest = kdf::KdfManifest::load(&sync_dir).unwrap();
+        assert!(manifest.find_slot(kdf::RETIRED_DATA_SLOT_NAME).is_none());
+        assert!(alice_mconf.state.conf.retired_data_key.is_none());
+        for f in core::find_syncfile_paths(&sync_dir) {
+            assert_eq!(syncfile::SyncFile::get_key_id(&PathBuf::from(&f)).unwrap(), data_key_id);
+        }
+        // the password still unlocks the same master key
+        assert!(config::get_encryption_key(&manifest, "swordfish", None) == master_key);
+
+        // nothing changes on the next sync, and bob picks up the change
+        core::do_sync(&mut alice_mconf.state);
+        verify_sync_state(alice_mconf, 3, 3);
+        core::do_sync(&mut bob_mconf.state);
+        verify_sync_state(bob_mconf, 3, 3);
+        assert!(bob_mconf.state.conf.retired_data_key.is_none());
+
+        // the master key alone no longer reads them
+        let master_only = alice_mconf.state.conf.with_data_keys(None, None);
+        let syncfiles = core::find_syncfile_paths(&sync_dir);
+        assert!(syncfile::SyncFile::from_syncfile(&master_only, &PathBuf::from(&syncfiles[0])).is_err());
+    }
+
+    #[test]
+    fn host_enroll_and_revoke() {
+        let (ref mut alice_mconf, ref mut bob_mconf) = basic_alice_bob_setup("commands_host_enroll_and_revoke");
+        let hosts = vec!["alice".to_owned(), "bob".to_owned()];
+        alice_mconf.state.conf.host_name = "alice".to_owned();
+        alice_mconf.state.conf = alice_mconf.state.conf.with_known_hosts(hosts.clone());
+        bob_mconf.state.conf.host_name = "bob".to_owned();
+        bob_mconf.state.conf = bob_mconf.state.conf.with_known_hosts(hosts);
+
+        core::do_sync(&mut alice_mconf.state);
+        verify_sync_state(alice_mconf, 2, 2);
+        let sync_dir = alice_mconf.state.conf.sync_dir().to_owned();
+        let old_key = alice_mconf.state.conf.encryption_key.clone().unwrap();
+
+        // alice enrolls herself, then bob after he publishes his key
+        super::host_enroll(&alice_mconf.state.conf, "alice");
+        super::host_enroll_request(&bob_mconf.state.conf);
+        assert!(host_key::published_key_path(&sync_dir, "bob").is_file());
+        super::host_enroll(&alice_mconf.state.conf, "bob");
+        assert!(!host_key::published_key_path(&sync_dir, "bob").is_file());
+
+        let manifest = kdf::KdfManifest::load(&sync_dir).unwrap();
+        let (slot, key) = config::host_key_unlock(&bob_mconf.state.conf, &manifest).unwrap();
+        assert_eq!(slot, "bob");
+        assert!(key == old_key);
+        assert!(config::agent_key_current(&manifest, kdf::DEFAULT_SLOT_NAME, &old_key));
+
+        super::host_revoke(&mut alice_mconf.state, "bob", false, super::RecoveryReplacement::None);
+        assert!(!kdf::pending_manifest_path(&sync_dir).is_file());
+
+        let manifest = kdf::KdfManifest::load(&sync_dir).unwrap();
+        assert!(manifest.find_slot("bob").is_none());
+        assert!(config::host_key_unlock(&bob_mconf.state.conf, &manifest).is_none());
+        let (_, new_key) = config::host_key_unlock(&alice_mconf.state.conf, &manifest).unwrap();
+        assert!(new_key != old_key);
+        assert!(alice_mconf.state.conf.encryption_key == Some(new_key.clone()));
+        assert!(config::get_encryption_key(&manifest, "swordfish", None) == new_key);
+        // an agent still holding the old key for the default slot, which still exists, isn't used
+        assert!(!config::agent_key_current(&manifest, kdf::DEFAULT_SLOT_NAME, &old_key));
+        assert!(config::agent_key_current(&manifest, kdf::DEFAULT_SLOT_NAME, &new_key));
+
+        // the files were re-encrypted, and nothing changes on the next sync
+        verify_sync_state(alice_mconf, 2, 2);
+        core::do_sync(&mut alice_mconf.state);
+        verify_sync_state(alice_mconf, 2, 2);
+
+        // bob's old key no longer reads them
+        let syncfiles = core::find_syncfile_paths(&sync_dir);
+        let pb = PathBuf::from(&syncfiles[0]);
+        assert!(syncfile::SyncFile::get_syncid_from_file(&bob_mconf.state.conf, &pb).is_err());
+    }
+
+    // alice and bob as separate hosts, both enrolled by alice, with a password slot for carol and
+    // a recovery slot as well
+    fn revoke_setup(testname: &str) -> (MetaConfig, MetaConfig) {
+        let (mut alice_mconf, mut bob_mconf) = basic_alice_bob_setup(testname);
+        let hosts = vec!["alice".to_owned(), "bob".to_owned()];
+        alice_mconf.state.conf.host_name = "alice".to_owned();
+        alice_mconf.state.conf = alice_mconf.state.conf.with_known_hosts(hosts.clone());
+        bob_mconf.state.conf.host_name = "bob".to_owned();
+        bob_mconf.state.conf = bob_mconf.state.conf.with_known_hosts(hosts);
+
+        core::do_sync(&mut alice_mconf.state);
+        super::host_enroll(&alice_mconf.state.conf, "alice");
+        super::host_enroll_request(&bob_mconf.state.conf);
+        super::host_enroll(&alice_mconf.state.conf, "bob");
+        super::keyslot_add(&alice_mconf.state.conf, "swordfish", "carol", false, test_kdf());
+        super::keyslot_add(&alice_mconf.state.conf, "swordfish", "spare", true, test_kdf());
+        (alice_mconf, bob_mconf)
+    }
+
+    fn slot_names(sync_dir: &str) -> Vec<String> {
+        kdf::KdfManifest::load(sync_dir).unwrap().slots.iter().map(|s| s.name.clone()).collect()
+    }
+
+    #[test]
+    #[should_panic(expected="--drop-slots")]
+    fn host_revoke_needs_drop_slots() {
+        let (ref mut alice_mconf, _) = revoke_setup("commands_host_revoke_needs_drop_slots");
+        // carol's slot would go, so nothing is changed without --drop-slots
+        super::host_revoke(&mut alice_mconf.state, "bob", false, super::RecoveryReplacement::Keys);
+    }
+
+    #[test]
+    fn host_revoke_slots() {
+        let (ref mut alice_mconf, _) = revoke_setup("commands_host_revoke_slots");
+        let sync_dir = alice_mconf.state.conf.sync_dir().to_owned();
+
+        let recovery_keys = super::host_revoke(&mut alice_mconf.state, "bob", true, super::RecoveryReplacement::Keys);
+
+        // the other host and the first password slot are kept, the recovery slot is replaced,
+        // and carol's password slot is dropped
+        assert_eq!(slot_names(&sync_dir), vec!["alice".to_owned(), "spare".to_owned(), kdf::DEFAULT_SLOT_NAME.to_owned()]);
+        let manifest = kdf::KdfManifest::load(&sync_dir).unwrap();
+        let new_key = alice_mconf.state.conf.encryption_key.clone().unwrap();
+        assert!(config::get_encryption_key(&manifest, "swordfish", None) == new_key);
+        assert_eq!(recovery_keys.len(), 1);
+        assert_eq!(recovery_keys[0].0, "spare");
+        assert!(manifest.slots[1].is_recovery());
+        assert!(config::get_encryption_key(&manifest, &recovery_keys[0].1[0], None) == new_key);
+
+        // or split into shares
+        let (ref mut alice_mconf, _) = revoke_setup("commands_host_revoke_slots_shares");
+        let sync_dir = alice_mconf.state.conf.sync_dir().to_owned();
+        let recovery_keys = super::host_revoke(&mut alice_mconf.state, "bob", true, super::RecoveryReplacement::Shares { count: 3, threshold: 2 });
+        assert_eq!(recovery_keys[0].1.len(), 3);
+        let shares:Vec<String> = recovery_keys[0].1[1..].to_vec();
+        assert_eq!(slot_names(&sync_dir), vec!["alice".to_owned(), "spare".to_owned(), kdf::DEFAULT_SLOT_NAME.to_owned()]);
+        super::recovery_combine(&alice_mconf.state.conf, &shares, "recovered", test_kdf());
+
+        // without replacing them, the recovery slots are dropped too
+        let (ref mut alice_mconf, _) = revoke_setup("commands_host_revoke_slots_drop");
+        let sync_dir = alice_mconf.state.conf.sync_dir().to_owned();
+        assert!(super::host_revoke(&mut alice_mconf.state, "bob", true, super::RecoveryReplacement::None).is_empty());
+        assert_eq!(slot_names(&sync_dir), vec!["alice".to_owned(), kdf::DEFAULT_SLOT_NAME.to_owned()]);
+    }
+
+    #[test]
+    fn new_password_sources() {
+        use password_source::PasswordSource;
+
+        // prompted twice; the test collect_new_password() skips the prompts
+        assert_eq!(super::get_new_password(&PasswordSource::Prompt), "swordfish");
+        assert_eq!(super::get_new_password(&PasswordSource::Config("hunter22".to_owned())), "hunter22");
+    }
+}
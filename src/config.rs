@@ -1,19 +1,26 @@
 use std::collections::BTreeMap;
+use std::collections::HashMap;
 use std::collections::HashSet;
 use std::path::{PathBuf};
 use std::fs::{PathExt};
 use std::fmt;
 use std::env;
 use std::io;
-use std::io::BufRead;
-
-extern crate crypto;
-use self::crypto::bcrypt_pbkdf::bcrypt_pbkdf;
+use std::io::{BufRead,Read};
+use std::fs::File;
 
 extern crate toml;
 
 use util;
 use mapping;
+use kdf;
+use crypto_util::SecretKey;
+use crypto_util;
+use agent;
+use host_key;
+use syncfile;
+use native_attrs;
+use password_source::PasswordSource;
 
 use rpassword::read_password;
 
@@ -23,8 +30,28 @@ pub const KEY_SIZE: usize = 32;
 pub struct SyncConfig {
     sync_dir: String, // use sync_dir() to read this
     pub host_name: String,
+    // every host name listed in [Mapping]; only these can be enrolled
+    pub known_hosts: Vec<String>,
     pub mapping: mapping::Mapping,
-    pub encryption_key: Option<[u8; KEY_SIZE]>,
+    pub encryption_key: Option<SecretKey>,
+    // The key that syncfiles are encrypted with, if it isn't the master key, and the one it
+    // replaced while the syncfiles are re-encrypted; see kdf::DATA_SLOT_NAME.
+    pub data_key: Option<SecretKey>,
+    pub retired_data_key: Option<SecretKey>,
+    // Keywords (uppercase) that have their own key; see keyword_key().  The value is None if
+    // this host doesn't have it, in which case the keyword's syncfiles are skipped.
+    pub keyword_keys: HashMap<String,Option<SecretKey>>,
+    // Ed25519 public keys of the hosts whose signatures are accepted, by host name; see
+    // host_key::trusted_signers().
+    pub signers: HashMap<String,Vec<u8>>,
+    // This host's signing key, if it is one of the signers.  Syncfiles are signed if it is set.
+    pub signing_key: Option<SecretKey>,
+    pub sync_ids: kdf::SyncIdScheme,
+    pub key_file: Option<String>,
+    pub padding: syncfile::Padding,
+    pub compression: syncfile::Compression,
+    // The file attributes that this host records in syncfiles and restores from them
+    pub preserve: native_attrs::Preserve,
     pub syncdb_dir: Option<String>,
     pub native_paths: Vec<String>
 }
@@ -35,12 +62,37 @@ impl fmt::Debug for SyncConfig {
             None => "missing",
             Some(_) => "present (value suppressed)"
         };
+        let mut kw_keys:Vec<String> = self.keyword_keys.iter().map(|(kw,k)| {
+            format!("{}: {}", kw, if k.is_some() { "present" } else { "missing" })
+        }).collect();
+        kw_keys.sort();
+        let mut signers:Vec<&String> = self.signers.keys().collect();
+        signers.sort();
+        let sk_str = match self.signing_key {
+            None => "missing",
+            Some(_) => "present (value suppressed)"
+        };
+        let dk_str = match (&self.data_key, &self.retired_data_key) {
+            (&None, _) => "master key",
+            (&Some(_), &None) => "present (value suppressed)",
+            (&Some(_), &Some(_)) => "present, with retired key (values suppressed)"
+        };
 
-        write!(f, "SyncConfig {{ sync_dir: {:?}, host_name: {:?}, mapping: {:?}, encryption_key: {}, syncdb_dir: {:?}, native_paths: {:?} }}",
+        write!(f, "SyncConfig {{ sync_dir: {:?}, host_name: {:?}, known_hosts: {:?}, mapping: {:?}, encryption_key: {}, data_key: {}, keyword_keys: {:?}, signers: {:?}, signing_key: {}, sync_ids: {:?}, key_file: {:?}, padding: {:?}, compression: {:?}, preserve: {:?}, syncdb_dir: {:?}, native_paths: {:?} }}",
             self.sync_dir,
             self.host_name,
+            self.known_hosts,
             self.mapping,
             ek_str,
+            dk_str,
+            kw_keys,
+            signers,
+            sk_str,
+            self.sync_ids,
+            self.key_file,
+            self.padding,
+            self.compression,
+            self.preserve,
             self.syncdb_dir,
             self.native_paths)
     }
@@ -64,7 +116,7 @@ impl SyncConfig {
     pub fn new(sync_dir: String,
         host_name: String,
         mapping: mapping::Mapping,
-        ek:Option<[u8;KEY_SIZE]>,
+        ek:Option<SecretKey>,
         syncdb_dir:Option<String>,
         native_paths: Vec<String>) -> Self {
             let mut pb = PathBuf::from(&sync_dir);
@@ -78,18 +130,147 @@ impl SyncConfig {
             let conf = SyncConfig {
                 sync_dir: pb.to_str().unwrap().to_owned(),
                 host_name: host_name,
+                known_hosts: Vec::new(),
                 mapping: mapping,
                 encryption_key: ek,
+                data_key: None,
+                retired_data_key: None,
+                keyword_keys: HashMap::new(),
+                signers: HashMap::new(),
+                signing_key: None,
+                sync_ids: kdf::SyncIdScheme::Hmac,
+                key_file: None,
+                padding: syncfile::Padding::None,
+                compression: syncfile::Compression::None,
+                preserve: native_attrs::Preserve::none(),
                 syncdb_dir: syncdb_dir,
                 native_paths: native_paths
             };
             conf
     }
     
-    pub fn with_encryption_key(&self,ek:Option<[u8;KEY_SIZE]>) -> Self {
+    pub fn with_encryption_key(&self,ek:Option<SecretKey>) -> Self {
         let myclone = self.clone();
         SyncConfig { encryption_key: ek, .. myclone } 
     } 
+
+    pub fn with_data_keys(&self,data_key:Option<SecretKey>,retired_data_key:Option<SecretKey>) -> Self {
+        let myclone = self.clone();
+        SyncConfig { data_key: data_key, retired_data_key: retired_data_key, .. myclone }
+    }
+
+    pub fn with_keyword_keys(&self,keyword_keys:HashMap<String,Option<SecretKey>>) -> Self {
+        let myclone = self.clone();
+        SyncConfig { keyword_keys: keyword_keys, .. myclone }
+    }
+
+    // The key for sync ids under kw: its own key if it has one, otherwise the master key.
+    // None if this host doesn't have it.
+    pub fn keyword_key(&self, kw:&str) -> Option<&SecretKey> {
+        match self.keyword_keys.get(&kw.to_uppercase()) {
+            Some(k) => k.as_ref(),
+            None => self.encryption_key.as_ref()
+        }
+    }
+
+    // The key that new syncfiles under kw are encrypted with: its own key if it has one,
+    // otherwise the data key.
+    pub fn file_key(&self, kw:&str) -> Option<&SecretKey> {
+        match (self.keyword_keys.get(&kw.to_uppercase()), self.encryption_key.as_ref()) {
+            (Some(k), _) => k.as_ref(),
+            (None, Some(_)) if self.data_key.is_some() => self.data_key.as_ref(),
+            (None, ek) => ek
+        }
+    }
+
+    // The key id written to syncfiles under kw, if it has its own key, or if there is a data
+    // key.  Files encrypted with the master key don't name one.
+    pub fn file_key_id(&self, kw:&str) -> Option<String> {
+        match self.keyword_keys.get(&kw.to_uppercase()) {
+            Some(&Some(ref k)) => Some(crypto_util::key_id(k)),
+            Some(&None) => None,
+            None => self.data_key.as_ref().map(|k| crypto_util::key_id(k))
+        }
+    }
+
+    // The key id of a data key, as written to syncfiles; None for the master key.
+    fn data_key_id(&self, key:Option<&SecretKey>) -> Option<String> {
+        match key {
+            Some(k) if self.encryption_key.as_ref() != Some(k) => Some(crypto_util::key_id(k)),
+            _ => None
+        }
+    }
+
+    // Whether a syncfile under kw may be encrypted with the key that key_id names: the keyword's
+    // own key if it has one, otherwise the data key or the retired one.
+    pub fn key_id_allowed(&self, kw:&str, key_id:Option<&str>) -> bool {
+        let key_id = key_id.map(|id| id.to_owned());
+        if key_id == self.file_key_id(kw) {
+            return true;
+        }
+        !self.keyword_keys.contains_key(&kw.to_uppercase()) && self.retired_data_key.is_some()
+            && key_id == self.data_key_id(self.retired_data_key.as_ref())
+    }
+
+    // The key for a syncfile that names key_id.  Files that don't name one use the master key,
+    // unless it has been replaced by a data key.
+    pub fn key_for_id(&self, key_id:Option<&str>) -> Option<&SecretKey> {
+        match key_id {
+            None => {
+                let master_retired = self.retired_data_key.is_some() && self.data_key_id(self.retired_data_key.as_ref()).is_none();
+                if self.data_key.is_none() || master_retired {
+                    self.encryption_key.as_ref()
+                } else {
+                    None
+                }
+            },
+            Some(id) => self.keyword_keys.values()
+                .filter_map(|k| k.as_ref())
+                .chain(self.data_key.iter())
+                .chain(self.retired_data_key.iter())
+                .find(|k| crypto_util::key_id(k) == id)
+        }
+    }
+
+    pub fn with_signers(&self,signers:HashMap<String,Vec<u8>>) -> Self {
+        let myclone = self.clone();
+        SyncConfig { signers: signers, .. myclone }
+    }
+
+    pub fn with_signing_key(&self,signing_key:Option<SecretKey>) -> Self {
+        let myclone = self.clone();
+        SyncConfig { signing_key: signing_key, .. myclone }
+    }
+
+    pub fn with_sync_ids(&self,sync_ids:kdf::SyncIdScheme) -> Self {
+        let myclone = self.clone();
+        SyncConfig { sync_ids: sync_ids, .. myclone }
+    }
+
+    pub fn with_known_hosts(&self,known_hosts:Vec<String>) -> Self {
+        let myclone = self.clone();
+        SyncConfig { known_hosts: known_hosts, .. myclone }
+    }
+
+    pub fn with_key_file(&self,key_file:Option<String>) -> Self {
+        let myclone = self.clone();
+        SyncConfig { key_file: key_file, .. myclone }
+    }
+
+    pub fn with_padding(&self,padding:syncfile::Padding) -> Self {
+        let myclone = self.clone();
+        SyncConfig { padding: padding, .. myclone }
+    }
+
+    pub fn with_compression(&self,compression:syncfile::Compression) -> Self {
+        let myclone = self.clone();
+        SyncConfig { compression: compression, .. myclone }
+    }
+
+    pub fn with_preserve(&self,preserve:native_attrs::Preserve) -> Self {
+        let myclone = self.clone();
+        SyncConfig { preserve: preserve, .. myclone }
+    }
 }
 
 pub fn def_config_file() -> String {
@@ -145,26 +326,262 @@ pub fn pw_prompt(pw_prompt_message:Option<&str>) -> String {
     password.to_owned()
 }
 
-pub fn get_encryption_key(password:&str) -> [u8;KEY_SIZE] {
-    // TODO: what should this salt be, where to store it, does it need to be protected?
-    // perhaps generate it once and store it in syncdir?
-    let salt = b"salt".to_vec();
-    let pw_bytes = password.as_bytes();  
-    
-    let mut ek: [u8;KEY_SIZE] = [0; KEY_SIZE];
-    bcrypt_pbkdf(pw_bytes, &salt, 5, &mut ek);
-    ek    
+// Panics if the password (and key file, if the slot uses one) doesn't unlock the master key.
+pub fn get_encryption_key(manifest:&kdf::KdfManifest, password:&str, key_file:Option<&[u8]>) -> SecretKey {
+    match manifest.unlock(password, key_file) {
+        Err(e) => panic!("{}", e),
+        Ok(k) => k
+    }
+}
+
+// Returns the contents of the KeyFile named in the config, if any.  Panics if it is set but
+// can't be read.
+pub fn read_key_file(conf:&SyncConfig) -> Option<Vec<u8>> {
+    match conf.key_file {
+        None => None,
+        Some(ref kf) => {
+            let pb = PathBuf::from(kf);
+            if !pb.is_file() {
+                panic!("Key file not found: {}; it is set by KeyFile in the [General] section of the config file", kf);
+            }
+            let mut data:Vec<u8> = Vec::new();
+            match File::open(&pb).and_then(|mut f| f.read_to_end(&mut data)) {
+                Err(e) => panic!("Unable to read key file: {}: {}", kf, e),
+                Ok(_) => ()
+            }
+            if data.is_empty() {
+                panic!("Key file is empty: {}", kf);
+            }
+            Some(data)
+        }
+    }
+}
+
+// Returns the password to use for the encryption key, from the source chosen in the config
+// file (see parse_unkeyed()).  Panics if it can't be read.
+pub fn get_password(source:&PasswordSource, pw_prompt_message:Option<&str>) -> String {
+    match *source {
+        PasswordSource::Prompt => pw_prompt(pw_prompt_message),
+        PasswordSource::Config(_) => source.read().unwrap(),
+        _ => {
+            info!("Reading password from {}", source.describe());
+            let password = match source.read() {
+                Err(e) => panic!("{}", e),
+                Ok(pw) => pw
+            };
+            if password.char_indices().count() < 6 {
+                panic!("Illegal password from {}, len < 6", source.describe());
+            }
+            password
+        }
+    }
+}
+
+// Whether a key that the agent holds for key slot slot can still be used with manifest: the slot
+// must still exist, and the key must still be the master key, which "host revoke" replaces.
+// Manifests from before version 10 don't record the master key's id, so only the slot is
+// checked for those.
+pub fn agent_key_current(manifest:&kdf::KdfManifest, slot:&str, key:&SecretKey) -> bool {
+    if manifest.find_slot(slot).is_none() {
+        warn!("Ignoring agent key for key slot '{}', which no longer exists", slot);
+        return false;
+    }
+    if manifest.key_matches(key) == Some(false) {
+        warn!("Ignoring agent key for key slot '{}', which is no longer the master key", slot);
+        return false;
+    }
+    true
+}
+
+// Returns the key slot name and master key for the config's sync dir from the agent, if one
+// is running and has been given the key.  A key that is no longer current (see
+// agent_key_current()) is removed from the agent, and the caller asks for the password instead.
+pub fn agent_key(conf:&SyncConfig, manifest:&kdf::KdfManifest) -> Option<(String,SecretKey)> {
+    match agent::get_key(conf.sync_dir()) {
+        Some((slot, ek)) => {
+            if !agent_key_current(manifest, &slot, &ek) {
+                agent::remove_key(conf.sync_dir());
+                return None;
+            }
+            info!("Using master key from agent (key slot '{}')", slot);
+            Some((slot, ek))
+        },
+        None => None
+    }
+}
+
+// Returns the key slot name and master key for the config's sync dir using this host's key, if
+// it has one and has been enrolled.  The key is only used if it is the one pinned on this host;
+// see host_key::check_master_key().
+pub fn host_key_unlock(conf:&SyncConfig, manifest:&kdf::KdfManifest) -> Option<(String,SecretKey)> {
+    let hk = match host_key::load(conf) {
+        Err(e) => {
+            warn!("Not using host key: {}", e);
+            return None;
+        },
+        Ok(None) => return None,
+        Ok(Some(hk)) => hk
+    };
+    match manifest.unlock_with_host_key(hk.secret()) {
+        None => None,
+        Some((idx, ek)) => {
+            match host_key::check_master_key(conf, &ek) {
+                Err(e) => {
+                    warn!("Not using host key: {}", e);
+                    None
+                },
+                Ok(false) => {
+                    warn!("The master key in key slot '{}' is not the one pinned on this host, so it was not used; \
+                        if the master key was replaced (\"host revoke\"), unlock with the password once to pin the new one", manifest.slots[idx].name);
+                    None
+                },
+                Ok(true) => {
+                    info!("Using master key from host key (key slot '{}')", manifest.slots[idx].name);
+                    Some((manifest.slots[idx].name.clone(), ek))
+                }
+            }
+        }
+    }
 }
 
 // Parse the specified toml config file.  If None, parse file named by
 // def_config_file() in the working directory.  Panics if there is
 // anything wrong with the file.
-// Will prompt for encryption password if it cannot be read from the config 
-// file (release mode).
+// Reads the KDF manifest from the sync dir and panics if it is missing or unreadable;
+// then gets the master key from the agent, if one is running, or with this host's key, if it
+// is enrolled, or else reads the encryption password from the configured source and gives the
+// unlocked key to the agent.  Keywords with their own key are unlocked too; a password that
+// only unlocks some of those gives a config without the master key.
+pub fn parse(cfgfile:Option<String>, hn_override:Option<String>, pw_prompt_message:Option<&str>) -> SyncConfig {
+    let (conf, pw_source) = parse_unkeyed(cfgfile, hn_override);
+
+    let manifest = match kdf::KdfManifest::load(conf.sync_dir()) {
+        Err(e) => panic!("Unable to read sync directory KDF manifest: {}\n\
+            If this is a new sync directory, run greycrypt with --init to create it.  \
+            If it was created by an older version of greycrypt, run with --upgrade.", e),
+        Ok(m) => m
+    };
+
+    if manifest.sync_ids != kdf::SyncIdScheme::Hmac {
+        warn!("This sync directory uses unkeyed sync ids, which reveal file paths to anyone who can guess them; \
+            stop greycrypt on other hosts and run with --upgrade to convert it.");
+    }
+
+    let (ek, password, key_file) = match agent_key(&conf, &manifest).or_else(|| host_key_unlock(&conf, &manifest)) {
+        Some((_, ek)) => (Ok(ek), None, None),
+        None => {
+            let key_file = read_key_file(&conf);
+            let password = get_password(&pw_source, pw_prompt_message);
+            let ek = match manifest.unlock_slot(&password, key_file.as_ref().map(|kf| &kf[..])) {
+                Err(e) => Err(e),
+                Ok((slot, ek)) => {
+                    if key_file.is_some() && !manifest.slots[slot].uses_key_file() {
+                        warn!("KeyFile is set, but key slot '{}' doesn't use it; run greycrypt with -p to add it.", manifest.slots[slot].name);
+                    }
+                    agent::add_key(conf.sync_dir(), &manifest.slots[slot].name, &ek);
+                    match host_key::pin_master_key(&conf, &ek) {
+                        Err(e) => warn!("{}", e),
+                        Ok(_) => ()
+                    }
+                    Ok(ek)
+                }
+            };
+            (ek, Some(password), key_file)
+        }
+    };
+
+    let keyword_keys = unlock_keyword_keys(conf.sync_dir(), ek.as_ref().ok(),
+        password.as_ref().map(|pw| &pw[..]), key_file.as_ref().map(|kf| &kf[..]));
+
+    // a password that was only given out for some keywords can still sync those
+    let ek = match ek {
+        Ok(ek) => Some(ek),
+        Err(e) => {
+            let mut unlocked:Vec<&String> = keyword_keys.iter().filter(|&(_,k)| k.is_some()).map(|(kw,_)| kw).collect();
+            if unlocked.is_empty() {
+                panic!("{}", e);
+            }
+            unlocked.sort();
+            info!("The password only unlocks the keys for keywords {:?}; syncfiles for other keywords will be skipped", unlocked);
+            None
+        }
+    };
+
+    let (data_key, retired_data_key) = match ek {
+        None => (None, None),
+        Some(ref ek) => match manifest.unlock_data_keys(ek) {
+            Err(e) => panic!("{}", e),
+            Ok(keys) => keys
+        }
+    };
+
+    let signers = match host_key::trusted_signers(&conf, &manifest) {
+        Err(e) => panic!("{}", e),
+        Ok(s) => s
+    };
+    let signing_key = host_signing_key(&conf, &signers);
+
+    conf.with_encryption_key(ek).with_data_keys(data_key, retired_data_key)
+        .with_keyword_keys(keyword_keys).with_sync_ids(manifest.sync_ids)
+        .with_signers(signers).with_signing_key(signing_key)
+}
+
+// This host's signing key, if its public half is one of the signers; files signed with any
+// other key would be rejected by the other hosts.
+fn host_signing_key(conf:&SyncConfig, signers:&HashMap<String,Vec<u8>>) -> Option<SecretKey> {
+    let hk = match host_key::load(conf) {
+        Err(e) => {
+            warn!("Not signing syncfiles: {}", e);
+            return None;
+        },
+        Ok(None) => return None,
+        Ok(Some(hk)) => hk
+    };
+    if signers.get(&conf.host_name).map(|k| &k[..]) == Some(&hk.signing_public[..]) {
+        Some(hk.signing_key())
+    } else {
+        info!("This host's signing key is not pinned in the sync directory; run \"greycrypt host enroll\" to sign its syncfiles");
+        None
+    }
+}
+
+// Read the manifests of keywords that have their own key, and unlock each key with the master
+// key if there is one, or else with the password.  Keywords whose key can't be unlocked map to
+// None.  Panics if a manifest can't be read.
+fn unlock_keyword_keys(sync_dir:&str, master_key:Option<&SecretKey>, password:Option<&str>, key_file:Option<&[u8]>) -> HashMap<String,Option<SecretKey>> {
+    let keywords = match kdf::find_keyword_manifests(sync_dir) {
+        Err(e) => panic!("{}", e),
+        Ok(kws) => kws
+    };
+    let mut keys = HashMap::new();
+    for kw in keywords {
+        let manifest = match kdf::KdfManifest::load_from(&kdf::keyword_manifest_path(sync_dir, &kw)) {
+            Err(e) => panic!("Unable to read KDF manifest for keyword {}: {}", kw, e),
+            Ok(m) => m
+        };
+        let key = match (master_key, password) {
+            (Some(mk), _) => match manifest.unlock_with_key(mk) {
+                Err(e) => {
+                    warn!("Keyword {}: {}; its syncfiles will be skipped", kw, e);
+                    None
+                },
+                Ok(k) => Some(k)
+            },
+            (None, Some(pw)) => manifest.unlock(pw, key_file).ok(),
+            (None, None) => None
+        };
+        keys.insert(kw, key);
+    }
+    keys
+}
+
+// Same as parse(), but does not read the manifest or the password; the returned
+// config has no encryption key.  The second tuple value is where the password should come
+// from, for use with get_password().
 
 // Note: maybe should change this to return a Result instead of panicking,
 // but the use of helper closures here makes it more convenient to just panic.
-pub fn parse(cfgfile:Option<String>, hn_override:Option<String>, pw_prompt_message:Option<&str>) -> SyncConfig {
+pub fn parse_unkeyed(cfgfile:Option<String>, hn_override:Option<String>) -> (SyncConfig, PasswordSource) {
     let file = match cfgfile {
         None => def_config_file(),
         Some(f) => f
@@ -219,22 +636,60 @@ pub fn parse(cfgfile:Option<String>, hn_override:Option<String>, pw_prompt_messa
             .unwrap_or_else(util::get_hostname)
     });
 
-    // in debug, allow password to be read from conf file
-    let ek = {
-        let password = if IS_REL {
-            pw_prompt(pw_prompt_message)
-        } else {
-            gen_sect
-            .and_then(|s| get_optional_string("Password", s))
-            .unwrap_or_else(|| pw_prompt(pw_prompt_message))
-        };
-        get_encryption_key(&password)
+    // password source: at most one of the non-interactive sources may be set.  Password is
+    // only honored by debug builds; otherwise, prompt.
+    let mut pw_sources = Vec::new();
+    if let Some(fd) = gen_sect.and_then(|s| s.get("PasswordFd")) {
+        match fd.as_integer() {
+            Some(fd) if fd >= 0 => pw_sources.push(PasswordSource::Fd(fd as i32)),
+            _ => panic!("PasswordFd must be a non-negative integer")
+        }
+    }
+    if let Some(var) = gen_sect.and_then(|s| get_optional_string("PasswordEnv", s)) {
+        pw_sources.push(PasswordSource::Env(var));
+    }
+    if let Some(path) = gen_sect.and_then(|s| get_optional_string("PasswordFile", s)) {
+        pw_sources.push(PasswordSource::File(path));
+    }
+    if let Some(cmd) = gen_sect.and_then(|s| get_optional_string("PasswordCommand", s)) {
+        pw_sources.push(PasswordSource::Command(cmd));
+    }
+    if pw_sources.len() > 1 {
+        panic!("Only one of PasswordFd, PasswordEnv, PasswordFile and PasswordCommand may be set, found: {:?}", pw_sources);
+    }
+    let conf_password = gen_sect.and_then(|s| get_optional_string("Password", s));
+    let pw_source = match pw_sources.pop() {
+        Some(src) => src,
+        None => match conf_password {
+            Some(ref pw) if !IS_REL => PasswordSource::Config(pw.clone()),
+            _ => PasswordSource::Prompt
+        }
     };
 
-    let (sync_dir, native_paths, mapping) = {
+    // an optional second factor; its contents are needed along with the password
+    let key_file = gen_sect.and_then(|s| get_optional_string("KeyFile", s));
+
+    let padding = match gen_sect.and_then(|s| get_optional_string("Padding", s)) {
+        None => syncfile::Padding::None,
+        Some(spec) => match syncfile::Padding::parse(&spec) {
+            Err(e) => panic!("{}", e),
+            Ok(p) => p
+        }
+    };
+
+    let compression = match gen_sect.and_then(|s| get_optional_string("Compression", s)) {
+        None => syncfile::Compression::None,
+        Some(spec) => match syncfile::Compression::parse(&spec) {
+            Err(e) => panic!("{}", e),
+            Ok(c) => c
+        }
+    };
+
+    let (sync_dir, native_paths, mapping, known_hosts, host_preserve) = {
         let mval = get_required_section("Mapping");
 
         let mut map_nicknames:HashSet<String> = HashSet::new();
+        let mut known_hosts:Vec<String> = Vec::new();
         for (map_nick,hn_list) in mval {
             match hn_list.as_slice() {
                 None => panic!("The value for map nick {} must be a list, like: [\"myhostname\"]", map_nick),
@@ -243,6 +698,7 @@ pub fn parse(cfgfile:Option<String>, hn_override:Option<String>, pw_prompt_messa
                         match lhn.as_str() {
                             None => panic!("The values in map nick list {} must be strings, like: [\"myhostname\"]", map_nick),
                             Some (lhn) => {
+                                known_hosts.push(lhn.to_owned());
                                 if lhn == hn {
                                     map_nicknames.insert(map_nick.to_owned());
                                 }
@@ -312,6 +768,15 @@ pub fn parse(cfgfile:Option<String>, hn_override:Option<String>, pw_prompt_messa
         }
 
 
+        // a host def can override PreserveAttributes from [General], since which attributes are
+        // worth keeping depends on the host's OS and file systems
+        let host_preserve = hn_config.remove("PreserveAttributes").map(|p| {
+            match p.as_str() {
+                None => panic!("Value for PreserveAttributes must be a string"),
+                Some(spec) => spec.trim().to_owned()
+            }
+        });
+
         // all the other key/value pairs are kw->dir mappings
         let map_count = hn_config.len();
         if map_count == 0 {
@@ -329,17 +794,26 @@ pub fn parse(cfgfile:Option<String>, hn_override:Option<String>, pw_prompt_messa
 
         //println!("{:?}",mapping);
 
-        (sync_dir, native_paths, mapping)
+        (sync_dir, native_paths, mapping, known_hosts, host_preserve)
+    };
+
+    let preserve = match host_preserve.or_else(|| gen_sect.and_then(|s| get_optional_string("PreserveAttributes", s))) {
+        None => native_attrs::Preserve::none(),
+        Some(spec) => match native_attrs::Preserve::parse(&spec) {
+            Err(e) => panic!("{}", e),
+            Ok(p) => p
+        }
     };
 
     let c = SyncConfig::new(
         sync_dir,
         hn,
         mapping,
-        Some(ek),
+        None,
         None,
         native_paths
-    );
+    ).with_key_file(key_file).with_known_hosts(known_hosts).with_padding(padding).with_compression(compression)
+        .with_preserve(preserve);
 
-    c
+    (c, pw_source)
 }
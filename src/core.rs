@@ -1,4 +1,5 @@
-use std::fs::{PathExt,remove_file,remove_dir,read_dir};
+use std::fs::{File,PathExt,metadata,remove_file,remove_dir,read_dir};
+use std::io::{Read,Write};
 //use std::io::{BufRead};
 use std::path::{Path,PathBuf};
 use std::collections::HashSet;
@@ -7,6 +8,8 @@ use std::cmp::Ordering;
 
 use util;
 use config;
+use crypto_util;
+use kdf;
 use syncfile;
 use syncdb;
 use trash;
@@ -14,6 +17,7 @@ use logging;
 
 extern crate uuid;
 extern crate glob;
+extern crate time;
 
 #[derive(Debug,Clone)]
 struct SyncData {
@@ -75,7 +79,13 @@ pub struct SyncState {
     pub syncdb: syncdb::SyncDb,
     pub sync_files_for_id: HashMap<String,Vec<String>>,
     pub sync_file_cache: SyncFileCache,
-    pub log_util: logging::LoggerUtil
+    pub log_util: logging::LoggerUtil,
+    // "<syncfile>:<seq>" for each rolled back syncfile that has been reported, so that it is only
+    // reported once
+    pub flagged_rollbacks: HashSet<String>,
+    // mtime of the KDF manifest when the data keys were last read from it
+    manifest_mtime: Option<u64>,
+    sync_marker_time: Option<u64>
 }
 
 impl SyncState {
@@ -85,7 +95,10 @@ impl SyncState {
             conf: conf,
             sync_files_for_id: HashMap::new(),
             sync_file_cache: SyncFileCache::new(),
-            log_util: log_util
+            log_util: log_util,
+            flagged_rollbacks: HashSet::new(),
+            manifest_mtime: None,
+            sync_marker_time: None
         }
     }
 
@@ -97,6 +110,17 @@ impl SyncState {
     }
 }
 
+// An old copy of a syncfile, put back by the storage provider or anyone else who can write to
+// the sync dir, still passes every check, but its seq is lower than one this host has already
+// synced.  It must not be applied; this reports it, once per file and seq.
+fn flag_rollback(flagged:&mut HashSet<String>, sd:&SyncData, found:u64, seen:u64) {
+    let key = format!("{}:{}", sd.syncfile.to_str().unwrap(), found);
+    if flagged.insert(key) {
+        error!("SECURITY: Ignoring syncfile {:?} (sid: {}); it is version {}, but version {} was already synced.  \
+            It may be an old copy put back by the storage provider or an attacker.", sd.syncfile, sd.syncid, found, seen);
+    }
+}
+
 fn compare_sync_state(state:&mut SyncState,sd:&SyncData) -> SyncAction {
     //println!("Comparing sync state on: {:?} and {:?}", sd.nativefile.file_name().unwrap(), sd.syncfile.file_name().unwrap());
     let nativefile = match sd.nativefile {
@@ -107,7 +131,7 @@ fn compare_sync_state(state:&mut SyncState,sd:&SyncData) -> SyncAction {
     let nativefile_str = nativefile.to_str().unwrap();
     let native_mtime = match util::get_file_mtime(&nativefile_str) {
         Err(e) => {
-            if !nativefile.is_file() {
+            if !util::is_file_or_link(nativefile) {
                 // sometimes the native file is already gone.  this happens for instance
                 // on windows, when you create a new text file on windows (created as "New Text File.txt"),
                 // which greycrypt picks up and syncs, then the native file is renamed to something else.
@@ -126,7 +150,7 @@ fn compare_sync_state(state:&mut SyncState,sd:&SyncData) -> SyncAction {
     };
     let sf = state.sync_file_cache.get(&state.conf,&sd.syncfile);
 
-    let sync_entry = match state.syncdb.get(&sf) {
+    let sync_entry = match get_sync_entry(&mut state.syncdb, &sf.id) {
         None => {
             if sf.is_deleted {
                 return SyncAction::ProcessSyncfileDelete(sd.clone());
@@ -140,6 +164,16 @@ fn compare_sync_state(state:&mut SyncState,sd:&SyncData) -> SyncAction {
     let revguid_changed = sf.revguid != sync_entry.revguid;
     let native_newer = native_mtime > sync_entry.native_mtime;
 
+    if revguid_changed && sf.seq < sync_entry.seq {
+        flag_rollback(&mut state.flagged_rollbacks, sd, sf.seq, sync_entry.seq);
+        // a local change replaces the old copy
+        if native_newer {
+            return SyncAction::UpdateSyncfile(sd.clone());
+        } else {
+            return SyncAction::Nothing;
+        }
+    }
+
     if sf.is_deleted {
         match (revguid_changed,native_newer) {
             (true,true) => {
@@ -235,15 +269,47 @@ fn update_sync_file(state:&mut SyncState,sd:&SyncData) -> SyncAction {
     };
     let nativefile_str = nativefile.to_str().unwrap();
 
-    info!("Copying local data in {:?} to {:?}", nativefile_str, sd.syncfile.file_name().unwrap());
-
     let native_mtime = match util::get_file_mtime(&nativefile_str) {
         Err(e) => panic!("Error getting file mtime: {:?}", e),
         Ok(mtime) => mtime
     };
 
+    // if the native file was only touched, it still matches the syncfile that this host last
+    // synced, and only the mtime in the syncdb needs updating.  (unless the mtime is preserved,
+    // in which case the new one should be synced too.)
+    if sd.syncfile.is_file() && !state.conf.preserve.times {
+        let synced_revguid = get_sync_entry(&mut state.syncdb, &sd.syncid).map(|e| e.revguid);
+        let sf = state.sync_file_cache.get(&state.conf,&sd.syncfile);
+        if !sf.is_deleted && Some(sf.revguid) == synced_revguid {
+            match sf.native_matches(&state.conf, nativefile_str) {
+                Err(e) => warn!("Failed to compare {:?} with its syncfile: {}", nativefile_str, e),
+                Ok(Some(true)) => {
+                    info!("Native file unchanged, updating syncdb: {:?}", nativefile_str);
+                    match state.syncdb.update(sf,native_mtime) {
+                        Err(e) => panic!("Failed to update sync db: {:?}", e),
+                        Ok(_) => ()
+                    }
+                    return SyncAction::Nothing;
+                },
+                Ok(_) => ()
+            }
+        }
+    }
+
+    info!("Copying local data in {:?} to {:?}", nativefile_str, sd.syncfile.file_name().unwrap());
+
+    // the new version must have a higher seq than anything synced so far, including the current
+    // syncfile, in case this host's syncdb is behind
+    let seen = get_sync_entry(&mut state.syncdb, &sd.syncid).map(|e| e.seq).unwrap_or(0);
+    let current = if sd.syncfile.is_file() {
+        state.sync_file_cache.get(&state.conf,&sd.syncfile).seq
+    } else {
+        0
+    };
+    let seq = if current > seen { current + 1 } else { seen + 1 };
+
     // always use the path from the sync data struct, since it may have been remapped
-    match syncfile::SyncFile::create_syncfile(&state.conf,&nativefile, Some(sd.syncfile.clone())) {
+    match syncfile::SyncFile::create_syncfile_with_seq(&state.conf,&nativefile, Some(sd.syncfile.clone()), seq) {
         Err(e) => panic!("Error creating sync file: {:?}", e),
         Ok((_,ref sf)) => {
             // update sync db
@@ -277,7 +343,14 @@ fn check_sync_revguid(state:&mut SyncState,sd:&SyncData) -> SyncAction {
 
     let sf = state.sync_file_cache.get(&state.conf,&sd.syncfile);
 
-    let sync_entry = state.syncdb.get(&sf);
+    let sync_entry = get_sync_entry(&mut state.syncdb, &sf.id);
+
+    if let Some(entry) = sync_entry {
+        if sf.revguid != entry.revguid && sf.seq < entry.seq {
+            flag_rollback(&mut state.flagged_rollbacks, sd, sf.seq, entry.seq);
+            return SyncAction::Nothing;
+        }
+    }
 
     if sf.is_deleted {
         if !sync_entry.is_none() && sync_entry.unwrap().revguid != sf.revguid {
@@ -303,9 +376,18 @@ fn check_sync_revguid(state:&mut SyncState,sd:&SyncData) -> SyncAction {
 }
 
 fn check_file_data_equal(state:&mut SyncState,syncfile:&PathBuf,nativefile:&PathBuf) -> Result<(bool,syncfile::SyncFile),String> {
-    let mut sf_data:Vec<u8> = Vec::new();
     let syncpath = syncfile.to_str().unwrap().to_owned();
-    let sf = load_syncfile_or_panic(state,&syncpath,&mut sf_data);
+    let mut sf = load_syncfile_or_panic(state,&syncpath);
+
+    // a syncfile with a content hash can be checked without decrypting it
+    match sf.native_matches(&state.conf, nativefile.to_str().unwrap()) {
+        Err(e) => return Err(format!("{}", e)),
+        Ok(Some(equal)) => return Ok((equal,sf)),
+        Ok(None) => ()
+    }
+
+    let mut sf_data = plaintext_buf(&syncpath);
+    decrypt_syncfile_or_panic(state,&mut sf,&mut sf_data);
     // if file is text, syncfile decryption will have decanoned the lines, so we can compare them
     // directly with native line format.  so use binary read for both text and binary files.
     let native_bytes = util::slurp_bin_file(&nativefile.to_str().unwrap());
@@ -385,7 +467,7 @@ fn create_new_native_file(state:&mut SyncState,sd:&SyncData) -> SyncAction {
 
     // find target native path, if it already exists...well thats a problem
     let nativefile_path = PathBuf::from(&sf.nativefile);
-    if nativefile_path.is_file() {
+    if util::is_file_or_link(&nativefile_path) {
         panic!("Native path already exists for syncfile, refusing to overwrite: {}", &sf.nativefile);
     }
     do_update_native_file(&mut sf, state);
@@ -394,7 +476,7 @@ fn create_new_native_file(state:&mut SyncState,sd:&SyncData) -> SyncAction {
 
 fn handle_delete(state:&mut SyncState, sf:&mut syncfile::SyncFile, syncpath: &PathBuf, mark_sf_as_deleted:bool) {
     let nativefile_path = PathBuf::from(&sf.nativefile);
-    if nativefile_path.is_file() {
+    if util::is_file_or_link(&nativefile_path) {
         info!("Sending deleted local file to Trash: {}", &sf.nativefile);
         match trash::send_to_trash(&sf.nativefile) {
             Err(e) => panic!("Failed to trash file: {:?}", e),
@@ -402,6 +484,11 @@ fn handle_delete(state:&mut SyncState, sf:&mut syncfile::SyncFile, syncpath: &Pa
         }
     }
     if mark_sf_as_deleted {
+        // the deleted version's seq is one higher than the highest seen
+        let seen = get_sync_entry(&mut state.syncdb, &sf.id).map(|e| e.seq).unwrap_or(0);
+        if seen > sf.seq {
+            sf.seq = seen;
+        }
         match sf.mark_deleted_and_save(&state.conf,Some(syncpath.clone())) {
             Err(e) => panic!("Failed to write syncfile: {:?}", e),
             Ok(_) => ()
@@ -452,7 +539,7 @@ fn process_syncfile_delete(state:&mut SyncState,sd:&SyncData) -> SyncAction {
     // machine, or it could have been recreated here with new content; either way, since we don't
     // have more context information, we can't process it
     let revguid = {
-        let sync_entry = match state.syncdb.get(&sf) {
+        let sync_entry = match get_sync_entry(&mut state.syncdb, &sf.id) {
             None => {
                 // here we would need to check to see if the local file has the same checksum
                 // as deleted - actually, maybe we want to check that no matter what
@@ -472,7 +559,7 @@ fn process_syncfile_delete(state:&mut SyncState,sd:&SyncData) -> SyncAction {
     } else {
         // we should have already handled this...but log if the native file exists (bug)
         let pb = PathBuf::from(&sf.nativefile);
-        if pb.is_file() {
+        if util::is_file_or_link(&pb) {
             error!("Left behind a file that should have been deleted: {:?}", sf.nativefile);
         }
         SyncAction::Nothing
@@ -523,10 +610,11 @@ fn pass3_commit(state:&mut SyncState,sa:&SyncAction) -> SyncAction {
     }
 }
 
-pub fn find_all_syncfiles(state:&SyncState) -> HashMap<String,Vec<String>> {
+// Return the paths of all syncfiles in the sync dir, without opening them.
+pub fn find_syncfile_paths(sync_dir:&str) -> Vec<String> {
     let sync_ext = "dat";
 
-    let mut files_for_id:HashMap<String,Vec<String>> = HashMap::new();
+    let mut files:Vec<String> = Vec::new();
     {
         let mut visitor = |pb: &PathBuf| {
             match pb.extension() {
@@ -537,44 +625,128 @@ pub fn find_all_syncfiles(state:&SyncState) -> HashMap<String,Vec<String>> {
                     }
                 }
             }
-
-            // have to read the header to get the syncid.  can't trust the
-            // filename because it could have been renamed.
-            let file_syncid = match syncfile::SyncFile::get_syncid_from_file(&state.conf,&pb) {
-                Err(e) => panic!("Error {:?}", e),
-                Ok(id) => id
-            };
-
-            let pbs = pb.to_str().unwrap().to_owned();
-            if files_for_id.contains_key(&file_syncid) {
-                files_for_id.get_mut(&file_syncid).unwrap().push(pbs);
-            } else {
-                files_for_id.insert(file_syncid,vec![pbs]);
-            }
+            files.push(pb.to_str().unwrap().to_owned());
         };
 
-        let d = &state.conf.sync_dir();
-        let dp = Path::new(d);
+        let dp = Path::new(sync_dir);
         let res = util::visit_dirs(&dp, &mut visitor);
         match res {
             Ok(_) => (),
-            Err(e) => panic!("failed to scan directory: {}: {}", d, e),
+            Err(e) => panic!("failed to scan directory: {}: {}", sync_dir, e),
+        }
+    }
+    files
+}
+
+pub fn find_all_syncfiles(state:&SyncState) -> HashMap<String,Vec<String>> {
+    let mut files_for_id:HashMap<String,Vec<String>> = HashMap::new();
+
+    for pbs in find_syncfile_paths(state.conf.sync_dir()) {
+        // have to read the header to get the syncid.  can't trust the
+        // filename because it could have been renamed.
+        let pb = PathBuf::from(&pbs);
+        if !syncfile_key_available(state,&pb) {
+            continue;
+        }
+        let file_syncid = match syncfile::SyncFile::get_syncid_from_file(&state.conf,&pb) {
+            Err(e) => {
+                warn!("Skipping unreadable syncfile: {:?}: {}", pb, e);
+                continue;
+            },
+            Ok(id) => id
+        };
+
+        if files_for_id.contains_key(&file_syncid) {
+            files_for_id.get_mut(&file_syncid).unwrap().push(pbs);
+        } else {
+            files_for_id.insert(file_syncid,vec![pbs]);
         }
     }
     files_for_id
 }
 
-fn load_syncfile_or_panic(state:&SyncState,syncpath:&String,data:&mut Vec<u8>) -> syncfile::SyncFile {
+// False for syncfiles of keywords whose key this host doesn't have; those are skipped
+// entirely.  So are files that aren't syncfiles at all, since anything can end up in the sync
+// dir; they are checked again on each sync.
+fn syncfile_key_available(state:&SyncState, pb:&PathBuf) -> bool {
+    match syncfile::SyncFile::key_available(&state.conf,pb) {
+        Err(e) => {
+            warn!("Skipping unreadable syncfile: {:?}: {}", pb, e);
+            false
+        },
+        Ok(true) => true,
+        Ok(false) => {
+            debug!("Skipping syncfile encrypted with a key this host doesn't have: {:?}", pb);
+            false
+        }
+    }
+}
+
+// Move this host's syncdb entries from unkeyed to keyed sync ids once the sync dir has been
+// upgraded (possibly by another host).  The old ids are recomputed from each syncfile's metadata.
+pub fn migrate_syncdb_sync_ids(state:&mut SyncState) {
+    if state.conf.sync_ids != kdf::SyncIdScheme::Hmac || state.syncdb.has_keyed_sync_ids() {
+        return;
+    }
+
+    let old_conf = state.conf.with_sync_ids(kdf::SyncIdScheme::Sha256);
+    let mut count = 0;
+    for pbs in find_syncfile_paths(state.conf.sync_dir()) {
+        let pb = PathBuf::from(&pbs);
+        if !syncfile_key_available(state,&pb) {
+            continue;
+        }
+        let sf = match syncfile::SyncFile::from_syncfile(&state.conf,&pb) {
+            Err(e) => panic!("Failed to read syncfile: {:?}", e),
+            Ok(sf) => sf
+        };
+        let old_sid = match syncfile::SyncFile::get_sync_id(&old_conf,&sf.keyword,&sf.relpath) {
+            Err(e) => panic!("Can't get old sync id: {}", e),
+            Ok(sid) => sid
+        };
+        match state.syncdb.move_entry(&old_sid,&sf.id) {
+            Err(e) => panic!("Failed to update syncdb: {}", e),
+            Ok(true) => count = count + 1,
+            Ok(false) => ()
+        }
+    }
+
+    match state.syncdb.set_keyed_sync_ids() {
+        Err(e) => panic!("{}", e),
+        Ok(_) => ()
+    }
+    info!("Moved {} syncdb entries to keyed sync ids", count);
+}
+
+// A buffer for the decrypted contents of a syncfile, wiped when dropped.  The plaintext is no
+// bigger than the syncfile, so sizing it to that avoids reallocations that would leave
+// unwiped copies behind.
+fn plaintext_buf(syncpath:&str) -> crypto_util::SecretBuf {
+    let len = metadata(syncpath).map(|m| m.len() as usize).unwrap_or(0);
+    crypto_util::SecretBuf::with_capacity(len)
+}
+
+fn load_syncfile_or_panic(state:&SyncState,syncpath:&String) -> syncfile::SyncFile {
     let pb = PathBuf::from(syncpath);
-    let mut sf = match syncfile::SyncFile::from_syncfile(&state.conf,&pb) {
+    match syncfile::SyncFile::from_syncfile(&state.conf,&pb) {
         Err(e) => panic!("Failed to read syncfile: {:?}", e),
         Ok(sf) => sf
-    };
+    }
+}
+
+fn decrypt_syncfile_or_panic(state:&SyncState,sf:&mut syncfile::SyncFile,data:&mut Vec<u8>) {
     match sf.decrypt_to_writer(&state.conf, data) {
         Err(e) => panic!("Error {:?}", e),
         Ok(_) => ()
     }
-    sf
+}
+
+// This host's own state, so a bad entry is a bug or local corruption, not something to sync past.
+fn get_sync_entry<'a>(syncdb:&'a mut syncdb::SyncDb, sid:&str) -> Option<&'a syncdb::SyncEntry> {
+    match syncdb.get_by_sid(sid) {
+        Err(e) => panic!("Failed to read syncdb entry for sid {}: {}", sid, e),
+        Ok(entry) => entry
+    }
 }
 
 // Given a list of sync files, remove all syncfiles whose _contents_ are a duplicate of the
@@ -601,16 +773,34 @@ fn dedup_helper(state:&SyncState,dup_cand_idx:usize, curr_revguid: Option<uuid::
     let candidate = &paths[dup_cand_idx];
     //println!("dup cand: {}; idx {}, paths: {:?}",candidate,dup_cand_idx,paths);
 
-    let mut cand_data:Vec<u8> = Vec::new();
-    let cand_sf = load_syncfile_or_panic(state,&candidate,&mut cand_data);
+    let mut cand_sf = load_syncfile_or_panic(state,&candidate);
+    // only decrypted if it has to be compared with a syncfile that has no content hash
+    let mut cand_data:Option<crypto_util::SecretBuf> = None;
 
     for i in 0 .. paths.len() {
         if i < dup_cand_idx {
             nondups.push(paths[i].clone());
         } else if i > dup_cand_idx {
-            let mut pot_dup_data:Vec<u8> = Vec::new();
-            let pot_dup_sf = load_syncfile_or_panic(state,&paths[i],&mut pot_dup_data);
-            if pot_dup_data == cand_data {
+            let mut pot_dup_sf = load_syncfile_or_panic(state,&paths[i]);
+            let same_hash = match (&cand_sf.content, &pot_dup_sf.content) {
+                // a link has the hash of no data
+                (&Some(ref a), &Some(ref b)) => Some(a == b && cand_sf.link == pot_dup_sf.link),
+                _ => None
+            };
+            let is_dup = match same_hash {
+                Some(same) => same,
+                None => {
+                    if cand_data.is_none() {
+                        let mut data = plaintext_buf(&candidate);
+                        decrypt_syncfile_or_panic(state,&mut cand_sf,&mut data);
+                        cand_data = Some(data);
+                    }
+                    let mut pot_dup_data = plaintext_buf(&paths[i]);
+                    decrypt_syncfile_or_panic(state,&mut pot_dup_sf,&mut pot_dup_data);
+                    *pot_dup_data == **cand_data.as_ref().unwrap()
+                }
+            };
+            if is_dup {
                 dups.push((pot_dup_sf,paths[i].clone()));
             } else {
                 nondups.push(paths[i].clone());
@@ -738,7 +928,7 @@ pub fn dedup_syncfiles(state:&mut SyncState) {
             // the dedup removes the file containing it; see below for how this is used.
 
             // get current revguid (if any)
-            let curr_revguid = state.syncdb.get_by_sid(sid).map(|entry| entry.revguid);
+            let curr_revguid = get_sync_entry(&mut state.syncdb, sid).map(|entry| entry.revguid);
 
             let mut dup_cand_idx = 0;
             let mut deduped = files.clone();
@@ -791,7 +981,7 @@ pub fn dedup_syncfiles(state:&mut SyncState) {
                     // curr revguid removed, so it must have been one of the dups which means the surviving
                     // file has already been processed - make sure the syncdb has the updated revguid
                     let (do_update,mtime) = {
-                        match state.syncdb.get(&sf) {
+                        match get_sync_entry(&mut state.syncdb, &sf.id) {
                             Some(entry) => {
                                 if sf.revguid != entry.revguid {
                                     (true,entry.native_mtime)
@@ -875,14 +1065,126 @@ fn filter_syncfiles(state:&mut SyncState) -> Vec<String> {
             Some (_) => ()
         }
 
+        // symlinks can't be restored here; see SyncFile::make_symlink()
+        if cfg!(target_os = "windows") && sf.link.is_some() {
+            state.log_util.warn_once(&format!("Ignoring sync file for a symlink, which can't be restored on this platform: {} (sid: {})", sf.relpath, sf.id));
+            continue;
+        }
+
         sync_files.push(syncfile);
     }
     sync_files
 }
 
+// Another host may have started or finished replacing the data key (see commands::rotate_key());
+// pick up the change without a restart.
+fn refresh_data_keys(state:&mut SyncState) {
+    let path = kdf::manifest_path(state.conf.sync_dir());
+    let mtime = util::get_file_mtime(path.to_str().unwrap()).ok();
+    if mtime.is_none() || mtime == state.manifest_mtime {
+        return;
+    }
+    state.manifest_mtime = mtime;
+
+    let keys = match state.conf.encryption_key {
+        None => return,
+        Some(ref mk) => kdf::KdfManifest::load(state.conf.sync_dir()).and_then(|m| m.unlock_data_keys(mk))
+    };
+    match keys {
+        Err(e) => warn!("Unable to reload the data keys from the KDF manifest: {}", e),
+        Ok((data_key, retired_data_key)) => {
+            if data_key != state.conf.data_key || retired_data_key != state.conf.retired_data_key {
+                info!("The data key was changed by another host; reloaded it from the KDF manifest");
+                state.conf = state.conf.with_data_keys(data_key, retired_data_key);
+            }
+        }
+    }
+}
+
+// Each host records the time of its last sync in a marker file in the sync dir, so that the
+// commands that re-encrypt every syncfile (see commands::host_revoke()) can refuse to run while
+// another host is syncing.  The time is stored in the file rather than taken from its mtime,
+// which file sharing services don't always preserve.
+const SYNC_MARKER_PREFIX: &'static str = "syncing-";
+const SYNC_MARKER_EXT: &'static str = ".txt";
+// A host whose marker is newer than this is considered to be syncing.  It is generous, to allow
+// for slow file sharing and for clocks that don't quite agree.
+pub const SYNC_MARKER_WINDOW_SECS: u64 = 600;
+// How often a syncing host rewrites its marker; more often would just churn the sync dir.
+const SYNC_MARKER_REFRESH_SECS: u64 = 60;
+
+fn now_secs() -> u64 {
+    time::get_time().sec as u64
+}
+
+pub fn sync_marker_path(sync_dir:&str, host_name:&str) -> PathBuf {
+    let mut pb = PathBuf::from(sync_dir);
+    pb.push(format!("{}{}{}", SYNC_MARKER_PREFIX, host_name.to_lowercase(), SYNC_MARKER_EXT));
+    pb
+}
+
+fn touch_sync_marker(state:&mut SyncState) {
+    let now = now_secs();
+    match state.sync_marker_time {
+        Some(t) if now < t + SYNC_MARKER_REFRESH_SECS => return,
+        _ => ()
+    }
+    let path = sync_marker_path(state.conf.sync_dir(), &state.conf.host_name);
+    match File::create(&path).and_then(|mut f| f.write_all(format!("{}\n", now).as_bytes())) {
+        Err(e) => warn!("Unable to write sync marker {:?}: {}", path, e),
+        Ok(_) => state.sync_marker_time = Some(now)
+    }
+}
+
+// The (lowercase) names of the hosts other than host_name that have synced recently enough that
+// they may still be syncing.
+pub fn other_hosts_syncing(sync_dir:&str, host_name:&str) -> Result<Vec<String>,String> {
+    let entries = match read_dir(sync_dir) {
+        Err(e) => return Err(format!("Unable to list sync directory {}: {}", sync_dir, e)),
+        Ok(entries) => entries
+    };
+    let this_host = host_name.to_lowercase();
+    let now = now_secs();
+    let mut hosts = Vec::new();
+    for entry in entries {
+        let entry = match entry {
+            Err(e) => return Err(format!("Unable to list sync directory {}: {}", sync_dir, e)),
+            Ok(entry) => entry
+        };
+        let name = entry.file_name();
+        let name = match name.to_str() {
+            None => continue,
+            Some(n) => n
+        };
+        if !name.starts_with(SYNC_MARKER_PREFIX) || !name.ends_with(SYNC_MARKER_EXT) {
+            continue;
+        }
+        let host = &name[SYNC_MARKER_PREFIX.len() .. name.len() - SYNC_MARKER_EXT.len()];
+        if host == this_host {
+            continue;
+        }
+        let mut text = String::new();
+        match File::open(entry.path()).and_then(|mut f| f.read_to_string(&mut text)) {
+            Err(e) => return Err(format!("Unable to read sync marker {:?}: {}", entry.path(), e)),
+            Ok(_) => ()
+        }
+        match text.trim().parse::<u64>() {
+            Err(_) => warn!("Ignoring unreadable sync marker {:?}", entry.path()),
+            Ok(t) => if t + SYNC_MARKER_WINDOW_SECS > now {
+                hosts.push(host.to_owned());
+            }
+        }
+    }
+    hosts.sort();
+    Ok(hosts)
+}
+
 pub fn do_sync(state:&mut SyncState) {
     state.sync_file_cache.flush();
 
+    touch_sync_marker(state);
+    refresh_data_keys(state);
+
     dedup_syncfiles(state);
 
     state.sync_files_for_id = find_all_syncfiles(state);
@@ -924,6 +1226,11 @@ pub fn do_sync(state:&mut SyncState) {
     // scan native files
     for nf in &native_files {
         //println!("native file: {}", nf);
+        // symlinks are synced as links, unless they point outside of the mapped directories
+        if let Err(e) = syncfile::SyncFile::read_link_target(&state.conf,&nf) {
+            warn!("Ignoring local file: {}: {}", &nf, &e);
+            continue
+        }
         let (sid,syncfile) = match syncfile::SyncFile::get_sync_id_and_path(&state.conf,&nf) {
             Err(e) => {
                 warn!("Ignoring local file: {}: {}", &nf, &e);
@@ -1065,12 +1372,18 @@ mod tests {
 
     use std::path::{PathBuf};
     use std::thread;
+    use std::collections::HashMap;
+    use std::fs::File;
+    use std::io::Write;
 
     extern crate toml;
 
     use config;
     use core;   
-    use testlib::util::{basic_alice_bob_setup,verify_sync_state,delete_text_file,update_text_file,cp_or_panic,write_text_file,find_all_files};
+    use crypto_util;
+    use syncfile;
+    use util;
+    use testlib::util::{basic_alice_bob_setup,verify_sync_state,delete_text_file,update_text_file,cp_or_panic,write_text_file,find_all_files,populate_native};
 
     #[test]
     fn sync() {
@@ -1095,12 +1408,66 @@ mod tests {
         core::do_sync(&mut alice_mconf.state);
 
         // change bob's password
-        let ek: [u8;config::KEY_SIZE] = [1; config::KEY_SIZE];
+        let ek = crypto_util::SecretKey::from_slice(&[1; config::KEY_SIZE]).unwrap();
         bob_mconf.state.conf.encryption_key = Some(ek);
 
         core::do_sync(&mut bob_mconf.state);;
      }
 
+     #[test]
+     fn keyword_key_not_shared() {
+        // alice's keyword has its own key, which bob doesn't have; bob skips its files until
+        // he gets it
+        let (ref mut alice_mconf, ref mut bob_mconf) = basic_alice_bob_setup("keyword_key_not_shared");
+        let kw_key = crypto_util::new_random_key();
+        let mut alice_keys = HashMap::new();
+        alice_keys.insert("HOME".to_owned(), Some(kw_key.clone()));
+        alice_mconf.state.conf = alice_mconf.state.conf.with_keyword_keys(alice_keys.clone());
+        let mut bob_keys = HashMap::new();
+        bob_keys.insert("HOME".to_owned(), None);
+        bob_mconf.state.conf = bob_mconf.state.conf.with_keyword_keys(bob_keys);
+
+        core::do_sync(&mut alice_mconf.state);
+        verify_sync_state(alice_mconf, 2, 2);
+
+        core::do_sync(&mut bob_mconf.state);
+        assert_eq!(find_all_files(&bob_mconf.native_root).len(), 0);
+
+        // the master key alone doesn't read them either
+        let master_only = alice_mconf.state.conf.with_keyword_keys(HashMap::new());
+        for f in core::find_syncfile_paths(alice_mconf.state.conf.sync_dir()) {
+            assert!(syncfile::SyncFile::from_syncfile(&master_only, &PathBuf::from(&f)).is_err());
+        }
+
+        bob_mconf.state.conf = bob_mconf.state.conf.with_keyword_keys(alice_keys);
+        core::do_sync(&mut bob_mconf.state);
+        verify_sync_state(bob_mconf, 2, 2);
+     }
+
+     #[test]
+     fn keyword_key_missing() {
+        // bob maps a keyword that he has no key for, and has files under it; they are ignored
+        // rather than stopping the sync
+        let (ref mut alice_mconf, ref mut bob_mconf) = basic_alice_bob_setup("keyword_key_missing");
+        let mut alice_keys = HashMap::new();
+        alice_keys.insert("HOME".to_owned(), Some(crypto_util::new_random_key()));
+        alice_mconf.state.conf = alice_mconf.state.conf.with_keyword_keys(alice_keys);
+        let mut bob_keys = HashMap::new();
+        bob_keys.insert("HOME".to_owned(), None);
+        bob_mconf.state.conf = bob_mconf.state.conf.with_keyword_keys(bob_keys);
+        populate_native(&bob_mconf.native_root, Some("docs"));
+
+        core::do_sync(&mut bob_mconf.state);
+        assert_eq!(core::find_syncfile_paths(bob_mconf.state.conf.sync_dir()).len(), 0);
+        assert_eq!(find_all_files(&bob_mconf.native_root).len(), 2);
+
+        core::do_sync(&mut alice_mconf.state);
+        verify_sync_state(alice_mconf, 2, 2);
+        core::do_sync(&mut bob_mconf.state);
+        assert_eq!(core::find_syncfile_paths(bob_mconf.state.conf.sync_dir()).len(), 2);
+        assert_eq!(find_all_files(&bob_mconf.native_root).len(), 2);
+     }
+
      #[test]
      fn syncback() {
         // run a sync on alice, run on bob, chance a file in bob, run on bob, run on alice,
@@ -1135,6 +1502,40 @@ mod tests {
         verify_sync_state(alice_mconf, 3, 3);
      }
 
+     #[test]
+     fn rollback() {
+        // alice updates a file and bob syncs it; then an old copy of its syncfile is put back in
+        // the sync dir.  bob must not apply it.
+        let (ref mut alice_mconf, ref mut bob_mconf) = basic_alice_bob_setup("rollback");
+        core::do_sync(&mut alice_mconf.state);
+        core::do_sync(&mut bob_mconf.state);
+        verify_sync_state(bob_mconf, 2, 2);
+
+        let old_syncfiles:Vec<(String,Vec<u8>)> = core::find_syncfile_paths(alice_mconf.state.conf.sync_dir())
+            .into_iter().map(|f| { let data = util::slurp_bin_file(&f); (f,data) }).collect();
+
+        thread::sleep_ms(1000);
+
+        update_text_file(alice_mconf, "Some updated text");
+        core::do_sync(&mut alice_mconf.state);
+        core::do_sync(&mut bob_mconf.state);
+
+        let mut text_pb = PathBuf::from(&bob_mconf.native_root);
+        text_pb.push("docs");
+        text_pb.push("test_text_file.txt");
+        assert_eq!(util::slurp_text_file(text_pb.to_str().unwrap()), "Some updated text");
+
+        // put the old syncfiles back
+        for &(ref f, ref data) in &old_syncfiles {
+            let mut fout = File::create(f).unwrap();
+            fout.write_all(data).unwrap();
+        }
+
+        core::do_sync(&mut bob_mconf.state);
+        assert_eq!(util::slurp_text_file(text_pb.to_str().unwrap()), "Some updated text");
+        assert_eq!(bob_mconf.state.flagged_rollbacks.len(), 1);
+     }
+
      fn dup_syncfiles(syncfiles:&Vec<String>, passes:usize) {
         // lets make a nice dup disaster area in there...
         for i in 0..passes {
@@ -1154,17 +1555,17 @@ mod tests {
         let (ref mut alice_mconf, _) = basic_alice_bob_setup("dedup");
         core::do_sync(&mut alice_mconf.state);
 
-        let syncfiles = find_all_files(alice_mconf.state.conf.sync_dir());
+        let syncfiles = core::find_syncfile_paths(alice_mconf.state.conf.sync_dir());
         let orig_count = syncfiles.len();
 
         let max_iter :usize= 3;
         dup_syncfiles(&syncfiles,max_iter);
-        let syncfiles = find_all_files(alice_mconf.state.conf.sync_dir());
+        let syncfiles = core::find_syncfile_paths(alice_mconf.state.conf.sync_dir());
         assert_eq!(syncfiles.len(), (max_iter + 1) * orig_count);
 
         // run sync again
         core::do_sync(&mut alice_mconf.state);
-        let syncfiles = find_all_files(alice_mconf.state.conf.sync_dir());
+        let syncfiles = core::find_syncfile_paths(alice_mconf.state.conf.sync_dir());
         // doesn't really matter which files survived, as long as the count is right
         assert_eq!(syncfiles.len(), orig_count);
      }
@@ -1223,7 +1624,7 @@ mod tests {
 
         verify_sync_state(&mut bob_mconf, 2, 1);
 
-        let syncfiles = find_all_files(alice_mconf.state.conf.sync_dir());
+        let syncfiles = core::find_syncfile_paths(alice_mconf.state.conf.sync_dir());
         dup_syncfiles(&syncfiles,2);
 
         core::do_sync(&mut alice_mconf.state);
@@ -1,4 +1,6 @@
 use std::iter::repeat;
+use std::ops::{Deref, DerefMut};
+use std::ptr;
 
 extern crate crypto;
 use self::crypto::{ symmetriccipher, buffer, aes, blockmodes };
@@ -6,12 +8,241 @@ use self::crypto::buffer::{ ReadBuffer, WriteBuffer, BufferResult };
 use self::crypto::sha2::Sha256;
 use self::crypto::hmac::Hmac;
 use self::crypto::mac::Mac;
+use self::crypto::hkdf::{hkdf_extract, hkdf_expand};
+use self::crypto::aes_gcm::AesGcm;
+use self::crypto::aead::{AeadEncryptor, AeadDecryptor};
+use self::crypto::util::fixed_time_eq;
+use self::crypto::curve25519::{curve25519, curve25519_base};
+use self::crypto::ed25519;
+
+extern crate rustc_serialize;
+use self::rustc_serialize::hex::ToHex;
 
 extern crate rand;
 use self::rand::{ Rng, OsRng, Isaac64Rng, SeedableRng, random};
 
+use config::KEY_SIZE;
+
 pub const IV_SIZE: usize = 16;
 
+// Key schemes, recorded in the syncfile preamble so that old files can still be read.
+// RAW uses the password-derived key directly for both encryption and both hmacs (files written
+// before the preamble existed).  HKDF derives a separate subkey for each purpose.
+pub const KEY_SCHEME_RAW: u32 = 1;
+pub const KEY_SCHEME_HKDF: u32 = 2;
+
+const HKDF_SALT: &'static [u8] = b"greycrypt subkeys";
+
+// Bytes of the key id; enough to pick the right key out of a handful, not to identify it
+// anywhere else.
+const KEY_ID_SIZE: usize = 8;
+
+// Ed25519 signatures, which attribute syncfiles to the host that wrote them.
+pub const SIGNATURE_SIZE: usize = 64;
+
+// Format 2 syncfiles encrypt file data in chunks of CHUNK_SIZE plaintext bytes, each followed
+// by its AES-GCM tag.  The last chunk is always shorter than CHUNK_SIZE (it may be empty) and is
+// flagged as final in its associated data, so truncation at a chunk boundary is detected.
+pub const CHUNK_SIZE: usize = 65536;
+pub const TAG_SIZE: usize = 16;
+const GCM_NONCE_SIZE: usize = 12;
+
+// The first nonce byte separates data chunks from the metadata, which share the file key.
+const NONCE_DOMAIN_DATA: u8 = 0;
+const NONCE_DOMAIN_METADATA: u8 = 1;
+
+// Overwrite secret data.  The writes are volatile so that the compiler can't skip them because
+// the memory is about to be freed.
+pub fn zero_bytes(buf: &mut [u8]) {
+    for b in buf.iter_mut() {
+        unsafe { ptr::write_volatile(b, 0) };
+    }
+}
+
+#[cfg(not(target_os = "windows"))]
+extern {
+    fn mlock(addr: *const u8, len: usize) -> i32;
+}
+
+#[cfg(target_os = "windows")]
+#[link(name = "kernel32")]
+extern "stdcall" {
+    fn VirtualLock(addr: *const u8, len: usize) -> i32;
+}
+
+// Keep the page holding buf out of swap, if the OS allows it; this can fail when the locked
+// memory limit is reached, which only costs the protection.  Pages are never unlocked, because
+// other keys may share them.
+#[cfg(not(target_os = "windows"))]
+fn lock_memory(buf: &[u8]) {
+    if unsafe { mlock(buf.as_ptr(), buf.len()) } != 0 {
+        debug!("Unable to lock key memory");
+    }
+}
+
+#[cfg(target_os = "windows")]
+fn lock_memory(buf: &[u8]) {
+    if unsafe { VirtualLock(buf.as_ptr(), buf.len()) } == 0 {
+        debug!("Unable to lock key memory");
+    }
+}
+
+// A key that is zeroed when dropped.  It lives on the heap, in locked memory where possible, so
+// moving it around doesn't leave copies behind.  There is deliberately no Debug impl, so it can't
+// end up in a log; derefs to the key bytes for the functions that use it.
+pub struct SecretKey {
+    key: Box<[u8;KEY_SIZE]>
+}
+
+impl SecretKey {
+    pub fn zeroed() -> Self {
+        let key = Box::new([0; KEY_SIZE]);
+        lock_memory(&key[..]);
+        SecretKey { key: key }
+    }
+
+    pub fn from_slice(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != KEY_SIZE {
+            return None;
+        }
+        let mut key = SecretKey::zeroed();
+        for (d, s) in key.iter_mut().zip(bytes.iter()) {
+            *d = *s;
+        }
+        Some(key)
+    }
+}
+
+impl Deref for SecretKey {
+    type Target = [u8;KEY_SIZE];
+    fn deref(&self) -> &[u8;KEY_SIZE] {
+        &self.key
+    }
+}
+
+impl DerefMut for SecretKey {
+    fn deref_mut(&mut self) -> &mut [u8;KEY_SIZE] {
+        &mut self.key
+    }
+}
+
+impl Clone for SecretKey {
+    fn clone(&self) -> Self {
+        SecretKey::from_slice(&self.key[..]).unwrap()
+    }
+}
+
+impl PartialEq for SecretKey {
+    fn eq(&self, other: &SecretKey) -> bool {
+        fixed_time_eq(&self.key[..], &other.key[..])
+    }
+}
+
+impl Drop for SecretKey {
+    fn drop(&mut self) {
+        zero_bytes(&mut self.key[..]);
+    }
+}
+
+// A scratch buffer for plaintext, zeroed when dropped.  Growing it reallocates and leaves the old
+// contents behind, so size it up front where possible.
+pub struct SecretBuf {
+    buf: Vec<u8>
+}
+
+impl SecretBuf {
+    pub fn with_capacity(capacity: usize) -> Self {
+        SecretBuf { buf: Vec::with_capacity(capacity) }
+    }
+
+    pub fn zeroed(len: usize) -> Self {
+        SecretBuf { buf: repeat(0).take(len).collect() }
+    }
+
+    // Take over plaintext that is already in a Vec, so that it is zeroed when dropped.
+    pub fn from_vec(buf: Vec<u8>) -> Self {
+        SecretBuf { buf: buf }
+    }
+}
+
+impl Deref for SecretBuf {
+    type Target = Vec<u8>;
+    fn deref(&self) -> &Vec<u8> {
+        &self.buf
+    }
+}
+
+impl DerefMut for SecretBuf {
+    fn deref_mut(&mut self) -> &mut Vec<u8> {
+        &mut self.buf
+    }
+}
+
+impl Drop for SecretBuf {
+    fn drop(&mut self) {
+        // zero the whole allocation, not just the current contents
+        let len = self.buf.len();
+        let capacity = self.buf.capacity();
+        unsafe { self.buf.set_len(capacity) };
+        zero_bytes(&mut self.buf[..]);
+        unsafe { self.buf.set_len(len) };
+    }
+}
+
+// Zeroed when dropped, though returning or moving one can leave copies that aren't.
+pub struct SubKeys {
+    pub content: [u8;KEY_SIZE],
+    pub header_mac: [u8;KEY_SIZE],
+    pub data_mac: [u8;KEY_SIZE],
+    pub sync_id: [u8;KEY_SIZE]
+}
+
+impl SubKeys {
+    pub fn raw(key:&[u8;KEY_SIZE]) -> Self {
+        SubKeys {
+            content: *key,
+            header_mac: *key,
+            data_mac: *key,
+            sync_id: *key
+        }
+    }
+
+    pub fn derive(key:&[u8;KEY_SIZE]) -> Self {
+        let mut prk: [u8;KEY_SIZE] = [0; KEY_SIZE];
+        hkdf_extract(Sha256::new(), HKDF_SALT, key, &mut prk);
+
+        let mut keys = SubKeys {
+            content: [0; KEY_SIZE],
+            header_mac: [0; KEY_SIZE],
+            data_mac: [0; KEY_SIZE],
+            sync_id: [0; KEY_SIZE]
+        };
+        hkdf_expand(Sha256::new(), &prk, b"content encryption", &mut keys.content);
+        hkdf_expand(Sha256::new(), &prk, b"header mac", &mut keys.header_mac);
+        hkdf_expand(Sha256::new(), &prk, b"data mac", &mut keys.data_mac);
+        hkdf_expand(Sha256::new(), &prk, b"sync id", &mut keys.sync_id);
+        zero_bytes(&mut prk);
+        keys
+    }
+
+    pub fn for_scheme(key:&[u8;KEY_SIZE], scheme:u32) -> Option<Self> {
+        match scheme {
+            KEY_SCHEME_RAW => Some(SubKeys::raw(key)),
+            KEY_SCHEME_HKDF => Some(SubKeys::derive(key)),
+            _ => None
+        }
+    }
+}
+
+impl Drop for SubKeys {
+    fn drop(&mut self) {
+        zero_bytes(&mut self.content);
+        zero_bytes(&mut self.header_mac);
+        zero_bytes(&mut self.data_mac);
+        zero_bytes(&mut self.sync_id);
+    }
+}
+
 pub struct CryptoHelper {
     encryptor: Box<crypto::symmetriccipher::Encryptor>,
     got_eof_on_encrypt: bool,
@@ -34,44 +265,219 @@ pub fn get_hmac(key: &[u8], data: &[u8]) -> Hmac<Sha256> {
     hmac
 }
 
-pub fn get_iv() -> [u8; IV_SIZE] {
-    // Use a combination of OsRng and 
-    // Isaac to fill the IV in case the OS rng has been backdoored 
+pub fn fill_random(buf: &mut [u8]) {
+    // Use a combination of OsRng and
+    // Isaac to fill the buffer in case the OS rng has been backdoored
     // (I'm looking at you, CryptGenRandom)
-    // ...this is probably needlessly paranoid, but hopefully not insecure  
+    // ...this is probably needlessly paranoid, but hopefully not insecure
     // TODO: needs crypto review; seed issac with (partially) Non-CSPRNG?
     // another interesting reference:
     // https://github.com/cathalgarvey/lamport_signatures/blob/c884eebc95eb88c619fbb415fb562b93b3e7ad4c/fallback_RNG.py#L73
-    
+
     let mut issac_rng = Isaac64Rng::new_unseeded();
     let mut os_rng = OsRng::new().ok().unwrap();
-    
+
     let issac_seed: &[_] = &[rand::random::<u64>(), rand::random::<u64>(), os_rng.next_u64(), os_rng.next_u64()];
     issac_rng.reseed(issac_seed);
-    
-    let mut iv: [u8; IV_SIZE] = [0; IV_SIZE];
+
+    let half = buf.len() / 2;
     {
-        let mut first = &mut iv[0 .. IV_SIZE/2];
+        let mut first = &mut buf[0 .. half];
         os_rng.fill_bytes(first);
     }
     {
-        let mut second = &mut iv[IV_SIZE/2 .. IV_SIZE];
+        let mut second = &mut buf[half ..];
         issac_rng.fill_bytes(second);
     }
+}
+
+pub fn get_random_bytes(count: usize) -> Vec<u8> {
+    let mut v: Vec<u8> = repeat(0).take(count).collect();
+    fill_random(&mut v);
+    v
+}
+
+// Each format 2 file has its own key, derived from the content subkey and the random salt in the
+// file header.  Since a key is never reused across files, chunk nonces can just count up from zero.
+pub fn derive_file_key(content_key:&[u8;KEY_SIZE], salt:&[u8]) -> SecretKey {
+    let mut prk = SecretKey::zeroed();
+    hkdf_extract(Sha256::new(), salt, content_key, &mut prk[..]);
+    let mut okm = SecretKey::zeroed();
+    hkdf_expand(Sha256::new(), &prk[..], b"file key", &mut okm[..]);
+    okm
+}
+
+// The key for the content hashes in syncfile metadata, derived from the key that a keyword's sync
+// ids use rather than the one its files are encrypted with, so that the hash of a file doesn't
+// change when the data key is rotated.
+pub fn derive_content_hash_key(keyword_key:&[u8;KEY_SIZE]) -> SecretKey {
+    let mut prk = SecretKey::zeroed();
+    hkdf_extract(Sha256::new(), HKDF_SALT, keyword_key, &mut prk[..]);
+    let mut okm = SecretKey::zeroed();
+    hkdf_expand(Sha256::new(), &prk[..], b"content hash", &mut okm[..]);
+    okm
+}
+
+fn gcm_nonce(domain:u8, index:u64) -> [u8;GCM_NONCE_SIZE] {
+    let mut nonce: [u8;GCM_NONCE_SIZE] = [0; GCM_NONCE_SIZE];
+    nonce[0] = domain;
+    for i in 0..8 {
+        nonce[4 + i] = (index >> (56 - 8 * i)) as u8;
+    }
+    nonce
+}
+
+// associated data for a chunk: big endian index followed by the final flag
+fn chunk_ad(index:u64, is_final:bool) -> [u8;9] {
+    let mut ad: [u8;9] = [0; 9];
+    for i in 0..8 {
+        ad[i] = (index >> (56 - 8 * i)) as u8;
+    }
+    ad[8] = if is_final { 1 } else { 0 };
+    ad
+}
+
+// Returns the ciphertext with the tag appended
+fn gcm_seal(key:&[u8;KEY_SIZE], nonce:&[u8], ad:&[u8], data:&[u8]) -> Vec<u8> {
+    let mut gcm = AesGcm::new(aes::KeySize::KeySize256, key, nonce, ad);
+    let mut sealed: Vec<u8> = repeat(0).take(data.len() + TAG_SIZE).collect();
+    {
+        let (ciphertext, tag) = sealed.split_at_mut(data.len());
+        gcm.encrypt(data, ciphertext, tag);
+    }
+    sealed
+}
+
+// Returns None if the data fails authentication.  The plaintext is zeroed when dropped either
+// way, since it is written out before the tag is checked.
+fn gcm_open(key:&[u8;KEY_SIZE], nonce:&[u8], ad:&[u8], sealed:&[u8]) -> Option<SecretBuf> {
+    if sealed.len() < TAG_SIZE {
+        return None;
+    }
+    let (ciphertext, tag) = sealed.split_at(sealed.len() - TAG_SIZE);
+    let mut gcm = AesGcm::new(aes::KeySize::KeySize256, key, nonce, ad);
+    let mut data = SecretBuf::zeroed(ciphertext.len());
+    if gcm.decrypt(ciphertext, &mut data[..], tag) {
+        Some(data)
+    } else {
+        None
+    }
+}
+
+pub fn seal_chunk(file_key:&[u8;KEY_SIZE], index:u64, is_final:bool, data:&[u8]) -> Vec<u8> {
+    gcm_seal(file_key, &gcm_nonce(NONCE_DOMAIN_DATA, index), &chunk_ad(index, is_final), data)
+}
+
+pub fn open_chunk(file_key:&[u8;KEY_SIZE], index:u64, is_final:bool, sealed:&[u8]) -> Option<SecretBuf> {
+    gcm_open(file_key, &gcm_nonce(NONCE_DOMAIN_DATA, index), &chunk_ad(index, is_final), sealed)
+}
+
+pub fn seal_metadata(file_key:&[u8;KEY_SIZE], data:&[u8]) -> Vec<u8> {
+    gcm_seal(file_key, &gcm_nonce(NONCE_DOMAIN_METADATA, 0), b"metadata", data)
+}
+
+pub fn open_metadata(file_key:&[u8;KEY_SIZE], sealed:&[u8]) -> Option<SecretBuf> {
+    gcm_open(file_key, &gcm_nonce(NONCE_DOMAIN_METADATA, 0), b"metadata", sealed)
+}
+
+pub fn new_random_key() -> SecretKey {
+    let mut key = SecretKey::zeroed();
+    fill_random(&mut key[..]);
+    key
+}
+
+// Encrypt a key with another key (the key encryption key).  The result is a random nonce
+// followed by the ciphertext and tag.  ad is authenticated but not stored.
+pub fn wrap_key(kek:&[u8;KEY_SIZE], ad:&[u8], key:&[u8;KEY_SIZE]) -> Vec<u8> {
+    let mut nonce: [u8;GCM_NONCE_SIZE] = [0; GCM_NONCE_SIZE];
+    fill_random(&mut nonce);
+    let mut wrapped = nonce.to_vec();
+    wrapped.extend(gcm_seal(kek, &nonce, ad, key).into_iter());
+    wrapped
+}
+
+// Returns None if kek or ad are wrong, or the wrapped key was modified.
+pub fn unwrap_key(kek:&[u8;KEY_SIZE], ad:&[u8], wrapped:&[u8]) -> Option<SecretKey> {
+    if wrapped.len() != GCM_NONCE_SIZE + KEY_SIZE + TAG_SIZE {
+        return None;
+    }
+    let (nonce, sealed) = wrapped.split_at(GCM_NONCE_SIZE);
+    match gcm_open(kek, nonce, ad, sealed) {
+        None => None,
+        Some(k) => SecretKey::from_slice(&k)
+    }
+}
+
+// A short, public name for a key, so that a syncfile can say which key it needs without
+// revealing anything about it.
+pub fn key_id(key:&[u8;KEY_SIZE]) -> String {
+    let mut id = hmac_to_vec(&mut get_hmac(key, b"greycrypt key id"));
+    id.truncate(KEY_ID_SIZE);
+    id.to_hex()
+}
+
+// The X25519 public key for a secret key.
+pub fn x25519_public_key(secret:&[u8;KEY_SIZE]) -> [u8;KEY_SIZE] {
+    curve25519_base(secret)
+}
+
+// The X25519 shared secret of a secret key and another party's public key; None if the public
+// key is the wrong size.
+pub fn x25519_shared_secret(secret:&[u8;KEY_SIZE], public:&[u8]) -> Option<SecretKey> {
+    if public.len() != KEY_SIZE {
+        return None;
+    }
+    let mut shared = curve25519(secret, public);
+    let key = SecretKey::from_slice(&shared);
+    zero_bytes(&mut shared);
+    key
+}
+
+// A separate key for Ed25519 signatures, derived from an X25519 secret key so that a host only
+// needs to keep one secret.
+pub fn derive_signing_key(secret:&[u8;KEY_SIZE]) -> SecretKey {
+    let mut prk = SecretKey::zeroed();
+    hkdf_extract(Sha256::new(), HKDF_SALT, secret, &mut prk[..]);
+    let mut okm = SecretKey::zeroed();
+    hkdf_expand(Sha256::new(), &prk[..], b"greycrypt signing key", &mut okm[..]);
+    okm
+}
+
+// The Ed25519 public key for a signing key.
+pub fn ed25519_public_key(signing_key:&[u8;KEY_SIZE]) -> [u8;KEY_SIZE] {
+    let (mut expanded, public) = ed25519::keypair(signing_key);
+    zero_bytes(&mut expanded);
+    public
+}
+
+pub fn ed25519_sign(signing_key:&[u8;KEY_SIZE], message:&[u8]) -> [u8;SIGNATURE_SIZE] {
+    let (mut expanded, _) = ed25519::keypair(signing_key);
+    let sig = ed25519::signature(message, &expanded);
+    zero_bytes(&mut expanded);
+    sig
+}
+
+pub fn ed25519_verify(public:&[u8], message:&[u8], sig:&[u8]) -> bool {
+    public.len() == KEY_SIZE && sig.len() == SIGNATURE_SIZE && ed25519::verify(message, public, sig)
+}
+
+pub fn get_iv() -> [u8; IV_SIZE] {
+    let mut iv: [u8; IV_SIZE] = [0; IV_SIZE];
+    fill_random(&mut iv);
     //println!("{:?}", iv);
-    iv    
+    iv
 }
 
 impl CryptoHelper {
-    pub fn new(key:&[u8], iv:&[u8]) -> Self {
+    pub fn new(enc_key:&[u8], mac_key:&[u8], iv:&[u8]) -> Self {
         let encryptor = aes::cbc_encryptor(
                 aes::KeySize::KeySize256,
-                key,
+                enc_key,
                 iv,
                 blockmodes::PkcsPadding);
         let decryptor = aes::cbc_decryptor(
                 aes::KeySize::KeySize256,
-                key,
+                enc_key,
                 iv,
                 blockmodes::PkcsPadding);
         CryptoHelper {
@@ -79,11 +485,13 @@ impl CryptoHelper {
             decryptor: decryptor,
             got_eof_on_encrypt: false,
             got_eof_on_decrypt: false,
-            encrypt_hmac: Hmac::new(Sha256::new(), &key),
-            decrypt_hmac: Hmac::new(Sha256::new(), &key),
+            encrypt_hmac: Hmac::new(Sha256::new(), mac_key),
+            decrypt_hmac: Hmac::new(Sha256::new(), mac_key),
         }
     }
 
+    // Only format 1 files were written with CBC; they can still be read, but are no longer written.
+    #[allow(dead_code)]
     pub fn encrypt(&mut self, data: &[u8], is_all_data:bool) -> Result<Vec<u8>, symmetriccipher::SymmetricCipherError> {
         if self.got_eof_on_encrypt {
             panic!("Already received encryption eof, can't encrypt anymore; reinit crypto helper");
@@ -0,0 +1,542 @@
+use std::fs;
+use std::fs::{File,rename,remove_file};
+use std::fs::{PathExt};
+use std::io::{Read,Write};
+use std::path::PathBuf;
+use std::collections::HashMap;
+
+#[cfg(not(target_os = "windows"))]
+use std::os::unix::fs::PermissionsExt;
+
+extern crate crypto;
+use self::crypto::sha2::Sha256;
+use self::crypto::digest::Digest;
+
+extern crate rustc_serialize;
+use self::rustc_serialize::base64::{ToBase64, STANDARD, FromBase64};
+use self::rustc_serialize::hex::{ToHex, FromHex};
+
+use config;
+use config::KEY_SIZE;
+use crypto_util;
+use crypto_util::SecretKey;
+use kdf;
+use syncdb;
+
+// Each host can have its own X25519 key pair, so that it can unlock the master key without the
+// password.  The secret key stays in the host's local state dir (next to the sync db); the
+// master key is wrapped to the public key in a host key slot in the sync dir's manifest.
+//
+// Enrolling a host takes two steps, so that the new host never needs the password: the new
+// host publishes its public key in the sync dir as "host-<name>.pub", and a host that already
+// has the master key wraps it to that key.
+//
+// The same secret also gives the host an Ed25519 key (see crypto_util::derive_signing_key()),
+// which signs every syncfile it writes.  The public half is pinned in the host's key slot, and
+// the first time another host sees it, in that host's SIGNER_PINS_FILE too, so that someone who
+// later gets the password can't swap in a key of their own.
+//
+// Anyone who can write to the sync dir can also add a host key slot for this host's public key,
+// holding a master key of their own; a host that used it would encrypt its files for them.  So
+// the master key is pinned in MASTER_KEY_PIN_FILE, by its key id, the first time the host
+// unlocks it, and a host key slot with a different key is refused after that.  Unlocking with
+// a password moves the pin, since that proves the key is the real one.
+const HOST_KEY_FILE: &'static str = "host.key";
+const SIGNER_PINS_FILE: &'static str = "signers";
+const MASTER_KEY_PIN_FILE: &'static str = "master.key_id";
+const PUBLISHED_KEY_PREFIX: &'static str = "host-";
+const PUBLISHED_KEY_EXT: &'static str = ".pub";
+
+// Bytes of the public key hash shown as its fingerprint
+const FINGERPRINT_SIZE: usize = 16;
+
+pub struct HostKey {
+    secret: SecretKey,
+    pub public: [u8;KEY_SIZE],
+    pub signing_public: [u8;KEY_SIZE]
+}
+
+impl HostKey {
+    pub fn generate() -> Self {
+        HostKey::from_secret(crypto_util::new_random_key())
+    }
+
+    fn from_secret(secret:SecretKey) -> Self {
+        let public = crypto_util::x25519_public_key(&secret);
+        let signing_public = crypto_util::ed25519_public_key(&crypto_util::derive_signing_key(&secret));
+        HostKey {
+            secret: secret,
+            public: public,
+            signing_public: signing_public
+        }
+    }
+
+    pub fn secret(&self) -> &SecretKey {
+        &self.secret
+    }
+
+    pub fn signing_key(&self) -> SecretKey {
+        crypto_util::derive_signing_key(&self.secret)
+    }
+}
+
+// A short hash of a host's public keys, for comparing by eye before enrolling it.  Hosts
+// enrolled before signing keys existed don't have one; pass an empty slice.
+pub fn fingerprint(public:&[u8], signing_public:&[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.input(public);
+    hasher.input(signing_public);
+    let mut hash = [0; 32];
+    hasher.result(&mut hash);
+    let hex = hash[0 .. FINGERPRINT_SIZE].to_hex();
+    let groups:Vec<&str> = (0 .. hex.len() / 4).map(|i| &hex[i * 4 .. (i + 1) * 4]).collect();
+    groups.join(":")
+}
+
+pub fn host_key_path(conf:&config::SyncConfig) -> Result<PathBuf,String> {
+    let mut pb = try!(syncdb::local_dir(conf));
+    pb.push(HOST_KEY_FILE);
+    Ok(pb)
+}
+
+pub fn published_key_path(sync_dir:&str, host_name:&str) -> PathBuf {
+    let mut pb = PathBuf::from(sync_dir);
+    pb.push(format!("{}{}{}", PUBLISHED_KEY_PREFIX, host_name.to_lowercase(), PUBLISHED_KEY_EXT));
+    pb
+}
+
+// The key file holds a secret, so only its owner may read it.
+#[cfg(not(target_os = "windows"))]
+fn restrict_permissions(path:&str) -> Result<(),String> {
+    match fs::set_permissions(path, fs::Permissions::from_mode(0o600)) {
+        Err(e) => Err(format!("Unable to set permissions on host key file {}: {}", path, e)),
+        Ok(_) => Ok(())
+    }
+}
+
+#[cfg(not(target_os = "windows"))]
+fn check_permissions(path:&PathBuf) -> Result<(),String> {
+    let mode = match fs::metadata(path) {
+        Err(e) => return Err(format!("Unable to read host key file {:?}: {}", path, e)),
+        Ok(m) => m.permissions().mode()
+    };
+    if mode & 0o077 != 0 {
+        return Err(format!("Host key file {:?} is accessible by other users (mode {:o}); run: chmod 600 {:?}", path, mode & 0o777, path));
+    }
+    Ok(())
+}
+
+// TODO: set and check the ACL on windows
+#[cfg(target_os = "windows")]
+fn restrict_permissions(_:&str) -> Result<(),String> {
+    Ok(())
+}
+
+#[cfg(target_os = "windows")]
+fn check_permissions(_:&PathBuf) -> Result<(),String> {
+    Ok(())
+}
+
+// This host's key, or None if it doesn't have one yet.
+pub fn load(conf:&config::SyncConfig) -> Result<Option<HostKey>,String> {
+    let path = try!(host_key_path(conf));
+    if !path.is_file() {
+        return Ok(None);
+    }
+    try!(check_permissions(&path));
+    let mut text = String::new();
+    match File::open(&path).and_then(|mut f| f.read_to_string(&mut text)) {
+        Err(e) => return Err(format!("Unable to read host key file {:?}: {}", path, e)),
+        Ok(_) => ()
+    }
+    let mut bytes = match text.trim().from_hex() {
+        Err(e) => return Err(format!("Invalid host key file {:?}: {}", path, e)),
+        Ok(b) => b
+    };
+    let secret = SecretKey::from_slice(&bytes);
+    crypto_util::zero_bytes(&mut bytes);
+    crypto_util::zero_bytes(unsafe { text.as_mut_vec() });
+    match secret {
+        None => Err(format!("Invalid host key file {:?}: wrong key size", path)),
+        Some(s) => Ok(Some(HostKey::from_secret(s)))
+    }
+}
+
+// This host's key, made and saved if it doesn't have one yet.
+pub fn load_or_create(conf:&config::SyncConfig) -> Result<HostKey,String> {
+    match try!(load(conf)) {
+        Some(hk) => return Ok(hk),
+        None => ()
+    }
+    let path = try!(host_key_path(conf));
+    match path.parent() {
+        None => (),
+        Some(dir) => match fs::create_dir_all(dir) {
+            Err(e) => return Err(format!("Unable to create directory for host key {:?}: {}", dir, e)),
+            Ok(_) => ()
+        }
+    }
+
+    let hk = HostKey::generate();
+    let tmp_path = format!("{}.gc_tmp", path.to_str().unwrap());
+    {
+        let mut f = match File::create(&tmp_path) {
+            Err(e) => return Err(format!("Unable to create host key file {}: {}", tmp_path, e)),
+            Ok(f) => f
+        };
+        try!(restrict_permissions(&tmp_path));
+        let mut hex = hk.secret[..].to_hex();
+        let res = writeln!(f, "{}", hex);
+        crypto_util::zero_bytes(unsafe { hex.as_mut_vec() });
+        match res {
+            Err(e) => return Err(format!("Unable to write host key file {}: {}", tmp_path, e)),
+            Ok(_) => ()
+        }
+    }
+    match rename(&tmp_path, &path) {
+        Err(e) => return Err(format!("Unable to move host key file into place: {:?}: {}", path, e)),
+        Ok(_) => ()
+    }
+    info!("Created host key: {:?}", path);
+    Ok(hk)
+}
+
+// Put host_name's public keys in the sync dir, where an enrolled host can find them: the X25519
+// key on the first line and the signing key on the second.
+pub fn publish(sync_dir:&str, host_name:&str, hk:&HostKey) -> Result<(),String> {
+    let path = published_key_path(sync_dir, host_name);
+    match File::create(&path).and_then(|mut f| write!(f, "{}\n{}\n", hk.public.to_base64(STANDARD), hk.signing_public.to_base64(STANDARD))) {
+        Err(e) => Err(format!("Unable to write public key {:?}: {}", path, e)),
+        Ok(_) => Ok(())
+    }
+}
+
+fn decode_public_key(path:&PathBuf, line:Option<&str>) -> Result<[u8;KEY_SIZE],String> {
+    let bytes = match line.map(|l| l.trim().from_base64()) {
+        None => return Err(format!("Invalid public key {:?}: missing key", path)),
+        Some(Err(e)) => return Err(format!("Invalid public key {:?}: {:?}", path, e)),
+        Some(Ok(b)) => b
+    };
+    if bytes.len() != KEY_SIZE {
+        return Err(format!("Invalid public key {:?}: wrong size", path));
+    }
+    let mut public = [0; KEY_SIZE];
+    for i in 0..KEY_SIZE {
+        public[i] = bytes[i];
+    }
+    Ok(public)
+}
+
+// The X25519 and signing public keys that host_name published.
+pub fn read_published(sync_dir:&str, host_name:&str) -> Result<([u8;KEY_SIZE],[u8;KEY_SIZE]),String> {
+    let path = published_key_path(sync_dir, host_name);
+    if !path.is_file() {
+        return Err(format!("No public key published for host {}; run \"greycrypt host enroll\" on that host first", host_name));
+    }
+    let mut text = String::new();
+    match File::open(&path).and_then(|mut f| f.read_to_string(&mut text)) {
+        Err(e) => return Err(format!("Unable to read public key {:?}: {}", path, e)),
+        Ok(_) => ()
+    }
+    let mut lines = text.lines();
+    let public = try!(decode_public_key(&path, lines.next()));
+    let signing_public = try!(decode_public_key(&path, lines.next()));
+    Ok((public, signing_public))
+}
+
+pub fn remove_published(sync_dir:&str, host_name:&str) {
+    let path = published_key_path(sync_dir, host_name);
+    if path.is_file() {
+        match remove_file(&path) {
+            Err(e) => warn!("Unable to remove published public key {:?}: {}", path, e),
+            Ok(_) => ()
+        }
+    }
+}
+
+fn signer_pins_path(conf:&config::SyncConfig) -> Result<PathBuf,String> {
+    let mut pb = try!(syncdb::local_dir(conf));
+    pb.push(SIGNER_PINS_FILE);
+    Ok(pb)
+}
+
+fn read_signer_pins(path:&PathBuf) -> Result<HashMap<String,Vec<u8>>,String> {
+    let mut pins = HashMap::new();
+    if !path.is_file() {
+        return Ok(pins);
+    }
+    let mut text = String::new();
+    match File::open(path).and_then(|mut f| f.read_to_string(&mut text)) {
+        Err(e) => return Err(format!("Unable to read pinned signing keys {:?}: {}", path, e)),
+        Ok(_) => ()
+    }
+    for line in text.lines().filter(|l| !l.trim().is_empty()) {
+        let parts:Vec<&str> = line.split_whitespace().collect();
+        let key = match parts.get(1).map(|k| k.from_base64()) {
+            Some(Ok(ref k)) if parts.len() == 2 && k.len() == KEY_SIZE => k.clone(),
+            _ => return Err(format!("Invalid line in pinned signing keys {:?}: {}", path, line))
+        };
+        pins.insert(parts[0].to_owned(), key);
+    }
+    Ok(pins)
+}
+
+fn write_signer_pins(path:&PathBuf, pins:&HashMap<String,Vec<u8>>) -> Result<(),String> {
+    let mut names:Vec<&String> = pins.keys().collect();
+    names.sort();
+    let mut text = String::new();
+    for name in names {
+        text.push_str(&format!("{} {}\n", name, pins[name].to_base64(STANDARD)));
+    }
+    match path.parent() {
+        None => (),
+        Some(dir) => match fs::create_dir_all(dir) {
+            Err(e) => return Err(format!("Unable to create directory for pinned signing keys {:?}: {}", dir, e)),
+            Ok(_) => ()
+        }
+    }
+    match File::create(path).and_then(|mut f| f.write_all(text.as_bytes())) {
+        Err(e) => Err(format!("Unable to write pinned signing keys {:?}: {}", path, e)),
+        Ok(_) => Ok(())
+    }
+}
+
+fn master_key_pin_path(conf:&config::SyncConfig) -> Result<PathBuf,String> {
+    let mut pb = try!(syncdb::local_dir(conf));
+    pb.push(MASTER_KEY_PIN_FILE);
+    Ok(pb)
+}
+
+fn read_master_key_pin(path:&PathBuf) -> Result<Option<String>,String> {
+    if !path.is_file() {
+        return Ok(None);
+    }
+    let mut text = String::new();
+    match File::open(path).and_then(|mut f| f.read_to_string(&mut text)) {
+        Err(e) => Err(format!("Unable to read pinned master key id {:?}: {}", path, e)),
+        Ok(_) => Ok(Some(text.trim().to_owned()))
+    }
+}
+
+// Pin key as the master key on this host, replacing any previous pin.
+pub fn pin_master_key(conf:&config::SyncConfig, key:&SecretKey) -> Result<(),String> {
+    let path = try!(master_key_pin_path(conf));
+    let key_id = crypto_util::key_id(key);
+    if try!(read_master_key_pin(&path)) == Some(key_id.clone()) {
+        return Ok(());
+    }
+    match path.parent() {
+        None => (),
+        Some(dir) => match fs::create_dir_all(dir) {
+            Err(e) => return Err(format!("Unable to create directory for pinned master key id {:?}: {}", dir, e)),
+            Ok(_) => ()
+        }
+    }
+    match File::create(&path).and_then(|mut f| writeln!(f, "{}", key_id)) {
+        Err(e) => Err(format!("Unable to write pinned master key id {:?}: {}", path, e)),
+        Ok(_) => Ok(())
+    }
+}
+
+// Whether key, from a host key slot, is the master key pinned on this host.  If none is
+// pinned yet, key is pinned and trusted.
+pub fn check_master_key(conf:&config::SyncConfig, key:&SecretKey) -> Result<bool,String> {
+    let path = try!(master_key_pin_path(conf));
+    match try!(read_master_key_pin(&path)) {
+        None => {
+            try!(pin_master_key(conf, key));
+            Ok(true)
+        },
+        Some(pinned) => Ok(pinned == crypto_util::key_id(key))
+    }
+}
+
+// The signing key of each host that has one in the manifest, by host name.  Only hosts listed in
+// the config's [Mapping] count.  A key is pinned on this host the first time it is seen; if the
+// manifest later has a different key for that host, the pinned one is still used (so files
+// signed with the new key are rejected) until the pin is removed by hand.
+pub fn trusted_signers(conf:&config::SyncConfig, manifest:&kdf::KdfManifest) -> Result<HashMap<String,Vec<u8>>,String> {
+    let path = try!(signer_pins_path(conf));
+    let mut pins = try!(read_signer_pins(&path));
+    let mut signers = HashMap::new();
+    let mut changed = false;
+    for slot in &manifest.slots {
+        let key = match slot.signing_key {
+            Some(ref k) if slot.is_host() && conf.known_hosts.iter().any(|h| *h == slot.name) => k,
+            _ => continue
+        };
+        match pins.get(&slot.name) {
+            Some(pinned) if pinned != key => {
+                warn!("The signing key for host {} in the sync directory doesn't match the one pinned on this host; \
+                    its new syncfiles will be rejected.  If it was enrolled again, remove its line from {:?}", slot.name, path);
+                signers.insert(slot.name.clone(), pinned.clone());
+                continue;
+            },
+            Some(_) => (),
+            None => changed = true
+        }
+        signers.insert(slot.name.clone(), key.clone());
+    }
+    if changed {
+        for (name, key) in &signers {
+            pins.insert(name.clone(), key.clone());
+        }
+        try!(write_signer_pins(&path, &pins));
+    }
+    Ok(signers)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{remove_dir_all,create_dir_all};
+    use std::fs::{PathExt};
+    use std::path::PathBuf;
+
+    use config;
+    use crypto_util;
+    use host_key;
+    use kdf;
+    use syncfile;
+    use testlib;
+
+    #[test]
+    fn load_or_create() {
+        let wd = env::current_dir().unwrap();
+        let mut dir = PathBuf::from(&wd);
+        dir.push("testdata");
+        dir.push("out_host_key");
+        if dir.is_dir() {
+            remove_dir_all(&dir).unwrap();
+        }
+        let mut conf = testlib::util::get_mock_config();
+        conf.syncdb_dir = Some(dir.to_str().unwrap().to_owned());
+
+        assert!(host_key::load(&conf).unwrap().is_none());
+        let hk = host_key::load_or_create(&conf).unwrap();
+        assert_eq!(hk.public, crypto_util::x25519_public_key(hk.secret()));
+        // the same key next time
+        let loaded = host_key::load(&conf).unwrap().unwrap();
+        assert!(*loaded.secret() == *hk.secret());
+        assert_eq!(host_key::load_or_create(&conf).unwrap().public, hk.public);
+
+        let sync_dir = dir.to_str().unwrap();
+        create_dir_all(&dir).unwrap();
+        assert!(host_key::read_published(sync_dir, "laptop").is_err());
+        host_key::publish(sync_dir, "laptop", &hk).unwrap();
+        assert_eq!(host_key::read_published(sync_dir, "LAPTOP").unwrap(), (hk.public, hk.signing_public));
+        host_key::remove_published(sync_dir, "laptop");
+        assert!(host_key::read_published(sync_dir, "laptop").is_err());
+
+        assert_eq!(host_key::fingerprint(&hk.public, &hk.signing_public).len(), 39);
+        assert!(host_key::fingerprint(&hk.public, &hk.signing_public) != host_key::fingerprint(&hk.public, &[]));
+    }
+
+    #[test]
+    fn trusted_signers() {
+        let wd = env::current_dir().unwrap();
+        let mut dir = PathBuf::from(&wd);
+        dir.push("testdata");
+        dir.push("out_host_key_signers");
+        if dir.is_dir() {
+            remove_dir_all(&dir).unwrap();
+        }
+        let mut conf = testlib::util::get_mock_config();
+        conf.syncdb_dir = Some(dir.to_str().unwrap().to_owned());
+        conf.known_hosts = vec!["laptop".to_owned()];
+
+        let master = crypto_util::new_random_key();
+        let laptop = host_key::HostKey::generate();
+        let stranger = host_key::HostKey::generate();
+        let mut manifest = kdf::KdfManifest::create(testlib::util::test_kdf(), "swordfish", None, &master);
+        manifest.add_slot(kdf::KeySlot::create_for_host("laptop", &laptop.public, Some(&laptop.signing_public[..]), &master)).unwrap();
+        // not in [Mapping]
+        manifest.add_slot(kdf::KeySlot::create_for_host("stranger", &stranger.public, Some(&stranger.signing_public[..]), &master)).unwrap();
+
+        let signers = host_key::trusted_signers(&conf, &manifest).unwrap();
+        assert_eq!(signers.len(), 1);
+        assert_eq!(signers.get("laptop"), Some(&laptop.signing_public.to_vec()));
+
+        // the key is pinned now, so a different one for the same host is not trusted
+        let idx = manifest.find_slot("laptop").unwrap();
+        manifest.replace_slot(idx, kdf::KeySlot::create_for_host("laptop", &stranger.public, Some(&stranger.signing_public[..]), &master));
+        let signers = host_key::trusted_signers(&conf, &manifest).unwrap();
+        assert_eq!(signers.len(), 1);
+        assert_eq!(signers.get("laptop"), Some(&laptop.signing_public.to_vec()));
+
+        // and the host still signs, so unsigned files or ones signed with the new key that claim
+        // to be from it are rejected
+        let mut testpath = PathBuf::from(&wd);
+        testpath.push("testdata");
+        testpath.push("test_text_file.txt");
+        let mut sfpath = dir.clone();
+        sfpath.push("trusted_signers.dat");
+        let mut laptop_conf = conf.clone();
+        laptop_conf.host_name = "laptop".to_owned();
+        let reader_conf = conf.with_signers(signers);
+        syncfile::SyncFile::create_syncfile(&laptop_conf, &testpath, Some(sfpath.clone())).unwrap();
+        assert!(syncfile::SyncFile::from_syncfile(&reader_conf, &sfpath).is_err());
+        let forged_conf = laptop_conf.with_signing_key(Some(stranger.signing_key()));
+        syncfile::SyncFile::create_syncfile(&forged_conf, &testpath, Some(sfpath.clone())).unwrap();
+        assert!(syncfile::SyncFile::from_syncfile(&reader_conf, &sfpath).is_err());
+        let signed_conf = laptop_conf.with_signing_key(Some(laptop.signing_key()));
+        syncfile::SyncFile::create_syncfile(&signed_conf, &testpath, Some(sfpath.clone())).unwrap();
+        assert!(syncfile::SyncFile::from_syncfile(&reader_conf, &sfpath).is_ok());
+    }
+
+    #[test]
+    fn master_key_pin() {
+        let wd = env::current_dir().unwrap();
+        let mut dir = PathBuf::from(&wd);
+        dir.push("testdata");
+        dir.push("out_host_key_master_pin");
+        if dir.is_dir() {
+            remove_dir_all(&dir).unwrap();
+        }
+        let mut conf = testlib::util::get_mock_config();
+        conf.syncdb_dir = Some(dir.to_str().unwrap().to_owned());
+
+        let master = crypto_util::new_random_key();
+        let hk = host_key::load_or_create(&conf).unwrap();
+        let mut manifest = kdf::KdfManifest::create(testlib::util::test_kdf(), "swordfish", None, &master);
+        manifest.add_slot(kdf::KeySlot::create_for_host("laptop", &hk.public, Some(&hk.signing_public[..]), &master)).unwrap();
+        let (_, key) = config::host_key_unlock(&conf, &manifest).unwrap();
+        assert!(key == master);
+
+        // a slot for this host's public key with some other master key is refused
+        let forged = crypto_util::new_random_key();
+        let idx = manifest.find_slot("laptop").unwrap();
+        manifest.replace_slot(idx, kdf::KeySlot::create_for_host("laptop", &hk.public, Some(&hk.signing_public[..]), &forged));
+        assert!(manifest.unlock_with_host_key(hk.secret()).unwrap().1 == forged);
+        assert!(config::host_key_unlock(&conf, &manifest).is_none());
+
+        // until the key is pinned some other way
+        host_key::pin_master_key(&conf, &forged).unwrap();
+        assert!(config::host_key_unlock(&conf, &manifest).unwrap().1 == forged);
+    }
+
+    #[test]
+    #[cfg(not(target_os = "windows"))]
+    fn permissions() {
+        use std::fs::{metadata,set_permissions,Permissions};
+        use std::os::unix::fs::PermissionsExt;
+
+        let wd = env::current_dir().unwrap();
+        let mut dir = PathBuf::from(&wd);
+        dir.push("testdata");
+        dir.push("out_host_key_permissions");
+        if dir.is_dir() {
+            remove_dir_all(&dir).unwrap();
+        }
+        let mut conf = testlib::util::get_mock_config();
+        conf.syncdb_dir = Some(dir.to_str().unwrap().to_owned());
+
+        host_key::load_or_create(&conf).unwrap();
+        let path = host_key::host_key_path(&conf).unwrap();
+        assert_eq!(metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+
+        set_permissions(&path, Permissions::from_mode(0o644)).unwrap();
+        let e = host_key::load(&conf).err().unwrap();
+        assert!(e.contains("chmod 600"), "{}", e);
+    }
+}
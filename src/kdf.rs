@@ -0,0 +1,1467 @@
+use std::fs::{File,create_dir_all,rename,read_dir};
+use std::fs::{PathExt};
+use std::io;
+use std::io::{Read,Write};
+use std::path::{PathBuf};
+
+extern crate crypto;
+use self::crypto::bcrypt_pbkdf::bcrypt_pbkdf;
+use self::crypto::scrypt::{scrypt, ScryptParams};
+use self::crypto::hkdf::{hkdf_extract, hkdf_expand};
+use self::crypto::sha2::Sha256;
+use self::crypto::mac::Mac;
+
+extern crate time;
+
+extern crate toml;
+
+extern crate rustc_serialize;
+use self::rustc_serialize::base64::{ToBase64, STANDARD, FromBase64};
+use self::rustc_serialize::hex::{ToHex, FromHex};
+
+use config::KEY_SIZE;
+use crypto_util::SecretKey;
+use crypto_util;
+
+// The manifest is a small plaintext toml file that lives at the root of the sync dir.
+// It is written when the sync dir is created, and every host reads it to turn the password
+// into the encryption key.  The master key that encrypts the syncfiles is random, and is
+// stored here in one or more key slots, each encrypted with a key derived from a different
+// passphrase (or recovery key) and salt.  The salts only need to be unique, not private.
+pub const MANIFEST_FILE: &'static str = "kdf.toml";
+// Written before an upgrade starts re-encrypting syncfiles, and renamed to MANIFEST_FILE
+// when it finishes.
+pub const PENDING_MANIFEST_FILE: &'static str = "kdf.toml.pending";
+// A keyword can have its own key, so that it can be shared without sharing the rest of the
+// sync dir.  That key is kept in a manifest of its own, "kdf-<keyword>.toml", in a slot
+// unlocked by the master key (MASTER_SLOT_NAME), and in password slots for anyone who should
+// only have that keyword.
+const KEYWORD_MANIFEST_PREFIX: &'static str = "kdf-";
+const KEYWORD_MANIFEST_EXT: &'static str = ".toml";
+pub const MASTER_SLOT_NAME: &'static str = "master";
+// While the master key is being replaced (see "host revoke"), the pending manifest holds the new
+// key in a slot unlocked by the old one, so that an interrupted rotation can be resumed.
+pub const ROTATION_SLOT_NAME: &'static str = "rotation";
+// The key that syncfiles are encrypted with can be separate from the master key, in a slot
+// unlocked by it, so that it can be replaced ("rotate-key") without touching the other slots.
+// While the syncfiles are being re-encrypted, the previous one is kept in the retired slot so
+// that files that haven't been re-encrypted yet can still be read.  Without a data key slot the
+// master key is also the data key.
+pub const DATA_SLOT_NAME: &'static str = "data-key";
+pub const RETIRED_DATA_SLOT_NAME: &'static str = "data-key-retired";
+
+// Version 2 added the sync id scheme; version 1 manifests always use unkeyed sync ids.
+// Version 3 added the wrapped master key; before that the password-derived key was used
+// directly.  Version 4 moved it into a list of key slots, version 5 added key files, version 6
+// added host key slots, version 7 pinned each host's signing key in its slot, version 8 added
+// data key slots, version 9 marks recovery slots, which were inferred from the kdf before, and
+// version 10 records the key id of the key that the slots hold.
+pub const MANIFEST_VERSION: i64 = 10;
+pub const SALT_SIZE: usize = 32;
+const MIN_SALT_SIZE: usize = 16;
+
+
+// scrypt uses 128 * r * 2^log_n bytes of memory; the defaults use 32MB.  Use kdf-benchmark
+// to pick something better suited to the hosts that share a sync dir.
+pub const DEFAULT_SCRYPT_LOG_N: u8 = 15;
+pub const DEFAULT_SCRYPT_R: u32 = 8;
+pub const DEFAULT_SCRYPT_P: u32 = 1;
+
+// Range searched by the benchmark.  2^20 with r = 8 is 1GB, more than enough.
+const MIN_BENCH_LOG_N: u8 = 10;
+const MAX_BENCH_LOG_N: u8 = 20;
+
+// Limits on the scrypt parameters that are accepted, including from a manifest, which anyone
+// who can write to the sync dir could edit: the most memory a derivation may use, which is what
+// the benchmark can pick, and the most passes over it.
+const MAX_SCRYPT_MEMORY: u64 = 1 << 30;
+const MAX_SCRYPT_P: u32 = 16;
+
+// Parameters used by sync dirs created before the manifest existed.
+const LEGACY_SALT: &'static [u8] = b"salt";
+const LEGACY_BCRYPT_ROUNDS: u32 = 5;
+
+// Name of the slot in manifests that have only ever had one password.
+pub const DEFAULT_SLOT_NAME: &'static str = "default";
+
+// Recovery keys are random, shown as hex digits in groups of RECOVERY_KEY_GROUP.
+pub const RECOVERY_KEY_SIZE: usize = 32;
+const RECOVERY_KEY_GROUP: usize = 8;
+
+// Bytes of the key file check value kept in the manifest.  Enough to tell a changed key file
+// from a wrong password, while saying little about the key file itself.
+const KEY_FILE_CHECK_SIZE: usize = 4;
+
+// The supported password-based key derivation functions.  Every variant carries its cost
+// parameters, which are recorded in the manifest so that all hosts derive the same key.
+#[derive(Debug,Clone,PartialEq)]
+pub enum KdfAlgorithm {
+    BcryptPbkdf { rounds: u32 },
+    Scrypt { log_n: u8, r: u32, p: u32 },
+    // No stretching; only used for recovery keys, which are random.  Can't be selected
+    // with --kdf.
+    Hkdf,
+    // Host key slots: the salt is an ephemeral X25519 public key, and the secret is the key it
+    // shares with the host's key.  Can't be selected with --kdf.
+    X25519
+}
+
+impl KdfAlgorithm {
+    pub fn default_scrypt() -> Self {
+        KdfAlgorithm::Scrypt { log_n: DEFAULT_SCRYPT_LOG_N, r: DEFAULT_SCRYPT_R, p: DEFAULT_SCRYPT_P }
+    }
+
+    // Parse the command line form produced by spec(): "scrypt:LOG_N:R:P" or "bcrypt_pbkdf:ROUNDS".
+    pub fn parse(spec:&str) -> Result<Self,String> {
+        let parts:Vec<&str> = spec.trim().split(':').collect();
+        let num = |idx:usize| {
+            match parts.get(idx) {
+                None => Err(format!("Missing parameter {} in kdf spec: {}", idx, spec)),
+                Some(v) => match u32::from_str_radix(v.trim(), 10) {
+                    Err(e) => Err(format!("Invalid parameter '{}' in kdf spec: {}: {}", v, spec, e)),
+                    Ok(n) => Ok(n)
+                }
+            }
+        };
+
+        let alg = match (parts[0], parts.len()) {
+            ("scrypt", 4) => {
+                let log_n = try!(num(1));
+                if log_n > (u8::max_value() as u32) {
+                    return Err(format!("scrypt log_n is too large: {}", log_n));
+                }
+                KdfAlgorithm::Scrypt { log_n: log_n as u8, r: try!(num(2)), p: try!(num(3)) }
+            },
+            ("bcrypt_pbkdf", 2) => KdfAlgorithm::BcryptPbkdf { rounds: try!(num(1)) },
+            _ => return Err(format!("Unrecognized kdf spec '{}'; expected scrypt:LOG_N:R:P or bcrypt_pbkdf:ROUNDS", spec))
+        };
+        try!(alg.validate());
+        Ok(alg)
+    }
+
+    pub fn spec(&self) -> String {
+        match *self {
+            KdfAlgorithm::BcryptPbkdf { rounds } => format!("bcrypt_pbkdf:{}", rounds),
+            KdfAlgorithm::Scrypt { log_n, r, p } => format!("scrypt:{}:{}:{}", log_n, r, p),
+            KdfAlgorithm::Hkdf => format!("hkdf"),
+            KdfAlgorithm::X25519 => format!("x25519")
+        }
+    }
+
+    // Check that the parameters are usable; the underlying implementations panic on bad values,
+    // and scrypt's memory use has to be bounded before anything is derived.
+    pub fn validate(&self) -> Result<(),String> {
+        match *self {
+            KdfAlgorithm::BcryptPbkdf { rounds } => {
+                if rounds < 1 {
+                    return Err(format!("bcrypt_pbkdf rounds must be at least 1"));
+                }
+            },
+            KdfAlgorithm::Scrypt { log_n, r, p } => {
+                if log_n < 1 || log_n > 30 {
+                    return Err(format!("scrypt log_n must be between 1 and 30, got {}", log_n));
+                }
+                if r < 1 || p < 1 {
+                    return Err(format!("scrypt r and p must be at least 1"));
+                }
+                if (r as u64) * (p as u64) >= (1 << 30) {
+                    return Err(format!("scrypt r * p must be less than 2^30"));
+                }
+                // rust-crypto's ScryptParams::new() asserts this
+                if (log_n as u64) >= 16 * (r as u64) {
+                    return Err(format!("scrypt log_n must be less than 16 * r, got log_n {} with r {}", log_n, r));
+                }
+                if p > MAX_SCRYPT_P {
+                    return Err(format!("scrypt p must be at most {}, got {}", MAX_SCRYPT_P, p));
+                }
+                match (128 * r as u64).checked_mul(1 << log_n) {
+                    Some(memory) if memory <= MAX_SCRYPT_MEMORY => (),
+                    _ => return Err(format!("scrypt log_n {} with r {} would use more than {} MB of memory", log_n, r, MAX_SCRYPT_MEMORY >> 20))
+                }
+            },
+            KdfAlgorithm::Hkdf | KdfAlgorithm::X25519 => ()
+        }
+        Ok(())
+    }
+
+    pub fn derive(&self, password:&[u8], salt:&[u8], out:&mut [u8]) {
+        match *self {
+            KdfAlgorithm::BcryptPbkdf { rounds } => bcrypt_pbkdf(password, salt, rounds, out),
+            KdfAlgorithm::Scrypt { log_n, r, p } => {
+                let params = ScryptParams::new(log_n, r, p);
+                scrypt(password, salt, &params, out)
+            },
+            KdfAlgorithm::Hkdf => {
+                let mut prk = SecretKey::zeroed();
+                hkdf_extract(Sha256::new(), salt, password, &mut prk[..]);
+                hkdf_expand(Sha256::new(), &prk[..], b"greycrypt recovery key", out)
+            },
+            KdfAlgorithm::X25519 => {
+                let mut prk = SecretKey::zeroed();
+                hkdf_extract(Sha256::new(), salt, password, &mut prk[..]);
+                hkdf_expand(Sha256::new(), &prk[..], b"greycrypt host key", out)
+            }
+        }
+    }
+}
+
+// How syncfile ids (and so syncfile names) are computed from the keyword and relpath.  Sha256
+// is a plain hash, so anyone who can read the sync dir can confirm a guessed path; Hmac is keyed
+// with a subkey of the encryption key.  Switched with --upgrade.
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum SyncIdScheme {
+    Sha256,
+    Hmac
+}
+
+// Make a new random recovery key, formatted for printing.
+pub fn new_recovery_key() -> String {
+    format_recovery_key(&crypto_util::get_random_bytes(RECOVERY_KEY_SIZE))
+}
+
+pub fn format_recovery_key(key:&[u8]) -> String {
+    let hex = key.to_hex();
+    let groups:Vec<&str> = (0 .. hex.len() / RECOVERY_KEY_GROUP).map(|i| {
+        &hex[i * RECOVERY_KEY_GROUP .. (i + 1) * RECOVERY_KEY_GROUP]
+    }).collect();
+    groups.join("-")
+}
+
+// Returns the bytes of a recovery key typed in by the user, or None if text doesn't look
+// like one.  Dashes, spaces and case are ignored.
+pub fn parse_recovery_key(text:&str) -> Option<Vec<u8>> {
+    let hex:String = text.chars().filter(|c| *c != '-' && !c.is_whitespace()).collect();
+    if hex.len() != RECOVERY_KEY_SIZE * 2 {
+        return None;
+    }
+    match hex.to_lowercase().from_hex() {
+        Err(_) => None,
+        Ok(bytes) => Some(bytes)
+    }
+}
+
+// One way to unlock the master key: its own kdf and salt, and the master key wrapped with
+// the key derived from them.
+#[derive(Debug,Clone,PartialEq)]
+pub struct KeySlot {
+    pub name: String,
+    pub algorithm: KdfAlgorithm,
+    pub salt: Vec<u8>,
+    // None in manifests older than version 3, where the derived key is the master key.
+    pub wrapped_key: Option<Vec<u8>>,
+    // Set if the slot also needs a key file; a short MAC of its contents, so that a missing or
+    // changed key file can be reported as such.
+    pub key_file_check: Option<Vec<u8>>,
+    // The public key of the host that can unlock a host key slot.
+    pub host_key: Option<Vec<u8>>,
+    // The Ed25519 public key that the host signs its syncfiles with.  Host slots from version 6
+    // manifests don't have one.
+    pub signing_key: Option<Vec<u8>>,
+    // Set for slots made by create_recovery().  The other hkdf slots hold a key wrapped with
+    // another key; see create_with_key().
+    pub recovery: bool
+}
+
+impl KeySlot {
+    // Fresh random salt, but no wrapped key yet; use create() to make a slot that can be
+    // written out.
+    pub fn with_algorithm(name:&str, algorithm:KdfAlgorithm) -> Self {
+        KeySlot {
+            name: name.to_owned(),
+            algorithm: algorithm,
+            salt: crypto_util::get_random_bytes(SALT_SIZE),
+            wrapped_key: None,
+            key_file_check: None,
+            host_key: None,
+            signing_key: None,
+            recovery: false
+        }
+    }
+
+    // Make a slot that stores master_key, wrapped with the key derived from password and, if
+    // given, the contents of a key file.
+    pub fn create(name:&str, algorithm:KdfAlgorithm, password:&str, key_file:Option<&[u8]>, master_key:&[u8;KEY_SIZE]) -> Self {
+        let mut slot = KeySlot::with_algorithm(name, algorithm);
+        let check = key_file.map(|kf| slot.check_key_file(kf));
+        slot.key_file_check = check;
+        let kek = slot.key_encryption_key(password, key_file);
+        slot.wrapped_key = Some(crypto_util::wrap_key(&kek, &slot.wrap_ad(), master_key));
+        slot
+    }
+
+    // Make a slot unlocked by recovery_key, which should come from new_recovery_key().  Recovery
+    // keys never need a key file.
+    pub fn create_recovery(name:&str, recovery_key:&str, master_key:&[u8;KEY_SIZE]) -> Self {
+        let mut slot = KeySlot::with_algorithm(name, KdfAlgorithm::Hkdf);
+        slot.recovery = true;
+        let kek = slot.key_encryption_key(recovery_key, None);
+        slot.wrapped_key = Some(crypto_util::wrap_key(&kek, &slot.wrap_ad(), master_key));
+        slot
+    }
+
+    // Make a slot that stores wrapped_key, wrapped with a key derived from another key rather
+    // than a password; keyword manifests use this to store the keyword key under the master key.
+    pub fn create_with_key(name:&str, key:&[u8;KEY_SIZE], wrapped_key:&[u8;KEY_SIZE]) -> Self {
+        let mut slot = KeySlot::with_algorithm(name, KdfAlgorithm::Hkdf);
+        let kek = slot.derive_from_bytes(key);
+        slot.wrapped_key = Some(crypto_util::wrap_key(&kek, &slot.wrap_ad(), wrapped_key));
+        slot
+    }
+
+    // Make a slot that stores master_key for the host with public key host_key.  A new ephemeral
+    // key pair is made for each slot; its public key is the salt, and its secret key is
+    // discarded, so only the host can derive the key encryption key.  signing_key, if given,
+    // is pinned as the key that the host's syncfiles must be signed with.
+    pub fn create_for_host(name:&str, host_key:&[u8;KEY_SIZE], signing_key:Option<&[u8]>, master_key:&[u8;KEY_SIZE]) -> Self {
+        let ephemeral = crypto_util::new_random_key();
+        let mut slot = KeySlot::with_algorithm(name, KdfAlgorithm::X25519);
+        slot.salt = crypto_util::x25519_public_key(&ephemeral).to_vec();
+        slot.host_key = Some(host_key.to_vec());
+        slot.signing_key = signing_key.map(|k| k.to_vec());
+        let shared = crypto_util::x25519_shared_secret(&ephemeral, host_key).unwrap();
+        let kek = slot.derive_from_bytes(&shared[..]);
+        slot.wrapped_key = Some(crypto_util::wrap_key(&kek, &slot.wrap_ad(), master_key));
+        slot
+    }
+
+    pub fn is_host(&self) -> bool {
+        self.algorithm == KdfAlgorithm::X25519
+    }
+
+    // Returns None unless this is the host key slot for the host with secret key host_secret.
+    pub fn unlock_with_host_key(&self, host_secret:&[u8;KEY_SIZE]) -> Option<SecretKey> {
+        let public = crypto_util::x25519_public_key(host_secret);
+        if !self.is_host() || self.host_key.as_ref().map(|k| &k[..]) != Some(&public[..]) {
+            return None;
+        }
+        let shared = match crypto_util::x25519_shared_secret(host_secret, &self.salt) {
+            None => return None,
+            Some(s) => s
+        };
+        let kek = self.derive_from_bytes(&shared[..]);
+        match self.wrapped_key {
+            None => None,
+            Some(ref wrapped) => crypto_util::unwrap_key(&kek, &self.wrap_ad(), wrapped)
+        }
+    }
+
+    pub fn uses_key_file(&self) -> bool {
+        self.key_file_check.is_some()
+    }
+
+    fn check_key_file(&self, key_file:&[u8]) -> Vec<u8> {
+        let mut mac = crypto_util::get_hmac(&self.salt, b"greycrypt key file check");
+        mac.input(key_file);
+        let mut check = crypto_util::hmac_to_vec(&mut mac);
+        check.truncate(KEY_FILE_CHECK_SIZE);
+        check
+    }
+
+    pub fn key_file_matches(&self, key_file:&[u8]) -> bool {
+        match self.key_file_check {
+            None => false,
+            Some(ref check) => *check == self.check_key_file(key_file)
+        }
+    }
+
+    // The password-derived key, with the key file mixed in if the slot uses one.  The key file
+    // is ignored for slots that don't.
+    fn key_encryption_key(&self, password:&str, key_file:Option<&[u8]>) -> SecretKey {
+        let dk = self.derive_key(password);
+        match (key_file, self.uses_key_file()) {
+            (Some(kf), true) => {
+                let mut mac = crypto_util::get_hmac(&dk[..], b"greycrypt key file");
+                mac.input(kf);
+                let mut mixed = crypto_util::hmac_to_vec(&mut mac);
+                let kek = SecretKey::from_slice(&mixed).unwrap();
+                crypto_util::zero_bytes(&mut mixed);
+                kek
+            },
+            _ => dk
+        }
+    }
+
+    pub fn is_recovery(&self) -> bool {
+        self.recovery
+    }
+
+    // True for slots made by create_with_key(), such as the data key slots and the master slot
+    // of a keyword manifest, which no password can unlock.
+    pub fn is_wrapped_by_key(&self) -> bool {
+        self.algorithm == KdfAlgorithm::Hkdf && !self.recovery
+    }
+
+    // True for DATA_SLOT_NAME and RETIRED_DATA_SLOT_NAME, which hold a data key rather than the
+    // master key.
+    pub fn is_data_key(&self) -> bool {
+        self.name == DATA_SLOT_NAME || self.name == RETIRED_DATA_SLOT_NAME
+    }
+
+    // The password-derived key.  This is only the master key for slots without a wrapped key;
+    // otherwise use unlock().
+    pub fn derive_key(&self, password:&str) -> SecretKey {
+        if self.is_recovery() {
+            let mut bytes = parse_recovery_key(password).unwrap_or(password.as_bytes().to_vec());
+            let ek = self.derive_from_bytes(&bytes);
+            crypto_util::zero_bytes(&mut bytes);
+            ek
+        } else {
+            self.derive_from_bytes(password.as_bytes())
+        }
+    }
+
+    fn derive_from_bytes(&self, secret:&[u8]) -> SecretKey {
+        let mut ek = SecretKey::zeroed();
+        self.algorithm.derive(secret, &self.salt, &mut ek[..]);
+        ek
+    }
+
+    // The wrapped key is bound to the kdf parameters and salt, so that it can't be moved into
+    // a slot with weaker ones.
+    fn wrap_ad(&self) -> Vec<u8> {
+        format!("greycrypt master key:{}:{}", self.algorithm.spec(), self.salt.to_base64(STANDARD)).into_bytes()
+    }
+
+    // Returns None if the password (or key file) is not the one for this slot.
+    pub fn unlock(&self, password:&str, key_file:Option<&[u8]>) -> Option<SecretKey> {
+        if self.uses_key_file() && key_file.is_none() {
+            return None;
+        }
+        let kek = self.key_encryption_key(password, key_file);
+        match self.wrapped_key {
+            None => Some(kek),
+            Some(ref wrapped) => crypto_util::unwrap_key(&kek, &self.wrap_ad(), wrapped)
+        }
+    }
+
+    // Returns None unless this is a slot made by create_with_key() for key.
+    pub fn unlock_with_key(&self, key:&[u8;KEY_SIZE]) -> Option<SecretKey> {
+        if !self.is_wrapped_by_key() {
+            return None;
+        }
+        match self.wrapped_key {
+            None => None,
+            Some(ref wrapped) => crypto_util::unwrap_key(&self.derive_from_bytes(key), &self.wrap_ad(), wrapped)
+        }
+    }
+}
+
+#[derive(Debug,Clone,PartialEq)]
+pub struct KdfManifest {
+    pub version: i64,
+    pub sync_ids: SyncIdScheme,
+    // Manifests older than version 4 have exactly one slot, named DEFAULT_SLOT_NAME.
+    pub slots: Vec<KeySlot>,
+    // crypto_util::key_id() of the master key (or, in a keyword manifest, the keyword key), so
+    // that a key that didn't come from a slot, such as one held by the agent, can be checked.
+    // None before version 10, and until a command that has the key rewrites the manifest.
+    pub key_id: Option<String>
+}
+
+pub fn manifest_path(sync_dir:&str) -> PathBuf {
+    let mut pb = PathBuf::from(sync_dir);
+    pb.push(MANIFEST_FILE);
+    pb
+}
+
+pub fn pending_manifest_path(sync_dir:&str) -> PathBuf {
+    let mut pb = PathBuf::from(sync_dir);
+    pb.push(PENDING_MANIFEST_FILE);
+    pb
+}
+
+pub fn keyword_manifest_path(sync_dir:&str, keyword:&str) -> PathBuf {
+    let mut pb = PathBuf::from(sync_dir);
+    pb.push(format!("{}{}{}", KEYWORD_MANIFEST_PREFIX, keyword.to_lowercase(), KEYWORD_MANIFEST_EXT));
+    pb
+}
+
+// The (uppercase) keywords that have their own manifest in the sync dir.
+pub fn find_keyword_manifests(sync_dir:&str) -> Result<Vec<String>,String> {
+    let entries = match read_dir(sync_dir) {
+        Err(e) => return Err(format!("Unable to list sync directory {}: {}", sync_dir, e)),
+        Ok(entries) => entries
+    };
+    let mut keywords = Vec::new();
+    for entry in entries {
+        let entry = match entry {
+            Err(e) => return Err(format!("Unable to list sync directory {}: {}", sync_dir, e)),
+            Ok(entry) => entry
+        };
+        let name = entry.file_name();
+        let name = match name.to_str() {
+            None => continue,
+            Some(n) => n
+        };
+        if name.starts_with(KEYWORD_MANIFEST_PREFIX) && name.ends_with(KEYWORD_MANIFEST_EXT) {
+            let kw = &name[KEYWORD_MANIFEST_PREFIX.len() .. name.len() - KEYWORD_MANIFEST_EXT.len()];
+            if valid_keyword(kw) {
+                keywords.push(kw.to_uppercase());
+            }
+        }
+    }
+    keywords.sort();
+    Ok(keywords)
+}
+
+fn get_toml_int(table:&toml::Table, key:&str, path:&PathBuf) -> Result<i64,String> {
+    match table.get(key).and_then(|v| v.as_integer()) {
+        None => Err(format!("KDF manifest {:?} is missing integer value '{}'", path, key)),
+        Some(i) => Ok(i)
+    }
+}
+
+fn get_toml_str(table:&toml::Table, key:&str, path:&PathBuf) -> Result<String,String> {
+    match table.get(key).and_then(|v| v.as_str()) {
+        None => Err(format!("KDF manifest {:?} is missing string value '{}'", path, key)),
+        Some(s) => Ok(s.to_owned())
+    }
+}
+
+// Before version 9 recovery slots weren't marked, and had to be told apart from the slots that
+// create_with_key() makes by their names: the data key slots, and the master and rotation slots,
+// which only appear in keyword manifests and pending manifests respectively.
+fn legacy_key_slot(name:&str, path:&PathBuf) -> bool {
+    let file_name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+    name == DATA_SLOT_NAME || name == RETIRED_DATA_SLOT_NAME ||
+        (name == MASTER_SLOT_NAME && file_name.starts_with(KEYWORD_MANIFEST_PREFIX)) ||
+        (name == ROTATION_SLOT_NAME && file_name == PENDING_MANIFEST_FILE)
+}
+
+pub fn valid_slot_name(name:&str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+// Keywords become part of a file name, so they are held to the same rules as slot names.
+pub fn valid_keyword(keyword:&str) -> bool {
+    valid_slot_name(keyword)
+}
+
+impl KdfManifest {
+    // Make a manifest for a new sync dir, with a single slot that stores master_key, wrapped with
+    // the key derived from password.
+    pub fn create(algorithm:KdfAlgorithm, password:&str, key_file:Option<&[u8]>, master_key:&[u8;KEY_SIZE]) -> Self {
+        KdfManifest {
+            version: MANIFEST_VERSION,
+            sync_ids: SyncIdScheme::Hmac,
+            slots: vec![KeySlot::create(DEFAULT_SLOT_NAME, algorithm, password, key_file, master_key)],
+            key_id: Some(crypto_util::key_id(master_key))
+        }
+    }
+
+    // Make a manifest for a keyword's own key, unlocked by the master key.  Add password slots
+    // to it for hosts that should only have this keyword.
+    pub fn create_for_keyword(master_key:&[u8;KEY_SIZE], keyword_key:&[u8;KEY_SIZE]) -> Self {
+        KdfManifest {
+            version: MANIFEST_VERSION,
+            sync_ids: SyncIdScheme::Hmac,
+            slots: vec![KeySlot::create_with_key(MASTER_SLOT_NAME, master_key, keyword_key)],
+            key_id: Some(crypto_util::key_id(keyword_key))
+        }
+    }
+
+    // Get a keyword key with the master key; see create_for_keyword().
+    pub fn unlock_with_key(&self, master_key:&[u8;KEY_SIZE]) -> Result<SecretKey,String> {
+        let unlocked = self.find_slot(MASTER_SLOT_NAME).and_then(|i| self.slots[i].unlock_with_key(master_key));
+        match unlocked {
+            None => Err(format!("Unable to unlock the keyword key with the master key")),
+            Some(key) => Ok(key)
+        }
+    }
+
+    // Same slots (so the same key), but with keyed sync ids.
+    pub fn with_keyed_sync_ids(&self) -> Self {
+        KdfManifest {
+            version: if self.version < 2 { 2 } else { self.version },
+            sync_ids: SyncIdScheme::Hmac,
+            .. self.clone()
+        }
+    }
+
+    // The hardcoded parameters that were used before the manifest existed.  Only useful for
+    // upgrading old sync dirs; never write this out as a manifest.
+    pub fn legacy() -> Self {
+        KdfManifest {
+            version: 0,
+            sync_ids: SyncIdScheme::Sha256,
+            slots: vec![KeySlot {
+                name: DEFAULT_SLOT_NAME.to_owned(),
+                algorithm: KdfAlgorithm::BcryptPbkdf { rounds: LEGACY_BCRYPT_ROUNDS },
+                salt: LEGACY_SALT.to_vec(),
+                wrapped_key: None,
+                key_file_check: None,
+                host_key: None,
+                signing_key: None,
+                recovery: false
+            }],
+            key_id: None
+        }
+    }
+
+    // False for manifests from before version 3, whose only slot derives the master key
+    // directly from the password.  Those must be upgraded before slots can be added.
+    pub fn has_wrapped_key(&self) -> bool {
+        self.slots.iter().all(|s| s.wrapped_key.is_some())
+    }
+
+    // Find the slot that password unlocks, and the master key.  Passwords that look like
+    // recovery keys are only tried on recovery slots, and others only on passphrase slots, so
+    // that each attempt costs one expensive derivation per passphrase slot at most.  Slots
+    // that need a key file are skipped if key_file is missing or doesn't match, and the error
+    // says so.
+    pub fn unlock_slot(&self, password:&str, key_file:Option<&[u8]>) -> Result<(usize, SecretKey),String> {
+        let is_recovery_key = parse_recovery_key(password).is_some();
+        let mut missing_key_file = false;
+        let mut changed_key_file = false;
+        for (i, slot) in self.slots.iter().enumerate() {
+            if slot.is_host() || slot.is_wrapped_by_key() || slot.is_recovery() != is_recovery_key {
+                continue;
+            }
+            if slot.uses_key_file() {
+                match key_file {
+                    None => {
+                        missing_key_file = true;
+                        continue;
+                    },
+                    Some(kf) => if !slot.key_file_matches(kf) {
+                        changed_key_file = true;
+                        continue;
+                    }
+                }
+            }
+            match slot.unlock(password, key_file) {
+                None => (),
+                Some(key) => return Ok((i, key))
+            }
+        }
+        if changed_key_file {
+            Err(format!("The key file does not match the one used to set the password; it may have been changed or replaced"))
+        } else if missing_key_file {
+            Err(format!("This sync directory needs a key file along with the password; set KeyFile in the [General] section of the config file"))
+        } else {
+            Err(format!("Unable to unlock the master key in the KDF manifest; likely incorrect password"))
+        }
+    }
+
+    // Get the key that encrypts the syncfiles.
+    pub fn unlock(&self, password:&str, key_file:Option<&[u8]>) -> Result<SecretKey,String> {
+        self.unlock_slot(password, key_file).map(|(_, key)| key)
+    }
+
+    // Record key as the key that the slots hold; see key_id.
+    pub fn set_key_id(&mut self, key:&[u8;KEY_SIZE]) {
+        self.key_id = Some(crypto_util::key_id(key));
+        self.version = MANIFEST_VERSION;
+    }
+
+    // Whether key is the one that the slots hold, or None if the manifest doesn't say.
+    pub fn key_matches(&self, key:&[u8;KEY_SIZE]) -> Option<bool> {
+        self.key_id.as_ref().map(|id| *id == crypto_util::key_id(key))
+    }
+
+    pub fn find_slot(&self, name:&str) -> Option<usize> {
+        self.slots.iter().position(|s| s.name == name)
+    }
+
+    // Find the host key slot for the host with secret key host_secret, and the master key.
+    pub fn unlock_with_host_key(&self, host_secret:&[u8;KEY_SIZE]) -> Option<(usize, SecretKey)> {
+        self.slots.iter().enumerate()
+            .filter_map(|(i, slot)| slot.unlock_with_host_key(host_secret).map(|key| (i, key)))
+            .nth(0)
+    }
+
+    pub fn add_slot(&mut self, slot:KeySlot) -> Result<(),String> {
+        if !self.has_wrapped_key() || slot.wrapped_key.is_none() {
+            return Err(format!("Key slots can only be added to sync directories with a wrapped master key; run greycrypt with --upgrade first"));
+        }
+        if !valid_slot_name(&slot.name) {
+            return Err(format!("Invalid key slot name '{}'; use letters, digits, '-', '_' and '.'", slot.name));
+        }
+        if slot.is_data_key() {
+            return Err(format!("Key slot name '{}' is reserved", slot.name));
+        }
+        if self.find_slot(&slot.name).is_some() {
+            return Err(format!("Key slot '{}' already exists", slot.name));
+        }
+        self.version = MANIFEST_VERSION;
+        self.slots.push(slot);
+        Ok(())
+    }
+
+    pub fn remove_slot(&mut self, name:&str) -> Result<KeySlot,String> {
+        let idx = match self.find_slot(name) {
+            None => return Err(format!("No key slot named '{}'", name)),
+            Some(i) => i
+        };
+        if self.slots[idx].is_data_key() {
+            return Err(format!("Key slot '{}' holds the data key; use rotate-key to replace it", name));
+        }
+        if self.slots.iter().filter(|s| !s.is_data_key()).count() == 1 {
+            return Err(format!("Can't remove '{}'; it is the only key slot", name));
+        }
+        self.version = MANIFEST_VERSION;
+        Ok(self.slots.remove(idx))
+    }
+
+    // Swap the slot at idx for a new one; used to change the password.
+    pub fn replace_slot(&mut self, idx:usize, slot:KeySlot) {
+        if slot.wrapped_key.is_some() && self.version < MANIFEST_VERSION {
+            self.version = MANIFEST_VERSION;
+        }
+        self.slots[idx] = slot;
+    }
+
+    // The data key and the retired data key, unlocked with the master key; see DATA_SLOT_NAME.
+    // None if there is no such slot.
+    pub fn unlock_data_keys(&self, master_key:&[u8;KEY_SIZE]) -> Result<(Option<SecretKey>, Option<SecretKey>),String> {
+        let unlock = |name:&str| {
+            match self.find_slot(name) {
+                None => Ok(None),
+                Some(i) => match self.slots[i].unlock_with_key(master_key) {
+                    None => Err(format!("Unable to unlock key slot '{}' with the master key", name)),
+                    Some(k) => Ok(Some(k))
+                }
+            }
+        };
+        let data_key = try!(unlock(DATA_SLOT_NAME));
+        let retired = try!(unlock(RETIRED_DATA_SLOT_NAME));
+        if data_key.is_none() && retired.is_some() {
+            return Err(format!("KDF manifest has a retired data key, but no data key"));
+        }
+        Ok((data_key, retired))
+    }
+
+    // Replace the data key slots with ones for data_key and retired, wrapped with the master key.
+    pub fn set_data_keys(&mut self, master_key:&[u8;KEY_SIZE], data_key:&[u8;KEY_SIZE], retired:Option<&[u8;KEY_SIZE]>) {
+        self.slots.retain(|s| !s.is_data_key());
+        self.slots.push(KeySlot::create_with_key(DATA_SLOT_NAME, master_key, data_key));
+        if let Some(r) = retired {
+            self.slots.push(KeySlot::create_with_key(RETIRED_DATA_SLOT_NAME, master_key, r));
+        }
+        self.version = MANIFEST_VERSION;
+    }
+
+    pub fn load(sync_dir:&str) -> Result<Self,String> {
+        KdfManifest::load_from(&manifest_path(sync_dir))
+    }
+
+    fn parse_slot(table:&toml::Table, version:i64, path:&PathBuf) -> Result<KeySlot,String> {
+        let name = if version < 4 {
+            DEFAULT_SLOT_NAME.to_owned()
+        } else {
+            try!(get_toml_str(table, "name", path))
+        };
+
+        let kdf_name = try!(get_toml_str(table, "kdf", path));
+        let algorithm = match &kdf_name[..] {
+            "bcrypt_pbkdf" => {
+                let rounds = try!(get_toml_int(table, "rounds", path));
+                if rounds < 1 || rounds > (u32::max_value() as i64) {
+                    return Err(format!("Invalid bcrypt_pbkdf rounds in KDF manifest: {}", rounds));
+                }
+                KdfAlgorithm::BcryptPbkdf { rounds: rounds as u32 }
+            },
+            "scrypt" => {
+                let log_n = try!(get_toml_int(table, "log_n", path));
+                let r = try!(get_toml_int(table, "r", path));
+                let p = try!(get_toml_int(table, "p", path));
+                if log_n < 1 || log_n > 30 || r < 1 || r > (u32::max_value() as i64) || p < 1 || p > (u32::max_value() as i64) {
+                    return Err(format!("Invalid scrypt parameters in KDF manifest: log_n {}, r {}, p {}", log_n, r, p));
+                }
+                KdfAlgorithm::Scrypt { log_n: log_n as u8, r: r as u32, p: p as u32 }
+            },
+            "hkdf" if version >= 4 => KdfAlgorithm::Hkdf,
+            "x25519" if version >= 6 => KdfAlgorithm::X25519,
+            other => return Err(format!("Unknown kdf '{}' in KDF manifest {:?}", other, path))
+        };
+        match algorithm.validate() {
+            Err(e) => return Err(format!("Invalid KDF manifest {:?}: {}", path, e)),
+            Ok(_) => ()
+        }
+
+        let salt = match try!(get_toml_str(table, "salt", path)).from_base64() {
+            Err(e) => return Err(format!("Failed to decode salt in KDF manifest: {:?}", e)),
+            Ok(s) => s
+        };
+        if salt.len() < MIN_SALT_SIZE {
+            return Err(format!("Salt in KDF manifest is too short: {} bytes", salt.len()));
+        }
+
+        let wrapped_key = if version < 3 {
+            None
+        } else {
+            match try!(get_toml_str(table, "wrapped_key", path)).from_base64() {
+                Err(e) => return Err(format!("Failed to decode wrapped key in KDF manifest: {:?}", e)),
+                Ok(k) => Some(k)
+            }
+        };
+
+        let key_file_check = match table.get("key_file_check") {
+            Some(_) if version >= 5 => {
+                match try!(get_toml_str(table, "key_file_check", path)).from_hex() {
+                    Err(e) => return Err(format!("Failed to decode key file check in KDF manifest: {:?}", e)),
+                    Ok(c) => Some(c)
+                }
+            },
+            _ => None
+        };
+
+        let host_key = if algorithm == KdfAlgorithm::X25519 {
+            match try!(get_toml_str(table, "host_key", path)).from_base64() {
+                Err(e) => return Err(format!("Failed to decode host key in KDF manifest: {:?}", e)),
+                Ok(k) => {
+                    if k.len() != KEY_SIZE {
+                        return Err(format!("Host key in KDF manifest has the wrong size: {} bytes", k.len()));
+                    }
+                    Some(k)
+                }
+            }
+        } else {
+            None
+        };
+
+        let signing_key = match table.get("signing_key") {
+            Some(_) if version >= 7 && algorithm == KdfAlgorithm::X25519 => {
+                match try!(get_toml_str(table, "signing_key", path)).from_base64() {
+                    Err(e) => return Err(format!("Failed to decode signing key in KDF manifest: {:?}", e)),
+                    Ok(k) => {
+                        if k.len() != KEY_SIZE {
+                            return Err(format!("Signing key in KDF manifest has the wrong size: {} bytes", k.len()));
+                        }
+                        Some(k)
+                    }
+                }
+            },
+            _ => None
+        };
+
+        let recovery = if version >= 9 {
+            match table.get("recovery") {
+                None => false,
+                Some(v) => match v.as_bool() {
+                    None => return Err(format!("Invalid recovery flag in KDF manifest {:?}", path)),
+                    Some(b) => b
+                }
+            }
+        } else {
+            algorithm == KdfAlgorithm::Hkdf && !legacy_key_slot(&name, path)
+        };
+        if recovery && algorithm != KdfAlgorithm::Hkdf {
+            return Err(format!("Key slot '{}' in KDF manifest {:?} is marked as a recovery slot, but doesn't use hkdf", name, path));
+        }
+
+        Ok(KeySlot {
+            name: name,
+            algorithm: algorithm,
+            salt: salt,
+            wrapped_key: wrapped_key,
+            key_file_check: key_file_check,
+            host_key: host_key,
+            signing_key: signing_key,
+            recovery: recovery
+        })
+    }
+
+    pub fn load_from(path:&PathBuf) -> Result<Self,String> {
+        if !path.is_file() {
+            return Err(format!("KDF manifest not found: {:?}", path));
+        }
+
+        let mut text = String::new();
+        match File::open(path).and_then(|mut f| f.read_to_string(&mut text)) {
+            Err(e) => return Err(format!("Can't read KDF manifest: {:?}: {}", path, e)),
+            Ok(_) => ()
+        }
+
+        let table = match toml::Parser::new(&text).parse() {
+            None => return Err(format!("KDF manifest is not valid toml: {:?}", path)),
+            Some(t) => t
+        };
+
+        let version = try!(get_toml_int(&table, "version", path));
+        if version < 1 || version > MANIFEST_VERSION {
+            return Err(format!("Unsupported KDF manifest version {} in {:?}; a newer version of greycrypt may be required", version, path));
+        }
+
+        let sync_ids = if version < 2 {
+            SyncIdScheme::Sha256
+        } else {
+            match &try!(get_toml_str(&table, "sync_ids", path))[..] {
+                "sha256" => SyncIdScheme::Sha256,
+                "hmac" => SyncIdScheme::Hmac,
+                other => return Err(format!("Unknown sync id scheme '{}' in KDF manifest {:?}", other, path))
+            }
+        };
+
+        let key_id = if version >= 10 && table.contains_key("key_id") {
+            Some(try!(get_toml_str(&table, "key_id", path)))
+        } else {
+            None
+        };
+
+        let mut slots:Vec<KeySlot> = Vec::new();
+        if version < 4 {
+            // the slot's values are at the top level
+            slots.push(try!(KdfManifest::parse_slot(&table, version, path)));
+        } else {
+            let slot_tables = match table.get("slot").and_then(|v| v.as_slice()) {
+                None => return Err(format!("KDF manifest {:?} has no key slots", path)),
+                Some(st) => st
+            };
+            for st in slot_tables {
+                let st = match st.as_table() {
+                    None => return Err(format!("Invalid key slot in KDF manifest {:?}", path)),
+                    Some(st) => st
+                };
+                let slot = try!(KdfManifest::parse_slot(st, version, path));
+                if slots.iter().any(|s| s.name == slot.name) {
+                    return Err(format!("Duplicate key slot '{}' in KDF manifest {:?}", slot.name, path));
+                }
+                slots.push(slot);
+            }
+            if slots.is_empty() {
+                return Err(format!("KDF manifest {:?} has no key slots", path));
+            }
+        }
+
+        Ok(KdfManifest {
+            version: version,
+            sync_ids: sync_ids,
+            slots: slots,
+            key_id: key_id
+        })
+    }
+
+    // Write the manifest into a sync dir, creating the directory if needed.  Refuses to
+    // overwrite an existing manifest, since that would orphan every syncfile in the dir.
+    pub fn save(&self, sync_dir:&str) -> Result<(),String> {
+        let path = manifest_path(sync_dir);
+        if path.is_file() {
+            return Err(format!("KDF manifest already exists: {:?}", path));
+        }
+        let sd = PathBuf::from(sync_dir);
+        if !sd.is_dir() {
+            match create_dir_all(&sd) {
+                Err(e) => return Err(format!("Failed to create sync directory: {:?}: {}", sd, e)),
+                Ok(_) => ()
+            }
+        }
+        self.write_to(&path)
+    }
+
+    fn write_slot_lines(&self, slot:&KeySlot, out:&mut Write) -> io::Result<()> {
+        if self.version >= 4 {
+            try!(writeln!(out, "name = \"{}\"", slot.name));
+        }
+        match slot.algorithm {
+            KdfAlgorithm::BcryptPbkdf { rounds } => {
+                try!(writeln!(out, "kdf = \"bcrypt_pbkdf\""));
+                try!(writeln!(out, "rounds = {}", rounds));
+            },
+            KdfAlgorithm::Scrypt { log_n, r, p } => {
+                try!(writeln!(out, "kdf = \"scrypt\""));
+                try!(writeln!(out, "log_n = {}", log_n));
+                try!(writeln!(out, "r = {}", r));
+                try!(writeln!(out, "p = {}", p));
+            },
+            KdfAlgorithm::Hkdf => {
+                try!(writeln!(out, "kdf = \"hkdf\""));
+            },
+            KdfAlgorithm::X25519 => {
+                try!(writeln!(out, "kdf = \"x25519\""));
+            }
+        }
+        try!(writeln!(out, "salt = \"{}\"", slot.salt.to_base64(STANDARD)));
+        if let Some(ref wrapped) = slot.wrapped_key {
+            try!(writeln!(out, "wrapped_key = \"{}\"", wrapped.to_base64(STANDARD)));
+        }
+        if let Some(ref check) = slot.key_file_check {
+            try!(writeln!(out, "key_file_check = \"{}\"", check.to_hex()));
+        }
+        if let Some(ref host_key) = slot.host_key {
+            try!(writeln!(out, "host_key = \"{}\"", host_key.to_base64(STANDARD)));
+        }
+        if let Some(ref signing_key) = slot.signing_key {
+            try!(writeln!(out, "signing_key = \"{}\"", signing_key.to_base64(STANDARD)));
+        }
+        if self.version >= 9 && slot.recovery {
+            try!(writeln!(out, "recovery = true"));
+        }
+        Ok(())
+    }
+
+    fn write_lines(&self, out:&mut Write) -> io::Result<()> {
+        try!(writeln!(out, "# GreyCrypt sync directory manifest; all hosts read this to derive the encryption key."));
+        try!(writeln!(out, "# Do not edit or remove it."));
+        try!(writeln!(out, "version = {}", self.version));
+        if self.version >= 2 {
+            let sync_ids = match self.sync_ids {
+                SyncIdScheme::Sha256 => "sha256",
+                SyncIdScheme::Hmac => "hmac"
+            };
+            try!(writeln!(out, "sync_ids = \"{}\"", sync_ids));
+        }
+        if self.version >= 10 {
+            if let Some(ref key_id) = self.key_id {
+                try!(writeln!(out, "key_id = \"{}\"", key_id));
+            }
+        }
+        if self.version < 4 {
+            try!(self.write_slot_lines(&self.slots[0], out));
+        } else {
+            for slot in &self.slots {
+                try!(writeln!(out, ""));
+                try!(writeln!(out, "[[slot]]"));
+                try!(self.write_slot_lines(slot, out));
+            }
+        }
+        Ok(())
+    }
+
+    // Write to a temporary file next to the target and rename it over, so that a crash never
+    // leaves a partially written manifest.
+    pub fn write_to(&self, path:&PathBuf) -> Result<(),String> {
+        if self.slots.is_empty() || (self.version < 4 && self.slots.len() > 1) {
+            return Err(format!("KDF manifest version {} can't hold {} key slots: {:?}", self.version, self.slots.len(), path));
+        }
+        if self.version >= 3 && !self.has_wrapped_key() {
+            return Err(format!("KDF manifest has no wrapped key: {:?}", path));
+        }
+        if self.version < 5 && self.slots.iter().any(|s| s.uses_key_file()) {
+            return Err(format!("KDF manifest version {} can't hold key file checks: {:?}", self.version, path));
+        }
+        if self.version < 6 && self.slots.iter().any(|s| s.is_host()) {
+            return Err(format!("KDF manifest version {} can't hold host key slots: {:?}", self.version, path));
+        }
+        if self.version < 7 && self.slots.iter().any(|s| s.signing_key.is_some()) {
+            return Err(format!("KDF manifest version {} can't hold signing keys: {:?}", self.version, path));
+        }
+        if self.version < 8 && self.slots.iter().any(|s| s.is_data_key()) {
+            return Err(format!("KDF manifest version {} can't hold data keys: {:?}", self.version, path));
+        }
+
+        let tmp_path = format!("{}.gc_tmp", path.to_str().unwrap());
+        {
+            let mut f = match File::create(&tmp_path) {
+                Err(e) => return Err(format!("Failed to create KDF manifest: {}: {}", tmp_path, e)),
+                Ok(f) => f
+            };
+            match self.write_lines(&mut f) {
+                Err(e) => return Err(format!("Failed to write KDF manifest: {}: {}", tmp_path, e)),
+                Ok(_) => ()
+            }
+            // the rename below is only atomic if the data reaches the disk first; otherwise a
+            // crash can leave an empty manifest, and no way to unlock the directory
+            match f.sync_all() {
+                Err(e) => return Err(format!("Failed to flush KDF manifest: {}: {}", tmp_path, e)),
+                Ok(_) => ()
+            }
+        }
+
+        match rename(&tmp_path, path) {
+            Err(e) => Err(format!("Failed to move KDF manifest into place: {:?}: {}", path, e)),
+            Ok(_) => Ok(())
+        }
+    }
+}
+
+// Find the most expensive scrypt parameters whose derivation takes no longer than target_ms
+// on this host.  Memory cost (log_n) is raised until the target is exceeded; r and p are left at
+// their defaults.  Returns the parameters along with the time they took.
+pub fn benchmark_scrypt(target_ms:u64) -> (KdfAlgorithm, u64) {
+    let salt = crypto_util::get_random_bytes(SALT_SIZE);
+    let mut out: [u8;KEY_SIZE] = [0; KEY_SIZE];
+
+    let mut best = None;
+    for log_n in MIN_BENCH_LOG_N .. MAX_BENCH_LOG_N + 1 {
+        let alg = KdfAlgorithm::Scrypt { log_n: log_n, r: DEFAULT_SCRYPT_R, p: DEFAULT_SCRYPT_P };
+
+        let start = time::precise_time_ns();
+        alg.derive(b"benchmark password", &salt, &mut out);
+        let elapsed_ms = (time::precise_time_ns() - start) / 1000000;
+        info!("{}: {} ms", alg.spec(), elapsed_ms);
+
+        if elapsed_ms > target_ms && best.is_some() {
+            break;
+        }
+        best = Some((alg, elapsed_ms));
+        if elapsed_ms > target_ms {
+            // even the cheapest setting is too slow; use it anyway
+            break;
+        }
+    }
+
+    best.unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{remove_dir_all};
+    use std::fs::{PathExt};
+    use std::path::{PathBuf};
+
+    use config::KEY_SIZE;
+    use crypto_util;
+    use kdf;
+
+    fn out_dir(name:&str) -> String {
+        let wd = env::current_dir().unwrap();
+        let mut pb = PathBuf::from(&wd);
+        pb.push("testdata");
+        pb.push(format!("out_kdf_{}", name));
+        if pb.is_dir() {
+            remove_dir_all(&pb).unwrap();
+        }
+        pb.to_str().unwrap().to_owned()
+    }
+
+    // keep the tests fast
+    fn test_alg() -> kdf::KdfAlgorithm {
+        kdf::KdfAlgorithm::Scrypt { log_n: 10, r: 8, p: 1 }
+    }
+
+    #[test]
+    fn save_load() {
+        let dir = out_dir("save_load");
+        let manifest = kdf::KdfManifest::create(test_alg(), "swordfish", None, &[1; KEY_SIZE]);
+        assert_eq!(manifest.slots[0].salt.len(), kdf::SALT_SIZE);
+
+        match manifest.save(&dir) {
+            Err(e) => panic!("{}", e),
+            Ok(_) => ()
+        }
+        let loaded = match kdf::KdfManifest::load(&dir) {
+            Err(e) => panic!("{}", e),
+            Ok(m) => m
+        };
+        assert_eq!(manifest, loaded);
+
+        // written once
+        assert!(kdf::KdfManifest::create(test_alg(), "swordfish", None, &[1; KEY_SIZE]).save(&dir).is_err());
+    }
+
+    #[test]
+    fn wrapped_key() {
+        let master = crypto_util::new_random_key();
+        let slot = kdf::KeySlot::create("default", test_alg(), "swordfish", None, &master);
+        assert!(slot.unlock("swordfish", None).unwrap() == master);
+        assert!(slot.derive_key("swordfish") != master);
+        assert!(slot.unlock("swordfish2", None).is_none());
+
+        // the same key wrapped with another password
+        let other = kdf::KeySlot::create("default", test_alg(), "marlin", None, &master);
+        assert!(other.salt != slot.salt);
+        assert!(other.wrapped_key != slot.wrapped_key);
+        assert!(other.unlock("marlin", None).unwrap() == master);
+
+        // changing the salt or kdf breaks the wrapped key even with the right password
+        let moved = kdf::KeySlot { salt: other.salt.clone(), .. slot.clone() };
+        assert!(moved.unlock("swordfish", None).is_none());
+        let weaker = kdf::KeySlot { algorithm: kdf::KdfAlgorithm::BcryptPbkdf { rounds: 1 }, .. slot.clone() };
+        assert!(weaker.unlock("swordfish", None).is_none());
+
+        // a slot without a wrapped key uses the derived key
+        let unwrapped = kdf::KeySlot { wrapped_key: None, .. slot.clone() };
+        assert!(unwrapped.unlock("swordfish", None).unwrap() == slot.derive_key("swordfish"));
+
+        // and can't be written as the current version
+        let dir = out_dir("wrapped_key");
+        let manifest = kdf::KdfManifest { slots: vec![unwrapped], .. kdf::KdfManifest::create(test_alg(), "swordfish", None, &master) };
+        assert!(manifest.save(&dir).is_err());
+    }
+
+    #[test]
+    fn key_slots() {
+        let dir = out_dir("key_slots");
+        let master = crypto_util::new_random_key();
+        let mut manifest = kdf::KdfManifest::create(test_alg(), "swordfish", None, &master);
+        manifest.add_slot(kdf::KeySlot::create("bob", test_alg(), "marlin", None, &master)).unwrap();
+        let recovery_key = kdf::new_recovery_key();
+        manifest.add_slot(kdf::KeySlot::create_recovery("recovery", &recovery_key, &master)).unwrap();
+
+        // names are unique
+        assert!(manifest.add_slot(kdf::KeySlot::create("bob", test_alg(), "tuna", None, &master)).is_err());
+        assert!(manifest.add_slot(kdf::KeySlot::create("bad name", test_alg(), "tuna", None, &master)).is_err());
+
+        manifest.save(&dir).unwrap();
+        let loaded = kdf::KdfManifest::load(&dir).unwrap();
+        assert_eq!(loaded, manifest);
+        assert_eq!(loaded.slots.len(), 3);
+
+        assert!(loaded.unlock_slot("swordfish", None).unwrap() == (0, master.clone()));
+        assert!(loaded.unlock_slot("marlin", None).unwrap() == (1, master.clone()));
+        assert!(loaded.unlock_slot(&recovery_key, None).unwrap() == (2, master.clone()));
+        // recovery keys can be typed without dashes, in any case
+        let typed = recovery_key.replace("-", " ").to_uppercase();
+        assert!(loaded.unlock_slot(&typed, None).unwrap() == (2, master.clone()));
+        assert!(loaded.unlock("tuna", None).is_err());
+        assert!(loaded.unlock(&kdf::new_recovery_key(), None).is_err());
+
+        // removing a slot stops its password from working
+        let mut manifest = loaded;
+        manifest.remove_slot("bob").unwrap();
+        assert!(manifest.unlock("marlin", None).is_err());
+        assert!(manifest.remove_slot("bob").is_err());
+        manifest.remove_slot("recovery").unwrap();
+        // but the last one stays
+        assert!(manifest.remove_slot("default").is_err());
+    }
+
+    #[test]
+    fn data_key_slots() {
+        let dir = out_dir("data_key_slots");
+        let master = crypto_util::new_random_key();
+        let mut manifest = kdf::KdfManifest::create(test_alg(), "swordfish", None, &master);
+        // without a data key slot, the master key is the data key
+        let (data_key, retired) = manifest.unlock_data_keys(&master).unwrap();
+        assert!(data_key.is_none() && retired.is_none());
+
+        let old_key = crypto_util::new_random_key();
+        let new_key = crypto_util::new_random_key();
+        manifest.set_data_keys(&master, &new_key, Some(&*old_key));
+        manifest.save(&dir).unwrap();
+        let loaded = kdf::KdfManifest::load(&dir).unwrap();
+        assert_eq!(loaded, manifest);
+        let (data_key, retired) = loaded.unlock_data_keys(&master).unwrap();
+        assert!(data_key == Some(new_key.clone()) && retired == Some(old_key.clone()));
+        assert!(loaded.unlock_data_keys(&crypto_util::new_random_key()).is_err());
+
+        // they aren't password or recovery slots, and can't be added or removed as such
+        assert!(loaded.slots.iter().all(|s| !s.is_data_key() || !s.is_recovery()));
+        assert!(loaded.unlock_slot("swordfish", None).unwrap() == (0, master.clone()));
+        let mut manifest = loaded;
+        assert!(manifest.remove_slot(kdf::DATA_SLOT_NAME).is_err());
+        assert!(manifest.remove_slot("default").is_err());
+        assert!(manifest.add_slot(kdf::KeySlot::create_recovery(kdf::DATA_SLOT_NAME, &kdf::new_recovery_key(), &master)).is_err());
+
+        // retiring the old key drops its slot
+        manifest.set_data_keys(&master, &new_key, None);
+        assert_eq!(manifest.slots.len(), 2);
+        let (data_key, retired) = manifest.unlock_data_keys(&master).unwrap();
+        assert!(data_key == Some(new_key.clone()) && retired.is_none());
+
+        let old = kdf::KdfManifest { version: 7, .. manifest };
+        assert!(old.write_to(&kdf::manifest_path(&dir)).is_err());
+    }
+
+    #[test]
+    fn host_key_slot() {
+        let dir = out_dir("host_key_slot");
+        let master = crypto_util::new_random_key();
+        let host_secret = crypto_util::new_random_key();
+        let host_public = crypto_util::x25519_public_key(&host_secret);
+        let signing_public = crypto_util::ed25519_public_key(&crypto_util::derive_signing_key(&host_secret));
+        let mut manifest = kdf::KdfManifest::create(test_alg(), "swordfish", None, &master);
+        manifest.add_slot(kdf::KeySlot::create_for_host("laptop", &host_public, Some(&signing_public[..]), &master)).unwrap();
+        assert!(manifest.slots[1].is_host());
+
+        manifest.save(&dir).unwrap();
+        let loaded = kdf::KdfManifest::load(&dir).unwrap();
+        assert_eq!(loaded, manifest);
+        assert!(loaded.unlock_with_host_key(&host_secret).unwrap() == (1, master.clone()));
+        // only that host's key works, and host slots aren't tried with passwords
+        assert!(loaded.unlock_with_host_key(&crypto_util::new_random_key()).is_none());
+        assert!(loaded.unlock_with_host_key(&master).is_none());
+        assert!(loaded.unlock_slot("swordfish", None).unwrap() == (0, master.clone()));
+
+        assert_eq!(loaded.slots[1].signing_key, Some(signing_public.to_vec()));
+
+        // an older manifest can't hold it, or the signing key
+        let old = kdf::KdfManifest { version: 6, .. loaded.clone() };
+        assert!(old.write_to(&kdf::manifest_path(&dir)).is_err());
+        let old = kdf::KdfManifest { version: 5, .. loaded };
+        assert!(old.write_to(&kdf::manifest_path(&dir)).is_err());
+    }
+
+    #[test]
+    fn keyword_manifest() {
+        let dir = out_dir("keyword_manifest");
+        let master = crypto_util::new_random_key();
+        let kw_key = crypto_util::new_random_key();
+        kdf::KdfManifest::create(test_alg(), "swordfish", None, &master).save(&dir).unwrap();
+        assert_eq!(kdf::find_keyword_manifests(&dir).unwrap().len(), 0);
+
+        let mut manifest = kdf::KdfManifest::create_for_keyword(&master, &kw_key);
+        manifest.add_slot(kdf::KeySlot::create("shared", test_alg(), "marlin", None, &kw_key)).unwrap();
+        manifest.write_to(&kdf::keyword_manifest_path(&dir, "Shared")).unwrap();
+        assert_eq!(kdf::find_keyword_manifests(&dir).unwrap(), vec!["SHARED".to_owned()]);
+
+        let loaded = kdf::KdfManifest::load_from(&kdf::keyword_manifest_path(&dir, "SHARED")).unwrap();
+        assert_eq!(loaded, manifest);
+        assert!(loaded.unlock_with_key(&master).unwrap() == kw_key);
+        assert!(loaded.unlock("marlin", None).unwrap() == kw_key);
+        // the master key is not a password, and other keys don't work
+        assert!(loaded.unlock("swordfish", None).is_err());
+        assert!(loaded.unlock_with_key(&kw_key).is_err());
+        assert!(loaded.slots[1].unlock_with_key(&master).is_none());
+
+        // the master slot isn't a recovery slot, also in manifests from before they were marked
+        let recovery_key = kdf::new_recovery_key();
+        let mut old = kdf::KdfManifest { version: 8, .. loaded };
+        old.slots.push(kdf::KeySlot::create_recovery("spare", &recovery_key, &kw_key));
+        old.write_to(&kdf::keyword_manifest_path(&dir, "SHARED")).unwrap();
+        let loaded = kdf::KdfManifest::load_from(&kdf::keyword_manifest_path(&dir, "SHARED")).unwrap();
+        assert_eq!(loaded.slots.iter().map(|s| s.is_recovery()).collect::<Vec<_>>(), vec![false, false, true]);
+        assert!(loaded.slots[0].is_wrapped_by_key());
+        assert!(loaded.unlock(&recovery_key, None).unwrap() == kw_key);
+    }
+
+    #[test]
+    fn manifest_key_id() {
+        let dir = out_dir("manifest_key_id");
+        let master = crypto_util::new_random_key();
+        let manifest = kdf::KdfManifest::create(test_alg(), "swordfish", None, &master);
+        manifest.save(&dir).unwrap();
+        let loaded = kdf::KdfManifest::load(&dir).unwrap();
+        assert_eq!(loaded, manifest);
+        assert_eq!(loaded.key_matches(&master), Some(true));
+        assert_eq!(loaded.key_matches(&crypto_util::new_random_key()), Some(false));
+
+        // older manifests don't record it, until a command that has the key adds it
+        let old = kdf::KdfManifest { version: 9, .. loaded };
+        old.write_to(&kdf::manifest_path(&dir)).unwrap();
+        let mut loaded = kdf::KdfManifest::load(&dir).unwrap();
+        assert_eq!(loaded.key_matches(&master), None);
+        loaded.set_key_id(&master);
+        assert_eq!(loaded.version, kdf::MANIFEST_VERSION);
+        assert_eq!(loaded.key_matches(&master), Some(true));
+    }
+
+    #[test]
+    fn key_file() {
+        let dir = out_dir("key_file");
+        let master = crypto_util::new_random_key();
+        let key_file = crypto_util::get_random_bytes(64);
+        let mut manifest = kdf::KdfManifest::create(test_alg(), "swordfish", Some(&key_file[..]), &master);
+        manifest.add_slot(kdf::KeySlot::create("nokeyfile", test_alg(), "marlin", None, &master)).unwrap();
+        manifest.save(&dir).unwrap();
+        let manifest = kdf::KdfManifest::load(&dir).unwrap();
+        assert!(manifest.slots[0].uses_key_file());
+        assert!(!manifest.slots[1].uses_key_file());
+
+        assert!(manifest.unlock("swordfish", Some(&key_file[..])).unwrap() == master);
+        // the password alone isn't enough
+        assert!(manifest.slots[0].unlock("swordfish", None).is_none());
+        let e = manifest.unlock("swordfish", None).err().unwrap();
+        assert!(e.contains("needs a key file"), "{}", e);
+        let mut changed = key_file.clone();
+        changed[0] ^= 1;
+        let e = manifest.unlock("swordfish", Some(&changed[..])).err().unwrap();
+        assert!(e.contains("does not match"), "{}", e);
+        // the key file alone isn't either
+        let e = manifest.unlock("tuna", Some(&key_file[..])).err().unwrap();
+        assert!(e.contains("incorrect password"), "{}", e);
+
+        // slots that don't use a key file ignore it
+        assert!(manifest.unlock("marlin", None).unwrap() == master);
+        assert!(manifest.unlock("marlin", Some(&key_file[..])).unwrap() == master);
+    }
+
+    #[test]
+    fn recovery_key_format() {
+        let key = kdf::new_recovery_key();
+        assert_eq!(kdf::parse_recovery_key(&key).unwrap().len(), kdf::RECOVERY_KEY_SIZE);
+        assert!(key != kdf::new_recovery_key());
+        assert!(kdf::parse_recovery_key("swordfish").is_none());
+        assert!(kdf::parse_recovery_key(&key[1..]).is_none());
+        let not_hex:String = key.chars().map(|c| if c == '-' { c } else { 'z' }).collect();
+        assert!(kdf::parse_recovery_key(&not_hex).is_none());
+    }
+
+    #[test]
+    fn sync_id_scheme() {
+        let dir = out_dir("sync_id_scheme");
+        let slot = kdf::KeySlot::with_algorithm(kdf::DEFAULT_SLOT_NAME, kdf::KdfAlgorithm::BcryptPbkdf { rounds: 4 });
+        let manifest = kdf::KdfManifest { version: 1, sync_ids: kdf::SyncIdScheme::Hmac, slots: vec![slot], key_id: None };
+
+        // a version 1 manifest has no sync id scheme, and uses unkeyed ids
+        manifest.save(&dir).unwrap();
+        let loaded = kdf::KdfManifest::load(&dir).unwrap();
+        assert_eq!(loaded.sync_ids, kdf::SyncIdScheme::Sha256);
+
+        // switching the scheme keeps the key
+        let keyed = loaded.with_keyed_sync_ids();
+        assert!(keyed.unlock("swordfish", None).unwrap() == loaded.unlock("swordfish", None).unwrap());
+        keyed.write_to(&kdf::manifest_path(&dir)).unwrap();
+        let loaded = kdf::KdfManifest::load(&dir).unwrap();
+        assert_eq!(loaded, keyed);
+        assert_eq!(loaded.sync_ids, kdf::SyncIdScheme::Hmac);
+    }
+
+    #[test]
+    fn missing() {
+        let dir = out_dir("missing");
+        assert!(kdf::KdfManifest::load(&dir).is_err());
+    }
+
+    #[test]
+    fn scrypt_save_load() {
+        let dir = out_dir("scrypt_save_load");
+        let alg = kdf::KdfAlgorithm::Scrypt { log_n: 10, r: 8, p: 2 };
+        let manifest = kdf::KdfManifest::create(alg.clone(), "swordfish", None, &[1; KEY_SIZE]);
+        manifest.save(&dir).unwrap();
+
+        let loaded = kdf::KdfManifest::load(&dir).unwrap();
+        assert_eq!(loaded.slots[0].algorithm, alg);
+        assert!(manifest.slots[0].derive_key("swordfish") == loaded.slots[0].derive_key("swordfish"));
+        assert!(*loaded.unlock("swordfish", None).unwrap() == [1; KEY_SIZE]);
+
+        // same salt, different kdf: different key
+        let bcrypt = kdf::KeySlot { algorithm: kdf::KdfAlgorithm::BcryptPbkdf { rounds: 4 }, .. manifest.slots[0].clone() };
+        assert!(manifest.slots[0].derive_key("swordfish") != bcrypt.derive_key("swordfish"));
+    }
+
+    #[test]
+    fn parse_spec() {
+        let alg = kdf::KdfAlgorithm::parse("scrypt:16:8:1").unwrap();
+        assert_eq!(alg, kdf::KdfAlgorithm::Scrypt { log_n: 16, r: 8, p: 1 });
+        assert_eq!(alg.spec(), "scrypt:16:8:1");
+
+        let alg = kdf::KdfAlgorithm::parse("bcrypt_pbkdf:32").unwrap();
+        assert_eq!(alg, kdf::KdfAlgorithm::BcryptPbkdf { rounds: 32 });
+        assert_eq!(kdf::KdfAlgorithm::parse(&alg.spec()).unwrap(), alg);
+
+        assert!(kdf::KdfAlgorithm::parse("scrypt:16:8").is_err());
+        assert!(kdf::KdfAlgorithm::parse("scrypt:0:8:1").is_err());
+        assert!(kdf::KdfAlgorithm::parse("scrypt:99:8:1").is_err());
+        assert!(kdf::KdfAlgorithm::parse("scrypt:x:8:1").is_err());
+        assert!(kdf::KdfAlgorithm::parse("argon2").is_err());
+        assert!(kdf::KdfAlgorithm::parse("hkdf").is_err());
+    }
+
+    #[test]
+    fn scrypt_limits() {
+        // the largest the benchmark picks is fine
+        assert!(kdf::KdfAlgorithm::parse("scrypt:20:8:1").is_ok());
+        assert!(kdf::KdfAlgorithm::parse("scrypt:10:8:16").is_ok());
+
+        // log_n must be less than 16 * r, memory is capped, and so is p
+        let bad = ["scrypt:20:1:1", "scrypt:16:1:1", "scrypt:21:8:1", "scrypt:14:100000:1", "scrypt:10:8:17", "scrypt:10:8:1000000"];
+        for spec in bad.iter() {
+            assert!(kdf::KdfAlgorithm::parse(spec).is_err(), "{}", spec);
+        }
+
+        // including from a manifest, before anything is derived
+        let dir = out_dir("scrypt_limits");
+        let mut manifest = kdf::KdfManifest::create(test_alg(), "swordfish", None, &[1; KEY_SIZE]);
+        manifest.save(&dir).unwrap();
+        for spec in bad.iter() {
+            let parts:Vec<u32> = spec.split(':').skip(1).map(|n| n.parse().unwrap()).collect();
+            manifest.slots[0].algorithm = kdf::KdfAlgorithm::Scrypt { log_n: parts[0] as u8, r: parts[1], p: parts[2] };
+            manifest.write_to(&kdf::manifest_path(&dir)).unwrap();
+            let e = kdf::KdfManifest::load_from(&kdf::manifest_path(&dir)).err().unwrap();
+            assert!(e.contains("scrypt"), "{}: {}", spec, e);
+        }
+    }
+
+    #[test]
+    fn salt_changes_key() {
+        let a = kdf::KeySlot::with_algorithm("a", test_alg());
+        let b = kdf::KeySlot::with_algorithm("b", test_alg());
+        assert!(a.salt != b.salt);
+        assert!(a.derive_key("swordfish") != b.derive_key("swordfish"));
+        assert!(a.derive_key("swordfish") == a.derive_key("swordfish"));
+        assert!(a.derive_key("swordfish") != kdf::KdfManifest::legacy().slots[0].derive_key("swordfish"));
+    }
+}
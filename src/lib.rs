@@ -0,0 +1,34 @@
+#![feature(plugin)]
+#![plugin(clippy)]
+
+#![feature(path_ext)]
+#![feature(append)] // for sync dedup, hopefully can remove
+
+// The modules are in a library so that the fuzz targets (see fuzz/) can use them; the program
+// itself is in main.rs.
+
+#[macro_use]
+extern crate log;
+
+extern crate rpassword;
+
+pub mod util;
+pub mod config;
+pub mod kdf;
+pub mod shamir;
+pub mod password_source;
+pub mod agent;
+pub mod host_key;
+pub mod mapping;
+pub mod syncfile;
+pub mod native_attrs;
+pub mod crypto_util;
+pub mod syncdb;
+pub mod core;
+pub mod commands;
+pub mod trash;
+pub mod logging;
+pub mod process_mutex;
+
+#[cfg(test)]
+mod testlib;
@@ -1,37 +1,43 @@
 #![feature(plugin)]
 #![plugin(clippy)]
 
-#![feature(path_ext)]
-#![feature(append)] // for sync dedup, hopefully can remove
-
 #[macro_use]
 extern crate log;
 
-mod util;
-mod config;
-mod mapping;
-mod syncfile;
-mod crypto_util;
-mod syncdb;
-mod core;
-mod commands;
-mod trash;
-mod logging;
-mod process_mutex;
-
-#[cfg(test)]
-mod testlib;
+extern crate grey_crypt;
+
+use grey_crypt::{agent,commands,config,core,crypto_util,kdf,logging,process_mutex,syncdb};
 
 use std::thread;
 
 extern crate getopts;
-extern crate rpassword;
 
 use getopts::Options;
 use std::env;
 
+// The value of a small count option, such as --shares.
+fn count_opt(matches: &getopts::Matches, name: &str, default: u8) -> u8 {
+    match matches.opt_str(name) {
+        None => default,
+        Some(c) => match u8::from_str_radix(&c,10) {
+            Err(e) => panic!("Unable to parse --{}: {}", name, e),
+            Ok(c) => c
+        }
+    }
+}
+
+// The master key and its key slot name from the agent, for the commands that only change the
+// manifest.  None if the agent doesn't have a current key, and the password is needed.
+fn agent_key(conf: &config::SyncConfig) -> Option<(String,crypto_util::SecretKey)> {
+    let manifest = match kdf::KdfManifest::load(conf.sync_dir()) {
+        Err(e) => panic!("Unable to read sync directory KDF manifest: {}", e),
+        Ok(m) => m
+    };
+    config::agent_key(conf, &manifest)
+}
+
 fn print_usage(program: &str, opts: Options) {
-    let brief = format!("Usage: {} [options]", program);
+    let brief = format!("Usage: {} [options] [kdf-benchmark | agent | agent lock | keyslot list | keyslot add NAME | keyslot remove NAME | recovery split [NAME] | recovery combine [NAME] | keyword add KEYWORD [NAME] | host enroll [NAME] | host revoke NAME | rotate-key]", program);
     print!("{}", opts.usage(&brief));
 }
 
@@ -46,6 +52,15 @@ fn main() {
     opts.optflag("x", "", "show syncfile metadata for all conflicted files");
     opts.optflag("v", "", "use verbose logging");
     opts.optflag("p", "", "change encryption password");
+    opts.optflag("", "init", "initialize a new sync directory");
+    opts.optflag("", "upgrade", "upgrade a sync directory created by an older version of greycrypt");
+    opts.optopt("", "kdf", "key derivation function for --init, --upgrade, -p or keyword add (e.g. scrypt:15:8:1)", "KDF_SPEC");
+    opts.optopt("", "target-ms", "target key derivation time for kdf-benchmark (default 1000)", "MILLISECONDS");
+    opts.optflag("", "recovery", "with keyslot add, generate a recovery key instead of asking for a password; with host revoke, replace the recovery keys");
+    opts.optopt("", "shares", "number of shares for recovery split, or host revoke's new recovery keys (default 5)", "COUNT");
+    opts.optopt("", "threshold", "number of shares needed to recover the key, for recovery split or host revoke (default 3)", "COUNT");
+    opts.optflag("", "drop-slots", "with host revoke, remove the key slots that can't be moved to the new master key");
+    opts.optopt("", "idle-timeout", "for agent, forget keys after this many idle seconds; 0 to keep them (default 3600)", "SECONDS");
     opts.optflag("h", "help", "print this help menu");
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => { m }
@@ -84,12 +99,153 @@ fn main() {
         }
     };
     
-    let changing_password = matches.opt_present("p");
-    let pw_prompt_message = if changing_password { Some("Enter old password:") } else { None };
+    if matches.free.iter().any(|a| a == "kdf-benchmark") {
+        let target_ms = match matches.opt_str("target-ms") {
+            None => 1000,
+            Some(t) => match u64::from_str_radix(&t,10) {
+                Err(e) => panic!("Unable to parse target time: {}", e),
+                Ok(t) => t
+            }
+        };
+        commands::kdf_benchmark(target_ms);
+        return;
+    }
+
+    // the agent is per user, not per config, so it doesn't read the config file
+    if matches.free.get(0).map(|a| &a[..]) == Some("agent") {
+        match matches.free.get(1).map(|a| &a[..]) {
+            None => {
+                let idle_timeout = match matches.opt_str("idle-timeout") {
+                    None => agent::DEFAULT_IDLE_TIMEOUT_SECS,
+                    Some(t) => match u64::from_str_radix(&t,10) {
+                        Err(e) => panic!("Unable to parse idle timeout: {}", e),
+                        Ok(t) => t
+                    }
+                };
+                agent::run(idle_timeout);
+            },
+            Some("lock") => {
+                match agent::lock() {
+                    Err(e) => panic!("{}", e),
+                    Ok(_) => info!("Agent locked")
+                }
+            },
+            _ => print_usage(&program, opts)
+        }
+        return;
+    }
+
+    let kdf_algorithm = match matches.opt_str("kdf") {
+        None => None,
+        Some(spec) => match kdf::KdfAlgorithm::parse(&spec) {
+            Err(e) => panic!("{}", e),
+            Ok(alg) => Some(alg)
+        }
+    };
+
     let hn_override = None;
 
-    // init conf and state
-    let conf = config::parse(cfile,hn_override,pw_prompt_message);
+    if matches.opt_present("init") {
+        let (conf, pw_source) = config::parse_unkeyed(cfile,hn_override);
+        let password = commands::get_new_password(&pw_source);
+        commands::init_sync_dir(&conf, kdf_algorithm.unwrap_or(kdf::KdfAlgorithm::default_scrypt()), &password);
+        return;
+    }
+
+    // these only change the manifest, so they don't need the sync state
+    if matches.opt_present("p") {
+        let (conf, pw_source) = config::parse_unkeyed(cfile,hn_override);
+        match agent_key(&conf) {
+            Some((slot, master_key)) => commands::change_password_unlocked(&conf, &slot, master_key, kdf_algorithm),
+            None => {
+                let password = config::get_password(&pw_source, Some("Enter old password:"));
+                commands::change_password(&conf, &password, kdf_algorithm);
+            }
+        }
+        return;
+    }
+
+    if matches.free.get(0).map(|a| &a[..]) == Some("keyslot") {
+        let (conf, pw_source) = config::parse_unkeyed(cfile,hn_override);
+        let slot_name = || {
+            match matches.free.get(2) {
+                None => panic!("A key slot name is required"),
+                Some(n) => n.to_owned()
+            }
+        };
+        match matches.free.get(1).map(|a| &a[..]) {
+            Some("list") => commands::keyslot_list(&conf),
+            Some("add") => {
+                let name = slot_name();
+                let recovery = matches.opt_present("recovery");
+                let algorithm = kdf_algorithm.unwrap_or(kdf::KdfAlgorithm::default_scrypt());
+                match agent_key(&conf) {
+                    Some((_, master_key)) => { commands::keyslot_add_unlocked(&conf, master_key, &name, recovery, algorithm); },
+                    None => {
+                        let password = config::get_password(&pw_source, Some("Enter an existing password:"));
+                        commands::keyslot_add(&conf, &password, &name, recovery, algorithm);
+                    }
+                }
+            },
+            Some("remove") => {
+                let name = slot_name();
+                match agent_key(&conf) {
+                    Some((_, master_key)) => commands::keyslot_remove_unlocked(&conf, master_key, &name),
+                    None => {
+                        let password = config::get_password(&pw_source, Some("Enter an existing password:"));
+                        commands::keyslot_remove(&conf, &password, &name);
+                    }
+                }
+            },
+            _ => print_usage(&program, opts)
+        }
+        return;
+    }
+
+    if matches.free.get(0).map(|a| &a[..]) == Some("recovery") {
+        let (conf, pw_source) = config::parse_unkeyed(cfile,hn_override);
+        match matches.free.get(1).map(|a| &a[..]) {
+            Some("split") => {
+                let name = matches.free.get(2).map(|n| n.to_owned()).unwrap_or("recovery".to_owned());
+                let count = count_opt(&matches, "shares", 5);
+                let threshold = count_opt(&matches, "threshold", 3);
+                match agent_key(&conf) {
+                    Some((_, master_key)) => { commands::recovery_split_unlocked(&conf, master_key, &name, count, threshold); },
+                    None => {
+                        let password = config::get_password(&pw_source, Some("Enter an existing password:"));
+                        commands::recovery_split(&conf, &password, &name, count, threshold);
+                    }
+                }
+            },
+            Some("combine") => {
+                let name = matches.free.get(2).map(|n| n.to_owned()).unwrap_or("recovered".to_owned());
+                let shares = commands::collect_recovery_shares();
+                commands::recovery_combine(&conf, &shares, &name,
+                    kdf_algorithm.unwrap_or(kdf::KdfAlgorithm::default_scrypt()));
+            },
+            _ => print_usage(&program, opts)
+        }
+        return;
+    }
+
+    // a new host publishes its key without the password; enrolling it needs the master key
+    if matches.free.get(0).map(|a| &a[..]) == Some("host") && matches.free.get(1).map(|a| &a[..]) == Some("enroll")
+        && matches.free.get(2).is_none() {
+        let (conf, _) = config::parse_unkeyed(cfile,hn_override);
+        commands::host_enroll_request(&conf);
+        return;
+    }
+
+    // init conf and state.  an upgrade can't use the normal parse, because the sync dir
+    // doesn't have a manifest yet.
+    let (conf, upgrade_password) =
+        if matches.opt_present("upgrade") {
+            let (conf, pw_source) = config::parse_unkeyed(cfile,hn_override);
+            let password = commands::get_new_password(&pw_source);
+            (conf, Some(password))
+        } else {
+            (config::parse(cfile,hn_override,None), None)
+        };
     let syncdb = match syncdb::SyncDb::new(&conf) {
         Err(e) => panic!("Failed to create syncdb: {:?}", e),
         Ok(sdb) => sdb
@@ -103,6 +259,11 @@ fn main() {
 
     let mut state = core::SyncState::new(conf,syncdb,log_util);
 
+    // an upgrade does this itself once the sync dir is converted
+    if upgrade_password.is_none() {
+        core::migrate_syncdb_sync_ids(&mut state);
+    }
+
     let poll_interval =
         if matches.opt_present("t") {
             match matches.opt_str("t") {
@@ -127,8 +288,39 @@ fn main() {
 
         }
     } 
-    else if changing_password {
-        commands::change_password(&mut state);
+    else if let Some(password) = upgrade_password {
+        commands::upgrade_sync_dir(&mut state, &password, kdf_algorithm.unwrap_or(kdf::KdfAlgorithm::default_scrypt()));
+    }
+    else if matches.free.get(0).map(|a| &a[..]) == Some("keyword") {
+        match (matches.free.get(1).map(|a| &a[..]), matches.free.get(2)) {
+            (Some("add"), Some(kw)) => {
+                let name = matches.free.get(3).map(|n| n.to_owned()).unwrap_or("shared".to_owned());
+                commands::keyword_add(&mut state, kw, &name, kdf_algorithm.unwrap_or(kdf::KdfAlgorithm::default_scrypt()));
+            },
+            _ => return print_usage(&program, opts)
+        }
+    }
+    else if matches.free.get(0).map(|a| &a[..]) == Some("host") {
+        match (matches.free.get(1).map(|a| &a[..]), matches.free.get(2)) {
+            (Some("enroll"), Some(name)) => commands::host_enroll(&state.conf, name),
+            (Some("revoke"), Some(name)) => {
+                let recovery = if matches.opt_present("shares") || matches.opt_present("threshold") {
+                    commands::RecoveryReplacement::Shares { count: count_opt(&matches, "shares", 5), threshold: count_opt(&matches, "threshold", 3) }
+                } else if matches.opt_present("recovery") {
+                    commands::RecoveryReplacement::Keys
+                } else {
+                    commands::RecoveryReplacement::None
+                };
+                commands::host_revoke(&mut state, name, matches.opt_present("drop-slots"), recovery);
+            },
+            _ => return print_usage(&program, opts)
+        }
+    }
+    else if matches.free.get(0).map(|a| &a[..]) == Some("rotate-key") {
+        // syncs as usual while it re-encrypts
+        info!("Starting");
+        info!("Poll interval: {} seconds", poll_interval);
+        commands::rotate_key(&mut state, poll_interval);
     }
     else if matches.opt_present("x") {
         state.sync_files_for_id = core::find_all_syncfiles(&mut state);
@@ -0,0 +1,335 @@
+// File attributes that can be recorded in a syncfile's (encrypted) metadata along with the
+// file's data, and restored with it: the permission bits, the access and modification times
+// and extended attributes.  Each host chooses which of them it records and restores with the
+// "PreserveAttributes" setting; see Preserve.
+
+extern crate filetime;
+#[cfg(not(target_os = "windows"))]
+extern crate xattr;
+extern crate rustc_serialize;
+
+use std::collections::BTreeMap;
+use std::fs::{metadata,Metadata};
+#[cfg(not(target_os = "windows"))]
+use std::fs::{set_permissions,Permissions};
+use std::io::Result;
+#[cfg(not(target_os = "windows"))]
+use std::os::unix::fs::PermissionsExt;
+use util::make_err;
+
+use self::filetime::FileTime;
+use self::rustc_serialize::base64::{ToBase64, FromBase64, STANDARD};
+use self::rustc_serialize::json::Json;
+
+// Only the permission bits are kept, so a setuid or setgid bit never comes from another host.
+const MODE_MASK: u32 = 0o777;
+
+// Attributes in these namespaces need privileges to set, or grant them (security.capability),
+// so they are neither recorded nor restored.
+const RESTRICTED_XATTR_PREFIXES: [&'static str; 3] = ["security.", "system.", "trusted."];
+
+// The metadata fields that hold the attributes.
+pub const METADATA_FIELDS: [&'static str; 4] = ["mode", "mtime", "atime", "xattrs"];
+
+#[derive(Clone,Debug,PartialEq)]
+pub struct Preserve {
+    pub mode: bool,
+    pub times: bool,
+    pub xattrs: bool
+}
+
+impl Preserve {
+    pub fn none() -> Self {
+        Preserve { mode: false, times: false, xattrs: false }
+    }
+
+    // Parse the config form: "none", or a comma separated list of "mode", "times" and "xattrs".
+    // Windows has neither permission bits nor extended attributes, so only "times" works there.
+    pub fn parse(spec:&str) -> ::std::result::Result<Self,String> {
+        let mut p = Preserve::none();
+        if spec.trim() == "none" {
+            return Ok(p);
+        }
+        for name in spec.split(',') {
+            match name.trim() {
+                n @ "mode" | n @ "xattrs" if cfg!(target_os = "windows") =>
+                    return Err(format!("Can't preserve {} on this platform; use none, or times", n)),
+                "mode" => p.mode = true,
+                "times" => p.times = true,
+                "xattrs" => p.xattrs = true,
+                _ => return Err(format!("Unrecognized attribute spec: {}; expected none, or a list of mode, times and xattrs", spec))
+            }
+        }
+        Ok(p)
+    }
+}
+
+// Times are (seconds since 1970, nanoseconds).  None for attributes that weren't recorded.
+#[derive(Clone,Debug,PartialEq)]
+pub struct NativeAttrs {
+    pub mode: Option<u32>,
+    pub mtime: Option<(u64,u32)>,
+    pub atime: Option<(u64,u32)>,
+    pub xattrs: Option<BTreeMap<String,Vec<u8>>>
+}
+
+fn is_restricted(name:&str) -> bool {
+    RESTRICTED_XATTR_PREFIXES.iter().any(|p| name.starts_with(p))
+}
+
+fn invalid<T>(field:&str) -> Result<T> {
+    make_err(&format!("Invalid {} in metadata", field))
+}
+
+#[cfg(not(target_os = "windows"))]
+fn read_mode(md:&Metadata) -> Result<u32> {
+    Ok(md.permissions().mode() as u32 & MODE_MASK)
+}
+
+#[cfg(not(target_os = "windows"))]
+fn set_mode(path:&str, mode:u32) -> Result<()> {
+    set_permissions(path, Permissions::from_mode(mode & MODE_MASK))
+}
+
+#[cfg(not(target_os = "windows"))]
+fn read_xattrs(path:&str) -> Result<BTreeMap<String,Vec<u8>>> {
+    let mut xattrs = BTreeMap::new();
+    for name in try!(xattr::list(path)) {
+        let name = match name.into_string() {
+            Err(name) => {
+                warn!("Skipping extended attribute with a non-utf8 name: {:?} on {}", name, path);
+                continue;
+            },
+            Ok(name) => name
+        };
+        if is_restricted(&name) {
+            continue;
+        }
+        let value = try!(xattr::get(path, &name));
+        xattrs.insert(name, value);
+    }
+    Ok(xattrs)
+}
+
+#[cfg(not(target_os = "windows"))]
+fn set_xattr(path:&str, name:&str, value:&[u8]) -> Result<()> {
+    xattr::set(path, name, value)
+}
+
+// Preserve::parse() refuses mode and xattrs on windows, so these aren't reached.
+#[cfg(target_os = "windows")]
+fn read_mode(_:&Metadata) -> Result<u32> {
+    make_err("File modes are not supported on this platform")
+}
+
+#[cfg(target_os = "windows")]
+fn set_mode(_:&str, _:u32) -> Result<()> {
+    make_err("File modes are not supported on this platform")
+}
+
+#[cfg(target_os = "windows")]
+fn read_xattrs(_:&str) -> Result<BTreeMap<String,Vec<u8>>> {
+    make_err("Extended attributes are not supported on this platform")
+}
+
+#[cfg(target_os = "windows")]
+fn set_xattr(_:&str, _:&str, _:&[u8]) -> Result<()> {
+    make_err("Extended attributes are not supported on this platform")
+}
+
+fn time_to_json(t:(u64,u32)) -> Json {
+    Json::Array(vec![Json::U64(t.0), Json::U64(t.1 as u64)])
+}
+
+fn time_from_json(md:&BTreeMap<String,Json>, field:&str) -> Result<Option<(u64,u32)>> {
+    match md.get(field) {
+        None => Ok(None),
+        Some(&Json::Array(ref t)) if t.len() == 2 => {
+            match (t[0].as_u64(), t[1].as_u64()) {
+                (Some(secs), Some(nanos)) if nanos < 1000000000 => Ok(Some((secs, nanos as u32))),
+                _ => invalid(field)
+            }
+        },
+        _ => invalid(field)
+    }
+}
+
+impl NativeAttrs {
+    pub fn none() -> Self {
+        NativeAttrs { mode: None, mtime: None, atime: None, xattrs: None }
+    }
+
+    // Read the attributes of path that preserve selects.
+    pub fn read(preserve:&Preserve, path:&str) -> Result<Self> {
+        let md = try!(metadata(path));
+        let mut attrs = NativeAttrs::none();
+        if preserve.mode {
+            attrs.mode = Some(try!(read_mode(&md)));
+        }
+        if preserve.times {
+            let mtime = FileTime::from_last_modification_time(&md);
+            let atime = FileTime::from_last_access_time(&md);
+            attrs.mtime = Some((mtime.seconds_relative_to_1970(), mtime.nanoseconds()));
+            attrs.atime = Some((atime.seconds_relative_to_1970(), atime.nanoseconds()));
+        }
+        if preserve.xattrs {
+            attrs.xattrs = Some(try!(read_xattrs(path)));
+        }
+        Ok(attrs)
+    }
+
+    // Set the attributes that were recorded and that preserve selects on path.
+    pub fn apply(&self, preserve:&Preserve, path:&str) -> Result<()> {
+        // before the mode, which may make the file read only
+        if let (true, Some(xattrs)) = (preserve.xattrs, self.xattrs.as_ref()) {
+            for (name, value) in xattrs.iter() {
+                if is_restricted(name) {
+                    warn!("Not restoring extended attribute {} on {}", name, path);
+                    continue;
+                }
+                try!(set_xattr(path, name, value));
+            }
+        }
+        if let (true, Some(mode)) = (preserve.mode, self.mode) {
+            try!(set_mode(path, mode));
+        }
+        if let (true, Some(mtime)) = (preserve.times, self.mtime) {
+            let atime = self.atime.unwrap_or(mtime);
+            try!(filetime::set_file_times(path,
+                FileTime::from_seconds_since_1970(atime.0, atime.1),
+                FileTime::from_seconds_since_1970(mtime.0, mtime.1)));
+        }
+        Ok(())
+    }
+
+    pub fn to_metadata(&self, md:&mut BTreeMap<String,Json>) {
+        if let Some(mode) = self.mode {
+            md.insert("mode".to_owned(), Json::U64(mode as u64));
+        }
+        if let Some(mtime) = self.mtime {
+            md.insert("mtime".to_owned(), time_to_json(mtime));
+        }
+        if let Some(atime) = self.atime {
+            md.insert("atime".to_owned(), time_to_json(atime));
+        }
+        if let Some(ref xattrs) = self.xattrs {
+            let values = xattrs.iter().map(|(name, value)| (name.clone(), Json::String(value.to_base64(STANDARD)))).collect();
+            md.insert("xattrs".to_owned(), Json::Object(values));
+        }
+    }
+
+    pub fn from_metadata(md:&BTreeMap<String,Json>) -> Result<Self> {
+        let mode = match md.get("mode") {
+            None => None,
+            Some(&Json::U64(mode)) if mode <= MODE_MASK as u64 => Some(mode as u32),
+            _ => return invalid("mode")
+        };
+        let xattrs = match md.get("xattrs") {
+            None => None,
+            Some(&Json::Object(ref values)) => {
+                let mut xattrs = BTreeMap::new();
+                for (name, value) in values.iter() {
+                    let value = match value.as_string().map(|v| v.from_base64()) {
+                        Some(Ok(v)) => v,
+                        _ => return invalid("xattrs")
+                    };
+                    if name.is_empty() || name.contains('\0') {
+                        return invalid("xattrs");
+                    }
+                    xattrs.insert(name.clone(), value);
+                }
+                Some(xattrs)
+            },
+            _ => return invalid("xattrs")
+        };
+        Ok(NativeAttrs {
+            mode: mode,
+            mtime: try!(time_from_json(md, "mtime")),
+            atime: try!(time_from_json(md, "atime")),
+            xattrs: xattrs
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use native_attrs::{Preserve,NativeAttrs};
+
+    use std::collections::BTreeMap;
+
+    extern crate rustc_serialize;
+    use self::rustc_serialize::json::Json;
+
+    #[test]
+    fn parse() {
+        assert_eq!(Preserve::parse("none").unwrap(), Preserve::none());
+        assert!(Preserve::parse("times").unwrap().times);
+        if cfg!(target_os = "windows") {
+            assert!(Preserve::parse("mode, times").is_err());
+            assert!(Preserve::parse("xattrs").is_err());
+        } else {
+            let p = Preserve::parse("mode, times").unwrap();
+            assert!(p.mode && p.times && !p.xattrs);
+        }
+        assert!(Preserve::parse("times,owner").is_err());
+        assert!(Preserve::parse("").is_err());
+    }
+
+    #[test]
+    fn metadata() {
+        let mut xattrs = BTreeMap::new();
+        xattrs.insert("user.a".to_owned(), b"value".to_vec());
+        let attrs = NativeAttrs { mode: Some(0o751), mtime: Some((1000000000, 500)), atime: Some((999999999, 0)), xattrs: Some(xattrs) };
+
+        // it survives the trip through the metadata
+        let mut md = BTreeMap::new();
+        attrs.to_metadata(&mut md);
+        assert_eq!(NativeAttrs::from_metadata(&md).unwrap(), attrs);
+        assert_eq!(NativeAttrs::from_metadata(&BTreeMap::new()).unwrap(), NativeAttrs::none());
+
+        // bad values are rejected
+        for (field, value) in vec![("mode", "4096"), ("mtime", "[1,1000000000]"), ("atime", "\"x\""), ("xattrs", "{\"user.a\":1}")] {
+            let mut md = BTreeMap::new();
+            md.insert(field.to_owned(), Json::from_str(value).unwrap());
+            assert!(NativeAttrs::from_metadata(&md).is_err(), "{} {}", field, value);
+        }
+    }
+
+    #[test]
+    #[cfg(not(target_os = "windows"))]
+    fn read_and_apply() {
+        use std::env;
+        use std::fs::{File,metadata,set_permissions,Permissions};
+        use std::os::unix::fs::PermissionsExt;
+        use std::path::PathBuf;
+
+        let wd = env::current_dir().unwrap();
+        let mut src = PathBuf::from(&wd);
+        src.push("testdata");
+        src.push("out_scratch");
+        src.push("native_attrs_src.txt");
+        let mut dst = src.clone();
+        dst.set_file_name("native_attrs_dst.txt");
+        File::create(&src).unwrap();
+        File::create(&dst).unwrap();
+        let src = src.to_str().unwrap();
+        let dst = dst.to_str().unwrap();
+        set_permissions(src, Permissions::from_mode(0o4751)).unwrap();
+        set_permissions(dst, Permissions::from_mode(0o600)).unwrap();
+
+        let all = Preserve::parse("mode,times").unwrap();
+        let mut attrs = NativeAttrs::read(&all, src).unwrap();
+        // the setuid bit isn't kept
+        assert_eq!(attrs.mode, Some(0o751));
+        assert!(attrs.xattrs.is_none());
+        attrs.mtime = Some((1000000000, 500));
+
+        // only what the host preserves is restored
+        attrs.apply(&Preserve::parse("times").unwrap(), dst).unwrap();
+        let restored = NativeAttrs::read(&all, dst).unwrap();
+        assert_eq!(restored.mode, Some(0o600));
+        assert_eq!(restored.mtime, Some((1000000000, 500)));
+        attrs.apply(&all, dst).unwrap();
+        assert_eq!(metadata(dst).unwrap().permissions().mode() & 0o7777, 0o751);
+    }
+}
@@ -0,0 +1,261 @@
+use std::env;
+use std::fmt;
+use std::fs;
+use std::fs::File;
+use std::io::Read;
+use std::path::PathBuf;
+use std::process::{Command,Stdio};
+
+#[cfg(not(target_os = "windows"))]
+use std::os::unix::io::FromRawFd;
+#[cfg(not(target_os = "windows"))]
+use std::os::unix::fs::{MetadataExt,PermissionsExt};
+
+#[cfg(not(target_os = "windows"))]
+extern {
+    fn getuid() -> u32;
+}
+
+// Where the encryption password comes from.  Prompting is the default; the others let
+// greycrypt run unattended, for example from cron or systemd.  They are set in the [General]
+// section of the config file, and at most one may be used.
+#[derive(Clone,PartialEq)]
+pub enum PasswordSource {
+    Prompt,
+    // "Password"; only used by debug builds
+    Config(String),
+    // "PasswordFd": read from an inherited file descriptor, like gpg --passphrase-fd
+    Fd(i32),
+    // "PasswordEnv": the name of an environment variable
+    Env(String),
+    // "PasswordFile": a file that only its owner can read
+    File(String),
+    // "PasswordCommand": a shell command that prints the password, like "pass show greycrypt"
+    Command(String)
+}
+
+// Never shows the password.
+impl fmt::Debug for PasswordSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.describe())
+    }
+}
+
+// Only the first line is used, so that a trailing newline doesn't become part of the password.
+fn first_line(text:&str) -> String {
+    text.lines().next().unwrap_or("").to_owned()
+}
+
+#[cfg(not(target_os = "windows"))]
+fn read_fd(fd:i32) -> Result<String,String> {
+    let mut f = unsafe { File::from_raw_fd(fd) };
+    let mut text = String::new();
+    match f.read_to_string(&mut text) {
+        Err(e) => Err(format!("Unable to read password from file descriptor {}: {}", fd, e)),
+        Ok(_) => Ok(first_line(&text))
+    }
+}
+
+#[cfg(target_os = "windows")]
+fn read_fd(_:i32) -> Result<String,String> {
+    Err(format!("PasswordFd is not supported on windows"))
+}
+
+// The file holds a secret, so refuse to use it if anyone else could read or replace it.
+// The metadata must come from the open file, not the path, so that the file can't be swapped
+// between the check and the read.
+#[cfg(not(target_os = "windows"))]
+fn check_password_file_permissions(path:&str, meta:&fs::Metadata) -> Result<(),String> {
+    check_password_file_owner(path, meta, unsafe { getuid() })
+}
+
+#[cfg(not(target_os = "windows"))]
+fn check_password_file_owner(path:&str, meta:&fs::Metadata, uid:u32) -> Result<(),String> {
+    if meta.uid() != uid {
+        return Err(format!("Password file {} is owned by uid {}, not {}", path, meta.uid(), uid));
+    }
+    let mode = meta.permissions().mode();
+    if mode & 0o077 != 0 {
+        return Err(format!("Password file {} is accessible by other users (mode {:o}); run: chmod 600 {}", path, mode & 0o777, path));
+    }
+    Ok(())
+}
+
+// TODO: check the ACL on windows
+#[cfg(target_os = "windows")]
+fn check_password_file_permissions(_:&str, _:&fs::Metadata) -> Result<(),String> {
+    Ok(())
+}
+
+#[cfg(not(target_os = "windows"))]
+fn shell_command(cmd:&str) -> Command {
+    let mut c = Command::new("sh");
+    c.arg("-c").arg(cmd);
+    c
+}
+
+#[cfg(target_os = "windows")]
+fn shell_command(cmd:&str) -> Command {
+    let mut c = Command::new("cmd");
+    c.arg("/C").arg(cmd);
+    c
+}
+
+impl PasswordSource {
+    // For logs and errors; safe to print.
+    pub fn describe(&self) -> String {
+        match *self {
+            PasswordSource::Prompt => format!("prompt"),
+            PasswordSource::Config(_) => format!("config file"),
+            PasswordSource::Fd(fd) => format!("file descriptor {}", fd),
+            PasswordSource::Env(ref var) => format!("environment variable {}", var),
+            PasswordSource::File(ref path) => format!("password file {}", path),
+            PasswordSource::Command(ref cmd) => format!("command '{}'", cmd)
+        }
+    }
+
+    // Get the password from a non-interactive source.  Errors never contain the password.
+    pub fn read(&self) -> Result<String,String> {
+        match *self {
+            PasswordSource::Prompt => Err(format!("Can't read a password from the prompt non-interactively")),
+            PasswordSource::Config(ref pw) => Ok(pw.clone()),
+            PasswordSource::Fd(fd) => read_fd(fd),
+            PasswordSource::Env(ref var) => {
+                match env::var(var) {
+                    Err(e) => Err(format!("Unable to read password from environment variable {}: {}", var, e)),
+                    Ok(pw) => {
+                        // so that it isn't inherited by child processes, such as a
+                        // PasswordCommand or an agent
+                        env::remove_var(var);
+                        Ok(first_line(&pw))
+                    }
+                }
+            },
+            PasswordSource::File(ref path) => {
+                let pb = PathBuf::from(path);
+                let mut f = match File::open(&pb) {
+                    Err(e) => return Err(format!("Unable to read password file {}: {}", path, e)),
+                    Ok(f) => f
+                };
+                let meta = match f.metadata() {
+                    Err(e) => return Err(format!("Unable to read password file {}: {}", path, e)),
+                    Ok(m) => m
+                };
+                if !meta.is_file() {
+                    return Err(format!("Password file {} is not a file", path));
+                }
+                try!(check_password_file_permissions(path, &meta));
+
+                let mut text = String::new();
+                match f.read_to_string(&mut text) {
+                    Err(e) => Err(format!("Unable to read password file {}: {}", path, e)),
+                    Ok(_) => Ok(first_line(&text))
+                }
+            },
+            PasswordSource::Command(ref cmd) => {
+                // stdin and stderr are passed through, so the command can prompt for its own
+                // passphrase (gpg does)
+                let child = shell_command(cmd)
+                    .stdin(Stdio::inherit())
+                    .stdout(Stdio::piped())
+                    .stderr(Stdio::inherit())
+                    .spawn();
+                let output = match child.and_then(|c| c.wait_with_output()) {
+                    Err(e) => return Err(format!("Unable to run password command '{}': {}", cmd, e)),
+                    Ok(o) => o
+                };
+                if !output.status.success() {
+                    return Err(format!("Password command '{}' failed: {}", cmd, output.status));
+                }
+                match String::from_utf8(output.stdout) {
+                    Err(_) => Err(format!("Password command '{}' printed invalid UTF-8", cmd)),
+                    Ok(text) => Ok(first_line(&text))
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{File,create_dir_all};
+    use std::io::Write;
+    use std::path::PathBuf;
+
+    use password_source::PasswordSource;
+
+    fn out_file(name:&str) -> String {
+        let wd = env::current_dir().unwrap();
+        let mut pb = PathBuf::from(&wd);
+        pb.push("testdata");
+        pb.push("out_password_source");
+        create_dir_all(&pb).unwrap();
+        pb.push(name);
+        pb.to_str().unwrap().to_owned()
+    }
+
+    #[test]
+    fn env_var() {
+        env::set_var("GREYCRYPT_TEST_PASSWORD", "swordfish\n");
+        let src = PasswordSource::Env("GREYCRYPT_TEST_PASSWORD".to_owned());
+        assert_eq!(src.read().unwrap(), "swordfish");
+        assert!(!format!("{:?}", src).contains("swordfish"));
+        // cleared once read
+        assert!(env::var("GREYCRYPT_TEST_PASSWORD").is_err());
+        assert!(src.read().is_err());
+
+        assert!(PasswordSource::Env("GREYCRYPT_TEST_PASSWORD_UNSET".to_owned()).read().is_err());
+    }
+
+    #[test]
+    fn command() {
+        let src = PasswordSource::Command("echo swordfish".to_owned());
+        assert_eq!(src.read().unwrap().trim(), "swordfish");
+        assert!(PasswordSource::Command("exit 3".to_owned()).read().is_err());
+    }
+
+    #[test]
+    #[cfg(not(target_os = "windows"))]
+    fn password_file() {
+        use std::fs::{set_permissions,Permissions};
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = out_file("password");
+        {
+            let mut f = File::create(&path).unwrap();
+            f.write_all(b"swordfish\n").unwrap();
+        }
+        let src = PasswordSource::File(path.clone());
+
+        set_permissions(&path, Permissions::from_mode(0o644)).unwrap();
+        let e = src.read().unwrap_err();
+        assert!(e.contains("chmod 600"), "{}", e);
+        assert!(!e.contains("swordfish"));
+
+        set_permissions(&path, Permissions::from_mode(0o600)).unwrap();
+        assert_eq!(src.read().unwrap(), "swordfish");
+
+        assert!(PasswordSource::File(out_file("missing")).read().is_err());
+    }
+
+    #[test]
+    #[cfg(not(target_os = "windows"))]
+    fn password_file_owner() {
+        use std::fs::{metadata,set_permissions,Permissions};
+        use std::os::unix::fs::{MetadataExt,PermissionsExt};
+
+        let path = out_file("password_owner");
+        {
+            let mut f = File::create(&path).unwrap();
+            f.write_all(b"swordfish\n").unwrap();
+        }
+        set_permissions(&path, Permissions::from_mode(0o600)).unwrap();
+        let meta = metadata(&path).unwrap();
+
+        assert!(super::check_password_file_owner(&path, &meta, meta.uid()).is_ok());
+        // tests can't chown, so pretend to be someone else
+        let e = super::check_password_file_owner(&path, &meta, meta.uid() + 1).unwrap_err();
+        assert!(e.contains("owned by uid"), "{}", e);
+    }
+}
@@ -0,0 +1,218 @@
+// Shamir secret sharing over GF(2^8), used to split recovery keys.  Each byte of the
+// secret is the constant term of its own random polynomial of degree threshold - 1; a share
+// is the value of every polynomial at one nonzero x.  Any threshold shares recover the secret
+// by interpolating at x = 0, and fewer reveal nothing about it.
+
+extern crate crypto;
+use self::crypto::sha2::Sha256;
+use self::crypto::digest::Digest;
+
+extern crate rustc_serialize;
+use self::rustc_serialize::hex::{ToHex, FromHex};
+
+use crypto_util;
+
+// Number of hex digits of checksum at the end of a printed share; catches typos.
+const CHECK_HEX_LEN: usize = 8;
+
+#[derive(Debug,Clone,PartialEq)]
+pub struct Share {
+    pub threshold: u8,
+    pub x: u8,
+    pub y: Vec<u8>
+}
+
+// Multiply in GF(2^8) with the AES polynomial.  No table lookups, so no secret-dependent
+// memory access.
+fn gf_mul(a:u8, b:u8) -> u8 {
+    let mut a = a;
+    let mut b = b;
+    let mut p = 0;
+    for _ in 0..8 {
+        p ^= a & (0u8.wrapping_sub(b & 1));
+        let hi = a >> 7;
+        a = (a << 1) ^ (0x1b & 0u8.wrapping_sub(hi));
+        b >>= 1;
+    }
+    p
+}
+
+// a^254 is the inverse of a; 0 has none, and maps to 0.
+fn gf_inv(a:u8) -> u8 {
+    let mut result = 1;
+    let mut base = a;
+    let mut e = 254;
+    while e > 0 {
+        if e & 1 == 1 {
+            result = gf_mul(result, base);
+        }
+        base = gf_mul(base, base);
+        e >>= 1;
+    }
+    result
+}
+
+pub fn split(secret:&[u8], threshold:u8, count:u8) -> Result<Vec<Share>,String> {
+    if threshold < 2 {
+        return Err(format!("Threshold must be at least 2"));
+    }
+    if count < threshold {
+        return Err(format!("Number of shares ({}) must be at least the threshold ({})", count, threshold));
+    }
+
+    // coefficients for x^1 .. x^(threshold-1), for every byte
+    let degree = (threshold - 1) as usize;
+    let coefs = crypto_util::get_random_bytes(secret.len() * degree);
+
+    let mut shares = Vec::new();
+    for x in 1 .. (count as u16) + 1 {
+        let x = x as u8;
+        let y:Vec<u8> = secret.iter().enumerate().map(|(i, s)| {
+            // horner's rule, from the highest coefficient down
+            let c = &coefs[i * degree .. (i + 1) * degree];
+            let mut acc = 0;
+            for j in (0..degree).rev() {
+                acc = gf_mul(acc, x) ^ c[j];
+            }
+            gf_mul(acc, x) ^ *s
+        }).collect();
+        shares.push(Share { threshold: threshold, x: x, y: y });
+    }
+    Ok(shares)
+}
+
+// Recombine shares from the same split.  Shares from different splits can't be detected here;
+// the result is just wrong.
+pub fn combine(shares:&[Share]) -> Result<Vec<u8>,String> {
+    let first = match shares.first() {
+        None => return Err(format!("No shares")),
+        Some(s) => s
+    };
+    if shares.len() < first.threshold as usize {
+        return Err(format!("Need {} shares, got {}", first.threshold, shares.len()));
+    }
+    let shares = &shares[0 .. first.threshold as usize];
+    for (i, s) in shares.iter().enumerate() {
+        if s.threshold != first.threshold || s.y.len() != first.y.len() {
+            return Err(format!("Shares are from different splits"));
+        }
+        if s.x == 0 || shares[0..i].iter().any(|o| o.x == s.x) {
+            return Err(format!("Duplicate or invalid share number {}", s.x));
+        }
+    }
+
+    // lagrange interpolation at 0; subtraction is xor
+    let mut secret = vec![0; first.y.len()];
+    for (i, si) in shares.iter().enumerate() {
+        let mut num = 1;
+        let mut den = 1;
+        for (j, sj) in shares.iter().enumerate() {
+            if i != j {
+                num = gf_mul(num, sj.x);
+                den = gf_mul(den, si.x ^ sj.x);
+            }
+        }
+        let l = gf_mul(num, gf_inv(den));
+        for (b, y) in secret.iter_mut().zip(si.y.iter()) {
+            *b ^= gf_mul(l, *y);
+        }
+    }
+    Ok(secret)
+}
+
+fn share_check(body:&str) -> String {
+    let mut sha = Sha256::new();
+    sha.input(body.as_bytes());
+    let hex = sha.result_str();
+    hex[0..CHECK_HEX_LEN].to_owned()
+}
+
+impl Share {
+    // Printed as THRESHOLD-X-Y-CHECK, with Y and CHECK in hex.
+    pub fn to_text(&self) -> String {
+        let body = format!("{}-{}-{}", self.threshold, self.x, self.y.to_hex());
+        let check = share_check(&body);
+        format!("{}-{}", body, check)
+    }
+
+    pub fn parse(text:&str) -> Result<Share,String> {
+        let text:String = text.chars().filter(|c| !c.is_whitespace()).collect::<String>().to_lowercase();
+        let parts:Vec<&str> = text.split('-').collect();
+        if parts.len() != 4 {
+            return Err(format!("Share is not in the expected format"));
+        }
+        let body = format!("{}-{}-{}", parts[0], parts[1], parts[2]);
+        if share_check(&body) != parts[3] {
+            return Err(format!("Share checksum doesn't match; check for typos"));
+        }
+        let threshold = match u8::from_str_radix(parts[0], 10) {
+            Err(_) => return Err(format!("Invalid share threshold")),
+            Ok(t) => t
+        };
+        let x = match u8::from_str_radix(parts[1], 10) {
+            Err(_) => return Err(format!("Invalid share number")),
+            Ok(x) => x
+        };
+        let y = match parts[2].from_hex() {
+            Err(_) => return Err(format!("Invalid share data")),
+            Ok(y) => y
+        };
+        Ok(Share { threshold: threshold, x: x, y: y })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use shamir;
+
+    #[test]
+    fn gf_inverse() {
+        for a in 1..256 {
+            let a = a as u8;
+            assert_eq!(shamir::gf_mul(a, shamir::gf_inv(a)), 1);
+        }
+        assert_eq!(shamir::gf_mul(0x53, 0xca), 1);
+    }
+
+    #[test]
+    fn split_combine() {
+        let secret:Vec<u8> = (0..32).map(|i| i as u8 * 7).collect();
+        let shares = shamir::split(&secret, 3, 5).unwrap();
+        assert_eq!(shares.len(), 5);
+
+        // any 3 will do
+        for a in 0..5 {
+            for b in 0..5 {
+                for c in 0..5 {
+                    if a == b || b == c || a == c {
+                        continue;
+                    }
+                    let subset = vec![shares[a].clone(), shares[b].clone(), shares[c].clone()];
+                    assert_eq!(shamir::combine(&subset).unwrap(), secret);
+                }
+            }
+        }
+
+        // 2 won't
+        assert!(shamir::combine(&shares[0..2]).is_err());
+        let mut fake = shares[2].clone();
+        fake.x = shares[0].x;
+        assert!(shamir::combine(&vec![shares[0].clone(), shares[1].clone(), fake]).is_err());
+
+        assert!(shamir::split(&secret, 1, 5).is_err());
+        assert!(shamir::split(&secret, 4, 3).is_err());
+    }
+
+    #[test]
+    fn share_text() {
+        let shares = shamir::split(b"secret", 2, 3).unwrap();
+        let text = shares[1].to_text();
+        assert_eq!(shamir::Share::parse(&text).unwrap(), shares[1]);
+        assert_eq!(shamir::Share::parse(&format!(" {} ", text.to_uppercase())).unwrap(), shares[1]);
+
+        // typo
+        let bad = text.replace("2-2-", "2-3-");
+        assert!(shamir::Share::parse(&bad).is_err());
+        assert!(shamir::Share::parse("swordfish").is_err());
+    }
+}
@@ -1,8 +1,8 @@
 extern crate uuid;
 
-use std::io::Write;
+use std::io::{Read,Write};
 use std::fs::{PathExt};
-use std::fs::{File,create_dir_all};
+use std::fs::{File,create_dir_all,rename,remove_file};
 use std::path::{PathBuf};
 use std::collections::HashMap;
 
@@ -10,9 +10,37 @@ use util;
 use config;
 use syncfile;
 
+// Written to the syncdb dir once its entries have been moved to keyed sync ids.  Each host
+// has its own syncdb, so each converts its own after the sync dir is upgraded.
+const KEYED_SYNC_IDS_MARKER: &'static str = "keyed_sync_ids";
+
 pub struct SyncEntry {
     pub revguid: uuid::Uuid,
-    pub native_mtime: u64
+    pub native_mtime: u64,
+    // The highest syncfile seq seen for this sync id.  A syncfile with a lower one is an old copy
+    // that was put back, and is not applied.  0 for entries written before seq existed.
+    pub seq: u64
+}
+
+// The directory for this host's own state: the conf's db dir if it has one; otherwise, formed
+// from the app data path.
+pub fn local_dir(conf: &config::SyncConfig) -> Result<PathBuf,String> {
+    match conf.syncdb_dir {
+        Some(ref dir) => Ok(PathBuf::from(&dir.to_owned())),
+        None => {
+            let ad_dir = util::get_appdata_dir();
+            match ad_dir {
+                None => Err("No appdata dir available for local state".to_owned()),
+                Some(dir) => {
+                    // append app name
+                    let mut pb = PathBuf::from(&dir);
+                    pb.push("GreyCrypt");
+                    pb.push(config::BUILD_PREFIX);
+                    Ok(pb)
+                }
+            }
+        }
+    }
 }
 
 pub struct SyncDb {
@@ -22,25 +50,7 @@ pub struct SyncDb {
 
 impl SyncDb {
     pub fn new(conf: &config::SyncConfig) -> Result<Self,String> {
-        // if conf has a db dir, use that; otherwise, form it from the app data path
-        let syncdb_dir = {
-            match conf.syncdb_dir {
-                Some(ref dir) => PathBuf::from(&dir.to_owned()),
-                None => {
-                    let ad_dir = util::get_appdata_dir();
-                    match ad_dir {
-                        None => return Err("No appdata dir available, can't initialize syncdb".to_owned()),
-                        Some(dir) => {
-                            // append app name
-                            let mut pb = PathBuf::from(&dir);
-                            pb.push("GreyCrypt");
-                            pb.push(config::BUILD_PREFIX);
-                            pb
-                        }
-                    }
-                }
-            }
-        };
+        let syncdb_dir = try!(local_dir(conf));
 
         if !syncdb_dir.is_dir() {
             let res = create_dir_all(&syncdb_dir);
@@ -67,12 +77,15 @@ impl SyncDb {
     }
 
     pub fn update(&mut self, sf:&syncfile::SyncFile, native_mtime:u64) -> Result<(),String> {
+        // never lower the seq, even when the revguid goes back (e.g. after a dedup)
+        let seen = try!(self.get(sf)).map(|e| e.seq).unwrap_or(0);
         let entry = SyncEntry {
             revguid: sf.revguid,
-            native_mtime: native_mtime
+            native_mtime: native_mtime,
+            seq: if sf.seq > seen { sf.seq } else { seen }
         };
 
-        // write to disk: two-line file
+        // write to disk: three-line file
         // should switch to toml if this gets more complicated
         {
             let storepath:PathBuf = self.get_store_path(&sf.id);
@@ -99,6 +112,10 @@ impl SyncDb {
                 Err(e) => return Err(format!("{:?}", e)),
                 Ok(_) => ()
             }
+            match writeln!(f, "seq: {}", entry.seq) {
+                Err(e) => return Err(format!("{:?}", e)),
+                Ok(_) => ()
+            }
         }
 
         let _ = self.cache.insert(sf.id.clone(),entry);
@@ -107,48 +124,110 @@ impl SyncDb {
     }
     
     
-    pub fn get(&mut self, sf:&syncfile::SyncFile) -> Option<&SyncEntry> {
+    pub fn get(&mut self, sf:&syncfile::SyncFile) -> Result<Option<&SyncEntry>,String> {
         self.get_by_sid(&sf.id)
     }
     
-    pub fn get_by_sid(&mut self, sid: &str) -> Option<&SyncEntry> {
+    pub fn get_by_sid(&mut self, sid: &str) -> Result<Option<&SyncEntry>,String> {
         // can't just get() the key here, because that will introduce an
         // immutable borrow on the map, and we may need to mutate it to add
         // the entry from disk.
         if self.cache.contains_key(sid) {
-            let res = self.cache.get(sid);
-            return res;
+            Ok(self.cache.get(sid))
         } else {
             // lookup in fs
             let storepath = self.get_store_path(&sid);
 
             if !storepath.is_file() {
-                return None
+                return Ok(None)
             }
 
-            let entry_text = util::slurp_text_file(&storepath.to_str().unwrap());
-            let lines:Vec<&str> = entry_text.lines().collect();
-            let hm = util::string_lines_to_hashmap(lines);
-            let revguid_str = hm.get("revguid").expect("Need revguid");
-            let mtime_str = hm.get("native_mtime").expect("Need native_mtime");
-
-            let revguid = match uuid::Uuid::parse_str(revguid_str) {
-                Err(e) => panic!("Couldn't parse UUID str: {:?}", e),
-                Ok(id) => id
-            };
-            let mtime = match u64::from_str_radix(mtime_str, 10) {
-                Err(e) => panic!("Couldn't parse mtime str: {:?}", e),
-                Ok(mtime) => mtime
-            };
-
-            let entry = SyncEntry {
-                revguid: revguid,
-                native_mtime: mtime
-            };
+            let entry = try!(SyncDb::read_entry(&storepath));
 
             assert!(!self.cache.contains_key(sid));
             self.cache.insert(sid.to_owned(), entry);
-            self.cache.get(sid)
+            Ok(self.cache.get(sid))
+        }
+    }
+
+    fn read_entry(storepath:&PathBuf) -> Result<SyncEntry,String> {
+        let mut entry_text = String::new();
+        match File::open(&storepath).and_then(|mut f| f.read_to_string(&mut entry_text)) {
+            Err(e) => return Err(format!("Failed to read syncdb entry: {:?}: {:?}", storepath, e)),
+            Ok(_) => ()
+        }
+        let lines:Vec<&str> = entry_text.lines().collect();
+        let hm = match util::string_lines_to_hashmap(lines) {
+            Err(e) => return Err(format!("Malformed syncdb entry: {:?}: {}", storepath, e)),
+            Ok(hm) => hm
+        };
+        let revguid = match hm.get("revguid").map(|s| uuid::Uuid::parse_str(s)) {
+            None => return Err(format!("Syncdb entry has no revguid: {:?}", storepath)),
+            Some(Err(e)) => return Err(format!("Couldn't parse UUID str: {:?}: {:?}", storepath, e)),
+            Some(Ok(id)) => id
+        };
+        let mtime = match hm.get("native_mtime").map(|s| u64::from_str_radix(s, 10)) {
+            None => return Err(format!("Syncdb entry has no native_mtime: {:?}", storepath)),
+            Some(Err(e)) => return Err(format!("Couldn't parse mtime str: {:?}: {:?}", storepath, e)),
+            Some(Ok(mtime)) => mtime
+        };
+        let seq = match hm.get("seq").map(|s| u64::from_str_radix(s, 10)) {
+            None => 0,
+            Some(Err(e)) => return Err(format!("Couldn't parse seq str: {:?}: {:?}", storepath, e)),
+            Some(Ok(seq)) => seq
+        };
+
+        Ok(SyncEntry {
+            revguid: revguid,
+            native_mtime: mtime,
+            seq: seq
+        })
+    }
+
+    // Move the entry for old_sid to new_sid.  Returns false if there was nothing to move.  If
+    // new_sid already has an entry, it is kept and the old one is removed.
+    pub fn move_entry(&mut self, old_sid: &str, new_sid: &str) -> Result<bool,String> {
+        let old_path = self.get_store_path(old_sid);
+        if !old_path.is_file() {
+            return Ok(false);
+        }
+        let new_path = self.get_store_path(new_sid);
+
+        let _ = self.cache.remove(old_sid);
+        let _ = self.cache.remove(new_sid);
+
+        if new_path.is_file() {
+            match remove_file(&old_path) {
+                Err(e) => return Err(format!("Failed to remove syncdb entry: {:?}: {:?}", old_path, e)),
+                Ok(_) => return Ok(false)
+            }
+        }
+
+        let new_path_par = new_path.parent().unwrap();
+        if !new_path_par.is_dir() {
+            match create_dir_all(&new_path_par) {
+                Err(e) => return Err(format!("Failed to create syncdb Store directory: {:?}: {:?}", new_path_par, e)),
+                Ok(_) => ()
+            }
+        }
+        match rename(&old_path, &new_path) {
+            Err(e) => Err(format!("Failed to move syncdb entry: {:?}: {:?}", old_path, e)),
+            Ok(_) => Ok(true)
+        }
+    }
+
+    pub fn has_keyed_sync_ids(&self) -> bool {
+        let mut marker = self.syncdb_dir.clone();
+        marker.push(KEYED_SYNC_IDS_MARKER);
+        marker.is_file()
+    }
+
+    pub fn set_keyed_sync_ids(&mut self) -> Result<(),String> {
+        let mut marker = self.syncdb_dir.clone();
+        marker.push(KEYED_SYNC_IDS_MARKER);
+        match File::create(&marker) {
+            Err(e) => Err(format!("Failed to create syncdb marker: {:?}: {:?}", marker, e)),
+            Ok(_) => Ok(())
         }
     }
 
@@ -164,6 +243,8 @@ mod tests {
 
     use std::path::{PathBuf};
     use std::env;
+    use std::fs::{File,create_dir_all};
+    use std::io::Write;
 
     use util;
     use syncdb;
@@ -200,7 +281,7 @@ mod tests {
         };
 
         let check_syncdb_empty = |syncdb:&mut syncdb::SyncDb| {
-            let entry = syncdb.get(&sf);
+            let entry = syncdb.get(&sf).unwrap();
             match entry {
                 None => (),
                 Some(_) => panic!("Syncdb should be empty, but has an entry")
@@ -209,6 +290,7 @@ mod tests {
         let check_entry = |entry: &syncdb::SyncEntry | {
             assert_eq!(entry.revguid, sf.revguid );
             assert_eq!(entry.native_mtime, mtime);
+            assert_eq!(entry.seq, sf.seq);
         };
 
         check_syncdb_empty(&mut syncdb);
@@ -220,7 +302,7 @@ mod tests {
 
         // entry should now exist
         {
-            let entry = syncdb.get(&sf).expect("Expected sync entry");
+            let entry = syncdb.get(&sf).unwrap().expect("Expected sync entry");
             check_entry(entry);
         }
 
@@ -232,8 +314,67 @@ mod tests {
 
         // entry should still exist, loaded from disk this time
         {
-            let entry = syncdb.get(&sf).expect("Expected sync entry");
+            let entry = syncdb.get(&sf).unwrap().expect("Expected sync entry");
             check_entry(entry);
         }
     }
+
+    #[test]
+    fn move_entry() {
+        let mut conf = testlib::util::get_mock_config();
+        let wd = env::current_dir().unwrap();
+        let mut syncdb_dir = PathBuf::from(&wd);
+        syncdb_dir.push("testdata");
+        syncdb_dir.push("out_syncdb_move_entry");
+        conf.syncdb_dir = Some(syncdb_dir.to_str().unwrap().to_owned());
+        testlib::util::clear_test_syncdb(&conf);
+
+        let mut syncdb = syncdb::SyncDb::new(&conf).unwrap();
+        assert!(!syncdb.has_keyed_sync_ids());
+
+        let mut syncpath = PathBuf::from(&wd);
+        syncpath.push("testdata");
+        syncpath.push("d759e740d8ecef87b9aa331b1e5edc3aeed133d51347beed735a802253b775b5.dat");
+        let sf = syncfile::SyncFile::from_syncfile(&conf,&syncpath).unwrap();
+
+        syncdb.update(&sf,1234).unwrap();
+        let new_sid = "ab0123";
+        assert!(syncdb.move_entry(&sf.id, new_sid).unwrap());
+        assert!(syncdb.get(&sf).unwrap().is_none());
+        assert_eq!(syncdb.get_by_sid(new_sid).unwrap().expect("Expected moved entry").revguid, sf.revguid);
+
+        // nothing left to move
+        assert!(!syncdb.move_entry(&sf.id, new_sid).unwrap());
+
+        syncdb.set_keyed_sync_ids().unwrap();
+        assert!(syncdb::SyncDb::new(&conf).unwrap().has_keyed_sync_ids());
+    }
+
+    #[test]
+    fn malformed_entry() {
+        let conf = testlib::util::get_mock_config();
+        testlib::util::clear_test_syncdb(&conf);
+        let mut syncdb = syncdb::SyncDb::new(&conf).unwrap();
+
+        let sid = "d759e740d8ecef87b9aa331b1e5edc3aeed133d51347beed735a802253b775b5";
+        let storepath = syncdb.get_store_path(sid);
+        create_dir_all(storepath.parent().unwrap()).unwrap();
+        for text in vec!["", "revguid", "revguid: nope\nnative_mtime: 1\n",
+                         "revguid: 67e55044-10b1-426f-9247-bb680e5fe0c8\nnative_mtime: -1\n",
+                         "revguid: 67e55044-10b1-426f-9247-bb680e5fe0c8\nnative_mtime: 1\nseq: x\n"] {
+            {
+                let mut f = File::create(&storepath).unwrap();
+                f.write_all(text.as_bytes()).unwrap();
+            }
+            syncdb.flush_cache();
+            assert!(syncdb.get_by_sid(sid).is_err(), "{:?}", text);
+        }
+
+        {
+            let mut f = File::create(&storepath).unwrap();
+            f.write_all(b"revguid: 67e55044-10b1-426f-9247-bb680e5fe0c8\nnative_mtime: 1\n").unwrap();
+        }
+        syncdb.flush_cache();
+        assert_eq!(syncdb.get_by_sid(sid).unwrap().unwrap().seq, 0);
+    }
 }
@@ -2,16 +2,21 @@ extern crate uuid;
 extern crate crypto;
 
 extern crate rustc_serialize;
+extern crate flate2;
 
 use util;
 use config;
+use kdf;
+use native_attrs;
 use crypto_util;
 use crypto_util::IV_SIZE;
 
 use std::str::FromStr;
-use std::collections::HashMap;
-use std::path::{PathBuf};
-use std::fs::{File,create_dir_all,rename,remove_file};
+use std::collections::{HashMap,BTreeMap};
+use std::path::{Path,PathBuf,Component};
+use std::fs::{File,create_dir_all,rename,remove_file,symlink_metadata,read_link};
+#[cfg(not(target_os = "windows"))]
+use std::os::unix::fs::symlink;
 use std::fs::{PathExt};
 use std::io::{Read, Write, BufReader, BufRead, SeekFrom, Seek, Result, Cursor};
 use std::io;
@@ -20,11 +25,333 @@ use util::make_err;
 use self::crypto::sha2::Sha256;
 use self::crypto::digest::Digest;
 use self::crypto::mac::{Mac,MacResult};
+use self::crypto::hmac::Hmac;
 use self::rustc_serialize::base64::{ToBase64, STANDARD, FromBase64 };
+use self::rustc_serialize::hex::ToHex;
+use self::rustc_serialize::json::Json;
+use self::flate2::write::{DeflateEncoder,DeflateDecoder};
+
+// New syncfiles begin with a plaintext preamble line, "GCSF:<format>:<key scheme>", that is
+// covered by the header hmac.  Files written before it existed begin with the base64 header
+// hmac instead, which can never contain a ':'; they are format 1 with the raw key scheme.
+// Files encrypted with a keyword's own key add a fourth field, its key id (see
+// crypto_util::key_id()); files without one use the master key.
+const PREAMBLE_MAGIC: &'static str = "GCSF";
+// Header lines followed by AES-256-CBC ciphertext; the data hmac is only checked at the end.
+const FORMAT_CBC: u32 = 1;
+// Header lines followed by AES-256-GCM chunks (see crypto_util::CHUNK_SIZE), each of which is
+// authenticated before its plaintext is used.  The iv line holds the salt for the per-file key,
+// and the metadata is also encrypted with GCM.
+const FORMAT_CHUNKED_GCM: u32 = 2;
+// Format 2 with an Ed25519 signature line, "<host>:<signature>", after the header hmac.  The
+// signature covers the same bytes as the header hmac, and the host must be the origin host named
+// in the metadata (see SyncConfig::signers).
+const FORMAT_SIGNED_GCM: u32 = 3;
+
+// No header line is anywhere near this long; it stops a file without newlines from being read
+// into memory whole.
+const MAX_HEADER_LINE_LEN: u64 = 1024 * 1024;
+
+struct Preamble {
+    format: u32,
+    key_scheme: u32,
+    key_id: Option<String>
+}
+
+impl Preamble {
+    fn current(key_id:Option<String>, signed:bool) -> Self {
+        let format = if signed { FORMAT_SIGNED_GCM } else { FORMAT_CHUNKED_GCM };
+        Preamble { format: format, key_scheme: crypto_util::KEY_SCHEME_HKDF, key_id: key_id }
+    }
+
+    // Returns None if the line is not a preamble (a legacy file)
+    fn parse(line:&str) -> Result<Option<Self>> {
+        let parts:Vec<&str> = line.split(':').collect();
+        if parts.len() == 1 {
+            return Ok(None);
+        }
+        if (parts.len() != 3 && parts.len() != 4) || parts[0] != PREAMBLE_MAGIC {
+            return make_err(&format!("Unrecognized syncfile preamble: {}", line));
+        }
+        let format = match u32::from_str(parts[1]) {
+            Err(e) => return make_err(&format!("Failed to parse syncfile format: {}: {}", line, e)),
+            Ok(f) => f
+        };
+        let key_scheme = match u32::from_str(parts[2]) {
+            Err(e) => return make_err(&format!("Failed to parse syncfile key scheme: {}: {}", line, e)),
+            Ok(k) => k
+        };
+        let key_id = match parts.get(3) {
+            None => None,
+            Some(id) if !id.is_empty() && id.chars().all(|c| c.is_digit(16)) => Some(id.to_lowercase()),
+            Some(_) => return make_err(&format!("Failed to parse syncfile key id: {}", line))
+        };
+        Ok(Some(Preamble { format: format, key_scheme: key_scheme, key_id: key_id }))
+    }
+
+    fn line(&self) -> String {
+        match self.key_id {
+            None => format!("{}:{}:{}", PREAMBLE_MAGIC, self.format, self.key_scheme),
+            Some(ref id) => format!("{}:{}:{}:{}", PREAMBLE_MAGIC, self.format, self.key_scheme, id)
+        }
+    }
+}
+
+// How much zero padding to add to a file's data before it is encrypted, so that the syncfile's
+// size doesn't give away the size of the file.  The amount is recorded in the (authenticated)
+// metadata and removed again on decrypt.  Set by Padding in the [General] section of the config.
+#[derive(Clone,Debug,PartialEq)]
+pub enum Padding {
+    None,
+    // up to the next power of two
+    PowerOfTwo,
+    // up to the next multiple of this many bytes
+    Block(u64)
+}
+
+impl Padding {
+    // Parse the config form: "none", "pow2" or "block:BYTES".
+    pub fn parse(spec:&str) -> ::std::result::Result<Self,String> {
+        let parts:Vec<&str> = spec.trim().split(':').collect();
+        match (parts[0], parts.len()) {
+            ("none", 1) => Ok(Padding::None),
+            ("pow2", 1) => Ok(Padding::PowerOfTwo),
+            ("block", 2) => {
+                match u64::from_str(parts[1].trim()) {
+                    Err(e) => Err(format!("Invalid block size in padding spec: {}: {}", spec, e)),
+                    Ok(0) => Err(format!("Padding block size must be at least 1: {}", spec)),
+                    Ok(n) => Ok(Padding::Block(n))
+                }
+            },
+            _ => Err(format!("Unrecognized padding spec: {}; expected none, pow2 or block:BYTES", spec))
+        }
+    }
+
+    // The number of padding bytes to add to size bytes of data.
+    pub fn pad_len(&self, size:u64) -> u64 {
+        match *self {
+            Padding::None => 0,
+            Padding::PowerOfTwo if size == 0 => 0,
+            Padding::PowerOfTwo => size.next_power_of_two() - size,
+            Padding::Block(n) => (n - size % n) % n
+        }
+    }
+}
+
+// How a file's data is compressed before it is encrypted (and padded).  Set by Compression in the
+// [General] section of the config; the codec used is recorded in each file's metadata.
+#[derive(Clone,Debug,PartialEq)]
+pub enum Compression {
+    None,
+    Deflate
+}
+
+impl Compression {
+    // Parse the config (and metadata) form: "none" or "deflate".
+    pub fn parse(spec:&str) -> ::std::result::Result<Self,String> {
+        match spec.trim() {
+            "none" => Ok(Compression::None),
+            "deflate" => Ok(Compression::Deflate),
+            _ => Err(format!("Unrecognized compression: {}; expected none or deflate", spec))
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Compression::None => "none",
+            Compression::Deflate => "deflate"
+        }
+    }
+}
+
+// Data is only compressed if a sample of this many bytes from its start shrinks to at most
+// COMPRESSION_MAX_PERCENT of its size; this skips files that are already compressed.
+const COMPRESSION_SAMPLE_SIZE: usize = 64 * 1024;
+const COMPRESSION_MAX_PERCENT: usize = 90;
+
+// Deflate all of input.  Returns the compressed data and the number of bytes read.
+fn deflate(input:&mut Read) -> Result<(Vec<u8>,u64)> {
+    let mut encoder = DeflateEncoder::new(Vec::new(), flate2::Compression::Default);
+    let read = try!(io::copy(input, &mut encoder));
+    Ok((try!(encoder.finish()), read))
+}
+
+fn worth_compressing(sample:&[u8]) -> Result<bool> {
+    if sample.is_empty() {
+        return Ok(false);
+    }
+    let (compressed, _) = try!(deflate(&mut Cursor::new(sample)));
+    Ok(compressed.len() * 100 <= sample.len() * COMPRESSION_MAX_PERCENT)
+}
+
+// A keyed hash of a file's plaintext and its size, recorded in the metadata so that a syncfile
+// can be compared with a native file, or with another syncfile, without decrypting it.  Text is
+// hashed in its canonical form (\n line endings).  Files written before it existed don't have one.
+#[derive(Clone,Debug,PartialEq)]
+pub struct ContentHash {
+    pub hash: String,
+    pub size: u64
+}
+
+fn content_hash_key(conf:&config::SyncConfig, kw:&str) -> Result<crypto_util::SecretKey> {
+    match conf.keyword_key(kw) {
+        None => make_err(&format!("No encryption key for keyword {}, can't compute content hash", kw)),
+        Some(k) => Ok(crypto_util::derive_content_hash_key(k))
+    }
+}
+
+// Where a symlink points, as a path under a mapped keyword (usually its own), so that it can be
+// pointed at the same file on hosts where the keyword is mapped to another directory.  relative
+// records whether the link was relative, so that it is restored that way.
+#[derive(Clone,Debug,PartialEq)]
+pub struct LinkTarget {
+    pub keyword: String,
+    pub relpath: String,
+    pub relative: bool
+}
+
+fn set_content_metadata(md:&mut Metadata, content:&ContentHash) {
+    md.insert("content_hash".to_owned(), Json::String(content.hash.clone()));
+    md.insert("size".to_owned(), Json::U64(content.size));
+}
+
+// Computes the content hash of everything that is read through it.
+struct HashingReader<'a> {
+    inner: &'a mut Read,
+    hmac: Hmac<Sha256>,
+    size: u64
+}
+
+impl<'a> HashingReader<'a> {
+    fn new(key:&[u8], inner:&'a mut Read) -> Self {
+        HashingReader { inner: inner, hmac: crypto_util::get_hmac(key, &[]), size: 0 }
+    }
+
+    fn finish(mut self) -> ContentHash {
+        ContentHash { hash: crypto_util::hmac_to_vec(&mut self.hmac).to_hex(), size: self.size }
+    }
+}
+
+impl<'a> Read for HashingReader<'a> {
+    fn read(&mut self, buf:&mut [u8]) -> Result<usize> {
+        let n = try!(self.inner.read(buf));
+        self.hmac.input(&buf[0 .. n]);
+        self.size = self.size + n as u64;
+        Ok(n)
+    }
+}
+
+fn hash_content(key:&[u8], data:&[u8]) -> Result<ContentHash> {
+    let mut cursor = Cursor::new(data);
+    let mut hasher = HashingReader::new(key, &mut cursor);
+    try!(io::copy(&mut hasher, &mut io::sink()));
+    Ok(hasher.finish())
+}
+
+// Metadata was originally written as "key: value" lines, which can't hold a relpath containing
+// a newline, and was parsed in a way that truncated values containing ':'.  It is now a JSON
+// object with typed values, recognized by its leading '{'; the old form is still read.  In both,
+// "ver" is the data format version (see pack_metadata).
+type Metadata = BTreeMap<String,Json>;
+
+// Fields that this version writes, along with native_attrs::METADATA_FIELDS; any others read from
+// a syncfile are kept and written back if it is saved again.
+const METADATA_FIELDS: [&'static str; 14] = ["ver", "kw", "relpath", "revguid", "is_binary", "is_deleted",
+    "seq", "pad", "compression", "content_hash", "size", "link_target", "origin_native_mtime", "origin_host"];
+
+fn parse_metadata(md:&str) -> Result<Metadata> {
+    if md.starts_with("{") {
+        return match Json::from_str(md) {
+            Err(e) => make_err(&format!("Failed to parse metadata: {}", e)),
+            Ok(Json::Object(mdmap)) => Ok(mdmap),
+            Ok(_) => make_err("Failed to parse metadata: not an object")
+        };
+    }
+
+    // "key: value" lines.  the values are converted to the types that the JSON form uses.
+    let mut mdmap = Metadata::new();
+    for l in md.lines() {
+        let parts:Vec<&str> = l.splitn(2, ':').collect();
+        if parts.len() != 2 {
+            return make_err(&format!("Malformed metadata line: {:?}", l));
+        }
+        let k = parts[0].trim().to_lowercase();
+        let v = parts[1].trim();
+        let v = match &k[..] {
+            "ver" | "seq" | "pad" | "origin_native_mtime" => match u64::from_str(v) {
+                Err(e) => return make_err(&format!("Failed to parse {}: {}", k, e)),
+                Ok(n) => Json::U64(n)
+            },
+            "is_binary" | "is_deleted" => match bool::from_str(v) {
+                Err(e) => return make_err(&format!("Failed to parse {} bool: {}", k, e)),
+                Ok(b) => Json::Boolean(b)
+            },
+            _ => Json::String(v.to_owned())
+        };
+        mdmap.insert(k, v);
+    }
+    Ok(mdmap)
+}
+
+fn md_str<'a>(mdmap:&'a Metadata, k:&str) -> Result<Option<&'a str>> {
+    match mdmap.get(k) {
+        None => Ok(None),
+        Some(&Json::String(ref v)) => Ok(Some(v)),
+        Some(v) => make_err(&format!("Metadata field {} should be a string, got: {}", k, v))
+    }
+}
+
+fn md_u64(mdmap:&Metadata, k:&str) -> Result<Option<u64>> {
+    match mdmap.get(k) {
+        None => Ok(None),
+        Some(&Json::U64(n)) => Ok(Some(n)),
+        Some(v) => make_err(&format!("Metadata field {} should be an unsigned integer, got: {}", k, v))
+    }
+}
+
+fn md_bool(mdmap:&Metadata, k:&str) -> Result<Option<bool>> {
+    match mdmap.get(k) {
+        None => Ok(None),
+        Some(&Json::Boolean(b)) => Ok(Some(b)),
+        Some(v) => make_err(&format!("Metadata field {} should be a bool, got: {}", k, v))
+    }
+}
+
+// A relpath names a file under its keyword's dir; one that could name anything else (through
+// "..", or an absolute path or drive once it is made native) is never written by greycrypt.
+fn relpath_is_safe(relpath:&str) -> bool {
+    if !relpath.starts_with("/") || relpath.contains('\0') {
+        return false;
+    }
+    let native = util::decanon_path(&relpath[1..]);
+    let native = Path::new(&native);
+    native.components().count() > 0 && native.components().all(|c| match c {
+        Component::Normal(_) => true,
+        _ => false
+    })
+}
+
+struct SyncFileHeader {
+    format: u32,
+    key_id: Option<String>,
+    // the host whose signature was verified, for signed files
+    signer: Option<String>,
+    keys: crypto_util::SubKeys,
+    syncid: String,
+    ivline: String,
+    mdline: String,
+    cipher_hmac: String
+}
 
 struct OpenFileState {
     handle: File,
-    iv: [u8;IV_SIZE]
+    format: u32,
+    // bytes of padding at the end of the data
+    pad: u64,
+    // applies to the data before the padding
+    compression: Compression,
+    iv: [u8;IV_SIZE],
+    keys: crypto_util::SubKeys
 }
 enum SyncFileState {
     Closed,
@@ -39,6 +366,19 @@ pub struct SyncFile {
     pub cipher_hmac: String,
     pub is_binary: bool,
     pub is_deleted: bool,
+    // Counts up with each change to the file (including deletion), so that an old copy put back
+    // in its place can be told from a real change; see SyncEntry::seq.  0 for files written
+    // before it existed.
+    pub seq: u64,
+    // None for files read from syncfiles written before it existed, and for files that haven't
+    // been saved yet
+    pub content: Option<ContentHash>,
+    // set if the native file is a symlink, which is stored with no data
+    pub link: Option<LinkTarget>,
+    // the attributes of the native file that the host that wrote it preserves
+    pub attrs: native_attrs::NativeAttrs,
+    // metadata fields from a newer version, see METADATA_FIELDS
+    extra_metadata: Metadata,
     sync_file_state: SyncFileState
 }
 
@@ -48,6 +388,18 @@ fn get_dummy_hmac() -> String {
     dummy_hmac    
 }
 
+// Read until buf is full or the input is exhausted; returns the number of bytes read.
+fn read_full(fin:&mut Read, buf:&mut [u8]) -> io::Result<usize> {
+    let mut total = 0;
+    while total < buf.len() {
+        match try!(fin.read(&mut buf[total ..])) {
+            0 => break,
+            n => total = total + n
+        }
+    }
+    Ok(total)
+}
+
 pub struct TempFileRemover {
     pub filename: String
 } 
@@ -55,7 +407,7 @@ pub struct TempFileRemover {
 impl Drop for TempFileRemover {
     fn drop(&mut self) {
         let pb = PathBuf::from(&self.filename);
-        if pb.is_file() {
+        if util::is_file_or_link(&pb) {
             match remove_file(&self.filename) {
                 Err(e) => warn!("Failed to remove temporary file: {}: {}", &self.filename, e),
                 Ok(_) => ()
@@ -65,12 +417,41 @@ impl Drop for TempFileRemover {
 }
 
 impl SyncFile {
-    pub fn get_sync_id(kw: &str, relpath: &str) -> String {
-        // make id from hash of kw + relpath
-        let mut hasher = Sha256::new();
-        hasher.input_str(kw);
-        hasher.input_str(&relpath.to_uppercase());
-        hasher.result_str()
+    // Fails if ids are keyed and this host has no key for kw, which happens when it maps a
+    // keyword whose key it wasn't given.
+    pub fn get_sync_id(conf:&config::SyncConfig, kw: &str, relpath: &str) -> ::std::result::Result<String,String> {
+        match conf.sync_ids {
+            kdf::SyncIdScheme::Sha256 => {
+                // make id from hash of kw + relpath
+                let mut hasher = Sha256::new();
+                hasher.input_str(kw);
+                hasher.input_str(&relpath.to_uppercase());
+                Ok(hasher.result_str())
+            },
+            kdf::SyncIdScheme::Hmac => {
+                // keyed, so the id can't be used to confirm a guessed path.  the separator keeps
+                // different kw/relpath splits from colliding.
+                let key = match conf.keyword_key(kw) {
+                    None => return Err(format!("No encryption key for keyword {}, can't compute sync id", kw)),
+                    Some(k) => k
+                };
+                let keys = crypto_util::SubKeys::derive(key);
+                let mut hmac = crypto_util::get_hmac(&keys.sync_id, kw.as_bytes());
+                hmac.input(&[0]);
+                hmac.input(relpath.to_uppercase().as_bytes());
+                Ok(crypto_util::hmac_to_vec(&mut hmac).to_hex())
+            }
+        }
+    }
+
+    // The path that a syncfile with the given id normally has.
+    pub fn get_default_path(conf:&config::SyncConfig, sid: &str) -> PathBuf {
+        let mut syncpath = PathBuf::from(&conf.sync_dir());
+        let prefix = &sid[0..2];
+        syncpath.push(prefix);
+        syncpath.push(sid);
+        syncpath.set_extension("dat");
+        syncpath
     }
 
     // Return the sync id and syncfile path for a given native file.  Note, the path in
@@ -85,13 +466,11 @@ impl SyncFile {
                 Some((kw,relpath)) => (kw,relpath)
             }
         };
-        let idstr = SyncFile::get_sync_id(kw,&relpath);
-
-        let mut syncpath = PathBuf::from(&conf.sync_dir());
-        let prefix = &idstr.to_owned()[0..2];
-        syncpath.push(prefix);
-        syncpath.push(&idstr);
-        syncpath.set_extension("dat");
+        let idstr = match SyncFile::get_sync_id(conf,kw,&relpath) {
+            Err(e) => return make_err(&e),
+            Ok(id) => id
+        };
+        let syncpath = SyncFile::get_default_path(conf,&idstr);
 
         Ok((idstr,syncpath))
     }
@@ -106,6 +485,11 @@ impl SyncFile {
             cipher_hmac: self.cipher_hmac.to_owned(),
             is_binary: self.is_binary,
             is_deleted: true,
+            seq: self.seq + 1,
+            content: None,
+            link: None,
+            attrs: native_attrs::NativeAttrs::none(),
+            extra_metadata: self.extra_metadata.clone(),
             sync_file_state: SyncFileState::Closed
         };
     }
@@ -119,11 +503,19 @@ impl SyncFile {
             }
         };
 
-        let idstr = SyncFile::get_sync_id(kw,&relpath);
+        let idstr = match SyncFile::get_sync_id(conf,kw,&relpath) {
+            Err(e) => return make_err(&e),
+            Ok(id) => id
+        };
 
-        let is_binary = match util::file_is_binary(nativefile) {
-            Err(e) => return make_err(&format!("Failed to check binary status: {:?}", e)),
-            Ok(isb) => isb
+        // a link's target isn't opened; it is synced (or not) in its own right
+        let link = try!(SyncFile::read_link_target(conf, nativefile));
+        let is_binary = match link {
+            Some(_) => true,
+            None => match util::file_is_binary(nativefile) {
+                Err(e) => return make_err(&format!("Failed to check binary status: {:?}", e)),
+                Ok(isb) => isb
+            }
         };
 
         let ret = SyncFile {
@@ -135,6 +527,11 @@ impl SyncFile {
             cipher_hmac: get_dummy_hmac(),
             is_binary: is_binary,
             is_deleted: false,
+            seq: 1,
+            content: None,
+            link: link,
+            attrs: native_attrs::NativeAttrs::none(),
+            extra_metadata: Metadata::new(),
             sync_file_state: SyncFileState::Closed
         };
 
@@ -147,8 +544,14 @@ impl SyncFile {
         let mut lines:Vec<String> = Vec::new();
         for i in 0 .. count {
             let mut line = String::new();
-            match reader.read_line(&mut line) {
+            match (&mut reader).take(MAX_HEADER_LINE_LEN).read_line(&mut line) {
                 Err(e) => return make_err(&format!("Failed to read header line {} from syncfile: {}", i, e)),
+                Ok(_) if !line.ends_with("\n") => {
+                    if line.len() as u64 == MAX_HEADER_LINE_LEN {
+                        return make_err(&format!("Header line {} in syncfile is too long", i));
+                    }
+                    return make_err(&format!("Syncfile header is truncated at line {}", i));
+                },
                 Ok(_) => {
                     lines.push(line.trim().to_owned());
                 }
@@ -173,21 +576,35 @@ impl SyncFile {
         if !syncpath.is_file() {
             return make_err(&format!("Syncfile does not exist: {:?}", syncpath));
         }    
-        let key = match conf.encryption_key {
-            None => return make_err(&"No encryption key".to_owned()),
-            Some(k) => k
+
+        let fin = match File::open(syncpath.to_str().unwrap()) {
+            Err(e) => return make_err(&format!("Can't open syncfile: {:?}: {}", syncpath, e)),
+            Ok(fin) => fin
         };
 
+        let header = try!(SyncFile::read_and_verify_header(&fin, conf));
+        Ok(header.syncid)
+    }
+
+    // The id of the key that the syncfile is encrypted with, from its preamble; None for the
+    // master key.
+    pub fn get_key_id(syncpath:&PathBuf) -> Result<Option<String>> {
         let fin = match File::open(syncpath.to_str().unwrap()) {
             Err(e) => return make_err(&format!("Can't open syncfile: {:?}: {}", syncpath, e)),
             Ok(fin) => fin
         };
+        let first = try!( SyncFile::read_top_lines(&fin,1) );
+        Ok(try!(Preamble::parse(&first[0])).and_then(|p| p.key_id))
+    }
 
-        let (syncid,_,_,_) = try!(SyncFile::read_and_verify_header(&fin, &key));
-        Ok(syncid)
+    // False if the syncfile was encrypted with a key that conf doesn't have, such as the key
+    // of a keyword that hasn't been shared with this host.  Only reads the preamble.
+    pub fn key_available(conf:&config::SyncConfig, syncpath:&PathBuf) -> Result<bool> {
+        let key_id = try!(SyncFile::get_key_id(syncpath));
+        Ok(conf.key_for_id(key_id.as_ref().map(|id| &id[..])).is_some())
     }
     
-    fn verify_header_hmac(key: &[u8;config::KEY_SIZE], header_hmac:&str, header_lines:&Vec<&String>) -> Result<()> {
+    fn verify_header_hmac(key: &[u8;config::KEY_SIZE], header_hmac:&str, header_lines:&Vec<String>) -> Result<()> {
         // dump the lines into a buffer and verify the hmac
         let mut buf:Vec<u8> = Vec::new();
         for l in header_lines {
@@ -200,6 +617,9 @@ impl SyncFile {
         };
         
         let mut computed_hmac = crypto_util::get_hmac(key, &buf);
+        if hmac_bytes.len() != computed_hmac.output_bytes() {
+            return make_err(&format!("Header hmac has the wrong length: {}", hmac_bytes.len()));
+        }
         let expected_hmac = MacResult::new(&hmac_bytes); 
         
         if computed_hmac.result() != expected_hmac {
@@ -209,30 +629,115 @@ impl SyncFile {
         }
     }
     
-    // Returns header lines:
-    // (syncid,ivline,mdline,cipher_hmac)
-    fn read_and_verify_header(fin:&File, key: &[u8;config::KEY_SIZE]) -> 
-        Result<(String,String,String,String)> {
-        let lines = try!( SyncFile::read_top_lines(&fin,5) );
-        let header_hmac = lines[0].clone();
-        let header_lines:Vec<&String> = lines.iter().skip(1).collect(); 
+    // The bytes that a syncfile signature covers: the signer's name, then the same lines as the
+    // header hmac.
+    fn signed_message(signer:&str, hmac_input:&[u8]) -> Result<Vec<u8>> {
+        let mut msg:Vec<u8> = Vec::new();
+        try!(writeln!(msg, "greycrypt syncfile signature:{}", signer));
+        try!(msg.write_all(hmac_input));
+        Ok(msg)
+    }
+
+    // Returns the name of the host that signed the header, if it is one of conf's signers and the
+    // signature is valid.
+    fn verify_signature(conf:&config::SyncConfig, sigline:&str, header_lines:&Vec<String>) -> Result<String> {
+        let (signer, sig) = match sigline.rfind(':') {
+            None => return make_err(&format!("Invalid signature line in syncfile header")),
+            Some(i) => (&sigline[.. i], &sigline[i + 1 ..])
+        };
+        let sig = match sig.from_base64() {
+            Err(e) => return make_err(&format!("Failed to extract header signature: error {:?}", e)),
+            Ok(s) => s
+        };
+        let public = match conf.signers.get(signer) {
+            None => return make_err(&format!("Syncfile is signed by host {}, which is not a trusted signer", signer)),
+            Some(k) => k
+        };
+        let mut buf:Vec<u8> = Vec::new();
+        for l in header_lines {
+            try!(writeln!(buf, "{}", l));
+        }
+        let msg = try!(SyncFile::signed_message(signer, &buf));
+        if !crypto_util::ed25519_verify(public, &msg, &sig) {
+            return make_err(&format!("Header signature from host {} is not valid; file modified by unauthorized agent", signer));
+        }
+        Ok(signer.to_owned())
+    }
+
+    // Picks the key from the preamble's key id.
+    fn read_and_verify_header(fin:&File, conf:&config::SyncConfig) -> Result<SyncFileHeader> {
+        let no_key = |key_id:Option<&str>| {
+            match key_id {
+                None => make_err(&"No encryption key".to_owned()),
+                Some(id) => make_err(&format!("No key for this syncfile's keyword (key id {}); it may not be shared with this host", id))
+            }
+        };
+        let first = try!( SyncFile::read_top_lines(&fin,1) );
+        let (format,key_id,keys,header_hmac,sigline,header_lines) = match try!(Preamble::parse(&first[0])) {
+            None => {
+                // legacy file, first line is the header hmac
+                let key = match conf.key_for_id(None) {
+                    None => return no_key(None),
+                    Some(k) => k
+                };
+                let lines = try!( SyncFile::read_top_lines(&fin,4) );
+                (FORMAT_CBC, None, crypto_util::SubKeys::raw(key), first[0].clone(), None, lines)
+            },
+            Some(preamble) => {
+                let key = match conf.key_for_id(preamble.key_id.as_ref().map(|id| &id[..])) {
+                    None => return no_key(preamble.key_id.as_ref().map(|id| &id[..])),
+                    Some(k) => k
+                };
+                if preamble.format != FORMAT_CBC && preamble.format != FORMAT_CHUNKED_GCM && preamble.format != FORMAT_SIGNED_GCM {
+                    return make_err(&format!("Unsupported syncfile format {}; a newer version of greycrypt may be required", preamble.format));
+                }
+                let keys = match crypto_util::SubKeys::for_scheme(key, preamble.key_scheme) {
+                    None => return make_err(&format!("Unsupported syncfile key scheme {}; a newer version of greycrypt may be required", preamble.key_scheme)),
+                    Some(keys) => keys
+                };
+                // the preamble is covered by the header hmac, which is the next line; the
+                // signature, if any, follows that
+                let signed = preamble.format == FORMAT_SIGNED_GCM;
+                let mut lines = try!( SyncFile::read_top_lines(&fin, if signed { 6 } else { 5 }) );
+                let header_hmac = lines.remove(0);
+                let sigline = if signed { Some(lines.remove(0)) } else { None };
+                lines.insert(0, first[0].clone());
+                (preamble.format, preamble.key_id.clone(), keys, header_hmac, sigline, lines)
+            }
+        };
         
-        try!(SyncFile::verify_header_hmac(key, &header_hmac, &header_lines));
+        try!(SyncFile::verify_header_hmac(&keys.header_mac, &header_hmac, &header_lines));
                 
         // if any lines are empty, its an error
-        if lines.iter().any(|l| l.trim() == "") {
+        if header_hmac.trim() == "" || header_lines.iter().any(|l| l.trim() == "") {
             return make_err(&format!("Found empty line in syncfile header, file is invalid, may need to be removed"));
         }    
-    
-        Ok((lines[1].to_owned(), lines[2].to_owned(), lines[3].to_owned(), lines[4].to_owned()))
-    }
 
-    fn init_sync_read(conf:&config::SyncConfig, syncpath:&PathBuf) -> Result<(File,String,[u8;IV_SIZE],HashMap<String,String>,String)> {
-        let key = match conf.encryption_key {
-            None => return make_err(&"No encryption key".to_owned()),
-            Some(k) => k
+        let signer = match sigline {
+            None => None,
+            Some(ref l) => Some(try!(SyncFile::verify_signature(conf, l, &header_lines)))
         };
+    
+        // the id is used in paths, so it must look like one that get_sync_id() makes
+        let n = header_lines.len();
+        let syncid = &header_lines[n-4];
+        if syncid.len() != 64 || !syncid.chars().all(|c| c.is_digit(16)) {
+            return make_err(&format!("Invalid sync id in syncfile header: {:?}", syncid));
+        }
+
+        Ok(SyncFileHeader {
+            format: format,
+            key_id: key_id,
+            signer: signer,
+            keys: keys,
+            syncid: header_lines[n-4].to_owned(),
+            ivline: header_lines[n-3].to_owned(),
+            mdline: header_lines[n-2].to_owned(),
+            cipher_hmac: header_lines[n-1].to_owned()
+        })
+    }
 
+    fn init_sync_read(conf:&config::SyncConfig, syncpath:&PathBuf) -> Result<(File,SyncFileHeader,[u8;IV_SIZE],Metadata)> {
         if !syncpath.is_file() {
             return make_err(&format!("Syncfile does not exist: {:?}", syncpath));
         }
@@ -248,12 +753,12 @@ impl SyncFile {
             Ok(fin) => fin
         };
         
-        let (syncid,ivline,mdline,cipher_hmac) = match SyncFile::read_and_verify_header(&fin, &key) {
+        let header = match SyncFile::read_and_verify_header(&fin, conf) {
             Err(e) => return make_err(&format!("Can't open syncfile: {:?}: {}", syncpath, e)),
-            Ok(stuff) => stuff
+            Ok(header) => header
         };
 
-        let iv = match ivline.from_base64() {
+        let iv = match header.ivline.from_base64() {
             Err(e) => return make_err(&format!("Unable to parse IV line: {}", e)),
             Ok(iv) => iv
         };
@@ -261,53 +766,41 @@ impl SyncFile {
             return make_err(&format!("Unexpected IV length: {}", iv.len()));
         }
 
-        // make crypto helper
-        let key:&[u8] = &key;
-        let iv:&[u8] = &iv;
-        let mut crypto = crypto_util::CryptoHelper::new(key,iv);
-
-        let md = mdline.from_base64();
+        let md = header.mdline.from_base64();
         let md = match md {
             Err(e) => return make_err(&format!("Failed to unpack metadata: error {:?}, line: {:?}", e, md)),
             Ok(md) => md
         };
 
-        let md = match crypto.decrypt(&md,true) {
-            Err(e) => return make_err(&format!("Failed to decrypt meta data; Error: {:?}", e)),
-            Ok(md) => md
-        }; 
+        let md = if header.format == FORMAT_CBC {
+            // make crypto helper
+            let iv:&[u8] = &iv;
+            let mut crypto = crypto_util::CryptoHelper::new(&header.keys.content,&header.keys.data_mac,iv);
+            match crypto.decrypt(&md,true) {
+                Err(e) => return make_err(&format!("Failed to decrypt meta data; Error: {:?}", e)),
+                Ok(md) => crypto_util::SecretBuf::from_vec(md)
+            }
+        } else {
+            let file_key = crypto_util::derive_file_key(&header.keys.content, &iv);
+            match crypto_util::open_metadata(&file_key, &md) {
+                None => return make_err(&format!("Failed to decrypt meta data; authentication failed")),
+                Some(md) => md
+            }
+        };
         
-        let md = match String::from_utf8(md) {
+        let mdmap = match ::std::str::from_utf8(&md) {
             Err(e) => return make_err(&format!("Failed to unpack utf8 metadata string: {:?}", e)),
-            Ok(md) => md
+            Ok(md) => try!(parse_metadata(md))
         };
-        let md:Vec<&str> = md.lines().collect();
 
-        // first line should be version, check that
-        {
-            let verline = md[0];
-            let verparts:Vec<&str> = verline.split(":").collect();
-            if verparts[0].trim() != "ver" {
-                return make_err(&format!("expected first line of metadata to be file version, got: {:?}", verparts[0]));
-            }
-            if verparts[1].trim() != "1" { // lame
-                return make_err(&format!("unexpected file version, got: {:?}", verline));
-            }
+        // version 2 adds padding, version 3 compression and version 4 symlinks
+        match md_u64(&mdmap, "ver") {
+            Err(e) => return Err(e),
+            Ok(None) => return make_err("Key 'ver' is required in metadata"),
+            Ok(Some(v)) if v < 1 || v > 4 => return make_err(&format!("unexpected file version, got: {}", v)),
+            Ok(Some(_)) => ()
         }
 
-        // build md map and parse md - a little tedious, but lets us error out if any of the
-        // values that we need are missing.
-        let mdmap = {
-            let mut mdmap:HashMap<String,String> = HashMap::new();
-            for l in md {
-                let parts:Vec<&str> = l.split(':').collect();
-                let k = parts[0].trim();
-                let v = parts[1].trim();
-                mdmap.insert(k.to_lowercase(),v.to_owned());
-            }
-            mdmap
-        };
-
         // :(
         // http://stackoverflow.com/questions/29570607/is-there-a-good-way-to-convert-a-vect-to-an-array
         let mut iv_copy:[u8;IV_SIZE] = [0;IV_SIZE];
@@ -315,37 +808,65 @@ impl SyncFile {
             iv_copy[i] = iv[i]
         }
 
-        Ok((fin,syncid.to_owned(),iv_copy,mdmap,cipher_hmac.to_owned()))
+        Ok((fin,header,iv_copy,mdmap))
     }
 
+    // The metadata fields, with the values as display strings.
     pub fn get_metadata_hash(conf:&config::SyncConfig, syncpath:&PathBuf) -> Result<HashMap<String,String>> {
-        let (_,_,_,mdmap,_) = match SyncFile::init_sync_read(conf,syncpath) {
+        let (_,_,_,mdmap) = match SyncFile::init_sync_read(conf,syncpath) {
             Err(e) => return Err(e),
             Ok(stuff) => stuff
         };
-        Ok(mdmap)
+        Ok(mdmap.into_iter().map(|(k,v)| {
+            let v = match v {
+                Json::String(v) => v,
+                v => v.to_string()
+            };
+            (k,v)
+        }).collect())
     }
 
     pub fn from_syncfile(conf:&config::SyncConfig, syncpath:&PathBuf) -> Result<SyncFile> {
-        let (fin,_,iv,mdmap,cipher_hmac) = match SyncFile::init_sync_read(conf,syncpath) {
+        let (fin,header,iv,mdmap) = match SyncFile::init_sync_read(conf,syncpath) {
             Err(e) => return Err(e),
             Ok(stuff) => stuff
         };
 
         let keyword:String = {
-            match mdmap.get("kw") {
+            match try!(md_str(&mdmap, "kw")) {
                 None => return make_err(&format!("Key 'kw' is required in metadata")),
                 Some(v) => v.to_owned()
             }
         };
+        // otherwise anyone with a shared keyword's key could write files into the others
+        if !conf.key_id_allowed(&keyword, header.key_id.as_ref().map(|id| &id[..])) {
+            return make_err(&format!("Syncfile for keyword {} is not encrypted with that keyword's key", keyword));
+        }
+        // hosts that sign their syncfiles always do, so anything else claiming to be from them
+        // is forged.  Once any host signs, unsigned files are only taken from hosts in [Mapping]
+        // that haven't been enrolled yet, so they can't claim to be from anywhere else either.
+        {
+            let origin_host = try!(md_str(&mdmap, "origin_host")).unwrap_or("");
+            match header.signer {
+                Some(ref signer) if signer != origin_host =>
+                    return make_err(&format!("Syncfile claims to be from host {}, but is signed by host {}", origin_host, signer)),
+                Some(_) => (),
+                None if conf.signers.contains_key(origin_host) =>
+                    return make_err(&format!("Syncfile claims to be from host {}, which signs its syncfiles, but is not signed", origin_host)),
+                None if !conf.signers.is_empty() && !conf.known_hosts.iter().any(|h| h == origin_host) =>
+                    return make_err(&format!("Syncfile claims to be from host {}, which is not a known host, and is not signed", origin_host)),
+                None => ()
+            }
+        }
         let relpath = {
-            match mdmap.get("relpath") {
+            match try!(md_str(&mdmap, "relpath")) {
                 None => return make_err(&format!("Key 'relpath' is required in metadata")),
+                Some(v) if !relpath_is_safe(v) => return make_err(&format!("Unsafe relpath in metadata: {:?}", v)),
                 Some(v) => v.to_owned()
             }
         };
         let revguid = {
-            match mdmap.get("revguid") {
+            match try!(md_str(&mdmap, "revguid")) {
                 None => return make_err(&format!("Key 'revguid' is required in metadata")),
                 Some(v) => {
                     match uuid::Uuid::parse_str(v) {
@@ -356,28 +877,51 @@ impl SyncFile {
             }
         };
         let is_binary = {
-            match mdmap.get("is_binary") {
+            match try!(md_bool(&mdmap, "is_binary")) {
                 None => return make_err(&format!("Key 'is_binary' is required in metadata")),
-                Some(v) => {
-                    match bool::from_str(v) {
-                        Err(e) => return make_err(&format!("Failed to parse is_binary bool: {}", e)),
-                        Ok(b) => b
-                    }
-                }
+                Some(b) => b
             }
         };
-        let is_deleted = {
-            match mdmap.get("is_deleted") {
-                // if it ain't there it ain't deleted
-                None => false,
+        // if it ain't there it ain't deleted
+        let is_deleted = try!(md_bool(&mdmap, "is_deleted")).unwrap_or(false);
+        let seq = try!(md_u64(&mdmap, "seq")).unwrap_or(0);
+        let pad = try!(md_u64(&mdmap, "pad")).unwrap_or(0);
+        let compression = {
+            match try!(md_str(&mdmap, "compression")) {
+                None => Compression::None,
                 Some(v) => {
-                    match bool::from_str(v) {
-                        Err(e) => return make_err(&format!("Failed to parse is_deleted bool: {}", e)),
-                        Ok(b) => b
+                    match Compression::parse(v) {
+                        Err(e) => return make_err(&format!("{}; a newer version of greycrypt may be required", e)),
+                        Ok(c) => c
                     }
                 }
             }
         };
+        let content = match (try!(md_str(&mdmap, "content_hash")), try!(md_u64(&mdmap, "size"))) {
+            (None, None) => None,
+            (Some(h), Some(size)) if h.len() == 64 && h.chars().all(|c| c.is_digit(16)) =>
+                Some(ContentHash { hash: h.to_lowercase(), size: size }),
+            _ => return make_err("Invalid content hash in metadata")
+        };
+        let attrs = try!(native_attrs::NativeAttrs::from_metadata(&mdmap));
+        let link = match mdmap.get("link_target") {
+            None => None,
+            Some(&Json::Object(ref t)) => {
+                match (try!(md_str(t, "kw")), try!(md_str(t, "relpath")), try!(md_bool(t, "relative"))) {
+                    (Some(kw), Some(rp), Some(relative)) if !kw.is_empty() && relpath_is_safe(rp) =>
+                        Some(LinkTarget { keyword: kw.to_owned(), relpath: rp.to_owned(), relative: relative }),
+                    _ => return make_err("Invalid link target in metadata")
+                }
+            },
+            Some(_) => return make_err("Invalid link target in metadata")
+        };
+        if (pad > 0 || compression != Compression::None) && header.format == FORMAT_CBC {
+            return make_err(&format!("Padding and compression are not supported in format {} syncfiles", FORMAT_CBC));
+        }
+
+        let extra_metadata:Metadata = mdmap.into_iter()
+            .filter(|&(ref k,_)| !METADATA_FIELDS.contains(&&k[..]) && !native_attrs::METADATA_FIELDS.contains(&&k[..]))
+            .collect();
 
         // :(
         // http://stackoverflow.com/questions/29570607/is-there-a-good-way-to-convert-a-vect-to-an-array
@@ -387,10 +931,17 @@ impl SyncFile {
         }
         let ofs = OpenFileState {
             handle: fin,
-            iv: iv_copy
+            format: header.format,
+            pad: pad,
+            compression: compression,
+            iv: iv_copy,
+            keys: header.keys
         };
 
-        let idstr = SyncFile::get_sync_id(&keyword,&relpath);
+        let idstr = match SyncFile::get_sync_id(conf,&keyword,&relpath) {
+            Err(e) => return make_err(&e),
+            Ok(id) => id
+        };
 
         let mut sf = SyncFile {
             id: idstr,
@@ -398,9 +949,14 @@ impl SyncFile {
             relpath: relpath,
             revguid: revguid,
             nativefile: "".to_owned(),
-            cipher_hmac: cipher_hmac,
+            cipher_hmac: header.cipher_hmac,
             is_binary: is_binary,
             is_deleted: is_deleted,
+            seq: seq,
+            content: content,
+            link: link,
+            attrs: attrs,
+            extra_metadata: extra_metadata,
             sync_file_state: SyncFileState::Open(ofs)
         };
 
@@ -428,14 +984,40 @@ impl SyncFile {
         }
     }
 
-    fn pack_metadata(&self, conf:&config::SyncConfig, v:&mut Vec<u8>) -> io::Result<()> {
-        let md_format_ver = 1;
-        try!(writeln!(v, "ver: {}", md_format_ver));
-        try!(writeln!(v, "kw: {}", self.keyword));
-        try!(writeln!(v, "relpath: {}", self.relpath));
-        try!(writeln!(v, "revguid: {}", self.revguid));
-        try!(writeln!(v, "is_binary: {}", self.is_binary));
-        try!(writeln!(v, "is_deleted: {}", self.is_deleted));
+    fn pack_metadata(&self, conf:&config::SyncConfig, pad:u64, compression:&Compression, content:&ContentHash) -> io::Result<Metadata> {
+        // older versions can't remove padding, decompress or make links (they would restore a
+        // link as an empty file), so "ver" records whether the file needs a reader that can.
+        // (versions from before the JSON encoding can't read any of these files.)
+        let md_format_ver = if self.link.is_some() { 4 } else if *compression != Compression::None { 3 } else if pad > 0 { 2 } else { 1 };
+        let mut md = self.extra_metadata.clone();
+        md.insert("ver".to_owned(), Json::U64(md_format_ver));
+        md.insert("kw".to_owned(), Json::String(self.keyword.clone()));
+        md.insert("relpath".to_owned(), Json::String(self.relpath.clone()));
+        md.insert("revguid".to_owned(), Json::String(self.revguid.to_string()));
+        md.insert("is_binary".to_owned(), Json::Boolean(self.is_binary));
+        md.insert("is_deleted".to_owned(), Json::Boolean(self.is_deleted));
+        md.insert("seq".to_owned(), Json::U64(self.seq));
+        if pad > 0 {
+            md.insert("pad".to_owned(), Json::U64(pad));
+        }
+        if *compression != Compression::None {
+            md.insert("compression".to_owned(), Json::String(compression.name().to_owned()));
+        }
+        set_content_metadata(&mut md, content);
+        if let Some(ref link) = self.link {
+            let mut t = Metadata::new();
+            t.insert("kw".to_owned(), Json::String(link.keyword.clone()));
+            t.insert("relpath".to_owned(), Json::String(link.relpath.clone()));
+            t.insert("relative".to_owned(), Json::Boolean(link.relative));
+            md.insert("link_target".to_owned(), Json::Object(t));
+        }
+        // a link's attributes would be read from (and restored to) what it points to
+        if !self.is_deleted && self.link.is_none() {
+            match native_attrs::NativeAttrs::read(&conf.preserve, &self.nativefile) {
+                Err(e) => return Err(io::Error::new(io::ErrorKind::Other, format!("Failed to read file attributes: {}",e))),
+                Ok(attrs) => attrs.to_metadata(&mut md)
+            }
+        }
 
         // additional fields that aren't required for sync but are helpful for resolving conflicts
         let mtime = {
@@ -448,13 +1030,109 @@ impl SyncFile {
                 0
             }
         };
-        try!(writeln!(v, "origin_native_mtime: {}", mtime));
-        try!(writeln!(v, "origin_host: {}", conf.host_name));
+        md.insert("origin_native_mtime".to_owned(), Json::U64(mtime));
+        md.insert("origin_host".to_owned(), Json::String(conf.host_name.clone()));
 
-        Ok(())
+        Ok(md)
+    }
+
+    // Format 1: decrypt the whole stream; the data hmac is checked by the caller afterwards.
+    fn decrypt_cbc(ofs:&OpenFileState, out:&mut Write) -> Result<Hmac<Sha256>> {
+        // make crypto helper, using the keys that the header was verified with
+        let iv:&[u8] = &ofs.iv;
+        let mut crypto = crypto_util::CryptoHelper::new(&ofs.keys.content,&ofs.keys.data_mac,iv);
+
+        let mut fin = &ofs.handle;
+
+        let mut buf:[u8;65536] = [0; 65536];
+
+        loop {
+            let read_res = fin.read(&mut buf);
+            match read_res {
+                Err(e) => { return make_err(&format!("Read error: {}", e)) },
+                Ok(num_read) => {
+                    let enc_bytes = &buf[0 .. num_read];
+                    let eof = num_read == 0;
+                    let res = crypto.decrypt(enc_bytes, eof);
+                    match res {
+                        Err(e) => return make_err(&format!("Encryption error: {:?}", e)),
+                        Ok(d) => try!(out.write_all(&d))
+                    }
+                    if eof {
+                        break;
+                    }
+                }
+            }
+        }
+
+        Ok(crypto.decrypt_hmac)
     }
 
-    fn decrypt_helper(&mut self, conf:&config::SyncConfig, out:&mut Write) -> Result<()> {
+    // Format 2: each chunk is authenticated before its plaintext is written to out.
+    fn decrypt_chunked(ofs:&OpenFileState, out:&mut Write) -> Result<Hmac<Sha256>> {
+        let file_key = crypto_util::derive_file_key(&ofs.keys.content, &ofs.iv);
+        let mut hmac = crypto_util::get_hmac(&ofs.keys.data_mac, &[]);
+
+        let mut fin = &ofs.handle;
+
+        // the amount of real data, so that the padding after it isn't written to out.  every
+        // chunk has a tag, and the final one is shorter than a full chunk.
+        let data_len = {
+            let start = try!(fin.seek(SeekFrom::Current(0)));
+            let sealed_len = try!(fin.metadata()).len().saturating_sub(start);
+            let num_chunks = sealed_len / (crypto_util::CHUNK_SIZE + crypto_util::TAG_SIZE) as u64 + 1;
+            let plain_len = sealed_len.saturating_sub(num_chunks * crypto_util::TAG_SIZE as u64);
+            if ofs.pad > plain_len {
+                return make_err(&format!("Syncfile padding ({}) is longer than its data ({})", ofs.pad, plain_len));
+            }
+            plain_len - ofs.pad
+        };
+        let mut written:u64 = 0;
+
+        // use vec to heap alloc the buffer
+        let mut buf: Vec<u8> = vec![0; crypto_util::CHUNK_SIZE + crypto_util::TAG_SIZE];
+        let mut index:u64 = 0;
+
+        loop {
+            let num_read = match read_full(&mut fin, &mut buf) {
+                Err(e) => return make_err(&format!("Read error: {}", e)),
+                Ok(n) => n
+            };
+            if num_read < crypto_util::TAG_SIZE {
+                return make_err(&format!("Syncfile data is truncated at chunk {}", index));
+            }
+            // only the final chunk is short
+            let is_final = num_read < buf.len();
+            let sealed = &buf[0 .. num_read];
+            hmac.input(sealed);
+
+            match crypto_util::open_chunk(&file_key, index, is_final, sealed) {
+                None => return make_err(&format!("Syncfile data chunk {} failed authentication; possible truncation, reordering or modification", index)),
+                Some(d) => {
+                    let keep = ::std::cmp::min(d.len() as u64, data_len - written);
+                    try!(out.write_all(&d[0 .. keep as usize]));
+                    written = written + keep;
+                }
+            }
+
+            if is_final {
+                break;
+            }
+            index = index + 1;
+        }
+
+        // nothing is allowed after the final chunk
+        let mut extra:[u8;1] = [0; 1];
+        match fin.read(&mut extra) {
+            Err(e) => return make_err(&format!("Read error: {}", e)),
+            Ok(0) => (),
+            Ok(_) => return make_err(&format!("Unexpected data after final chunk in syncfile"))
+        }
+
+        Ok(hmac)
+    }
+
+    fn decrypt_helper(&mut self, out:&mut Write) -> Result<()> {
         {
             let ofs = {
                 match self.sync_file_state {
@@ -462,40 +1140,21 @@ impl SyncFile {
                     _ => return make_err(&"Sync file not open".to_owned())
                 }
             };
-            let key = match conf.encryption_key {
-                None => return make_err(&"No encryption key".to_owned()),
-                Some(k) => k
+
+            let mut computed_hmac = if ofs.format == FORMAT_CBC {
+                try!(SyncFile::decrypt_cbc(ofs, out))
+            } else if ofs.compression == Compression::Deflate {
+                let mut inflater = DeflateDecoder::new(&mut *out);
+                let hmac = try!(SyncFile::decrypt_chunked(ofs, &mut inflater));
+                try!(inflater.finish());
+                hmac
+            } else {
+                try!(SyncFile::decrypt_chunked(ofs, out))
             };
-            // make crypto helper
-            let key:&[u8] = &key;
-            let iv:&[u8] = &ofs.iv;
-            let mut crypto = crypto_util::CryptoHelper::new(key,iv);
-
-            let mut fin = &ofs.handle;
-
-            let mut buf:[u8;65536] = [0; 65536];
-
-            loop {
-                let read_res = fin.read(&mut buf);
-                match read_res {
-                    Err(e) => { return make_err(&format!("Read error: {}", e)) },
-                    Ok(num_read) => {
-                        let enc_bytes = &buf[0 .. num_read];
-                        let eof = num_read == 0;
-                        let res = crypto.decrypt(enc_bytes, eof);
-                        match res {
-                            Err(e) => return make_err(&format!("Encryption error: {:?}", e)),
-                            Ok(d) => try!(out.write_all(&d))
-                        }
-                        if eof {
-                            match out.flush() {
-                                Err(e) => return make_err(&format!("Failed to flush output reader: {}",e)),
-                                Ok(_) => ()
-                            }
-                            break;
-                        }
-                    }
-                }
+
+            match out.flush() {
+                Err(e) => return make_err(&format!("Failed to flush output reader: {}",e)),
+                Ok(_) => ()
             }
             
             // verify hmac
@@ -504,7 +1163,9 @@ impl SyncFile {
                 Ok(d) => d        
             };
             
-            let mut computed_hmac = crypto.decrypt_hmac;
+            if hmac_bytes.len() != computed_hmac.output_bytes() {
+                return make_err(&format!("Data hmac has the wrong length: {}", hmac_bytes.len()));
+            }
             let expected_hmac = MacResult::new(&hmac_bytes); 
             
             if computed_hmac.result() != expected_hmac {
@@ -522,26 +1183,123 @@ impl SyncFile {
         self.sync_file_state = SyncFileState::Closed;
     }
 
-    pub fn decrypt_to_writer(&mut self, conf:&config::SyncConfig, out:&mut Write) -> Result<()> {
+    pub fn decrypt_to_writer(&mut self, _conf:&config::SyncConfig, out:&mut Write) -> Result<()> {
         // if file is binary, can go directly to target_out.  otherwise, have to
         // stream to intermediate buffer and nativize the line endings.
         if self.is_binary {
-            self.decrypt_helper(conf,out)
+            self.decrypt_helper(out)
         } else {
-            let mut temp_out:Vec<u8> = Vec::new();
+            // the plaintext is no bigger than what the content hash records, where there is one
+            let capacity = self.content.as_ref().map(|c| c.size as usize).unwrap_or(0);
+            let mut temp_out = crypto_util::SecretBuf::with_capacity(capacity);
 
-            try!(self.decrypt_helper(conf,&mut temp_out));
+            try!(self.decrypt_helper(&mut *temp_out));
 
-            let s = String::from_utf8(temp_out).unwrap();
-            let s = util::decanon_lines(&s);
-            let temp_out = s.as_bytes();
-            //println!("dec: {:?}", String::from_utf8(temp_out.clone()).unwrap());
-            try!(out.write_all(&temp_out));
+            let mut s = match ::std::str::from_utf8(&temp_out) {
+                Err(e) => return make_err(&format!("Text syncfile does not contain utf8: {}", e)),
+                Ok(s) => util::decanon_lines(s)
+            };
+            let res = out.write_all(s.as_bytes());
+            crypto_util::zero_bytes(unsafe { s.as_mut_vec() });
+            res
+        }
+    }
 
-            Ok(())
+    // The target of nativefile if it is a symlink, or None if it isn't one.  A link to anything
+    // outside of the mapped directories is an error, since it couldn't be restored on other
+    // hosts, and it could expose files that aren't meant to be synced.
+    pub fn read_link_target(conf:&config::SyncConfig, nativefile:&str) -> Result<Option<LinkTarget>> {
+        if !try!(symlink_metadata(nativefile)).file_type().is_symlink() {
+            return Ok(None);
+        }
+        let target = try!(read_link(nativefile));
+        let nativepath = PathBuf::from(nativefile);
+        let abs_target = util::normalize_path(&nativepath.parent().unwrap().join(&target));
+        let abs_target = match abs_target.to_str() {
+            None => return make_err(&format!("Symlink target is not valid unicode: {}: {:?}", nativefile, target)),
+            Some(t) => t
+        };
+        match conf.mapping.get_kw_relpath(abs_target) {
+            None => make_err(&format!("Symlink points outside of the mapped directories: {} -> {:?}", nativefile, target)),
+            Some((kw,relpath)) => Ok(Some(LinkTarget {
+                keyword: kw.to_owned(),
+                relpath: relpath,
+                relative: target.is_relative()
+            }))
+        }
+    }
+
+    // The path to give a symlink at linkpath that is restored on this host.
+    fn link_path(conf:&config::SyncConfig, link:&LinkTarget, linkpath:&Path) -> Result<PathBuf> {
+        let mut target = match conf.mapping.lookup_dir(&link.keyword) {
+            None => return make_err(&format!("Symlink target keyword {} is not mapped on this host", link.keyword)),
+            Some(dir) => PathBuf::from(dir)
+        };
+        target.push(&util::decanon_path(&link.relpath[1..]));
+        if !link.relative {
+            return Ok(target);
+        }
+        let linkdir = util::normalize_path(linkpath.parent().unwrap());
+        Ok(util::relative_path(&linkdir, &util::normalize_path(&target)))
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    fn make_symlink(target:&Path, path:&str) -> Result<()> {
+        if util::is_file_or_link(Path::new(path)) {
+            try!(remove_file(path));
+        }
+        match symlink(target, path) {
+            Err(e) => make_err(&format!("Failed to create symlink: {:?}: {:?}", path, e)),
+            Ok(_) => Ok(())
         }
     }
 
+    // TODO: windows can make symlinks, but only with a privilege that most users don't have;
+    // link syncfiles are skipped there (see core::filter_syncfiles()).
+    #[cfg(target_os = "windows")]
+    fn make_symlink(target:&Path, path:&str) -> Result<()> {
+        make_err(&format!("Can't create symlink {:?} to {:?}; symlinks are not supported on this platform", path, target))
+    }
+
+    // Whether nativefile holds the data recorded in this syncfile's content hash, which is
+    // decided without decrypting anything; None if the syncfile predates content hashes.  For a
+    // link, whether nativefile is a link to the same place.
+    pub fn native_matches(&self, conf:&config::SyncConfig, nativefile:&str) -> Result<Option<bool>> {
+        let content = match self.content {
+            None => return Ok(None),
+            Some(ref c) => c
+        };
+        if try!(symlink_metadata(nativefile)).file_type().is_symlink() || self.link.is_some() {
+            let native_link = try!(SyncFile::read_link_target(conf, nativefile));
+            return Ok(Some(native_link == self.link));
+        }
+        let key = try!(content_hash_key(conf, &self.keyword));
+        let mut fin = match File::open(nativefile) {
+            Err(e) => return make_err(&format!("Can't open native file: {}: {}", nativefile, e)),
+            Ok(fin) => fin
+        };
+        let native = if self.is_binary {
+            // binary data is stored as is, so its size alone can rule it out
+            if try!(fin.metadata()).len() != content.size {
+                return Ok(Some(false));
+            }
+            let mut hasher = HashingReader::new(&key[..], &mut fin);
+            try!(io::copy(&mut hasher, &mut io::sink()));
+            hasher.finish()
+        } else {
+            let mut line_bytes = crypto_util::SecretBuf::with_capacity(try!(fin.metadata()).len() as usize);
+            try!(fin.read_to_end(&mut *line_bytes));
+            let mut canon = match ::std::str::from_utf8(&line_bytes) {
+                Err(_) => return Ok(Some(false)),
+                Ok(l) => util::canon_lines(l)
+            };
+            let hash = hash_content(&key[..], canon.as_bytes());
+            crypto_util::zero_bytes(unsafe { canon.as_mut_vec() });
+            try!(hash)
+        };
+        Ok(Some(native == *content))
+    }
+
     pub fn restore_native(&mut self, conf:&config::SyncConfig) -> Result<String> {
         { // check to make sure file is open, scoped to prevent borrow conflicts
             match self.sync_file_state {
@@ -572,33 +1330,49 @@ impl SyncFile {
         let tmp_outpath = format!("{}.gc_tmp", outpath); // this is actually easier than trying to use PathBuf to append the extension
         let remover = TempFileRemover { filename: tmp_outpath.to_owned() };
         let _ = remover; // silence warning
-        {
-            let res = File::create(&tmp_outpath);
-            let mut fout = match res {
-                Err(e) => return make_err(&format!("Failed to create output file: {:?}: {:?}", tmp_outpath, e)),
-                Ok(f) => f
-            };
-    
-            match self.decrypt_to_writer(conf,&mut fout) {
-                Err(e) => return make_err(&format!("Failed to decrypt file: {:?}: {:?}", outpath, e)),
-                Ok(_) => ()
+        if let Some(ref link) = self.link {
+            // a link has no data, so there is nothing to decrypt
+            let target = try!(SyncFile::link_path(conf, link, &outpath_pb));
+            try!(SyncFile::make_symlink(&target, &tmp_outpath));
+        } else {
+            {
+                let res = File::create(&tmp_outpath);
+                let mut fout = match res {
+                    Err(e) => return make_err(&format!("Failed to create output file: {:?}: {:?}", tmp_outpath, e)),
+                    Ok(f) => f
+                };
+
+                match self.decrypt_to_writer(conf,&mut fout) {
+                    Err(e) => return make_err(&format!("Failed to decrypt file: {:?}: {:?}", outpath, e)),
+                    Ok(_) => ()
+                }
+            }
+
+            // the attributes are set before the file is moved, so that it doesn't appear without
+            // them.  a file system that can't hold them shouldn't stop the data from being restored.
+            if let Err(e) = self.attrs.apply(&conf.preserve, &tmp_outpath) {
+                warn!("Failed to restore file attributes of {}: {}", outpath, e);
             }
         }
-        
+
         // succeeded, move file over
         try!(rename(tmp_outpath, outpath));
 
         Ok(outpath.to_owned())
     }
     
-    fn open_output_syncfile(&self, conf:&config::SyncConfig, override_path: Option<PathBuf>) -> Result<(String,String,File)> {
-        let (sid,outpath) = match SyncFile::get_sync_id_and_path(conf,&self.nativefile) {
-            Err(e) => return make_err(&format!("Can't get id/path: {:?}", e)),
-            Ok(pair) => pair
+    // The file is opened at a temporary path next to the syncfile; the caller moves it over the
+    // syncfile once it is complete, so that a failed save leaves the previous version intact.
+    fn open_output_syncfile(&self, conf:&config::SyncConfig, override_path: Option<PathBuf>) -> Result<(String,String,String,File)> {
+        // use the keyword and relpath rather than the native path, since the keyword may not be
+        // mapped on this host (e.g. when re-encrypting).
+        let sid = match SyncFile::get_sync_id(conf,&self.keyword,&self.relpath) {
+            Err(e) => return make_err(&e),
+            Ok(id) => id
         };
 
         let outpath = match override_path {
-            None => outpath,
+            None => SyncFile::get_default_path(conf,&sid),
             Some(path) => path
         };
 
@@ -612,44 +1386,41 @@ impl SyncFile {
         }
 
         let outname = outpath.to_str().unwrap();
-        let fout = match File::create(outname) {
+        let tmp_outname = format!("{}.gc_tmp", outname);
+        let fout = match File::create(&tmp_outname) {
             Err(e) => return make_err(&format!("Can't create output file: {:?}", e)),
             Ok(f) => f
         };    
         
-        Ok((sid.to_owned(),outname.to_owned(),fout))
+        Ok((sid.to_owned(),outname.to_owned(),tmp_outname,fout))
     }
     
-    fn get_iv_and_key(&self, conf:&config::SyncConfig) -> Result<([u8;IV_SIZE],[u8;config::KEY_SIZE])> {
-        let key = match conf.encryption_key {
-            None => return make_err(&format!("No encryption key")),
+    // Uses the keyword's own key if it has one; the returned preamble names it.
+    fn get_iv_and_keys(&self, conf:&config::SyncConfig) -> Result<([u8;IV_SIZE],crypto_util::SubKeys,Preamble)> {
+        let key = match conf.file_key(&self.keyword) {
+            None => return make_err(&format!("No encryption key for keyword {}", self.keyword)),
             Some(k) => k
         };
             
         // create random iv
         let iv = crypto_util::get_iv();
         
-        Ok((iv,key))
+        Ok((iv,crypto_util::SubKeys::derive(key),Preamble::current(conf.file_key_id(&self.keyword), conf.signing_key.is_some())))
     }
         
-    fn write_syncfile_header<T: Write>(&self, conf:&config::SyncConfig, sid:&str, key: &[u8;config::KEY_SIZE], iv: &[u8;IV_SIZE], out: &mut T) -> Result<(())> {
-        // make crypto helper
-        let mut crypto = crypto_util::CryptoHelper::new(key,iv);
+    fn write_syncfile_header<T: Write>(sid:&str, keys: &crypto_util::SubKeys, iv: &[u8;IV_SIZE], md: &Metadata, out: &mut T) -> Result<(())> {
+        let file_key = crypto_util::derive_file_key(&keys.content, iv);
 
         // write sync id to file (unencrypted)
         try!(writeln!(out, "{}", sid));
         
-        // write iv to file (unencrypted, base64 encoded)
+        // write iv (the file key salt) to file (unencrypted, base64 encoded)
         try!(writeln!(out, "{}", iv.to_base64(STANDARD)));
-        // write metadata (encrypted, base64 encoded string)
+        // write metadata (encrypted, base64 encoded string).  it is sealed on its own so that it
+        // can be decrypted without needing to read the whole file.
         let mut v:Vec<u8> = Vec::new();
-        try!(self.pack_metadata(conf, &mut v));
-        // pass true to indicate EOF so that the metadata can be decrypted without needing to read
-        // the whole file.
-        let md_ciphertext = match crypto.encrypt(&v[..], true) {
-            Err(e) => return make_err(&format!("Encryption error: {:?}", e)),
-            Ok(d) => d
-        };
+        try!(write!(v, "{}", Json::Object(md.clone())));
+        let md_ciphertext = crypto_util::seal_metadata(&file_key, &v[..]);
                 
         {
             let b64_out = md_ciphertext[..].to_base64(STANDARD);
@@ -661,111 +1432,231 @@ impl SyncFile {
         
         // write an hmac for zero-length ciphertext data, will update later if data is attached
         let dummy_data:[u8;0] = [0;0];
-        let mut hmac = crypto_util::get_hmac(key, &dummy_data);
+        let mut hmac = crypto_util::get_hmac(&keys.data_mac, &dummy_data);
         try!(writeln!(out, "{}", crypto_util::hmac_to_vec(&mut hmac).to_base64(STANDARD)));
         
         Ok(())
     }
 
-    pub fn mark_deleted_and_save(&mut self, conf:&config::SyncConfig, override_path: Option<PathBuf>) -> Result<String> {
-        self.set_deleted();
-        let (iv,key) = try!(self.get_iv_and_key(conf));
-        
-        let (sid,outname,mut fout) = try!(self.open_output_syncfile(conf,override_path));
-        
-        let mut temp:Vec<u8> = Vec::new();
-                
-        match self.write_syncfile_header(conf,&sid,&key,&iv,&mut temp) {
-            Err(e) => return make_err(&format!("Failed to write syncfile header: {}", e)),
-            Ok(stuff) => stuff
+    // The signature line for a signed file, signed with conf's signing key; with a zero
+    // signature if hmac_input is None, to reserve space for it.
+    fn signature_line(conf:&config::SyncConfig, hmac_input:Option<&[u8]>) -> Result<String> {
+        let sig = match (hmac_input, conf.signing_key.as_ref()) {
+            (Some(input), Some(key)) => {
+                let msg = try!(SyncFile::signed_message(&conf.host_name, input));
+                crypto_util::ed25519_sign(key, &msg)
+            },
+            _ => [0; crypto_util::SIGNATURE_SIZE]
         };
-        
-        // compute hmac
-        let header_hmac = crypto_util::hmac_to_vec(&mut crypto_util::get_hmac(&key, &temp)).to_base64(STANDARD);
-        // rewrite header fo file
+        Ok(format!("{}:{}", conf.host_name, sig.to_base64(STANDARD)))
+    }
+
+    // Write the preamble, header hmac, signature (for signed files) and header lines at the start
+    // of the file.  The header hmac and signature cover the preamble and the header lines.
+    fn write_final_header(conf:&config::SyncConfig, fout:&mut File, preamble:&Preamble, keys: &crypto_util::SubKeys, headerbuf:&Vec<u8>) -> Result<()> {
+        let signed = preamble.format == FORMAT_SIGNED_GCM;
+        let preamble = preamble.line();
+
+        let mut hmac_input:Vec<u8> = Vec::new();
+        try!(writeln!(hmac_input, "{}", preamble));
+        try!(hmac_input.write_all(&headerbuf));
+        let header_hmac = crypto_util::hmac_to_vec(&mut crypto_util::get_hmac(&keys.header_mac, &hmac_input)).to_base64(STANDARD);
+
         try!(fout.seek(SeekFrom::Start(0)));
+        try!(writeln!(fout, "{}", preamble));
         try!(writeln!(fout, "{}", header_hmac));
-        try!(fout.write_all(&temp));
-        
-        Ok(outname)
+        if signed {
+            try!(writeln!(fout, "{}", try!(SyncFile::signature_line(conf, Some(&hmac_input)))));
+        }
+        try!(fout.write_all(&headerbuf));
+        Ok(())
     }
-    
-    fn save<T: Read>(&self, conf:&config::SyncConfig, input_data: &mut BufReader<T>, override_path: Option<PathBuf>) -> Result<String> {
+
+    pub fn mark_deleted_and_save(&mut self, conf:&config::SyncConfig, override_path: Option<PathBuf>) -> Result<String> {
+        self.set_deleted();
+        // no data, but still write the (empty) final chunk
+        self.save_with_data(conf, override_path, &[])
+    }
+
+    // Encrypt all of the input as chunks, writing them to out.  Returns the hmac of the
+    // ciphertext.
+    fn write_chunks(keys: &crypto_util::SubKeys, iv: &[u8;IV_SIZE], input: &mut Read, out: &mut Write) -> Result<Hmac<Sha256>> {
+        let file_key = crypto_util::derive_file_key(&keys.content, iv);
+        let mut hmac = crypto_util::get_hmac(&keys.data_mac, &[]);
+
+        // use vec to heap alloc the buffer
+        let mut buf: Vec<u8> = vec![0; crypto_util::CHUNK_SIZE];
+        let mut index:u64 = 0;
+
+        loop {
+            let num_read = try!(read_full(input, &mut buf));
+            // a full chunk is never final; if the data is an exact multiple of the chunk size,
+            // an empty final chunk follows.
+            let is_final = num_read < buf.len();
+            let sealed = crypto_util::seal_chunk(&file_key, index, is_final, &buf[0 .. num_read]);
+            hmac.input(&sealed);
+            try!(out.write_all(&sealed));
+
+            if is_final {
+                break;
+            }
+            index = index + 1;
+        }
+
+        Ok(hmac)
+    }
+
+    // size is the length of input_data, which is needed up front to record the padding.
+    fn save<T: Read>(&self, conf:&config::SyncConfig, input_data: &mut BufReader<T>, size: u64, override_path: Option<PathBuf>) -> Result<String> {
         // save n lines of base64-encoded headers followed by the binary ciphertext. 
-        // use two HMACs.  The first covers the header lines and metadata, and is the first line of the file.
+        // use two HMACs.  The first covers the preamble, header lines and metadata, and follows the preamble line.
         // the second covers the ciphertext and is the last header line.
         
-        // write the header lines to a temporary buffer, use a temporary value for the ciphertext hmac.  
-        // write the temp header to the file to reserve space for the final header.  
+        // write the header lines to a temporary buffer, use a temporary value for the ciphertext hmac
+        // (and for the content hash, if the data is streamed).  reserve space for the final header
+        // in the file with zeros; the temporary metadata is never written, since it is sealed with
+        // the same nonce as the final metadata.
         // write the ciphertext and compute its hmac.  
         // update the ciphertext hmac in the header buffer, compute the header hmac,
         // and write the final header to the beginning of the file.
         // this is a bit of hoop-jumping, but it lets us have all the data in a single file 
         // and only do IO on the ciphertext once.
-        let (iv,key) = try!(self.get_iv_and_key(conf));
-        let (sid,outname,mut fout) = try!(self.open_output_syncfile(conf,override_path));
+        let (iv,keys,preamble) = try!(self.get_iv_and_keys(conf));
+        let hash_key = try!(content_hash_key(conf, &self.keyword));
+
+        // for text files, read them in and normalized the line endings (use \n), so that
+        // the (decrypted) binary value is same on all platforms.  this is required for de-dup
+        // comparisons.  when unpacking to native on a target platform, we'll restore the
+        // proper line endings
+        // the plaintext buffers are wiped when dropped
+        let text_data = if self.is_binary {
+            None
+        } else {
+            let mut line_bytes = crypto_util::SecretBuf::with_capacity(size as usize);
+            try!(input_data.read_to_end(&mut *line_bytes));
+            match ::std::str::from_utf8(&line_bytes) {
+                Err(e) => return make_err(&format!("Failed to read alleged text file: {}; Error: {}", &self.nativefile, e)),
+                Ok(l) => Some(crypto_util::SecretBuf::from_vec(util::canon_lines(l).into_bytes()))
+            }
+        };
+
+        let mut content = match text_data {
+            Some(ref d) => Some(try!(hash_content(&hash_key[..], d))),
+            None => None
+        };
+
+        // binary files are only read up front if compression is tried, and then only a sample
+        let mut sample = crypto_util::SecretBuf::with_capacity(0);
+        let compression = match conf.compression {
+            Compression::None => Compression::None,
+            ref c => {
+                let worth = match text_data {
+                    Some(ref d) => try!(worth_compressing(&d[0 .. ::std::cmp::min(d.len(), COMPRESSION_SAMPLE_SIZE)])),
+                    None => {
+                        sample = crypto_util::SecretBuf::zeroed(::std::cmp::min(size, COMPRESSION_SAMPLE_SIZE as u64) as usize);
+                        let n = try!(read_full(input_data, &mut sample));
+                        sample.truncate(n);
+                        try!(worth_compressing(&sample))
+                    }
+                };
+                if worth { c.clone() } else { Compression::None }
+            }
+        };
+
+        // compressed data is kept in memory, since its size is needed for the padding;
+        // uncompressed binary files are streamed
+        let stored = match (&compression, text_data) {
+            (&Compression::None, d) => d,
+            (_, Some(d)) => Some(crypto_util::SecretBuf::from_vec(try!(deflate(&mut Cursor::new(&d[..]))).0)),
+            (_, None) => {
+                let rest = size - sample.len() as u64;
+                let mut input = Cursor::new(&sample[..]).chain((&mut *input_data).take(rest));
+                let mut hasher = HashingReader::new(&hash_key[..], &mut input);
+                let (compressed, read) = try!(deflate(&mut hasher));
+                if read < size {
+                    return make_err(&format!("File changed while it was being saved: {}", &self.nativefile));
+                }
+                content = Some(hasher.finish());
+                Some(crypto_util::SecretBuf::from_vec(compressed))
+            }
+        };
+        let plain_size = size;
+        let size = match stored {
+            None => size,
+            Some(ref d) => d.len() as u64
+        };
+        let pad = conf.padding.pad_len(size);
+
+        let (sid,outname,tmp_outname,mut fout) = try!(self.open_output_syncfile(conf,override_path));
+        let remover = TempFileRemover { filename: tmp_outname.to_owned() };
+        let _ = remover; // silence warning
         
+        // a streamed file's hash isn't known until it has been read, but its size is, and the
+        // hash is always the same length
+        let streamed = content.is_none();
+        let placeholder = ContentHash { hash: ::std::iter::repeat('0').take(64).collect(), size: plain_size };
+        let mut md = try!(self.pack_metadata(conf, pad, &compression, content.as_ref().unwrap_or(&placeholder)));
+
         let mut headerbuf:Vec<u8> = Vec::new();
         
-        match self.write_syncfile_header(conf,&sid,&key,&iv,&mut headerbuf) {
+        match SyncFile::write_syncfile_header(&sid,&keys,&iv,&md,&mut headerbuf) {
             Err(e) => return make_err(&format!("Failed to write syncfile header: {}", e)),
             Ok(_) => ()
         };
         
-        // write dummy hmac and header to file to set file position for cipher data
+        // write preamble, dummy hmac, dummy signature and reserved header to file to set file
+        // position for cipher data
         let d = get_dummy_hmac();
+        try!(writeln!(fout, "{}", preamble.line()));
         try!(writeln!(fout, "{}", d));
-        try!(fout.write_all(&headerbuf));
+        if preamble.format == FORMAT_SIGNED_GCM {
+            try!(writeln!(fout, "{}", try!(SyncFile::signature_line(conf, None))));
+        }
+        try!(fout.write_all(&vec![0; headerbuf.len()]));
 
         // get current file position for verification later        
         let orig_header_end = try!(fout.seek(SeekFrom::Current(0)));
 
-        // remake crypto helper for file data
-        let mut crypto = crypto_util::CryptoHelper::new(&key,&iv);
-        
-        if self.is_binary {
-            // stream-encrypt binary files
-
-            // use vec to heap alloc the buffer
-            const SIZE: usize = 1048576;
-            let mut v: Vec<u8> = vec![0;SIZE];
-            let mut buf = &mut v;
-
-            loop {
-                let num_read = try!(input_data.read(buf));
-                let enc_bytes = &buf[0 .. num_read];
-                let eof = num_read == 0;
-                let res = crypto.encrypt(enc_bytes, eof);
-                match res {
-                    Err(e) => return make_err(&format!("Encryption error: {:?}", e)),
-                    Ok(d) => try!(fout.write_all(&d))
-                }
-                //println!("encrypted {} bytes",num_read);
-                if eof {
-                    break;
+        // stream-encrypt binary files; the data is followed by the padding.  if the file gets
+        // shorter while it is being read, the recorded padding would be wrong, so that fails; it
+        // will be saved again on the next sync.
+        let (mut data_hmac, short_by) = {
+            let mut stored_cursor;
+            let mut binary_input;
+            let input:&mut Read = match stored {
+                None => { binary_input = Cursor::new(&sample[..]).chain(input_data); &mut binary_input },
+                Some(ref d) => { stored_cursor = Cursor::new(&d[..]); &mut stored_cursor }
+            };
+            let mut data = input.take(size);
+            let hmac = {
+                let mut hasher = HashingReader::new(&hash_key[..], &mut data);
+                let hmac = {
+                    let mut padded = (&mut hasher).chain(io::repeat(0).take(pad));
+                    try!(SyncFile::write_chunks(&keys, &iv, &mut padded, &mut fout))
+                };
+                if streamed {
+                    content = Some(hasher.finish());
                 }
-            }
-        } else {
-            // for text files, read them in and normalized the line endings (use \n), so that
-            // the (decrypted) binary value is same on all platforms.  this is required for de-dup
-            // comparisons.  when unpacking to native on a target platform, we'll restore the
-            // proper line endings
-            let mut line_bytes:Vec<u8> = Vec::new();
-            try!(input_data.read_to_end(&mut line_bytes));
-            let line_str = match String::from_utf8(line_bytes) {
-                Err(e) => return make_err(&format!("Failed to read alleged text file: {}; Error: {}", &self.nativefile, e)),
-                Ok(ref l) => util::canon_lines(l)
+                hmac
             };
-
-            let line_bytes = line_str.as_bytes();
-
-            let enc_bytes = &line_bytes[0 .. line_bytes.len()];
-
-            match crypto.encrypt(enc_bytes, true) {
-                Err(e) => return make_err(&format!("Encryption error: {:?}", e)),
-                Ok(d) => try!(fout.write_all(&d))
-            }
+            (hmac, data.limit())
+        };
+        if short_by > 0 {
+            return make_err(&format!("File changed while it was being saved: {}", &self.nativefile));
         }
+
+        // now that the content hash is known, the metadata can be sealed
+        let headerbuf = if streamed {
+            set_content_metadata(&mut md, content.as_ref().unwrap());
+            let mut headerbuf:Vec<u8> = Vec::new();
+            match SyncFile::write_syncfile_header(&sid,&keys,&iv,&md,&mut headerbuf) {
+                Err(e) => return make_err(&format!("Failed to write syncfile header: {}", e)),
+                Ok(_) => ()
+            };
+            headerbuf
+        } else {
+            headerbuf
+        };
         
         // update the ciphertext hmac at the end of the header lines
         let headerbuf = {           
@@ -780,49 +1671,63 @@ impl SyncFile {
             for l in &lines {
                 try!(writeln!(headerbuf, "{}", l));
             } 
-            try!(writeln!(headerbuf, "{}", crypto_util::hmac_to_vec(&mut crypto.encrypt_hmac).to_base64(STANDARD)));
+            try!(writeln!(headerbuf, "{}", crypto_util::hmac_to_vec(&mut data_hmac).to_base64(STANDARD)));
             
             assert!(headerbuf.len() == orig_len, format!("Mismatched header len: orig: {}, new: {}", orig_len, headerbuf.len()));
              
             headerbuf
         };
         
-        let header_hmac = crypto_util::hmac_to_vec(&mut crypto_util::get_hmac(&key, &headerbuf)).to_base64(STANDARD);
         // rewrite header to file
-        try!(fout.seek(SeekFrom::Start(0)));
-        try!(writeln!(fout, "{}", header_hmac));
-        try!(fout.write_all(&headerbuf));
+        try!(SyncFile::write_final_header(conf, &mut fout, &preamble, &keys, &headerbuf));
         
         let header_end = try!(fout.seek(SeekFrom::Current(0)));
         assert!(header_end == orig_header_end, format!("Mismatched header len: orig: {}, new: {}", header_end, orig_header_end));
 
+        // succeeded, move file over
+        drop(fout);
+        try!(rename(&tmp_outname, &outname));
+
         Ok(outname.to_owned())            
     }
 
     pub fn read_native_and_save(&self, conf:&config::SyncConfig, override_path: Option<PathBuf>) -> Result<String> {
+        if self.link.is_some() {
+            return self.save_with_data(conf, override_path, &[]);
+        }
         let fin = match File::open(&self.nativefile) {
             Err(e) => return make_err(&format!("Can't open input native file: {}: {}", &self.nativefile, e)),
             Ok(fin) => fin
         };
+        let size = try!(fin.metadata()).len();
         
         let mut br = BufReader::new(fin);
         
-        self.save(conf,&mut br,override_path)
+        self.save(conf,&mut br,size,override_path)
     }
     
-    pub fn save_with_data(&self, conf:&config::SyncConfig, override_path: Option<PathBuf>, data: Vec<u8>) -> Result<String> {
+    // data is borrowed, so that a caller holding plaintext can keep it in a SecretBuf.
+    pub fn save_with_data(&self, conf:&config::SyncConfig, override_path: Option<PathBuf>, data: &[u8]) -> Result<String> {
+        let size = data.len() as u64;
         let cursor = Cursor::new(data);
         let mut br = BufReader::new(cursor);
-        self.save(conf,&mut br,override_path)
+        self.save(conf,&mut br,size,override_path)
     }
 
     pub fn create_syncfile(conf:&config::SyncConfig, nativepath:&PathBuf, override_path: Option<PathBuf>) -> Result<(String,SyncFile)> {
+        SyncFile::create_syncfile_with_seq(conf, nativepath, override_path, 1)
+    }
+
+    // Same as create_syncfile(), for a new version of an existing file; seq must be higher than
+    // that of any earlier version.
+    pub fn create_syncfile_with_seq(conf:&config::SyncConfig, nativepath:&PathBuf, override_path: Option<PathBuf>, seq: u64) -> Result<(String,SyncFile)> {
         let res = SyncFile::from_native(&conf, nativepath.to_str().unwrap());
-        let sf = match res {
+        let mut sf = match res {
             Err(e) => return make_err(&format!("Failed to create sync file: {:?}", e)),
             Ok(sf) => sf
             
         };
+        sf.seq = seq;
 
         let res = sf.read_native_and_save(&conf, override_path);
         match res {
@@ -835,16 +1740,20 @@ impl SyncFile {
 #[cfg(test)]
 mod tests {
     use std::env;
-    // use std::fs::File;
-    // use std::io::Write;
-    use std::path::{PathBuf};
+    use std::collections::HashMap;
+    use std::fs::{File,PathExt};
+    use std::io::{Write,BufReader,Cursor};
+    use std::path::PathBuf;
     use util;
     use mapping;
     use syncfile;
     use testlib;
     use crypto_util;
+    use native_attrs;
 
     extern crate toml;
+    extern crate rustc_serialize;
+    use self::rustc_serialize::json::Json;
 
     #[test]
     fn write_read_syncfile() {
@@ -872,7 +1781,7 @@ mod tests {
         match res {
             Err(e) => panic!("Error {:?}", e),
             Ok(sf) => {
-                let eid = syncfile::SyncFile::get_sync_id(&sf.keyword,&sf.relpath);
+                let eid = syncfile::SyncFile::get_sync_id(&conf,&sf.keyword,&sf.relpath).unwrap();
                 assert_eq!(eid,sf.id);
                 assert_eq!(eid,file_syncid);
                 assert_eq!(sf.keyword, "GCPROJROOT");
@@ -881,6 +1790,7 @@ mod tests {
                 assert_eq!(sf.nativefile, savetp);
                 assert_eq!(sf.is_binary, false);
                 assert_eq!(sf.is_deleted, false);
+                assert_eq!(sf.seq, 1);
                 // file should be open
                 if let syncfile::SyncFileState::Open(ref ofs) = sf.sync_file_state {
                         // assume handle is valid (will check anyway when we read data)
@@ -1024,6 +1934,669 @@ mod tests {
         }
     }
 
+    #[test]
+    fn preamble_and_key_scheme() {
+        let conf = testlib::util::get_mock_config();
+        let wd = env::current_dir().unwrap();
+        let mut testpath = PathBuf::from(&wd);
+        testpath.push("testdata");
+        testpath.push("test_text_file.txt");
+
+        let mut outdir = PathBuf::from(&wd);
+        outdir.push("testdata");
+        outdir.push("out_scratch");
+        let mut sfpath = outdir.clone();
+        sfpath.push("preamble_test.dat");
+
+        match syncfile::SyncFile::create_syncfile(&conf,&testpath,Some(sfpath.clone())) {
+            Err(e) => panic!("Error {:?}", e),
+            Ok(_) => ()
+        };
+
+        let bytes = util::slurp_bin_file(sfpath.to_str().unwrap());
+        let preamble = format!("GCSF:2:{}\n", crypto_util::KEY_SCHEME_HKDF);
+        assert!(bytes.starts_with(preamble.as_bytes()));
+        assert!(syncfile::SyncFile::from_syncfile(&conf,&sfpath).is_ok());
+
+        // rewriting the preamble must not let the file be read with a different key scheme or format
+        let rewrite = |name:&str, new_preamble:&str| {
+            let mut data:Vec<u8> = new_preamble.as_bytes().to_vec();
+            data.extend(bytes[preamble.len() ..].iter().map(|&b| b));
+            let mut path = outdir.clone();
+            path.push(name);
+            let mut f = File::create(&path).unwrap();
+            f.write_all(&data).unwrap();
+            path
+        };
+
+        let raw = rewrite("preamble_raw.dat", &format!("GCSF:2:{}\n", crypto_util::KEY_SCHEME_RAW));
+        assert!(syncfile::SyncFile::from_syncfile(&conf,&raw).is_err());
+        let cbc = rewrite("preamble_cbc.dat", &format!("GCSF:1:{}\n", crypto_util::KEY_SCHEME_HKDF));
+        assert!(syncfile::SyncFile::from_syncfile(&conf,&cbc).is_err());
+        let unknown = rewrite("preamble_unknown.dat", "GCSF:99:2\n");
+        match syncfile::SyncFile::from_syncfile(&conf,&unknown) {
+            Err(e) => assert!(format!("{}", e).contains("Unsupported syncfile format")),
+            Ok(_) => panic!("Read a syncfile with an unknown format")
+        }
+    }
+
+    #[test]
+    fn keyword_key() {
+        let conf = testlib::util::get_mock_config();
+        let kw_key = crypto_util::new_random_key();
+        let mut kw_keys = HashMap::new();
+        kw_keys.insert("GCPROJROOT".to_owned(), Some(kw_key.clone()));
+        let kw_conf = conf.with_keyword_keys(kw_keys);
+        let wd = env::current_dir().unwrap();
+        let mut testpath = PathBuf::from(&wd);
+        testpath.push("testdata");
+        testpath.push("test_text_file.txt");
+
+        let mut sfpath = PathBuf::from(&wd);
+        sfpath.push("testdata");
+        sfpath.push("out_scratch");
+        sfpath.push("keyword_key_test.dat");
+
+        match syncfile::SyncFile::create_syncfile(&kw_conf,&testpath,Some(sfpath.clone())) {
+            Err(e) => panic!("Error {:?}", e),
+            Ok(_) => ()
+        };
+
+        // the preamble names the key, and the sync id depends on it
+        let bytes = util::slurp_bin_file(sfpath.to_str().unwrap());
+        let preamble = format!("GCSF:2:{}:{}\n", crypto_util::KEY_SCHEME_HKDF, crypto_util::key_id(&kw_key));
+        assert!(bytes.starts_with(preamble.as_bytes()));
+        let sf = syncfile::SyncFile::from_syncfile(&kw_conf,&sfpath).unwrap();
+        assert!(sf.id != syncfile::SyncFile::get_sync_id(&conf, &sf.keyword, &sf.relpath).unwrap());
+        assert!(syncfile::SyncFile::key_available(&kw_conf,&sfpath).unwrap());
+
+        // without the keyword key it can't be read, and the master key doesn't help
+        assert!(!syncfile::SyncFile::key_available(&conf,&sfpath).unwrap());
+        assert!(syncfile::SyncFile::from_syncfile(&conf,&sfpath).is_err());
+        let mut missing = HashMap::new();
+        missing.insert("GCPROJROOT".to_owned(), None);
+        assert!(syncfile::SyncFile::from_syncfile(&conf.with_keyword_keys(missing),&sfpath).is_err());
+
+        // a file written with the master key can't claim a keyword that has its own key
+        let mut master_path = sfpath.clone();
+        master_path.set_file_name("keyword_key_master.dat");
+        syncfile::SyncFile::create_syncfile(&conf,&testpath,Some(master_path.clone())).unwrap();
+        assert!(syncfile::SyncFile::key_available(&kw_conf,&master_path).unwrap());
+        assert!(syncfile::SyncFile::from_syncfile(&kw_conf,&master_path).is_err());
+    }
+
+    #[test]
+    fn signed_syncfile() {
+        let conf = testlib::util::get_mock_config();
+        let signing_key = crypto_util::derive_signing_key(&crypto_util::new_random_key());
+        let signing_public = crypto_util::ed25519_public_key(&signing_key);
+        let mut signers = HashMap::new();
+        signers.insert(conf.host_name.clone(), signing_public.to_vec());
+        let reader_conf = conf.with_signers(signers);
+        let signed_conf = reader_conf.with_signing_key(Some(signing_key));
+        let wd = env::current_dir().unwrap();
+        let mut testpath = PathBuf::from(&wd);
+        testpath.push("testdata");
+        testpath.push("test_text_file.txt");
+
+        let mut sfpath = PathBuf::from(&wd);
+        sfpath.push("testdata");
+        sfpath.push("out_scratch");
+        sfpath.push("signed_syncfile_test.dat");
+        syncfile::SyncFile::create_syncfile(&signed_conf,&testpath,Some(sfpath.clone())).unwrap();
+
+        let bytes = util::slurp_bin_file(sfpath.to_str().unwrap());
+        assert!(bytes.starts_with(format!("GCSF:3:{}\n", crypto_util::KEY_SCHEME_HKDF).as_bytes()));
+        let mut sf = syncfile::SyncFile::from_syncfile(&reader_conf,&sfpath).unwrap();
+        let mut out:Vec<u8> = Vec::new();
+        sf.decrypt_to_writer(&reader_conf, &mut out).unwrap();
+
+        // a host that doesn't trust the signer rejects it
+        assert!(syncfile::SyncFile::from_syncfile(&conf,&sfpath).is_err());
+        let mut other = HashMap::new();
+        other.insert(conf.host_name.clone(), crypto_util::ed25519_public_key(&crypto_util::new_random_key()).to_vec());
+        assert!(syncfile::SyncFile::from_syncfile(&conf.with_signers(other),&sfpath).is_err());
+
+        // or trusts that key under another name
+        let mut renamed = HashMap::new();
+        renamed.insert("otherhost".to_owned(), signing_public.to_vec());
+        assert!(syncfile::SyncFile::from_syncfile(&conf.with_signers(renamed),&sfpath).is_err());
+
+        // an unsigned file can't claim to be from a host that signs
+        let mut unsigned_path = sfpath.clone();
+        unsigned_path.set_file_name("signed_syncfile_unsigned.dat");
+        syncfile::SyncFile::create_syncfile(&conf,&testpath,Some(unsigned_path.clone())).unwrap();
+        assert!(syncfile::SyncFile::from_syncfile(&conf,&unsigned_path).is_ok());
+        assert!(syncfile::SyncFile::from_syncfile(&reader_conf,&unsigned_path).is_err());
+
+        // or from a host that isn't a signer, unless it is a known host that hasn't been enrolled
+        let mut other_conf = conf.clone();
+        other_conf.host_name = "otherhost".to_owned();
+        syncfile::SyncFile::create_syncfile(&other_conf,&testpath,Some(unsigned_path.clone())).unwrap();
+        assert!(syncfile::SyncFile::from_syncfile(&reader_conf,&unsigned_path).is_err());
+        let known = vec![conf.host_name.clone(), "otherhost".to_owned()];
+        assert!(syncfile::SyncFile::from_syncfile(&reader_conf.with_known_hosts(known),&unsigned_path).is_ok());
+    }
+
+    #[test]
+    fn chunk_boundaries() {
+        let conf = testlib::util::get_mock_config();
+        let wd = env::current_dir().unwrap();
+        let mut testpath = PathBuf::from(&wd);
+        testpath.push("testdata");
+        testpath.push("test_binary.png");
+
+        let sf = syncfile::SyncFile::from_native(&conf, testpath.to_str().unwrap()).unwrap();
+
+        // exact multiples of the chunk size get an empty final chunk
+        for size in vec![0, 1, crypto_util::CHUNK_SIZE - 1, crypto_util::CHUNK_SIZE, crypto_util::CHUNK_SIZE * 2, crypto_util::CHUNK_SIZE * 2 + 1] {
+            let mut sfpath = PathBuf::from(&wd);
+            sfpath.push("testdata");
+            sfpath.push("out_scratch");
+            sfpath.push(&format!("chunk_boundaries_{}.dat", size));
+
+            let in_bytes:Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+            sf.save_with_data(&conf, Some(sfpath.clone()), &in_bytes).unwrap();
+
+            let mut rsf = syncfile::SyncFile::from_syncfile(&conf,&sfpath).unwrap();
+            let mut out_bytes:Vec<u8> = Vec::new();
+            rsf.decrypt_to_writer(&conf, &mut out_bytes).unwrap();
+            assert_eq!(in_bytes, out_bytes);
+        }
+    }
+
+    #[test]
+    fn padding() {
+        assert_eq!(syncfile::Padding::parse("none").unwrap(), syncfile::Padding::None);
+        assert_eq!(syncfile::Padding::parse("pow2").unwrap(), syncfile::Padding::PowerOfTwo);
+        assert_eq!(syncfile::Padding::parse("block:4096").unwrap(), syncfile::Padding::Block(4096));
+        assert!(syncfile::Padding::parse("block:0").is_err());
+        assert!(syncfile::Padding::parse("block").is_err());
+        assert!(syncfile::Padding::parse("pow3").is_err());
+        assert_eq!(syncfile::Padding::PowerOfTwo.pad_len(0), 0);
+        assert_eq!(syncfile::Padding::PowerOfTwo.pad_len(5), 3);
+        assert_eq!(syncfile::Padding::PowerOfTwo.pad_len(8), 0);
+        assert_eq!(syncfile::Padding::Block(4096).pad_len(4097), 4095);
+        assert_eq!(syncfile::Padding::Block(4096).pad_len(8192), 0);
+
+        let wd = env::current_dir().unwrap();
+        let mut testpath = PathBuf::from(&wd);
+        testpath.push("testdata");
+        testpath.push("test_binary.png");
+
+        let block = crypto_util::CHUNK_SIZE as u64 + 1000;
+        for padding in vec![syncfile::Padding::PowerOfTwo, syncfile::Padding::Block(block)] {
+            let conf = testlib::util::get_mock_config().with_padding(padding.clone());
+            let sf = syncfile::SyncFile::from_native(&conf, testpath.to_str().unwrap()).unwrap();
+
+            // sizes in the same bucket produce syncfiles of the same size
+            let mut file_sizes = Vec::new();
+            for size in vec![crypto_util::CHUNK_SIZE + 1, crypto_util::CHUNK_SIZE + 900] {
+                let mut sfpath = PathBuf::from(&wd);
+                sfpath.push("testdata");
+                sfpath.push("out_scratch");
+                sfpath.push(&format!("padding_{:?}_{}.dat", padding, size));
+
+                let in_bytes:Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+                sf.save_with_data(&conf, Some(sfpath.clone()), &in_bytes).unwrap();
+                file_sizes.push(util::slurp_bin_file(sfpath.to_str().unwrap()).len());
+
+                let md = syncfile::SyncFile::get_metadata_hash(&conf,&sfpath).unwrap();
+                assert_eq!(md.get("ver").unwrap(), "2");
+                assert_eq!(md.get("pad").unwrap(), &format!("{}", padding.pad_len(size as u64)));
+
+                // reading doesn't depend on the reader's padding setting
+                let reader_conf = conf.with_padding(syncfile::Padding::None);
+                let mut rsf = syncfile::SyncFile::from_syncfile(&reader_conf,&sfpath).unwrap();
+                let mut out_bytes:Vec<u8> = Vec::new();
+                rsf.decrypt_to_writer(&reader_conf, &mut out_bytes).unwrap();
+                assert_eq!(in_bytes, out_bytes);
+            }
+            assert_eq!(file_sizes[0], file_sizes[1]);
+        }
+
+        // unpadded files are unchanged
+        let conf = testlib::util::get_mock_config();
+        let sf = syncfile::SyncFile::from_native(&conf, testpath.to_str().unwrap()).unwrap();
+        let mut sfpath = PathBuf::from(&wd);
+        sfpath.push("testdata");
+        sfpath.push("out_scratch");
+        sfpath.push("padding_none.dat");
+        sf.save_with_data(&conf, Some(sfpath.clone()), &[1,2,3]).unwrap();
+        let md = syncfile::SyncFile::get_metadata_hash(&conf,&sfpath).unwrap();
+        assert_eq!(md.get("ver").unwrap(), "1");
+        assert!(md.get("pad").is_none());
+    }
+
+    #[test]
+    fn metadata_encoding() {
+        let conf = testlib::util::get_mock_config();
+        let wd = env::current_dir().unwrap();
+        let mut testpath = PathBuf::from(&wd);
+        testpath.push("testdata");
+        testpath.push("test_binary.png");
+        let mut sfpath = PathBuf::from(&wd);
+        sfpath.push("testdata");
+        sfpath.push("out_scratch");
+        sfpath.push("metadata_encoding.dat");
+
+        // any relpath round trips, and fields this version doesn't know about are kept
+        let relpath = "/dir/a: b\n\"c\"\t\u{e9}/d:e\r\n.png";
+        let mut sf = syncfile::SyncFile::from_native(&conf, testpath.to_str().unwrap()).unwrap();
+        sf.relpath = relpath.to_owned();
+        sf.extra_metadata.insert("from_the_future".to_owned(), Json::Array(vec![Json::U64(1), Json::Null]));
+        sf.save_with_data(&conf, Some(sfpath.clone()), &[1,2,3]).unwrap();
+
+        let md = syncfile::SyncFile::get_metadata_hash(&conf,&sfpath).unwrap();
+        assert_eq!(md.get("relpath").unwrap(), relpath);
+        assert_eq!(md.get("from_the_future").unwrap(), "[1,null]");
+        let mut rsf = syncfile::SyncFile::from_syncfile(&conf,&sfpath).unwrap();
+        assert_eq!(rsf.relpath, relpath);
+        assert_eq!(rsf.extra_metadata.len(), 1);
+
+        rsf.set_deleted();
+        rsf.save_with_data(&conf, Some(sfpath.clone()), &[]).unwrap();
+        let md = syncfile::SyncFile::get_metadata_hash(&conf,&sfpath).unwrap();
+        assert_eq!(md.get("from_the_future").unwrap(), "[1,null]");
+        assert_eq!(md.get("is_deleted").unwrap(), "true");
+
+        // the old "key: value" form splits on the first ':' only, and values are typed
+        let md = syncfile::parse_metadata("ver: 1\nkw: HOME\nrelpath: /a:b:c.txt\nseq: 4\nis_binary: false\n").unwrap();
+        assert_eq!(md.get("relpath").unwrap(), &Json::String("/a:b:c.txt".to_owned()));
+        assert_eq!(syncfile::md_u64(&md, "seq").unwrap(), Some(4));
+        assert_eq!(syncfile::md_bool(&md, "is_binary").unwrap(), Some(false));
+        assert!(syncfile::md_str(&md, "seq").is_err());
+        assert!(syncfile::parse_metadata("ver: 1\nseq: four\n").is_err());
+        assert!(syncfile::parse_metadata("ver: 1\nnocolon\n").is_err());
+        assert!(syncfile::parse_metadata("[1]").is_err());
+        assert!(syncfile::parse_metadata("{\"ver\": ").is_err());
+    }
+
+    #[test]
+    fn compression() {
+        assert_eq!(syncfile::Compression::parse("none").unwrap(), syncfile::Compression::None);
+        assert_eq!(syncfile::Compression::parse("deflate").unwrap(), syncfile::Compression::Deflate);
+        assert!(syncfile::Compression::parse("zip").is_err());
+
+        let conf = testlib::util::get_mock_config().with_compression(syncfile::Compression::Deflate);
+        let wd = env::current_dir().unwrap();
+        let mut testpath = PathBuf::from(&wd);
+        testpath.push("testdata");
+        testpath.push("test_binary.png");
+        let sf = syncfile::SyncFile::from_native(&conf, testpath.to_str().unwrap()).unwrap();
+
+        // repetitive data spanning several chunks, and the sample, is compressed
+        let size = crypto_util::CHUNK_SIZE * 3 + 17;
+        let compressible:Vec<u8> = (0..size).map(|i| (i % 13) as u8).collect();
+        // this doesn't compress, so it is stored as is
+        let mut x:u32 = 2463534242;
+        let incompressible:Vec<u8> = (0..size).map(|_| { x ^= x << 13; x ^= x >> 17; x ^= x << 5; x as u8 }).collect();
+
+        for (name, in_bytes, compressed) in vec![("yes", compressible, true), ("no", incompressible, false)] {
+            for padding in vec![syncfile::Padding::None, syncfile::Padding::PowerOfTwo] {
+                let conf = conf.with_padding(padding.clone());
+                let mut sfpath = PathBuf::from(&wd);
+                sfpath.push("testdata");
+                sfpath.push("out_scratch");
+                sfpath.push(&format!("compression_{}_{:?}.dat", name, padding));
+                sf.save_with_data(&conf, Some(sfpath.clone()), &in_bytes).unwrap();
+
+                let sf_len = util::slurp_bin_file(sfpath.to_str().unwrap()).len();
+                let md = syncfile::SyncFile::get_metadata_hash(&conf,&sfpath).unwrap();
+                if compressed {
+                    assert!(sf_len < size / 2);
+                    assert_eq!(md.get("ver").unwrap(), "3");
+                    assert_eq!(md.get("compression").unwrap(), "deflate");
+                } else {
+                    assert!(sf_len > size);
+                    assert!(md.get("compression").is_none());
+                }
+
+                // reading doesn't depend on the reader's compression setting
+                let reader_conf = testlib::util::get_mock_config();
+                let mut rsf = syncfile::SyncFile::from_syncfile(&reader_conf,&sfpath).unwrap();
+                let mut out_bytes:Vec<u8> = Vec::new();
+                rsf.decrypt_to_writer(&reader_conf, &mut out_bytes).unwrap();
+                assert!(in_bytes == out_bytes);
+            }
+        }
+
+        // a file that gets shorter while it is being saved (simulated with a reader that ends
+        // early) fails to save, and the previous version of the syncfile is still intact
+        let data:Vec<u8> = (0..size).map(|i| (i % 13) as u8).collect();
+        for c in vec![conf.clone(), conf.with_compression(syncfile::Compression::None)] {
+            let mut sfpath = PathBuf::from(&wd);
+            sfpath.push("testdata");
+            sfpath.push("out_scratch");
+            sfpath.push(&format!("compression_changed_{:?}.dat", c.compression));
+            sf.save_with_data(&c, Some(sfpath.clone()), &data).unwrap();
+            let mut short = BufReader::new(Cursor::new(&data[0 .. size / 2]));
+            assert!(sf.save(&c, &mut short, size as u64, Some(sfpath.clone())).is_err());
+            assert!(!PathBuf::from(format!("{}.gc_tmp", sfpath.to_str().unwrap())).is_file());
+
+            let mut rsf = syncfile::SyncFile::from_syncfile(&c,&sfpath).unwrap();
+            let mut out_bytes:Vec<u8> = Vec::new();
+            rsf.decrypt_to_writer(&c, &mut out_bytes).unwrap();
+            assert!(data == out_bytes);
+        }
+    }
+
+    #[test]
+    fn content_hash() {
+        let conf = testlib::util::get_mock_config();
+        let wd = env::current_dir().unwrap();
+        let mut textpath = PathBuf::from(&wd);
+        textpath.push("testdata");
+        textpath.push("test_text_file.txt");
+        let mut binpath = PathBuf::from(&wd);
+        binpath.push("testdata");
+        binpath.push("test_binary.png");
+        let text = textpath.to_str().unwrap();
+        let bin = binpath.to_str().unwrap();
+
+        let mut sfpath = PathBuf::from(&wd);
+        sfpath.push("testdata");
+        sfpath.push("out_scratch");
+        sfpath.push("content_hash.dat");
+
+        let canon_text = util::canon_lines(&String::from_utf8(util::slurp_bin_file(text)).unwrap());
+        let mut hashes = Vec::new();
+        for &(native, other, size) in &[(text, bin, canon_text.len() as u64), (bin, text, util::slurp_bin_file(bin).len() as u64)] {
+            // streamed or compressed, and before and after the data key is rotated, the hash is
+            // the same
+            let confs = vec![conf.clone(),
+                conf.with_compression(syncfile::Compression::Deflate),
+                conf.with_data_keys(Some(crypto_util::new_random_key()), None)];
+            let mut content = None;
+            for c in &confs {
+                syncfile::SyncFile::create_syncfile(c,&PathBuf::from(native),Some(sfpath.clone())).unwrap();
+                let md = syncfile::SyncFile::get_metadata_hash(c,&sfpath).unwrap();
+                assert_eq!(md.get("content_hash").unwrap().len(), 64);
+                assert_eq!(md.get("size").unwrap(), &size.to_string());
+
+                let sf = syncfile::SyncFile::from_syncfile(c,&sfpath).unwrap();
+                assert_eq!(sf.native_matches(c, native).unwrap(), Some(true));
+                assert_eq!(sf.native_matches(c, other).unwrap(), Some(false));
+                if let Some(ref prev) = content {
+                    assert_eq!(sf.content.as_ref(), Some(prev));
+                }
+                content = sf.content.clone();
+            }
+            hashes.push(content.unwrap());
+        }
+        assert!(hashes[0] != hashes[1]);
+
+        // text is compared in its canonical form
+        let mut crlfpath = sfpath.clone();
+        crlfpath.set_file_name("content_hash_crlf.txt");
+        {
+            let mut f = File::create(&crlfpath).unwrap();
+            f.write_all(canon_text.replace("\n", "\r\n").as_bytes()).unwrap();
+        }
+        syncfile::SyncFile::create_syncfile(&conf,&textpath,Some(sfpath.clone())).unwrap();
+        let sf = syncfile::SyncFile::from_syncfile(&conf,&sfpath).unwrap();
+        assert_eq!(sf.native_matches(&conf, crlfpath.to_str().unwrap()).unwrap(), Some(true));
+        {
+            let mut f = File::create(&crlfpath).unwrap();
+            f.write_all(b"something else\n").unwrap();
+        }
+        assert_eq!(sf.native_matches(&conf, crlfpath.to_str().unwrap()).unwrap(), Some(false));
+    }
+
+    #[test]
+    fn native_attrs() {
+        let wd = env::current_dir().unwrap();
+        let mut testpath = PathBuf::from(&wd);
+        testpath.push("testdata");
+        testpath.push("out_scratch");
+        testpath.push("native_attrs.sh");
+        {
+            let mut f = File::create(&testpath).unwrap();
+            f.write_all(b"#!/bin/sh\n").unwrap();
+        }
+        let native = testpath.to_str().unwrap();
+        let mut attrs = native_attrs::NativeAttrs::none();
+        attrs.mode = Some(0o750);
+        attrs.mtime = Some((1000000000, 0));
+        let all = native_attrs::Preserve::parse("mode,times").unwrap();
+        attrs.apply(&all, native).unwrap();
+
+        let mut sfpath = PathBuf::from(&wd);
+        sfpath.push("testdata");
+        sfpath.push("out_scratch");
+        sfpath.push("native_attrs.dat");
+
+        let conf = testlib::util::get_mock_config().with_preserve(all.clone());
+        syncfile::SyncFile::create_syncfile(&conf,&testpath,Some(sfpath.clone())).unwrap();
+
+        let mut outpath = PathBuf::from(&wd);
+        outpath.push("testdata");
+        outpath.push("out_nativedir");
+        let mapping = format!("gcprojroot = '{}'", outpath.to_str().unwrap());
+        let mapping = toml::Parser::new(&mapping).parse().unwrap();
+        let mapping = mapping::Mapping::new(&mapping).ok().expect("WTF?");
+
+        // a host restores the attributes that it preserves
+        for preserve in vec![all, native_attrs::Preserve::parse("times").unwrap(), native_attrs::Preserve::none()] {
+            let mut rconf = testlib::util::get_mock_config().with_preserve(preserve.clone());
+            rconf.mapping = mapping.clone();
+            let mut sf = syncfile::SyncFile::from_syncfile(&rconf,&sfpath).unwrap();
+            assert_eq!(sf.attrs.mode, Some(0o750));
+            assert_eq!(sf.attrs.mtime, Some((1000000000, 0)));
+            assert!(sf.attrs.xattrs.is_none());
+            assert!(sf.set_nativefile_path(&rconf).is_ok());
+            let outfile = sf.restore_native(&rconf).unwrap();
+
+            let restored = native_attrs::NativeAttrs::read(&native_attrs::Preserve::parse("mode,times").unwrap(), &outfile).unwrap();
+            assert_eq!(restored.mode == Some(0o750), preserve.mode);
+            assert_eq!(restored.mtime == Some((1000000000, 0)), preserve.times);
+        }
+    }
+
+    #[test]
+    #[cfg(not(target_os = "windows"))]
+    fn symlinks() {
+        use std::fs::{create_dir_all,remove_file,read_link};
+        use std::os::unix::fs::symlink;
+        use std::path::Path;
+
+        let conf = testlib::util::get_mock_config();
+        let wd = env::current_dir().unwrap();
+        let mut linkdir = PathBuf::from(&wd);
+        linkdir.push("testdata");
+        linkdir.push("out_scratch");
+        linkdir.push("links");
+        create_dir_all(&linkdir).unwrap();
+        let mut target = linkdir.clone();
+        target.push("target.txt");
+        {
+            let mut f = File::create(&target).unwrap();
+            f.write_all(b"pointed at\n").unwrap();
+        }
+        let link = |name:&str, to:&Path| {
+            let mut l = linkdir.clone();
+            l.push(name);
+            if util::is_file_or_link(&l) {
+                remove_file(&l).unwrap();
+            }
+            symlink(to, &l).unwrap();
+            l
+        };
+        let rel = link("rel", Path::new("target.txt"));
+        let abs = link("abs", &target);
+        let dir = link("dir", Path::new(".."));
+        let escape = link("escape", Path::new("../../../.."));
+
+        // the walk reports links without following them
+        let mut visited = 0;
+        util::visit_dirs(&linkdir, &mut |_| visited = visited + 1).unwrap();
+        assert_eq!(visited, 5);
+
+        assert!(syncfile::SyncFile::read_link_target(&conf, escape.to_str().unwrap()).is_err());
+        assert!(syncfile::SyncFile::from_native(&conf, escape.to_str().unwrap()).is_err());
+        assert_eq!(syncfile::SyncFile::read_link_target(&conf, target.to_str().unwrap()).unwrap(), None);
+
+        let mut outpath = PathBuf::from(&wd);
+        outpath.push("testdata");
+        outpath.push("out_nativedir");
+        let mut rconf = conf.clone();
+        let mapping = format!("gcprojroot = '{}'", outpath.to_str().unwrap());
+        let mapping = toml::Parser::new(&mapping).parse().unwrap();
+        rconf.mapping = mapping::Mapping::new(&mapping).ok().expect("WTF?");
+        let mut out_target = outpath.clone();
+        out_target.push("testdata");
+        out_target.push("out_scratch");
+        out_target.push("links");
+        out_target.push("target.txt");
+
+        let mut sfpath = linkdir.clone();
+        sfpath.set_file_name("symlink.dat");
+        for (l, relpath, relative, restored) in vec![
+                (rel, "/testdata/out_scratch/links/target.txt", true, PathBuf::from("target.txt")),
+                (abs, "/testdata/out_scratch/links/target.txt", false, out_target),
+                (dir, "/testdata/out_scratch", true, PathBuf::from(".."))] {
+            syncfile::SyncFile::create_syncfile(&conf,&l,Some(sfpath.clone())).unwrap();
+            let md = syncfile::SyncFile::get_metadata_hash(&conf,&sfpath).unwrap();
+            assert_eq!(md.get("ver").unwrap(), "4");
+
+            let mut sf = syncfile::SyncFile::from_syncfile(&rconf,&sfpath).unwrap();
+            assert_eq!(sf.link, Some(syncfile::LinkTarget { keyword: "GCPROJROOT".to_owned(), relpath: relpath.to_owned(), relative: relative }));
+            assert_eq!(sf.native_matches(&conf, l.to_str().unwrap()).unwrap(), Some(true));
+            assert_eq!(sf.native_matches(&conf, target.to_str().unwrap()).unwrap(), Some(false));
+
+            // restored through this host's mapping
+            let outfile = sf.restore_native(&rconf).unwrap();
+            assert_eq!(read_link(&outfile).unwrap(), restored);
+        }
+
+        // a file that is no longer a link doesn't match
+        syncfile::SyncFile::create_syncfile(&conf,&target,Some(sfpath.clone())).unwrap();
+        let sf = syncfile::SyncFile::from_syncfile(&conf,&sfpath).unwrap();
+        assert!(sf.link.is_none());
+        let mut rel = linkdir.clone();
+        rel.push("rel");
+        assert_eq!(sf.native_matches(&conf, rel.to_str().unwrap()).unwrap(), Some(false));
+    }
+
+    #[test]
+    fn hostile_input() {
+        assert!(syncfile::relpath_is_safe("/dir/a:b.txt"));
+        for bad in vec!["", "/", "dir/a.txt", "/../a.txt", "/dir/../../a.txt", "//etc/passwd", "/./a", "/a\0b"] {
+            assert!(!syncfile::relpath_is_safe(bad), "{:?}", bad);
+        }
+
+        let conf = testlib::util::get_mock_config();
+        let wd = env::current_dir().unwrap();
+        let mut outdir = PathBuf::from(&wd);
+        outdir.push("testdata");
+        outdir.push("out_scratch");
+        let mut testpath = PathBuf::from(&wd);
+        testpath.push("testdata");
+        testpath.push("test_text_file.txt");
+        let mut sfpath = outdir.clone();
+        sfpath.push("hostile_input.dat");
+        syncfile::SyncFile::create_syncfile(&conf,&testpath,Some(sfpath.clone())).unwrap();
+        let bytes = util::slurp_bin_file(sfpath.to_str().unwrap());
+
+        // none of these may panic, and none are readable
+        let check_fails = |data:&[u8]| {
+            let mut path = outdir.clone();
+            path.push("hostile_input_case.dat");
+            {
+                let mut f = File::create(&path).unwrap();
+                f.write_all(data).unwrap();
+            }
+            let _ = syncfile::SyncFile::key_available(&conf,&path);
+            let _ = syncfile::SyncFile::get_metadata_hash(&conf,&path);
+            let _ = syncfile::SyncFile::get_syncid_from_file(&conf,&path);
+            let mut out:Vec<u8> = Vec::new();
+            let res = syncfile::SyncFile::from_syncfile(&conf,&path).and_then(|mut sf| sf.decrypt_to_writer(&conf, &mut out));
+            assert!(res.is_err(), "read {} bytes of hostile input", data.len());
+        };
+
+        check_fails(&b""[..]);
+        check_fails(&b"\n\n\n\n\n\n"[..]);
+        check_fails(&b"GCSF\n"[..]);
+        check_fails(&b"GCSF:2:2:\n"[..]);
+        check_fails(&b"GCSF:2:2:zz\n"[..]);
+        check_fails(&b"GCSF:4294967296:2\n"[..]);
+        check_fails(&b"a:b:c:d:e\n"[..]);
+        check_fails(&[0xff, 0xfe, b'\n'][..]);
+        let mut long_line = b"GCSF:2:2\n".to_vec();
+        long_line.extend((0 .. syncfile::MAX_HEADER_LINE_LEN + 10).map(|_| b'A'));
+        check_fails(&long_line[..]);
+        for len in 0 .. bytes.len() {
+            check_fails(&bytes[0 .. len]);
+        }
+        let mut flipped = bytes.clone();
+        for i in 0 .. flipped.len() {
+            flipped[i] = flipped[i] ^ 0x41;
+            check_fails(&flipped[..]);
+            flipped[i] = flipped[i] ^ 0x41;
+        }
+    }
+
+    #[test]
+    fn chunk_tampering() {
+        let conf = testlib::util::get_mock_config();
+        let wd = env::current_dir().unwrap();
+        let mut testpath = PathBuf::from(&wd);
+        testpath.push("testdata");
+        testpath.push("test_binary.png");
+
+        let mut outdir = PathBuf::from(&wd);
+        outdir.push("testdata");
+        outdir.push("out_scratch");
+        let mut sfpath = outdir.clone();
+        sfpath.push("chunk_tampering.dat");
+
+        // use three chunks
+        let sf = syncfile::SyncFile::from_native(&conf, testpath.to_str().unwrap()).unwrap();
+        let in_bytes:Vec<u8> = (0..crypto_util::CHUNK_SIZE * 2 + 100).map(|i| (i % 251) as u8).collect();
+        sf.save_with_data(&conf, Some(sfpath.clone()), &in_bytes).unwrap();
+
+        let bytes = util::slurp_bin_file(sfpath.to_str().unwrap());
+        // data starts after the six header lines
+        let data_start = bytes.iter().enumerate().filter(|&(_,b)| *b == b'\n').nth(5).unwrap().0 + 1;
+        let sealed_chunk = crypto_util::CHUNK_SIZE + crypto_util::TAG_SIZE;
+        assert_eq!(bytes.len() - data_start, sealed_chunk * 2 + 100 + crypto_util::TAG_SIZE);
+
+        let check_fails = |name:&str, data:Vec<u8>| {
+            let mut path = outdir.clone();
+            path.push(name);
+            {
+                let mut f = File::create(&path).unwrap();
+                f.write_all(&data).unwrap();
+            }
+            // the header is intact, so the file opens; decryption must fail
+            let mut sf = syncfile::SyncFile::from_syncfile(&conf,&path).unwrap();
+            let mut out:Vec<u8> = Vec::new();
+            assert!(sf.decrypt_to_writer(&conf, &mut out).is_err(), "decrypted tampered file: {}", name);
+        };
+
+        let mut flipped = bytes.clone();
+        flipped[data_start + 10] ^= 1;
+        check_fails("chunk_flipped.dat", flipped);
+
+        let truncated = bytes[0 .. bytes.len() - 1].to_vec();
+        check_fails("chunk_truncated.dat", truncated);
+
+        // drop the final chunk entirely, leaving only full chunks
+        let no_final = bytes[0 .. data_start + sealed_chunk * 2].to_vec();
+        check_fails("chunk_no_final.dat", no_final);
+
+        // swap the first two chunks
+        let mut swapped = bytes[0 .. data_start].to_vec();
+        swapped.extend(bytes[data_start + sealed_chunk .. data_start + sealed_chunk * 2].iter().map(|&b| b));
+        swapped.extend(bytes[data_start .. data_start + sealed_chunk].iter().map(|&b| b));
+        swapped.extend(bytes[data_start + sealed_chunk * 2 ..].iter().map(|&b| b));
+        check_fails("chunk_swapped.dat", swapped);
+
+        let mut extra = bytes.clone();
+        extra.push(0);
+        check_fails("chunk_extra.dat", extra);
+    }
+
     #[test]
     fn deleted() {
         let conf = testlib::util::get_mock_config();
@@ -1045,6 +2618,8 @@ mod tests {
             readit(syncpath)
         };
         let start_revguid = sf.revguid;
+        // written before seq existed
+        assert_eq!(sf.seq, 0);
 
         let mut syncpath = PathBuf::from(&wd);
         syncpath.push("testdata");
@@ -1063,6 +2638,8 @@ mod tests {
         assert!(sf.is_deleted);
         assert!(start_revguid != sf.revguid);
         assert_eq!(new_revguid, sf.revguid);
+        // deleting is a change too
+        assert_eq!(sf.seq, 1);
 
         // should have no data
         let mut data:Vec<u8> = Vec::new();
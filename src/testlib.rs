@@ -9,6 +9,8 @@ pub mod util {
 
 	use config;
     use core;
+    use crypto_util;
+    use kdf;
     use logging;
     use mapping;
     use syncdb;
@@ -62,7 +64,7 @@ pub mod util {
         syncdb_dir.push("testdata");
         syncdb_dir.push("out_syncdb");
 
-        let ek: [u8;config::KEY_SIZE] = [0; config::KEY_SIZE];
+        let ek = crypto_util::SecretKey::zeroed();
 
         let conf = config::SyncConfig::new(
             outpath.to_str().unwrap().to_owned(),
@@ -147,7 +149,7 @@ pub mod util {
         let mapping = toml::Parser::new(&mapping).parse().unwrap();
         let mapping = mapping::Mapping::new(&mapping).ok().expect("WTF?");
 
-        let ek: [u8;config::KEY_SIZE] = [0; config::KEY_SIZE];
+        let ek = crypto_util::SecretKey::zeroed();
 
         let conf = config::SyncConfig::new(
             sync_dir.to_owned(),
@@ -239,7 +241,7 @@ pub mod util {
     //  the mtime for each native file matches the mtime in the syncdb
     pub fn verify_sync_state(mconf: &mut MetaConfig, expected_syncfiles: usize, expected_nativefiles: usize) {
         // find all the syncfiles
-        let syncfiles = find_all_files(mconf.state.conf.sync_dir());
+        let syncfiles = core::find_syncfile_paths(mconf.state.conf.sync_dir());
         // verify that the number found == expected
         assert_eq!(syncfiles.len(), expected_syncfiles);
 
@@ -251,7 +253,7 @@ pub mod util {
         };
 
         let verify_sync_entry = |syncdb: &mut syncdb::SyncDb, sf: &syncfile::SyncFile| {
-            let entry = syncdb.get(sf);
+            let entry = syncdb.get(sf).unwrap();
             match entry {
                 None => panic!("Syncdb should have an entry, but has none"),
                 Some(entry) => {
@@ -313,9 +315,21 @@ pub mod util {
         assert_eq!(nfiles.len(), expected_nativefiles);
     }
 
+    // cheap, to keep the tests fast
+    pub fn test_kdf() -> kdf::KdfAlgorithm {
+        kdf::KdfAlgorithm::Scrypt { log_n: 10, r: 8, p: 1 }
+    }
+
     pub fn basic_alice_bob_setup(testname:&str) -> (MetaConfig, MetaConfig) {
         let dirs = init_test_directories(testname);
         let (mut alice_mconf, mut bob_mconf) = config_alice_and_bob(&dirs);
+
+        // the shared sync dir needs a manifest, like a real one
+        let manifest = kdf::KdfManifest::create(test_kdf(), "swordfish", None, alice_mconf.state.conf.encryption_key.as_ref().unwrap());
+        match manifest.save(&dirs.sync_dir) {
+            Err(e) => panic!("Failed to write test KDF manifest: {}", e),
+            Ok(_) => ()
+        }
     
         // populate alice's native directory
         populate_native(&dirs.alice_native, Some("docs"));
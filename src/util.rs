@@ -1,6 +1,6 @@
 use std::io;
 use std::fs::{self, PathExt};
-use std::path::{Path, PathBuf};
+use std::path::{Path, PathBuf, Component};
 use std::collections::BTreeMap;
 use std::collections::HashMap;
 use std::process::Command;
@@ -12,7 +12,7 @@ use std::env;
 use std::fs::File;
 use std::io::{Read,BufRead,Result};
 
-use std::fs::{metadata};
+use std::fs::{symlink_metadata};
 #[cfg(target_os = "windows")]
 use std::os::windows::fs::MetadataExt;
 
@@ -24,11 +24,13 @@ pub fn make_err<T> (m:&str) -> Result<T> {
 
 // From: https://doc.rust-lang.org/stable/std/fs/fn.read_dir.html
 // walk_dir unstable, avoiding it for now
+// Symlinks are passed to file_cb rather than followed, so that a link to a directory doesn't
+// duplicate its contents.
 pub fn visit_dirs(dir: &Path, file_cb: &mut FnMut(&PathBuf)) -> io::Result<()> {
     if dir.is_dir() {
         for entry in try!(fs::read_dir(dir)) {
             let entry = try!(entry);
-            if entry.path().is_dir() {
+            if try!(symlink_metadata(&entry.path())).is_dir() {
                 try!(visit_dirs(&entry.path(), file_cb));
             } else {
                 file_cb(&entry.path());
@@ -38,6 +40,46 @@ pub fn visit_dirs(dir: &Path, file_cb: &mut FnMut(&PathBuf)) -> io::Result<()> {
     Ok(())
 }
 
+// Like Path::is_file(), but also true for a symlink, whatever it points to.
+pub fn is_file_or_link(p:&Path) -> bool {
+    match symlink_metadata(p) {
+        Err(_) => false,
+        Ok(md) => md.is_file() || md.file_type().is_symlink()
+    }
+}
+
+// Resolve "." and ".." in p without looking at the file system.
+pub fn normalize_path(p:&Path) -> PathBuf {
+    let mut out = PathBuf::new();
+    for c in p.components() {
+        match c {
+            Component::CurDir => (),
+            Component::ParentDir => { out.pop(); },
+            c => out.push(c.as_os_str())
+        }
+    }
+    out
+}
+
+// The relative path from the directory base to target, which must both be absolute and
+// normalized.
+pub fn relative_path(base:&Path, target:&Path) -> PathBuf {
+    let base:Vec<Component> = base.components().collect();
+    let target:Vec<Component> = target.components().collect();
+    let common = base.iter().zip(target.iter()).take_while(|&(b,t)| b == t).count();
+    let mut out = PathBuf::new();
+    for _ in common .. base.len() {
+        out.push("..");
+    }
+    for c in &target[common ..] {
+        out.push(c.as_os_str());
+    }
+    if out.as_os_str().is_empty() {
+        out.push(".");
+    }
+    out
+}
+
 fn slurp_file<T,F>(fname:&str, slurper_fn: F ) -> T
     where F: Fn(File) -> Result<T> {
     let f = File::open(fname);
@@ -147,16 +189,18 @@ pub fn canon_lines(s:&str) -> String {
 }
 
 // TODO: should just use serialization
-pub fn string_lines_to_hashmap(lines:Vec<&str>) -> HashMap<String,String> {
+pub fn string_lines_to_hashmap(lines:Vec<&str>) -> Result<HashMap<String,String>> {
     let mut hm:HashMap<String,String> = HashMap::new();
     for l in lines {
-        // TODO: Actually, this should just split on the first ":"
-        let parts:Vec<&str> = l.split(':').collect();
+        let parts:Vec<&str> = l.splitn(2, ':').collect();
+        if parts.len() != 2 {
+            return make_err(&format!("Expected 'key: value', got: {:?}", l));
+        }
         let k = parts[0].trim();
         let v = parts[1].trim();
         hm.insert(k.to_lowercase(),v.to_owned());
     }
-    hm
+    Ok(hm)
 }
 
 #[cfg(target_os = "windows")]
@@ -174,15 +218,16 @@ pub fn decanon_path(p:&str) -> String {
     fixpath(p)
 }
 
+// These give the mtime of a symlink itself, not of what it points to.
 #[cfg(target_os = "windows")]
 pub fn get_file_mtime(path:&str) -> io::Result<u64> {
-    let md = try!(metadata(&path));
+    let md = try!(symlink_metadata(&path));
     Ok(md.last_write_time())
 }
 
 #[cfg(not(target_os = "windows"))]
 pub fn get_file_mtime(path:&str) -> io::Result<u64> {
-    let md = try!(metadata(&path));
+    let md = try!(symlink_metadata(&path));
     let mtime = md.mtime();
     if mtime < 0 {
         panic!("Unexpected mtime, < 0: {:?}", mtime)
@@ -240,9 +285,19 @@ pub fn file_is_binary(fpath:&str) -> io::Result<bool> {
 #[cfg(test)]
 mod tests {
     use std::env;
-    use std::path::{PathBuf};
+    use std::path::{Path,PathBuf};
     use util;
 
+    #[test]
+    fn path_arithmetic() {
+        assert_eq!(util::normalize_path(Path::new("/a/./b/../c/")), PathBuf::from("/a/c"));
+        assert_eq!(util::normalize_path(Path::new("/a/../../b")), PathBuf::from("/b"));
+        assert_eq!(util::relative_path(Path::new("/a/b"), Path::new("/a/b/c")), PathBuf::from("c"));
+        assert_eq!(util::relative_path(Path::new("/a/b"), Path::new("/a/c/d")), PathBuf::from("../c/d"));
+        assert_eq!(util::relative_path(Path::new("/a/b"), Path::new("/x")), PathBuf::from("../../x"));
+        assert_eq!(util::relative_path(Path::new("/a/b"), Path::new("/a/b")), PathBuf::from("."));
+    }
+
     #[test]
     fn file_slurp() {
         let wd = env::current_dir().unwrap();